@@ -34,7 +34,7 @@ export default function Home() {
 	const keyDown = (event: React.KeyboardEvent) => {
 		console.log("pressed something");
 		if (inputValue.length > 0 && event.key == "Enter") {
-			sendMsg.mutate({ channel_id: selectedChannel, content: inputValue });
+			sendMsg.mutate({ channel_id: selectedChannel, content: inputValue, recipient: null });
 			console.log("channelid: ", selectedChannel);
 		}
 	};
@@ -9,6 +9,7 @@ import { LoginContext } from "../../components/contexts/loginContext";
 
 export default function SignIn() {
 	const [username, setUsername] = useState("");
+	const [password, setPassword] = useState("");
 	const { setIsLoggedIn } = useContext(LoginContext);
 	const [isLoading, setIsLoading] = useState(false);
 
@@ -20,7 +21,7 @@ export default function SignIn() {
 		event.preventDefault();
 		setIsLoading(true);
 		mutate(
-			{ username: username },
+			{ username: username, passphrase: password },
 			{
 				onSuccess: (loginSuccess) => {
 					console.log(loginSuccess);
@@ -59,6 +60,16 @@ export default function SignIn() {
 							className="py-1 px-1 rounded bg-darkBackground w-full"
 						/>
 					</label>
+					<label>
+						Password
+						<br />
+						<input
+							required
+							onChange={(e) => setPassword(e.target.value)}
+							type="password"
+							className="py-1 px-1 rounded bg-darkBackground w-full"
+						/>
+					</label>
 					<button
 						type="submit"
 						className="py-2 hover:bg-darkHover rounded drop-shadow-lg bg-darkBackground text-white"
@@ -2,6 +2,7 @@
 
 import { LoginContext } from "@/components/contexts/loginContext";
 import { useRspc } from "@/integration";
+import { AccountKeyAlgorithm } from "@/integration/bindings";
 import { useRouter } from "next/navigation";
 import { useContext, useState } from "react";
 
@@ -9,6 +10,7 @@ export default function SignUp() {
 	const [password, setPassword] = useState("");
 	const [username, setUsername] = useState("");
 	const [confirmPassword, setConfirmPassword] = useState("");
+	const [algorithm, setAlgorithm] = useState<AccountKeyAlgorithm>("Ed25519");
 	const [passwordsMatch, setPasswordsMatch] = useState(true);
 	const [isLoading, setIsLoading] = useState(false);
 	const { setIsLoggedIn } = useContext(LoginContext);
@@ -24,7 +26,7 @@ export default function SignUp() {
 
 		if (passwordsMatch) {
 			createAccount.mutate(
-				{ username: username, passphrase: password },
+				{ username: username, passphrase: password, algorithm: algorithm },
 				{
 					onSuccess: (loginSuccess) => {
 						console.log(loginSuccess);
@@ -87,6 +89,19 @@ export default function SignUp() {
 							className="py-1 px-1 rounded bg-darkBackground w-full"
 						/>
 					</label>
+					<label>
+						Key Type
+						<br />
+						<select
+							value={algorithm}
+							onChange={(e) => setAlgorithm(e.target.value as AccountKeyAlgorithm)}
+							className="py-1 px-1 rounded bg-darkBackground w-full"
+						>
+							<option value="Ed25519">Ed25519 (recommended)</option>
+							<option value="Rsa3072">RSA 3072</option>
+							<option value="Rsa4096">RSA 4096</option>
+						</select>
+					</label>
 					{!passwordsMatch && <p className="text-red-500">Passwords do not match</p>}
 					<button
 						type="submit"
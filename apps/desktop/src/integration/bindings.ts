@@ -10,16 +10,18 @@ export type Procedures = {
     mutations: 
         { key: "account.create", input: CreateAccountArgs, result: null } | 
         { key: "account.login", input: LoginArgs, result: null } | 
+        { key: "account.logout", input: never, result: null } | 
         { key: "channel.create", input: CreateChannelArgs, result: Channel } | 
+        { key: "channel.read", input: ReadMessagesArgs, result: null } | 
         { key: "channel.send", input: SendMessageArgs, result: null } | 
         { key: "settings.theme", input: Theme, result: null },
     subscriptions: 
         { key: "event", input: never, result: Event }
 };
 
-export type LoginArgs = { username: string }
+export type LoginArgs = { username: string; passphrase: string }
 
-export type Event = "Tick" | "NotConnected" | { MessageReceived: { author: string; channel_id: string; content: string } }
+export type Event = "Tick" | "NotConnected" | { MessageReceived: { author: string; channel_id: string; content: string; message_id: string } } | { MessageDelivered: { message_id: string } } | { MessageRead: { message_id: string } } | { MessageSynced: { recipient: string; channel_id: string; content: string } }
 
 export type CreateChannelArgs = { title: string }
 
@@ -28,7 +30,12 @@ export type User = { id: number[]; username: string }
 /**
  * Send a message to the network.
  */
-export type SendMessageArgs = { channel_id: number; content: string }
+export type SendMessageArgs = { channel_id: number; content: string; recipient: string | null }
+
+/**
+ * Tell the author of messages that they were read.
+ */
+export type ReadMessagesArgs = { author: string; message_ids: string[] }
 
 export type Channel = { id: number; title: string }
 
@@ -37,6 +44,11 @@ export type Channel = { id: number; title: string }
  */
 export type Theme = "Light" | "Dark"
 
-export type CreateAccountArgs = { username: string; passphrase: string }
+export type CreateAccountArgs = { username: string; passphrase: string; algorithm: AccountKeyAlgorithm }
+
+/**
+ * The algorithm of the key of a new account.
+ */
+export type AccountKeyAlgorithm = "Ed25519" | "Rsa3072" | "Rsa4096"
 
-export type Message = { id: number; content: string; timestamp: string; authorId: number[]; channelId: number }
+export type Message = { id: number; messageId: string | null; content: string; timestamp: string; deliveredAt: string | null; readAt: string | null; authorId: number[]; channelId: number }
@@ -0,0 +1,7 @@
+-- AlterTable
+ALTER TABLE "Message" ADD COLUMN "messageId" TEXT;
+ALTER TABLE "Message" ADD COLUMN "deliveredAt" DATETIME;
+ALTER TABLE "Message" ADD COLUMN "readAt" DATETIME;
+
+-- CreateIndex
+CREATE INDEX "Message_messageId_idx" ON "Message"("messageId");
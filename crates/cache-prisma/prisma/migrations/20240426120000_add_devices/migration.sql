@@ -0,0 +1,10 @@
+-- CreateTable
+CREATE TABLE "Device" (
+    "id" BLOB NOT NULL PRIMARY KEY,
+    "userId" BLOB NOT NULL,
+    "name" TEXT NOT NULL,
+    CONSTRAINT "Device_userId_fkey" FOREIGN KEY ("userId") REFERENCES "User" ("id") ON DELETE RESTRICT ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE INDEX "Device_userId_idx" ON "Device"("userId");
@@ -1,27 +1,48 @@
 //! Handles the Double-Ratchet (DR) key exchange for communications
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, Payload},
+    ChaCha20Poly1305,
+};
 use double_ratchet_rs::{Header, Ratchet};
-use nom::combinator::map;
 use pgp::{
-    composed::{SignedPublicKey, SignedSecretKey},
+    composed::SignedPublicKey,
     crypto::hash::HashAlgorithm,
-    ser::Serialize,
-    types::{mpi, KeyTrait, PublicKeyTrait, SecretKeyTrait},
+    types::{KeyTrait, PublicKeyTrait},
     Deserializable,
 };
-use rand::rngs::OsRng;
+use rand::{rngs::OsRng, RngCore};
 use sha2::{Digest, Sha256};
 use std::{
     collections::{hash_map::Entry, HashMap},
-    fmt,
-    io::Cursor,
+    fmt, fs,
+    io::{self, Cursor},
     net::SocketAddr,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    time::{Instant, SystemTime},
+};
+use string_protocol::{
+    crypto,
+    prost::{DecodeError, Message},
 };
-use string_protocol::crypto;
 use thiserror::Error;
-use tracing::debug;
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
 use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::{
+    device::{verify_certificate, DeviceError, Devices},
+    group::{GroupKeyError, GroupSession},
+    key::{parse_signature, UnlockedKey},
+    reset::{check_reset, session_reset, SessionHealth, SessionResetError},
+    rotation::{verify_statement, KeyStatementError, KeyStatements, VerifiedStatement},
+    safety::SafetyNumber,
+    socket::SocketEvent,
+    x3dh::{Prekeys, X3dhError},
+};
 
 #[derive(Error, Debug)]
 pub enum DoubleRatchetError {
@@ -31,21 +52,102 @@ pub enum DoubleRatchetError {
     // We should not have this error
     #[error("Ratchet not initialised")]
     MissingRatchet,
-    // Currently generic, todo make more specific
-    #[error("Ciphertext is bad")]
-    BadCiphertext,
+    #[error("Key exchange carries a bad pubkey")]
+    BadKexPubkey,
+    // Only an initiator, or a responder that handled the key exchange, sends one
+    #[error("Ratchet has no key exchange to send")]
+    NoKeyExchange,
+    #[error("Ciphertext is malformed")]
+    MalformedCiphertext(#[from] DecodeError),
+    #[error("Unsupported ciphertext version {0}")]
+    UnsupportedVersion(u32),
+    #[error("Ciphertext header is bad")]
+    BadHeader,
+    #[error("Ciphertext nonce is bad")]
+    BadNonce,
+    // Too many message keys would be skipped to reach the message
+    #[error("Ciphertext skips too many messages")]
+    TooManySkipped,
+    // The ciphertext was tampered with, or is not for this session
+    #[error("Failed to decrypt ciphertext")]
+    DecryptFail,
+    // A stored ratchet could not be restored
+    #[error("Stored ratchet is bad")]
+    BadStoredRatchet,
+    // Ratchet restored from before sessions were bound to identities
+    #[error("Ratchet is not bound to identities")]
+    UnboundSession,
+}
+
+/// Domain separation for the associated data of a ratchet, which also versions it.
+const ASSOCIATED_DATA_LABEL: &[u8] = b"string DR v1";
+
+/// The version of the [crypto::v1::RatchetCiphertext] envelope written by
+/// [DoubleRatchet::encrypt].
+pub const RATCHET_CIPHERTEXT_VERSION: u32 = 1;
+
+/// The size of the nonce of a ratchet ciphertext, in bytes.
+const RATCHET_NONCE_SIZE: usize = 12;
+
+/// How many message keys a single ciphertext may make us skip, on the chain it was sent with and
+/// on the chain before it. Ciphertexts further ahead are dropped before they reach the ratchet.
+pub const MAX_SKIPPED_MESSAGE_KEYS: u64 = 100;
+
+/// The version of the session store written by [Crypto::save]. Version 2 keys sessions by node
+/// ID instead of username.
+pub const SESSION_STORE_VERSION: u32 = 2;
+
+/// The size of the salt the session store key is derived with, in bytes.
+const SESSION_SALT_SIZE: usize = 16;
+
+/// The size of the nonce the session store is encrypted with, in bytes.
+const SESSION_NONCE_SIZE: usize = 12;
+
+#[derive(Error, Debug)]
+pub enum SessionStoreError {
+    #[error("Failed to read or write the session store")]
+    IoFail(#[from] io::Error),
+    #[error("Session store is malformed")]
+    DecodeFail(#[from] DecodeError),
+    #[error("Unsupported session store version")]
+    UnsupportedVersion,
+    #[error("Failed to derive the session store key")]
+    KeyDerivationFail,
+    /// Also returned when the passphrase is wrong, or the store belongs to another key
+    #[error("Failed to encrypt or decrypt the session store")]
+    CipherFail,
+    #[error("Failed to restore a stored session")]
+    BadSession(#[from] DoubleRatchetError),
+    #[error("Failed to restore the stored prekeys")]
+    BadPrekeys(#[from] X3dhError),
+    #[error("Failed to restore a stored group session")]
+    BadGroup(#[from] GroupKeyError),
+    #[error("Failed to restore a stored key statement")]
+    BadKeyStatement(#[from] KeyStatementError),
+    #[error("Failed to restore a stored device certificate")]
+    BadDeviceCertificate(#[from] DeviceError),
 }
 
 #[derive(Error, Debug)]
 pub enum SigningError {
     #[error("Fingerprint mismatch")]
     FingerprintMismatch,
+    #[error("Username mismatch")]
+    UsernameMismatch,
     #[error("Generic PGP Error")]
     PgpError(#[from] pgp::errors::Error),
     #[error("Missing public key for node")]
     MissingPubKey,
     #[error("MPI parsing went wrong")]
     MpiFail,
+    #[error("Secret key is locked")]
+    Locked,
+    #[error("Wrong passphrase for secret key")]
+    BadPassphrase,
+    #[error("Key has been revoked")]
+    Revoked,
+    #[error("Unsupported key algorithm")]
+    UnsupportedAlgorithm,
 }
 
 /// Key exchange process happens as follows:
@@ -73,6 +175,18 @@ pub enum SigningError {
 ///    Both sides are now initialized.
 ///
 /// 8. Alice sends any encrypted message to Bob so Bob's ratchet can encrypt too
+///
+/// Every ciphertext is bound to the fingerprints of Alice and Bob and to the session ID, a hash of
+/// both DH pubkeys, through the associated data of the ratchet. A packet decrypted in one session
+/// can therefore not be replayed into another.
+///
+/// When Bob is offline, Alice can skip steps 1 to 3 by deriving SK from Bob's prekey bundle
+/// instead, see [crate::x3dh]. She sends her first packet encrypted under it, and Bob starts
+/// from step 3 with the same SK whenever he receives it.
+///
+/// If Alice and Bob both start at step 1 at the same time, each receives the other's DH pubkey
+/// while still an initiator. The one with the lower fingerprint stays Alice and ignores it, and
+/// the other becomes Bob and carries on from step 2, see [DoubleRatchet::resolve_glare].
 
 /// An enum to handle the Double-Ratchet (DR) key exchange for communications.
 pub enum DoubleRatchet {
@@ -80,6 +194,12 @@ pub enum DoubleRatchet {
     Initiator { dh_privkey: StaticSecret },
     /// A responder to a key exchange.
     Responder { dh_privkey: StaticSecret },
+    /// An initiator that derived the shared secret from a prekey bundle, waiting on the DR pubkey
+    /// of the responder.
+    PrekeyInitiator {
+        shared_secret: Zeroizing<[u8; 32]>,
+        session_id: Vec<u8>,
+    },
     /// Intermediate state to complete key exchange
     /// When we call init_bob, it returns a DR pubkey and completes the ratchet
     /// We need to send this DR pubkey back to Alice before counting
@@ -88,60 +208,680 @@ pub enum DoubleRatchet {
         ratchet: Ratchet,
         dh_privkey: StaticSecret,
         dr_pubkey: PublicKey,
+        associated_data: Vec<u8>,
     },
     /// An initialized DoubleRatchet object, with associated data.
     Initialized {
         ratchet: Ratchet,
         associated_data: Vec<u8>,
+        receiving: ReceivingChain,
     },
 }
 
+/// How far the sending chains of the peer have reached us, which bounds how many message keys a
+/// ciphertext can make the ratchet skip before it is authenticated.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReceivingChain {
+    /// The DR pubkey of the peer we last received with, and the number of the next message
+    /// expected with it. Unknown for ratchets stored before it was tracked.
+    current: Option<([u8; 32], u64)>,
+    /// The DR pubkey of the peer before it, whose late messages use skipped keys.
+    previous: Option<[u8; 32]>,
+}
+
+impl ReceivingChain {
+    /// Check that a message sent with the given DR pubkey and numbers skips at most
+    /// [MAX_SKIPPED_MESSAGE_KEYS] keys.
+    fn check(
+        &self,
+        dr_pubkey: &[u8; 32],
+        previous_chain_length: u64,
+        message_number: u64,
+    ) -> Result<(), DoubleRatchetError> {
+        let skipped = match self.current {
+            // the first chain of the peer, which may start past messages that were lost
+            None => previous_chain_length.max(message_number),
+            Some((current, next)) if current == *dr_pubkey => message_number.saturating_sub(next),
+            Some(_) if self.previous == Some(*dr_pubkey) => 0,
+            // a new chain, which skips the rest of the current one and the start of the new one
+            Some((_, next)) => previous_chain_length
+                .saturating_sub(next)
+                .max(message_number),
+        };
+        if skipped > MAX_SKIPPED_MESSAGE_KEYS {
+            return Err(DoubleRatchetError::TooManySkipped);
+        }
+        Ok(())
+    }
+
+    /// Returns true if a message sent with the given DR pubkey and number may still arrive: it is
+    /// on the current chain past the messages we received, or on a new chain. Late messages of
+    /// the previous chain only ever use skipped keys. A new chain is expected whatever it claims
+    /// to follow, since that is how the chain of a sender that lost its state looks.
+    fn expects(&self, dr_pubkey: &[u8; 32], message_number: u64) -> bool {
+        match self.current {
+            None => true,
+            Some((current, next)) if current == *dr_pubkey => message_number >= next,
+            Some(_) => self.previous != Some(*dr_pubkey),
+        }
+    }
+
+    /// Record that a message sent with the given DR pubkey and number was decrypted.
+    fn advance(&mut self, dr_pubkey: &[u8; 32], message_number: u64) {
+        let next = message_number.saturating_add(1);
+        match &mut self.current {
+            Some((current, expected)) if current == dr_pubkey => {
+                *expected = (*expected).max(next);
+            }
+            Some(_) if self.previous == Some(*dr_pubkey) => {}
+            current => {
+                self.previous = current.map(|(previous, _)| previous);
+                *current = Some((*dr_pubkey, next));
+            }
+        }
+    }
+}
+
+/// Parse a [crypto::v1::RatchetCiphertext] into the header, nonce and ciphertext the ratchet
+/// decrypts, without trusting any of its fields.
+fn parse_ciphertext(
+    data: &[u8],
+) -> Result<(Header, [u8; RATCHET_NONCE_SIZE], Vec<u8>), DoubleRatchetError> {
+    let envelope = crypto::v1::RatchetCiphertext::decode(data)?;
+    if envelope.version != RATCHET_CIPHERTEXT_VERSION {
+        return Err(DoubleRatchetError::UnsupportedVersion(envelope.version));
+    }
+    let dr_pubkey: [u8; 32] = envelope
+        .dr_pubkey
+        .as_slice()
+        .try_into()
+        .map_err(|_| DoubleRatchetError::BadHeader)?;
+    let header = Header {
+        public_key: PublicKey::from(dr_pubkey),
+        pn: envelope
+            .previous_chain_length
+            .try_into()
+            .map_err(|_| DoubleRatchetError::BadHeader)?,
+        n: envelope
+            .message_number
+            .try_into()
+            .map_err(|_| DoubleRatchetError::BadHeader)?,
+    };
+    let nonce = envelope
+        .nonce
+        .as_slice()
+        .try_into()
+        .map_err(|_| DoubleRatchetError::BadNonce)?;
+    Ok((header, nonce, envelope.ciphertext))
+}
+
 #[derive(Debug)]
 pub enum PgpPubKey {
     PeerUninit { fingerprint: Vec<u8> },
-    NodeUninit { reply_to: Vec<SocketAddr> },
     Initialized { pubkey: SignedPublicKey },
 }
 
+/// Returns the ID a node is addressed by, the hex encoded fingerprint of its key. Unlike a
+/// username, which anyone can put in their key, only the holder of the key can sign for its ID.
+pub fn node_id(fingerprint: &[u8]) -> String {
+    hex::encode(fingerprint)
+}
+
 pub struct Crypto {
+    /// Ratchet sessions with other nodes, by node ID
     pub ratchets: HashMap<String, DoubleRatchet>,
+    /// Public keys of other nodes, by node ID
     pub pubkeys: HashMap<String, PgpPubKey>,
-    /// Our private key
-    pub secret_key: SignedSecretKey,
+    /// The IDs of the public keys claiming each username
+    usernames: HashMap<String, Vec<String>>,
+    /// Group sessions of the channels we are a member of, used for channel-scoped broadcasts
+    pub groups: HashMap<String, GroupSession>,
+    /// Our private key, unlocked
+    pub key: UnlockedKey,
+    /// Our identity key and prekeys, which let others start sessions with us while we are offline
+    pub prekeys: Prekeys,
+    /// The fingerprints of the contacts the user verified out of band, by node ID. A contact is
+    /// only verified while its key still has that fingerprint.
+    verified: HashMap<String, Vec<u8>>,
+    /// The rotations and revocations of keys we know of
+    pub statements: KeyStatements,
+    /// The devices of the identities we know of, ours included
+    pub devices: Devices,
+    /// How the ratchet with each node is doing, which tells when to reset it
+    health: SessionHealth,
+    /// Where key changes are published, if anyone is interested
+    events: Option<broadcast::Sender<SocketEvent>>,
 }
 
 impl Crypto {
-    pub fn new(secret_key: SignedSecretKey) -> Self {
+    pub fn new(key: UnlockedKey) -> Self {
         Self {
             ratchets: HashMap::new(),
             pubkeys: HashMap::new(),
-            secret_key,
+            usernames: HashMap::new(),
+            groups: HashMap::new(),
+            key,
+            prekeys: Prekeys::generate(),
+            verified: HashMap::new(),
+            statements: KeyStatements::default(),
+            devices: Devices::default(),
+            health: SessionHealth::default(),
+            events: None,
+        }
+    }
+
+    /// Publish a [SocketEvent::KeyChanged] whenever a new key claims the username of a contact,
+    /// and the other key events when keys are rotated or revoked, or devices are added.
+    pub fn set_event_sender(&mut self, events: broadcast::Sender<SocketEvent>) {
+        self.events = Some(events);
+    }
+
+    fn publish(&self, event: SocketEvent) {
+        if let Some(ref events) = self.events {
+            // nobody may be listening, which is fine
+            let _ = events.send(event);
         }
     }
 
+    /// Verify and apply a key rotation or revocation. Returns false if we already knew of it, or
+    /// it is void, in which case it should not be passed on.
+    ///
+    /// Once a key is rotated, its ratchet is dropped, since the node only speaks with its new
+    /// key. Whether the contact was verified carries over to the new key, which the old one
+    /// vouched for. Once a key is revoked, its ratchet is dropped too, and nothing it signs is
+    /// trusted any more.
+    pub fn apply_key_statement(
+        &mut self,
+        signed: &crypto::v1::SignedKeyStatement,
+    ) -> Result<bool, KeyStatementError> {
+        let statement = verify_statement(signed)?;
+        if !self.statements.insert(&statement, signed.clone()) {
+            return Ok(false);
+        }
+        let subject = node_id(&statement.subject().fingerprint());
+        let username = Crypto::get_pubkey_username(statement.subject().clone());
+        self.ratchets.remove(&subject);
+        match statement {
+            VerifiedStatement::Rotation { previous, next, .. } => {
+                let verified = self.is_verified(&subject);
+                let fingerprint = next.fingerprint();
+                let id = self.add_pubkey(next)?;
+                if verified {
+                    self.verified.insert(id.clone(), fingerprint.clone());
+                }
+                debug!(username, previous = subject, id, "key rotated");
+                self.publish(SocketEvent::KeyRotated {
+                    username,
+                    previous: previous.fingerprint(),
+                    fingerprint,
+                    verified,
+                });
+            }
+            VerifiedStatement::Revocation { pubkey, reason, .. } => {
+                // a revoked device is retired, and a revoked identity takes its devices with it
+                self.devices.remove(&subject);
+                match self.verified.remove(&subject).is_some() {
+                    true => warn!(username, id = subject, reason, "verified key revoked"),
+                    false => debug!(username, id = subject, reason, "key revoked"),
+                }
+                self.publish(SocketEvent::KeyRevoked {
+                    username,
+                    fingerprint: pubkey.fingerprint(),
+                    reason,
+                });
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns the node ID of the current key of the node with the given ID, following its
+    /// rotations, which gossip to it should be addressed to and encrypted for. Fails if any key
+    /// along the way was revoked.
+    pub fn resolve_id(&self, id: &str) -> Result<String, KeyStatementError> {
+        self.statements.resolve(id)
+    }
+
+    /// Verify and apply a device certificate. Returns false if we already knew of it, in which
+    /// case it should not be passed on. Both keys are added to our known keys; the devices of an
+    /// identity all claim its username, which is not a key change.
+    pub fn apply_device_certificate(
+        &mut self,
+        signed: &crypto::v1::SignedDeviceCertificate,
+    ) -> Result<bool, DeviceError> {
+        let verified = verify_certificate(signed)?;
+        let identity = node_id(&verified.identity.fingerprint());
+        let device = node_id(&verified.device.fingerprint());
+        if self.statements.is_revoked(&identity) || self.statements.is_revoked(&device) {
+            return Err(DeviceError::Revoked);
+        }
+        if !self.devices.insert(&verified, signed.clone())? {
+            return Ok(false);
+        }
+        let username = Crypto::get_pubkey_username(verified.device.clone());
+        self.add_pubkey(verified.identity.clone())?;
+        self.add_pubkey(verified.device.clone())?;
+        debug!(
+            username,
+            identity,
+            device,
+            name = verified.name,
+            "device added"
+        );
+        self.publish(SocketEvent::DeviceAdded {
+            username,
+            identity: verified.identity.fingerprint(),
+            fingerprint: verified.device.fingerprint(),
+            name: verified.name,
+        });
+        Ok(true)
+    }
+
+    /// Record that a ciphertext from `source` failed to decrypt. Once its ratchet keeps failing,
+    /// the ratchet is replaced with a new one, and the reset to send the node is returned.
+    /// Ciphertexts on a chain the ratchet no longer expects are replays, and do not count.
+    pub fn decrypt_failed(
+        &mut self,
+        source: &str,
+        ciphertext: &[u8],
+    ) -> Result<Option<crypto::v1::SessionReset>, DoubleRatchetError> {
+        match self.ratchets.get(source) {
+            // a key exchange is under way, and the node may not have completed it yet
+            Some(
+                DoubleRatchet::Initiator { .. }
+                | DoubleRatchet::Responder { .. }
+                | DoubleRatchet::AlmostInitialized { .. },
+            ) => return Ok(None),
+            Some(ratchet) if !ratchet.expects(ciphertext) => return Ok(None),
+            Some(DoubleRatchet::PrekeyInitiator { .. } | DoubleRatchet::Initialized { .. })
+            | None => {}
+        }
+        if !self.health.record_failure(source, Instant::now()) {
+            return Ok(None);
+        }
+        warn!(
+            source,
+            "ratchet keeps failing to decrypt, resetting session"
+        );
+        self.reset_session(source).map(Some)
+    }
+
+    /// Record that a packet from `source` decrypted.
+    pub fn decrypt_succeeded(&mut self, source: &str) {
+        self.health.record_success(source);
+    }
+
+    /// Replace our ratchet with the node with a new initiator, returning the reset that asks the
+    /// node to do the same.
+    pub fn reset_session(
+        &mut self,
+        node: &str,
+    ) -> Result<crypto::v1::SessionReset, DoubleRatchetError> {
+        let mut ratchet = DoubleRatchet::new_initiator();
+        let key_exchange = ratchet.generate_kex_message()?;
+        self.ratchets.insert(node.to_string(), ratchet);
+        self.health.record_reset(node, Instant::now());
+        self.publish(SocketEvent::SessionReset {
+            node: node.to_string(),
+            initiated: true,
+        });
+        Ok(session_reset(key_exchange))
+    }
+
+    /// Replace our ratchet with `source` with a responder to the key exchange of its reset,
+    /// returning the key exchange that completes the new session. Returns [None] if we are
+    /// starting a session with the node ourselves, and keep it over the reset, see
+    /// [DoubleRatchet::resolve_glare].
+    pub fn accept_session_reset(
+        &mut self,
+        source: &str,
+        reset: crypto::v1::SessionReset,
+    ) -> Result<Option<crypto::v1::DrKeyExchange>, SessionResetError> {
+        let key_exchange = check_reset(reset, SystemTime::now())?;
+        if !self.health.record_accepted(&key_exchange, Instant::now()) {
+            return Err(SessionResetError::Replayed);
+        }
+        let local = self.key.fingerprint();
+        let remote = self.fingerprint_of(source)?;
+        if let Some(ratchet) = self.ratchets.get_mut(source) {
+            if ratchet.resolve_glare(&key_exchange, &local, &remote) {
+                debug!(source, "keeping our session over the reset of the node");
+                return Ok(None);
+            }
+        }
+        let mut ratchet = DoubleRatchet::new_responder();
+        ratchet.handle_kex(key_exchange, &local, &remote)?;
+        let reply = ratchet.generate_kex_message()?;
+        self.ratchets.insert(source.to_string(), ratchet);
+        self.health.record_reset(source, Instant::now());
+        debug!(source, "session reset");
+        self.publish(SocketEvent::SessionReset {
+            node: source.to_string(),
+            initiated: false,
+        });
+        Ok(Some(reply))
+    }
+
+    /// Returns the node ID of our identity, which is our own node ID unless we applied a
+    /// certificate for our key.
+    pub fn own_identity(&self) -> String {
+        let id = node_id(&self.key.fingerprint());
+        match self.devices.identity_of(&id) {
+            Some(identity) => identity.to_string(),
+            None => id,
+        }
+    }
+
+    /// Returns the node IDs of the devices that packets for the identity with the given node ID
+    /// go to, following their rotations. A node we know no devices of is its own only device.
+    pub fn devices_of(&self, identity: &str) -> Result<Vec<String>, KeyStatementError> {
+        let devices = self.devices.devices_of(identity);
+        if devices.is_empty() {
+            return Ok(vec![self.resolve_id(identity)?]);
+        }
+        Ok(devices
+            .iter()
+            .filter_map(|device| self.resolve_id(device).ok())
+            .collect())
+    }
+
+    /// Compute the safety number between us and the node with the given ID, which the users
+    /// compare out of band before marking each other verified.
+    pub fn safety_number(&self, id: &str) -> Result<SafetyNumber, SigningError> {
+        // read from the secret key, which needs no signing, so this works while the key is locked
+        let own = self.key.secret_key();
+        let pubkey = self
+            .pubkeys
+            .get(id)
+            .and_then(|pubkey| match pubkey {
+                PgpPubKey::Initialized { pubkey } => Some(pubkey),
+                PgpPubKey::PeerUninit { .. } => None,
+            })
+            .ok_or(SigningError::MissingPubKey)?;
+        Ok(SafetyNumber::new(
+            &own.fingerprint(),
+            own.details.users[0].id.id(),
+            &pubkey.fingerprint(),
+            &Crypto::get_pubkey_username(pubkey.clone()),
+        ))
+    }
+
+    /// Mark the node with the given ID as verified, pinning its current key, or forget that it
+    /// was.
+    pub fn set_verified(&mut self, id: &str, verified: bool) -> Result<(), SigningError> {
+        if !verified {
+            self.verified.remove(id);
+            return Ok(());
+        }
+        match self.pubkeys.get(id) {
+            Some(PgpPubKey::Initialized { pubkey }) => {
+                self.verified.insert(id.to_string(), pubkey.fingerprint());
+                Ok(())
+            }
+            Some(PgpPubKey::PeerUninit { .. }) | None => Err(SigningError::MissingPubKey),
+        }
+    }
+
+    /// Returns true if the user verified the node with the given ID, and its key has not changed
+    /// since.
+    pub fn is_verified(&self, id: &str) -> bool {
+        match (self.verified.get(id), self.pubkeys.get(id)) {
+            (Some(fingerprint), Some(PgpPubKey::Initialized { pubkey })) => {
+                pubkey.fingerprint() == *fingerprint
+            }
+            _ => false,
+        }
+    }
+
+    /// Build our prekey bundle for publishing, rotating the signed prekey and replenishing the
+    /// one-time prekeys first if they are due.
+    pub fn prekey_bundle(&mut self) -> Result<crypto::v1::PrekeyBundle, SigningError> {
+        self.prekeys.rotate(SystemTime::now());
+        self.prekeys.replenish();
+        self.prekeys.bundle(&self.key)
+    }
+
+    /// Write every ratchet session, our prekeys, our group sessions and the key statements and
+    /// device certificates we know of to `path`, encrypted with a key derived from `passphrase`,
+    /// so they can be restored with [Crypto::load] after a restart. The store is bound to our
+    /// key, and replaced atomically.
+    pub fn save<P: AsRef<Path>>(&self, path: P, passphrase: &str) -> Result<(), SessionStoreError> {
+        let sessions = crypto::v1::StoredSessions {
+            ratchets: self
+                .ratchets
+                .iter()
+                .map(|(node, ratchet)| (node.clone(), ratchet.export()))
+                .collect(),
+            prekeys: Some(self.prekeys.export()),
+            verified: self.verified.clone(),
+            groups: self
+                .groups
+                .iter()
+                .map(|(channel, session)| (channel.clone(), session.export()))
+                .collect(),
+            key_statements: self.statements.export(),
+            device_certificates: self.devices.export(),
+        };
+        let plaintext = Zeroizing::new(sessions.encode_to_vec());
+        let store = seal_sessions(passphrase, &self.key.fingerprint(), &plaintext)?;
+
+        // write next to the store first, so a crash never leaves a partial store behind
+        let path = path.as_ref();
+        let partial = path.with_extension("partial");
+        fs::write(&partial, store.encode_to_vec())?;
+        fs::rename(partial, path)?;
+        Ok(())
+    }
+
+    /// Restore the ratchet sessions, prekeys, group sessions, key statements and device
+    /// certificates written by [Crypto::save], replacing any session we already have with the same
+    /// node or channel. Nothing is restored if any session is bad. Returns the number of sessions
+    /// restored. Our prekey bundle changes, so it must be published again: restore the store
+    /// before the [crate::Socket] is bound, see [crate::Socket::bind_with_crypto].
+    pub fn load<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        passphrase: &str,
+    ) -> Result<usize, SessionStoreError> {
+        let store = crypto::v1::EncryptedSessions::decode(fs::read(path)?.as_slice())?;
+        let plaintext = open_sessions(passphrase, &self.key.fingerprint(), &store)?;
+        let sessions = crypto::v1::StoredSessions::decode(plaintext.as_slice())?;
+
+        let ratchets = sessions
+            .ratchets
+            .into_iter()
+            .map(|(node, stored)| Ok((node, DoubleRatchet::import(stored)?)))
+            .collect::<Result<Vec<_>, DoubleRatchetError>>()?;
+        let prekeys = sessions.prekeys.map(Prekeys::import).transpose()?;
+        let groups = sessions
+            .groups
+            .into_iter()
+            .map(|(channel, stored)| Ok((channel, GroupSession::import(stored)?)))
+            .collect::<Result<Vec<_>, GroupKeyError>>()?;
+        let statements = sessions
+            .key_statements
+            .into_iter()
+            .map(|signed| Ok((verify_statement(&signed)?, signed)))
+            .collect::<Result<Vec<_>, KeyStatementError>>()?;
+        let certificates = sessions
+            .device_certificates
+            .into_iter()
+            .map(|signed| Ok((verify_certificate(&signed)?, signed)))
+            .collect::<Result<Vec<_>, DeviceError>>()?;
+        let count = ratchets.len();
+        self.ratchets.extend(ratchets);
+        if let Some(prekeys) = prekeys {
+            self.prekeys = prekeys;
+        }
+        self.verified.extend(sessions.verified);
+        self.groups.extend(groups);
+        for (statement, signed) in statements {
+            self.statements.insert(&statement, signed);
+        }
+        for (verified, signed) in certificates {
+            self.devices.insert(&verified, signed)?;
+        }
+        debug!(count, "restored ratchet sessions");
+        Ok(count)
+    }
+
+    /// Start a group session for the given channel with the given members, replacing any we had.
+    /// Returns our sender key, which must be sent to every other member.
+    pub fn create_group(
+        &mut self,
+        channel: &str,
+        members: impl IntoIterator<Item = String>,
+    ) -> crypto::v1::SenderKeyDistribution {
+        let session = GroupSession::new(members);
+        let distribution = session.distribution(channel);
+        self.groups.insert(channel.to_string(), session);
+        distribution
+    }
+
+    /// Store the sender key a member of a channel sent us. If we had no session for the channel,
+    /// we were just added to it: a session is started with the members the sender knows of. If
+    /// we did, the members it knows of that we did not are added, and those it removed are
+    /// removed, in which case we rekey. Either way, our own sender key is returned along with the
+    /// members it must be sent to. Members are node IDs.
+    pub fn receive_sender_key(
+        &mut self,
+        own_id: &str,
+        sender: &str,
+        distribution: &crypto::v1::SenderKeyDistribution,
+    ) -> Result<Option<(crypto::v1::SenderKeyDistribution, Vec<String>)>, GroupKeyError> {
+        let channel = &distribution.channel;
+        let (session, joined) = match self.groups.entry(channel.clone()) {
+            Entry::Occupied(entry) => (entry.into_mut(), false),
+            Entry::Vacant(entry) => {
+                let members = &distribution.members;
+                if !members.iter().any(|member| member == own_id) {
+                    return Err(GroupKeyError::NotMember);
+                }
+                (
+                    entry.insert(GroupSession::new(members.iter().cloned())),
+                    true,
+                )
+            }
+        };
+        let added = session.receive_distribution(sender, distribution)?;
+        let recipients: Vec<String> = match joined {
+            true => session.members().iter().cloned().collect(),
+            false => added,
+        };
+        let recipients: Vec<String> = recipients
+            .into_iter()
+            .filter(|member| member != own_id)
+            .collect();
+        if recipients.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some((session.distribution(channel), recipients)))
+    }
+
+    /// Encrypt the data with our sender key for the given channel.
+    pub fn group_encrypt(
+        &mut self,
+        channel: &str,
+        source: &str,
+        data: &[u8],
+    ) -> Result<Vec<u8>, GroupKeyError> {
+        self.groups
+            .get_mut(channel)
+            .ok_or(GroupKeyError::MissingKey)?
+            .encrypt(channel, source, data)
+    }
+
+    /// Decrypt data `sender` encrypted with [Crypto::group_encrypt] for the given channel.
+    pub fn group_decrypt(
+        &mut self,
+        channel: &str,
+        sender: &str,
+        data: &[u8],
+    ) -> Result<Vec<u8>, GroupKeyError> {
+        self.groups
+            .get_mut(channel)
+            .ok_or(GroupKeyError::MissingKey)?
+            .decrypt(channel, sender, data)
+    }
+
     pub fn get_self_pubkey(&self) -> Result<Vec<u8>, SigningError> {
-        let armored = self
-            .secret_key
-            .public_key()
-            .sign(&self.secret_key, || "testpassword".to_string())?
-            .to_armored_bytes(None)?;
+        let armored = self.key.signed_public_key()?.to_armored_bytes(None)?;
         Ok(armored)
     }
 
+    /// Returns the first user id of the key. It is chosen by whoever made the key, so it is only
+    /// fit for display.
     pub fn get_pubkey_username(pubkey: SignedPublicKey) -> String {
         format!("{0}", pubkey.details.users[0].id.id())
     }
 
+    /// Returns the username of the node with the given ID, for display.
+    pub fn username_of(&self, id: &str) -> Option<String> {
+        match self.pubkeys.get(id) {
+            Some(PgpPubKey::Initialized { pubkey }) => {
+                Some(Crypto::get_pubkey_username(pubkey.clone()))
+            }
+            Some(PgpPubKey::PeerUninit { .. }) | None => None,
+        }
+    }
+
+    /// Add the public key of a node, returning its node ID. Revoked keys are refused. If the key is
+    /// new to us and another key we know of claims the same username, and was neither rotated to
+    /// this one nor is of the same identity, a [SocketEvent::KeyChanged] is published: either the
+    /// contact changed keys, or someone is impersonating them. The new key is not verified,
+    /// whether the old one was or not.
     pub fn add_pubkey(&mut self, pubkey: SignedPublicKey) -> Result<String, SigningError> {
-        let nodename = Crypto::get_pubkey_username(pubkey.clone());
+        let fingerprint = pubkey.fingerprint();
+        let id = node_id(&fingerprint);
+        if self.statements.is_revoked(&id) {
+            return Err(SigningError::Revoked);
+        }
+        let username = Crypto::get_pubkey_username(pubkey.clone());
+        let known = matches!(self.pubkeys.get(&id), Some(PgpPubKey::Initialized { .. }));
+        let previous = match known {
+            true => None,
+            false => self
+                .usernames
+                .get(&username)
+                .into_iter()
+                .flatten()
+                .find_map(|other| match self.pubkeys.get(other) {
+                    Some(PgpPubKey::Initialized { pubkey })
+                        if self.statements.rotated_to(other) != Some(id.as_str())
+                            && !self.devices.same_identity(other, &id) =>
+                    {
+                        Some((other.clone(), pubkey.fingerprint()))
+                    }
+                    _ => None,
+                }),
+        };
+        if !known {
+            self.usernames
+                .entry(username.clone())
+                .or_default()
+                .push(id.clone());
+        }
         self.pubkeys.insert(
-            nodename.clone(),
+            id.clone(),
             PgpPubKey::Initialized {
                 pubkey: pubkey.clone(),
             },
         );
-        debug!("Got pubkey for {0}", nodename);
-        Ok(nodename)
+        debug!(id, username, "Got pubkey");
+
+        if let Some((previous_id, previous)) = previous {
+            let verified = self.is_verified(&previous_id);
+            match verified {
+                true => warn!(username, id, "new key for verified contact"),
+                false => debug!(username, id, "new key for contact"),
+            }
+            self.publish(SocketEvent::KeyChanged {
+                username,
+                previous,
+                fingerprint,
+                verified,
+            });
+        }
+        Ok(id)
     }
 
     pub fn add_pubkey_raw(&mut self, pubkey_bytes: &Vec<u8>) -> Result<String, SigningError> {
@@ -167,45 +907,23 @@ impl Crypto {
         let result = match self.pubkeys.entry(dest) {
             Entry::Occupied(entry) => match entry.get() {
                 PgpPubKey::Initialized { pubkey } => Some(pubkey.clone()),
-                PgpPubKey::NodeUninit { .. } | PgpPubKey::PeerUninit { .. } => None,
+                PgpPubKey::PeerUninit { .. } => None,
             },
             Entry::Vacant(_) => None,
         };
         result
     }
 
-    pub fn insert_pubkey_reply_to(
-        &mut self,
-        dest: String,
-        whos_asking: Option<SocketAddr>,
-    ) -> Option<Vec<SocketAddr>> {
-        let result = match self.pubkeys.entry(dest) {
-            Entry::Occupied(mut entry) => match entry.get_mut() {
-                PgpPubKey::NodeUninit { reply_to } => {
-                    if let Some(who) = whos_asking {
-                        reply_to.push(who);
-                    }
-                    Some(reply_to.clone())
-                }
-                PgpPubKey::Initialized { .. } | PgpPubKey::PeerUninit { .. } => None,
-            },
-            Entry::Vacant(entry) => {
-                let mut reply_to = Vec::new();
-                if let Some(who) = whos_asking {
-                    reply_to.push(who);
-                }
-                entry.insert(PgpPubKey::NodeUninit {
-                    reply_to: reply_to.clone(),
-                });
-                Some(reply_to)
-            }
-        };
-        result
+    /// Returns the fingerprint of the key of the node with the given ID.
+    pub fn fingerprint_of(&self, id: &str) -> Result<Vec<u8>, SigningError> {
+        match self.pubkeys.get(id) {
+            Some(PgpPubKey::Initialized { pubkey }) => Ok(pubkey.fingerprint()),
+            Some(PgpPubKey::PeerUninit { .. }) | None => Err(SigningError::MissingPubKey),
+        }
     }
 
     pub fn sign_data(&self, bytes: &Vec<u8>) -> Result<Vec<u8>, SigningError> {
-        // So apparently the official RFC calls for more stuff but this works
-        Crypto::sign_data_static(&self.secret_key, bytes)
+        self.key.sign(bytes)
     }
 
     pub fn verify_data(
@@ -214,13 +932,16 @@ impl Crypto {
         signature: &[u8],
         bytes: &[u8],
     ) -> Result<(), SigningError> {
+        if self.statements.is_revoked(source) {
+            return Err(SigningError::Revoked);
+        }
         let signed_pub_key = match self
             .pubkeys
             .get(source)
             .ok_or(SigningError::MissingPubKey)?
         {
             PgpPubKey::Initialized { pubkey } => pubkey,
-            PgpPubKey::PeerUninit { .. } | PgpPubKey::NodeUninit { .. } => {
+            PgpPubKey::PeerUninit { .. } => {
                 return Err(SigningError::MissingPubKey);
             }
         };
@@ -228,29 +949,27 @@ impl Crypto {
         Crypto::verify_data_static(signed_pub_key, signature, bytes)
     }
 
-    pub fn sign_data_static<Data: AsRef<[u8]>>(
-        secret_key: &SignedSecretKey,
-        bytes: Data,
-    ) -> Result<Vec<u8>, SigningError> {
-        // So apparently the official RFC calls for more stuff but this works
-        let digest = {
-            let mut hasher = Sha256::new();
-            hasher.update(bytes);
-            hasher.finalize()
-        };
-        let digest = digest.as_slice();
-
-        let signature = secret_key.create_signature(
-            || "testpassword".to_string(),
-            HashAlgorithm::SHA2_256,
-            digest,
-        )?;
-
-        let mut allbytes: Vec<Vec<u8>> = Vec::new();
-        for mpi in signature {
-            allbytes.push(mpi.to_bytes()?);
+    /// Parse an armored public key that claims to belong to `fingerprint` and `username`, and
+    /// verify `signature` over `bytes` with it. Used for records that carry the key they are
+    /// signed with, so they can be checked without knowing the key beforehand.
+    pub fn verify_self_certified(
+        pubkey_bytes: &[u8],
+        fingerprint: &[u8],
+        username: &str,
+        signature: &[u8],
+        bytes: &[u8],
+    ) -> Result<SignedPublicKey, SigningError> {
+        let (pubkey, _headers) = SignedPublicKey::from_armor_single(Cursor::new(pubkey_bytes))?;
+        if pubkey.fingerprint() != fingerprint {
+            return Err(SigningError::FingerprintMismatch);
+        }
+        if pubkey.details.users.len() != 1
+            || Crypto::get_pubkey_username(pubkey.clone()) != username
+        {
+            return Err(SigningError::UsernameMismatch);
         }
-        Ok(allbytes.concat())
+        Crypto::verify_data_static(&pubkey, signature, bytes)?;
+        Ok(pubkey)
     }
 
     pub fn verify_data_static(
@@ -265,8 +984,7 @@ impl Crypto {
         };
         let digest = digest.as_slice();
 
-        let (_unused, mpi_sig) =
-            map(mpi, |v| vec![v.to_owned()])(signature).map_err(|_| SigningError::MpiFail)?;
+        let mpi_sig = parse_signature(pubkey.algorithm(), signature)?;
 
         pubkey.verify_signature(HashAlgorithm::SHA2_256, digest, &mpi_sig)?;
         debug!("signature verified");
@@ -274,6 +992,85 @@ impl Crypto {
     }
 }
 
+/// Derive the key of the session store from the passphrase.
+fn derive_session_key(
+    passphrase: &str,
+    salt: &[u8],
+) -> Result<Zeroizing<[u8; 32]>, SessionStoreError> {
+    let mut key = Zeroizing::new([0; 32]);
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+        .map_err(|_| SessionStoreError::KeyDerivationFail)?;
+    Ok(key)
+}
+
+/// Derive the associated data of a ratchet from the fingerprints of the initiator and the
+/// responder, and the ID of the session.
+fn session_associated_data(initiator: &[u8], responder: &[u8], session_id: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(ASSOCIATED_DATA_LABEL);
+    for part in [initiator, responder, session_id] {
+        hasher.update((part.len() as u32).to_be_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().to_vec()
+}
+
+/// Encrypt encoded sessions with a key derived from the passphrase under a fresh salt. `aad` is
+/// bound to the ciphertext, so the store only opens for the same `aad`.
+fn seal_sessions(
+    passphrase: &str,
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<crypto::v1::EncryptedSessions, SessionStoreError> {
+    let mut salt = vec![0; SESSION_SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    let key = derive_session_key(passphrase, &salt)?;
+    let cipher = ChaCha20Poly1305::new(key.as_ref().into());
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| SessionStoreError::CipherFail)?;
+    Ok(crypto::v1::EncryptedSessions {
+        version: SESSION_STORE_VERSION,
+        salt,
+        nonce: nonce.to_vec(),
+        ciphertext,
+    })
+}
+
+/// Decrypt sessions encrypted with [seal_sessions].
+fn open_sessions(
+    passphrase: &str,
+    aad: &[u8],
+    store: &crypto::v1::EncryptedSessions,
+) -> Result<Zeroizing<Vec<u8>>, SessionStoreError> {
+    if store.version != SESSION_STORE_VERSION {
+        return Err(SessionStoreError::UnsupportedVersion);
+    }
+    if store.nonce.len() != SESSION_NONCE_SIZE {
+        return Err(SessionStoreError::CipherFail);
+    }
+    let key = derive_session_key(passphrase, &store.salt)?;
+    let cipher = ChaCha20Poly1305::new(key.as_ref().into());
+    let plaintext = cipher
+        .decrypt(
+            store.nonce.as_slice().into(),
+            Payload {
+                msg: &store.ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| SessionStoreError::CipherFail)?;
+    Ok(Zeroizing::new(plaintext))
+}
+
 impl fmt::Debug for DoubleRatchet {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "<Opaque DoubleRatchet object>")
@@ -310,22 +1107,210 @@ impl DoubleRatchet {
         }
     }
 
+    /// Create a new [DoubleRatchet] instance as an initiator that has sent its first packet in a
+    /// prekey message, using the shared secret and session ID derived from the bundle.
+    pub fn new_prekey_initiator(shared_secret: Zeroizing<[u8; 32]>, session_id: Vec<u8>) -> Self {
+        Self::PrekeyInitiator {
+            shared_secret,
+            session_id,
+        }
+    }
+
+    /// Create a new [DoubleRatchet] instance as the responder to a prekey message, using the
+    /// shared secret and session ID derived from it, and the fingerprints of our key and the key
+    /// of the initiator. The ratchet is initialized, and only needs its DR pubkey sent with
+    /// [DoubleRatchet::generate_kex_message].
+    pub fn new_prekey_responder(
+        shared_secret: &[u8; 32],
+        session_id: &[u8],
+        local: &[u8],
+        remote: &[u8],
+    ) -> Self {
+        let (ratchet, dr_pubkey) = Ratchet::init_bob(*shared_secret);
+        // the initiator has no use for a DH pubkey, but the key exchange packet carries one
+        Self::AlmostInitialized {
+            ratchet,
+            dh_privkey: StaticSecret::random_from_rng(OsRng),
+            dr_pubkey,
+            associated_data: session_associated_data(remote, local, session_id),
+        }
+    }
+
+    /// Export the ratchet in a form that can be stored, skipped message keys included. The result
+    /// holds private keys, so it must never leave the device unencrypted.
+    pub fn export(&self) -> crypto::v1::StoredRatchet {
+        use crypto::v1::stored_ratchet::State;
+
+        let state = match self {
+            DoubleRatchet::Initiator { dh_privkey } => {
+                State::Initiator(dh_privkey.to_bytes().to_vec())
+            }
+            DoubleRatchet::Responder { dh_privkey } => {
+                State::Responder(dh_privkey.to_bytes().to_vec())
+            }
+            DoubleRatchet::PrekeyInitiator {
+                shared_secret,
+                session_id,
+            } => State::PrekeyInitiator(crypto::v1::PrekeyInitiatorRatchet {
+                shared_secret: shared_secret.to_vec(),
+                session_id: session_id.clone(),
+            }),
+            DoubleRatchet::AlmostInitialized {
+                ratchet,
+                dh_privkey,
+                dr_pubkey,
+                associated_data,
+            } => State::AlmostInitialized(crypto::v1::AlmostInitializedRatchet {
+                ratchet: ratchet.export(),
+                dh_privkey: dh_privkey.to_bytes().to_vec(),
+                dr_pubkey: dr_pubkey.as_bytes().to_vec(),
+                associated_data: associated_data.clone(),
+            }),
+            DoubleRatchet::Initialized {
+                ratchet,
+                associated_data,
+                receiving,
+            } => State::Initialized(crypto::v1::InitializedRatchet {
+                ratchet: ratchet.export(),
+                associated_data: associated_data.clone(),
+                receiving_dr_pubkey: receiving
+                    .current
+                    .map(|(current, _)| current.to_vec())
+                    .unwrap_or_default(),
+                receiving_next: receiving.current.map(|(_, next)| next).unwrap_or_default(),
+                previous_dr_pubkey: receiving
+                    .previous
+                    .map(|previous| previous.to_vec())
+                    .unwrap_or_default(),
+            }),
+        };
+        crypto::v1::StoredRatchet { state: Some(state) }
+    }
+
+    /// Restore a ratchet exported with [DoubleRatchet::export].
+    pub fn import(stored: crypto::v1::StoredRatchet) -> Result<Self, DoubleRatchetError> {
+        use crypto::v1::stored_ratchet::State;
+
+        let key = |bytes: &[u8]| -> Result<[u8; 32], DoubleRatchetError> {
+            bytes
+                .try_into()
+                .map_err(|_| DoubleRatchetError::BadStoredRatchet)
+        };
+        let ratchet =
+            |bytes: &[u8]| Ratchet::import(bytes).ok_or(DoubleRatchetError::BadStoredRatchet);
+
+        let ratchet = match stored.state.ok_or(DoubleRatchetError::BadStoredRatchet)? {
+            State::Initiator(dh_privkey) => DoubleRatchet::Initiator {
+                dh_privkey: StaticSecret::from(key(&dh_privkey)?),
+            },
+            State::Responder(dh_privkey) => DoubleRatchet::Responder {
+                dh_privkey: StaticSecret::from(key(&dh_privkey)?),
+            },
+            State::PrekeyInitiator(stored) => DoubleRatchet::PrekeyInitiator {
+                shared_secret: Zeroizing::new(key(&stored.shared_secret)?),
+                session_id: stored.session_id,
+            },
+            State::AlmostInitialized(stored) => DoubleRatchet::AlmostInitialized {
+                ratchet: ratchet(&stored.ratchet)?,
+                dh_privkey: StaticSecret::from(key(&stored.dh_privkey)?),
+                dr_pubkey: PublicKey::from(key(&stored.dr_pubkey)?),
+                associated_data: stored.associated_data,
+            },
+            State::Initialized(stored) => {
+                let optional_key = |bytes: &[u8]| match bytes.is_empty() {
+                    true => Ok(None),
+                    false => key(bytes).map(Some),
+                };
+                DoubleRatchet::Initialized {
+                    ratchet: ratchet(&stored.ratchet)?,
+                    associated_data: stored.associated_data,
+                    receiving: ReceivingChain {
+                        current: optional_key(&stored.receiving_dr_pubkey)?
+                            .map(|current| (current, stored.receiving_next)),
+                        previous: optional_key(&stored.previous_dr_pubkey)?,
+                    },
+                }
+            }
+        };
+        Ok(ratchet)
+    }
+
+    /// Resolve a key exchange the node started while ours with it is under way, which happens when
+    /// both nodes start one at the same time. The node with the lower fingerprint stays the
+    /// initiator, so that both converge on its session. Returns true if that is us, in which case
+    /// the packet is to be ignored: the node answers ours instead. Otherwise we become the
+    /// responder to the packet. A prekey initiator always keeps its session, which the node
+    /// accepts along with the packet it carries.
+    pub fn resolve_glare(
+        &mut self,
+        packet: &crypto::v1::DrKeyExchange,
+        local: &[u8],
+        remote: &[u8],
+    ) -> bool {
+        // only initiators send a key exchange without a DR pubkey
+        if !packet.dr_pubkey.is_empty() {
+            return false;
+        }
+        match self {
+            DoubleRatchet::PrekeyInitiator { .. } => true,
+            DoubleRatchet::Initiator { .. } if local < remote => true,
+            DoubleRatchet::Initiator { .. } => {
+                *self = DoubleRatchet::new_responder();
+                false
+            }
+            DoubleRatchet::Responder { .. }
+            | DoubleRatchet::AlmostInitialized { .. }
+            | DoubleRatchet::Initialized { .. } => false,
+        }
+    }
+
     /// Handle a key exchange packet, updating the internal state of the [DoubleRatchet] instance.
+    /// `local` and `remote` are the fingerprints of our key and the key of the node the session is
+    /// with, which the ratchet is bound to once it is established.
     pub fn handle_kex(
         &mut self,
         packet: crypto::v1::DrKeyExchange,
+        local: &[u8],
+        remote: &[u8],
     ) -> Result<(), DoubleRatchetError> {
+        // the shared secret is already known, so only the DR pubkey of the responder is needed
+        if let DoubleRatchet::PrekeyInitiator {
+            shared_secret,
+            session_id,
+        } = self
+        {
+            let peer_dr_pubkey_bytes: [u8; 32] = packet
+                .dr_pubkey
+                .as_slice()
+                .try_into()
+                .map_err(|_| DoubleRatchetError::BadKexPubkey)?;
+            let ratchet =
+                Ratchet::init_alice(**shared_secret, PublicKey::from(peer_dr_pubkey_bytes));
+            *self = DoubleRatchet::Initialized {
+                ratchet,
+                associated_data: session_associated_data(local, remote, session_id),
+                receiving: ReceivingChain::default(),
+            };
+            return Ok(());
+        }
+
         // ensure we are not already initialized
         let dh_privkey = match self {
             DoubleRatchet::Initiator { dh_privkey } => dh_privkey,
             DoubleRatchet::Responder { dh_privkey } => dh_privkey,
-            DoubleRatchet::AlmostInitialized { .. } => return Err(DoubleRatchetError::NonKexFail),
-            DoubleRatchet::Initialized { .. } => return Err(DoubleRatchetError::NonKexFail),
+            DoubleRatchet::PrekeyInitiator { .. }
+            | DoubleRatchet::AlmostInitialized { .. }
+            | DoubleRatchet::Initialized { .. } => return Err(DoubleRatchetError::NonKexFail),
         };
 
-        let peer_dh_pubkey_bytes: [u8; 32] = packet.dh_pubkey[..32].try_into().unwrap();
+        let peer_dh_pubkey_bytes: [u8; 32] = packet
+            .dh_pubkey
+            .as_slice()
+            .try_into()
+            .map_err(|_| DoubleRatchetError::BadKexPubkey)?;
         let peer_dh_pubkey = PublicKey::from(peer_dh_pubkey_bytes);
         let shared_secret = dh_privkey.diffie_hellman(&peer_dh_pubkey);
+        let dh_pubkey = PublicKey::from(&*dh_privkey);
 
         debug!(
             shared_secret = hex::encode(shared_secret.as_bytes()),
@@ -339,13 +1324,20 @@ impl DoubleRatchet {
                 "init_bob and generated"
             );
 
+            // we are Bob
+            let session_id = [peer_dh_pubkey.as_bytes().as_slice(), dh_pubkey.as_bytes()].concat();
             *self = DoubleRatchet::AlmostInitialized {
                 ratchet,
                 dh_privkey: dh_privkey.clone(),
                 dr_pubkey,
+                associated_data: session_associated_data(remote, local, &session_id),
             }
         } else {
-            let peer_dr_pubkey_bytes: [u8; 32] = packet.dr_pubkey[..32].try_into().unwrap();
+            let peer_dr_pubkey_bytes: [u8; 32] = packet
+                .dr_pubkey
+                .as_slice()
+                .try_into()
+                .map_err(|_| DoubleRatchetError::BadKexPubkey)?;
             let peer_dr_pubkey = PublicKey::from(peer_dr_pubkey_bytes);
             debug!(
                 pubkey = hex::encode(peer_dr_pubkey.clone().as_bytes()),
@@ -353,30 +1345,33 @@ impl DoubleRatchet {
             );
             let ratchet = Ratchet::init_alice(shared_secret.to_bytes(), peer_dr_pubkey);
 
+            // we are Alice
+            let session_id = [dh_pubkey.as_bytes().as_slice(), peer_dh_pubkey.as_bytes()].concat();
             *self = DoubleRatchet::Initialized {
                 ratchet,
-                associated_data: vec![],
+                associated_data: session_associated_data(local, remote, &session_id),
+                receiving: ReceivingChain::default(),
             }
         }
         Ok(())
     }
 
-    /// Create a new key exchange packet.
-    pub fn generate_kex_message(&mut self) -> crypto::v1::DrKeyExchange {
-        // if the ratchet is not initialised, we cannot decrypt
+    /// Create a new key exchange packet. Fails unless we are an initiator, or a responder that
+    /// handled the key exchange of the initiator.
+    pub fn generate_kex_message(
+        &mut self,
+    ) -> Result<crypto::v1::DrKeyExchange, DoubleRatchetError> {
         let (dh_privkey, dr_pubkey) = match self {
             DoubleRatchet::Initiator { dh_privkey } => (dh_privkey, None),
             DoubleRatchet::AlmostInitialized {
-                ratchet: _,
                 dh_privkey,
                 dr_pubkey,
+                ..
             } => (dh_privkey, Some(dr_pubkey)),
-            DoubleRatchet::Responder { .. } => {
-                panic!("Should not initiate with Responder");
-            }
-            DoubleRatchet::Initialized { .. } => {
-                panic!("Ratchet should not be initialised at this point");
-            }
+            // a prekey initiator sent its key exchange in the prekey message
+            DoubleRatchet::Responder { .. }
+            | DoubleRatchet::PrekeyInitiator { .. }
+            | DoubleRatchet::Initialized { .. } => return Err(DoubleRatchetError::NoKeyExchange),
         };
 
         // get public keys in raw bytes
@@ -387,30 +1382,39 @@ impl DoubleRatchet {
         };
 
         match self {
-            DoubleRatchet::AlmostInitialized { ratchet, .. } => {
+            DoubleRatchet::AlmostInitialized {
+                ratchet,
+                associated_data,
+                ..
+            } => {
                 *self = DoubleRatchet::Initialized {
-                    ratchet: Ratchet::import(&ratchet.export()).unwrap(),
-                    associated_data: vec![],
+                    ratchet: Ratchet::import(&ratchet.export())
+                        .ok_or(DoubleRatchetError::BadStoredRatchet)?,
+                    associated_data: associated_data.clone(),
+                    receiving: ReceivingChain::default(),
                 }
             }
             DoubleRatchet::Initiator { .. }
             | DoubleRatchet::Responder { .. }
+            | DoubleRatchet::PrekeyInitiator { .. }
             | DoubleRatchet::Initialized { .. } => {}
         };
 
         // create a new packet
-        crypto::v1::DrKeyExchange {
+        Ok(crypto::v1::DrKeyExchange {
             dh_pubkey: dh_pubkey_raw,
             dr_pubkey: dr_pubkey_raw,
-        }
+        })
     }
 
-    /// Encrypt the data using the ratchet, advancing the ratchet state in the process.
+    /// Encrypt the data using the ratchet, advancing the ratchet state in the process. Returns an
+    /// encoded [crypto::v1::RatchetCiphertext].
     pub fn encrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, DoubleRatchetError> {
         // if the ratchet is not initialised, we cannot decrypt
         let (ratchet, associated_data) = match self {
             DoubleRatchet::Initiator { .. }
             | DoubleRatchet::Responder { .. }
+            | DoubleRatchet::PrekeyInitiator { .. }
             | DoubleRatchet::AlmostInitialized { .. } => {
                 return Err(DoubleRatchetError::MissingRatchet)
             }
@@ -420,6 +1424,9 @@ impl DoubleRatchet {
                 ..
             } => (ratchet, associated_data),
         };
+        if associated_data.is_empty() {
+            return Err(DoubleRatchetError::UnboundSession);
+        }
         let (header, encrypted, nonce) = ratchet.encrypt(data, associated_data);
         debug!(
             header = hex::encode(Vec::<u8>::from(header.clone())),
@@ -428,53 +1435,329 @@ impl DoubleRatchet {
             "encrypted ended"
         );
 
-        let mut size_encoded = Vec::new();
-        let _ = size_encoded.write_u64::<BigEndian>(encrypted.len().try_into().unwrap());
-        let ciphertext = [size_encoded, Vec::from(header), encrypted, nonce.to_vec()].concat();
+        let envelope = crypto::v1::RatchetCiphertext {
+            version: RATCHET_CIPHERTEXT_VERSION,
+            dr_pubkey: header.public_key.as_bytes().to_vec(),
+            previous_chain_length: header.pn as u64,
+            message_number: header.n as u64,
+            nonce: nonce.to_vec(),
+            ciphertext: encrypted,
+        };
+        Ok(envelope.encode_to_vec())
+    }
 
-        Ok(ciphertext)
+    /// Returns true if the header of an encoded [crypto::v1::RatchetCiphertext] is on a chain the
+    /// ratchet still expects, see [ReceivingChain::expects]. A ciphertext that fails to decrypt
+    /// and is not is a replay of one we already decrypted.
+    pub fn expects(&self, data: &[u8]) -> bool {
+        let DoubleRatchet::Initialized { receiving, .. } = self else {
+            return true;
+        };
+        match parse_ciphertext(data) {
+            Ok((header, _, _)) => receiving.expects(&header.public_key.to_bytes(), header.n as u64),
+            Err(_) => true,
+        }
     }
 
-    /// Decrypt the data using the ratchet, advancing the ratchet state in the process.
-    pub fn decrypt(&mut self, data: &Vec<u8>) -> Result<Vec<u8>, DoubleRatchetError> {
+    /// Decrypt an encoded [crypto::v1::RatchetCiphertext] using the ratchet, advancing the
+    /// ratchet state in the process. A ciphertext that is malformed or skips too many messages is
+    /// dropped before it reaches the ratchet, and the state is left as it was if the ciphertext
+    /// does not decrypt.
+    pub fn decrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, DoubleRatchetError> {
         // if the ratchet is not initialised, we cannot decrypt
-        let (ratchet, associated_data) = match self {
+        let (ratchet, associated_data, receiving) = match self {
             DoubleRatchet::Initiator { .. }
             | DoubleRatchet::Responder { .. }
+            | DoubleRatchet::PrekeyInitiator { .. }
             | DoubleRatchet::AlmostInitialized { .. } => {
                 return Err(DoubleRatchetError::MissingRatchet)
             }
             DoubleRatchet::Initialized {
                 ratchet,
                 associated_data,
-                ..
-            } => (ratchet, associated_data),
+                receiving,
+            } => (ratchet, associated_data, receiving),
         };
+        if associated_data.is_empty() {
+            return Err(DoubleRatchetError::UnboundSession);
+        }
+
+        let (header, nonce, encrypted) = parse_ciphertext(data)?;
+        let dr_pubkey = header.public_key.to_bytes();
+        receiving.check(&dr_pubkey, header.pn as u64, header.n as u64)?;
+        debug!(
+            dr_pubkey = hex::encode(dr_pubkey),
+            pn = header.pn,
+            n = header.n,
+            encrypted = hex::encode(&encrypted),
+            nonce = hex::encode(nonce),
+            "decryption started"
+        );
 
-        let mut cursor = Cursor::new(data);
-        let size: usize = match cursor.read_u64::<BigEndian>() {
-            Ok(s) => s.try_into().unwrap(),
+        // the ratchet steps before it authenticates the ciphertext, and panics if it does not
+        let snapshot = ratchet.export();
+        let decrypted = panic::catch_unwind(AssertUnwindSafe(|| {
+            ratchet.decrypt(&header, &encrypted, &nonce, associated_data)
+        }));
+        match decrypted {
+            Ok(decrypted) => {
+                receiving.advance(&dr_pubkey, header.n as u64);
+                Ok(decrypted)
+            }
             Err(_) => {
-                return Err(DoubleRatchetError::BadCiphertext);
+                *ratchet =
+                    Ratchet::import(&snapshot).ok_or(DoubleRatchetError::BadStoredRatchet)?;
+                Err(DoubleRatchetError::DecryptFail)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        keygen::{generate_key, KeyAlgorithm},
+        reset::MAX_DECRYPT_FAILURES,
+    };
+
+    #[test]
+    fn test_sessions_round_trip() {
+        let store = seal_sessions("passphrase", b"fingerprint", b"sessions").unwrap();
+        let opened = open_sessions("passphrase", b"fingerprint", &store).unwrap();
+        assert_eq!(opened.as_slice(), b"sessions");
+
+        assert!(matches!(
+            open_sessions("wrong", b"fingerprint", &store),
+            Err(SessionStoreError::CipherFail)
+        ));
+        assert!(matches!(
+            open_sessions("passphrase", b"another key", &store),
+            Err(SessionStoreError::CipherFail)
+        ));
+    }
+
+    #[test]
+    fn test_crypto_save_load_round_trip() {
+        let secret_key = generate_key(
+            "alice".to_string(),
+            "passphrase".to_string(),
+            KeyAlgorithm::Ed25519,
+        )
+        .unwrap();
+        let key = UnlockedKey::unlock(secret_key, "passphrase".to_string()).unwrap();
+        let mut crypto = Crypto::new(key.clone());
+        let ratchet = DoubleRatchet::new_initiator();
+        let DoubleRatchet::Initiator { dh_privkey } = &ratchet else {
+            unreachable!()
+        };
+        let expected = dh_privkey.to_bytes();
+        crypto.ratchets.insert("bob".to_string(), ratchet);
+        let bundle = crypto.prekey_bundle().unwrap();
+
+        let path = std::env::temp_dir().join(format!(
+            "string-sessions-{}",
+            hex::encode(key.fingerprint())
+        ));
+        crypto.save(&path, "passphrase").unwrap();
+        let mut restored = Crypto::new(key);
+        let wrong = restored.load(&path, "wrong");
+        let count = restored.load(&path, "passphrase");
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(wrong, Err(SessionStoreError::CipherFail)));
+        assert_eq!(count.unwrap(), 1);
+
+        match restored.ratchets.get("bob") {
+            Some(DoubleRatchet::Initiator { dh_privkey }) => {
+                assert_eq!(dh_privkey.to_bytes(), expected)
             }
+            _ => panic!("restored the wrong ratchet"),
+        }
+        // the prekeys we published are the ones we answer with
+        let restored_bundle = restored.prekey_bundle().unwrap();
+        assert_eq!(restored_bundle.signed_prekey, bundle.signed_prekey);
+        assert_eq!(restored_bundle.one_time_prekeys, bundle.one_time_prekeys);
+    }
+
+    /// Complete a key exchange between two new ratchets, returning the initiator and responder.
+    fn session(initiator: &[u8], responder: &[u8]) -> (DoubleRatchet, DoubleRatchet) {
+        let mut alice = DoubleRatchet::new_initiator();
+        let mut bob = DoubleRatchet::new_responder();
+        let kex = alice.generate_kex_message().unwrap();
+        bob.handle_kex(kex, responder, initiator).unwrap();
+        let reply = bob.generate_kex_message().unwrap();
+        alice.handle_kex(reply, initiator, responder).unwrap();
+        (alice, bob)
+    }
+
+    #[test]
+    fn test_tampered_ciphertext_fails() {
+        let (mut alice, mut bob) = session(b"alice", b"bob");
+        let ciphertext = alice.encrypt(b"hello").unwrap();
+        let mut envelope = crypto::v1::RatchetCiphertext::decode(ciphertext.as_slice()).unwrap();
+        envelope.ciphertext[0] ^= 1;
+        assert!(matches!(
+            bob.decrypt(&envelope.encode_to_vec()),
+            Err(DoubleRatchetError::DecryptFail)
+        ));
+
+        // the failed ciphertext left the ratchet as it was
+        assert_eq!(bob.decrypt(&ciphertext).unwrap(), b"hello");
+        let reply = bob.encrypt(b"hi").unwrap();
+        assert_eq!(alice.decrypt(&reply).unwrap(), b"hi");
+    }
+
+    #[test]
+    fn test_diverged_ratchet_resets_session() {
+        let secret_key = generate_key(
+            "bob".to_string(),
+            "passphrase".to_string(),
+            KeyAlgorithm::Ed25519,
+        )
+        .unwrap();
+        let mut crypto =
+            Crypto::new(UnlockedKey::unlock(secret_key, "passphrase".to_string()).unwrap());
+        let (mut alice, bob) = session(b"alice", b"bob");
+        crypto.ratchets.insert("alice".to_string(), bob);
+        let ciphertext = alice.encrypt(b"hello").unwrap();
+        let ratchet = crypto.ratchets.get_mut("alice").unwrap();
+        assert_eq!(ratchet.decrypt(&ciphertext).unwrap(), b"hello");
+
+        // alice lost her state, and carries on with a session bob never saw
+        let (mut alice, _) = session(b"alice", b"bob");
+        for sent in 1..=MAX_DECRYPT_FAILURES {
+            let ciphertext = alice.encrypt(b"hello").unwrap();
+            let ratchet = crypto.ratchets.get_mut("alice").unwrap();
+            assert!(matches!(
+                ratchet.decrypt(&ciphertext),
+                Err(DoubleRatchetError::DecryptFail)
+            ));
+            let reset = crypto.decrypt_failed("alice", &ciphertext).unwrap();
+            assert_eq!(reset.is_some(), sent == MAX_DECRYPT_FAILURES);
+        }
+        assert!(matches!(
+            crypto.ratchets.get("alice"),
+            Some(DoubleRatchet::Initiator { .. })
+        ));
+    }
+
+    #[test]
+    fn test_export_kex_state() {
+        let initiator = DoubleRatchet::new_initiator();
+        let DoubleRatchet::Initiator { dh_privkey } = &initiator else {
+            unreachable!()
         };
+        let expected = dh_privkey.to_bytes();
+        match DoubleRatchet::import(initiator.export()).unwrap() {
+            DoubleRatchet::Initiator { dh_privkey } => assert_eq!(dh_privkey.to_bytes(), expected),
+            _ => panic!("restored the wrong state"),
+        }
 
-        // First 8 bytes is u64 size
-        let ciphertext = data[8..].to_vec();
-        let header_start: usize = ciphertext.len() - size - 12;
-        let nonce_start: usize = header_start + size;
+        let mut stored = DoubleRatchet::new_responder().export();
+        stored.state = Some(crypto::v1::stored_ratchet::State::Responder(vec![0; 31]));
+        assert!(matches!(
+            DoubleRatchet::import(stored),
+            Err(DoubleRatchetError::BadStoredRatchet)
+        ));
+    }
 
-        let header = Header::from(&ciphertext[..header_start]);
-        let encrypted = &ciphertext[header_start..nonce_start];
-        let nonce: [u8; 12] = ciphertext[nonce_start..].try_into().unwrap();
-        debug!(
-            header = hex::encode(Vec::<u8>::from(header.clone())),
-            encrypted = hex::encode(encrypted),
-            nonce = hex::encode(nonce),
-            "decryption started"
-        );
+    #[test]
+    fn test_resolve_glare() {
+        let mut initiator = DoubleRatchet::new_initiator();
+        let kex = initiator.generate_kex_message().unwrap();
+        let response = crypto::v1::DrKeyExchange {
+            dh_pubkey: vec![1; 32],
+            dr_pubkey: vec![2; 32],
+        };
+
+        // the lower fingerprint keeps initiating, and the other yields to it
+        let mut lower = DoubleRatchet::new_initiator();
+        assert!(lower.resolve_glare(&kex, b"a", b"b"));
+        assert!(matches!(lower, DoubleRatchet::Initiator { .. }));
+        let mut higher = DoubleRatchet::new_initiator();
+        assert!(!higher.resolve_glare(&kex, b"b", b"a"));
+        assert!(matches!(higher, DoubleRatchet::Responder { .. }));
+
+        // a response to our key exchange is no glare
+        let mut higher = DoubleRatchet::new_initiator();
+        assert!(!higher.resolve_glare(&response, b"b", b"a"));
+        assert!(matches!(higher, DoubleRatchet::Initiator { .. }));
+
+        let mut prekey = DoubleRatchet::new_prekey_initiator(Zeroizing::new([0; 32]), vec![]);
+        assert!(prekey.resolve_glare(&kex, b"b", b"a"));
+    }
+
+    #[test]
+    fn test_parse_ciphertext() {
+        let envelope = crypto::v1::RatchetCiphertext {
+            version: RATCHET_CIPHERTEXT_VERSION,
+            dr_pubkey: vec![1; 32],
+            previous_chain_length: 2,
+            message_number: 3,
+            nonce: vec![4; RATCHET_NONCE_SIZE],
+            ciphertext: vec![5; 7],
+        };
+        let (header, nonce, ciphertext) = parse_ciphertext(&envelope.encode_to_vec()).unwrap();
+        assert_eq!(header.public_key.as_bytes(), &[1; 32]);
+        assert_eq!((header.pn, header.n), (2, 3));
+        assert_eq!(nonce, [4; RATCHET_NONCE_SIZE]);
+        assert_eq!(ciphertext, vec![5; 7]);
+
+        let malformed = |change: fn(&mut crypto::v1::RatchetCiphertext)| {
+            let mut envelope = envelope.clone();
+            change(&mut envelope);
+            parse_ciphertext(&envelope.encode_to_vec())
+        };
+        assert!(matches!(
+            malformed(|envelope| envelope.version = 2),
+            Err(DoubleRatchetError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            malformed(|envelope| envelope.dr_pubkey.truncate(31)),
+            Err(DoubleRatchetError::BadHeader)
+        ));
+        assert!(matches!(
+            malformed(|envelope| envelope.nonce.clear()),
+            Err(DoubleRatchetError::BadNonce)
+        ));
+        // the old length-prefixed layout
+        assert!(matches!(
+            parse_ciphertext(&[0, 0, 0, 0, 0, 0, 0, 64, 1, 2, 3]),
+            Err(DoubleRatchetError::MalformedCiphertext(_))
+        ));
+    }
+
+    #[test]
+    fn test_receiving_chain_bounds_skipped_keys() {
+        let max = MAX_SKIPPED_MESSAGE_KEYS;
+        let mut receiving = ReceivingChain::default();
+        // the first chain is new, and bounded the same way
+        receiving.check(&[1; 32], max, max).unwrap();
+        assert!(receiving.check(&[1; 32], 0, max + 1).is_err());
+        assert!(receiving.check(&[1; 32], max + 1, 0).is_err());
+        receiving.advance(&[1; 32], 0);
+
+        receiving.check(&[1; 32], 0, max + 1).unwrap();
+        assert!(matches!(
+            receiving.check(&[1; 32], 0, max + 2),
+            Err(DoubleRatchetError::TooManySkipped)
+        ));
+
+        // a new chain skips the rest of the current one, then the start of its own
+        receiving.check(&[2; 32], max + 1, max).unwrap();
+        assert!(receiving.check(&[2; 32], max + 2, 0).is_err());
+        assert!(receiving.check(&[2; 32], 1, max + 1).is_err());
+        receiving.advance(&[2; 32], 5);
 
-        let decrypted = ratchet.decrypt(&header, encrypted, &nonce, associated_data);
-        Ok(decrypted)
+        // late messages of the previous chain use keys that were already skipped
+        receiving.check(&[1; 32], 0, max * 2).unwrap();
+        assert!(!receiving.expects(&[1; 32], max * 2));
+        assert!(!receiving.expects(&[2; 32], 5));
+        assert!(receiving.expects(&[2; 32], 6));
+        // a new chain, whether it follows the current one or not
+        assert!(receiving.expects(&[3; 32], 0));
+        receiving.advance(&[1; 32], max * 2);
+        assert_eq!(receiving.current, Some(([2; 32], 6)));
+        assert_eq!(receiving.previous, Some([1; 32]));
     }
 }
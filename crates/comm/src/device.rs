@@ -0,0 +1,340 @@
+//! Defines device certificates, which let one user run several devices.
+//!
+//! A user has an identity key, which certifies the key of each of their devices. Every device is a
+//! node of its own, with its own key, ratchets and node ID, and the identity is addressed by the
+//! node ID of its key. What is sent to the identity is sent to every one of its devices over its
+//! own ratchet, and a copy of every message a device sends goes to the other devices of the user
+//! as a [messages::v1::SentTranscript], so that they all show the same history. Certificates are
+//! cross-signed: the device key signs the fingerprint of the identity first, so an identity cannot
+//! claim a key it does not hold. They carry both keys, so any node can verify them and pass them
+//! on. A device announces its own certificate once its identity has signed it, and lighthouses
+//! hand it to the nodes that were offline. A device is retired by revoking its key, and revoking
+//! the identity key drops every one of its devices.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Cursor,
+    time::SystemTime,
+};
+
+use pgp::{composed::SignedPublicKey, types::KeyTrait, Deserializable};
+use string_protocol::{
+    crypto, messages,
+    prost::{DecodeError, Message},
+    ProtocolPacket, ProtocolPacketType,
+};
+use thiserror::Error;
+
+use crate::{
+    crypto::{node_id, Crypto, SigningError},
+    key::UnlockedKey,
+};
+
+/// The most devices an identity may have, which bounds how many copies of a packet are sent.
+pub const MAX_DEVICES: usize = 16;
+
+/// Domain separation for the signature of a device key over the fingerprint of its identity.
+const DEVICE_BINDING_LABEL: &[u8] = b"string device of ";
+
+/// An enumeration of possible errors that can occur when handling device certificates.
+#[derive(Error, Debug)]
+pub enum DeviceError {
+    #[error("Device certificate is missing")]
+    MissingCertificate,
+    #[error("Device certificate has no signing time")]
+    MissingTime,
+    #[error("Device certificate is not signed by the device")]
+    MissingDeviceSignature,
+    #[error("Identity key certifies itself")]
+    SelfCertified,
+    #[error("Failed to verify device certificate")]
+    SigningFail(#[from] SigningError),
+    #[error("Malformed key in device certificate")]
+    PgpError(#[from] pgp::errors::Error),
+    #[error("Malformed device certificate")]
+    DecodeFail(#[from] DecodeError),
+    #[error("Key has been revoked")]
+    Revoked,
+    #[error("Identity has too many devices")]
+    TooManyDevices,
+    #[error("Device belongs to another identity")]
+    OtherIdentity,
+    #[error("Device certificate is not for our key")]
+    NotOurDevice,
+}
+
+/// A device certificate whose signature has been verified.
+#[derive(Debug, Clone)]
+pub struct VerifiedDevice {
+    /// The identity key, which signed the certificate.
+    pub identity: SignedPublicKey,
+    /// The key of the device.
+    pub device: SignedPublicKey,
+    /// The name of the device.
+    pub name: String,
+    pub time_signed: SystemTime,
+}
+
+/// Sign with the key of a device that it belongs to the identity with the given fingerprint. The
+/// identity includes the signature in the certificate it signs with [sign_device_certificate].
+pub fn sign_device_binding(
+    device: &UnlockedKey,
+    identity_fingerprint: &[u8],
+) -> Result<Vec<u8>, SigningError> {
+    device.sign(binding_data(identity_fingerprint))
+}
+
+fn binding_data(identity_fingerprint: &[u8]) -> Vec<u8> {
+    [DEVICE_BINDING_LABEL, identity_fingerprint].concat()
+}
+
+/// Sign a certificate with our identity key that `device` is one of our devices, given the
+/// signature of the device made with [sign_device_binding].
+pub fn sign_device_certificate(
+    identity: &UnlockedKey,
+    device: &SignedPublicKey,
+    name: String,
+    device_signature: Vec<u8>,
+) -> Result<crypto::v1::SignedDeviceCertificate, SigningError> {
+    let certificate = crypto::v1::DeviceCertificate {
+        identity_pubkey: identity.signed_public_key()?.to_armored_bytes(None)?,
+        device_pubkey: device.to_armored_bytes(None)?,
+        name,
+        time_signed: Some(SystemTime::now().into()),
+        device_signature,
+    };
+    Ok(crypto::v1::SignedDeviceCertificate {
+        signature: identity.sign(certificate.encode_to_vec())?,
+        certificate: Some(certificate),
+    })
+}
+
+/// Verify a signed certificate against both keys it carries.
+pub fn verify_certificate(
+    signed: &crypto::v1::SignedDeviceCertificate,
+) -> Result<VerifiedDevice, DeviceError> {
+    let certificate = signed
+        .certificate
+        .as_ref()
+        .ok_or(DeviceError::MissingCertificate)?;
+    let identity = parse_pubkey(&certificate.identity_pubkey)?;
+    let device = parse_pubkey(&certificate.device_pubkey)?;
+    if identity.fingerprint() == device.fingerprint() {
+        return Err(DeviceError::SelfCertified);
+    }
+    let time_signed = certificate
+        .time_signed
+        .clone()
+        .and_then(|time| SystemTime::try_from(time).ok())
+        .ok_or(DeviceError::MissingTime)?;
+    if certificate.device_signature.is_empty() {
+        return Err(DeviceError::MissingDeviceSignature);
+    }
+    Crypto::verify_data_static(
+        &device,
+        &certificate.device_signature,
+        &binding_data(&identity.fingerprint()),
+    )?;
+    Crypto::verify_data_static(&identity, &signed.signature, &certificate.encode_to_vec())?;
+    Ok(VerifiedDevice {
+        identity,
+        device,
+        name: certificate.name.clone(),
+        time_signed,
+    })
+}
+
+/// Decode and verify an encoded signed certificate, as it is registered with the lighthouse.
+pub fn verify_encoded_certificate(
+    bytes: &[u8],
+) -> Result<(VerifiedDevice, crypto::v1::SignedDeviceCertificate), DeviceError> {
+    let signed = crypto::v1::SignedDeviceCertificate::decode(bytes)?;
+    Ok((verify_certificate(&signed)?, signed))
+}
+
+fn parse_pubkey(bytes: &[u8]) -> Result<SignedPublicKey, DeviceError> {
+    let (pubkey, _headers) = SignedPublicKey::from_armor_single(Cursor::new(bytes))?;
+    Ok(pubkey)
+}
+
+/// Build the packet that passes a copy of a message we sent to `destination` to another of our
+/// devices. It must be sent encrypted with the device's ratchet.
+pub fn transcript_packet(destination: String, message: messages::v1::Message) -> ProtocolPacket {
+    ProtocolPacket {
+        packet_type: Some(ProtocolPacketType::PktSentTranscript(
+            messages::v1::SentTranscript {
+                destination,
+                message: Some(message),
+            },
+        )),
+    }
+}
+
+/// A device we accepted the certificate of.
+#[derive(Debug, Clone)]
+struct Device {
+    name: String,
+    signed: crypto::v1::SignedDeviceCertificate,
+}
+
+/// The devices we know of, by the node ID of their identity.
+#[derive(Debug, Default)]
+pub struct Devices {
+    /// Devices of each identity, by node ID, in a stable order.
+    devices: HashMap<String, BTreeMap<String, Device>>,
+    /// The node ID of the identity of each device.
+    identities: HashMap<String, String>,
+}
+
+impl Devices {
+    /// Record a verified certificate. Returns false if we already had it. A key can only be a
+    /// device of one identity.
+    pub fn insert(
+        &mut self,
+        verified: &VerifiedDevice,
+        signed: crypto::v1::SignedDeviceCertificate,
+    ) -> Result<bool, DeviceError> {
+        self.insert_device(
+            node_id(&verified.identity.fingerprint()),
+            node_id(&verified.device.fingerprint()),
+            verified.name.clone(),
+            signed,
+        )
+    }
+
+    fn insert_device(
+        &mut self,
+        identity: String,
+        device: String,
+        name: String,
+        signed: crypto::v1::SignedDeviceCertificate,
+    ) -> Result<bool, DeviceError> {
+        match self.identities.get(&device) {
+            Some(other) if *other != identity => return Err(DeviceError::OtherIdentity),
+            Some(_) => return Ok(false),
+            None => {}
+        }
+        // an identity is not a device of another one, nor the other way around
+        if self.identities.contains_key(&identity) || self.devices.contains_key(&device) {
+            return Err(DeviceError::OtherIdentity);
+        }
+        let devices = self.devices.entry(identity.clone()).or_default();
+        if devices.len() >= MAX_DEVICES {
+            return Err(DeviceError::TooManyDevices);
+        }
+        devices.insert(device.clone(), Device { name, signed });
+        self.identities.insert(device, identity);
+        Ok(true)
+    }
+
+    /// Forget the device or identity with the given node ID, along with every device of the
+    /// identity. Returns false if we knew of neither.
+    pub fn remove(&mut self, id: &str) -> bool {
+        if let Some(identity) = self.identities.remove(id) {
+            if let Some(devices) = self.devices.get_mut(&identity) {
+                devices.remove(id);
+                if devices.is_empty() {
+                    self.devices.remove(&identity);
+                }
+            }
+            return true;
+        }
+        match self.devices.remove(id) {
+            Some(devices) => {
+                for device in devices.keys() {
+                    self.identities.remove(device);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the node ID of the identity of the device with the given node ID, if it is one.
+    pub fn identity_of(&self, id: &str) -> Option<&str> {
+        self.identities.get(id).map(String::as_str)
+    }
+
+    /// Returns the node IDs of the devices of the identity with the given node ID.
+    pub fn devices_of(&self, identity: &str) -> Vec<String> {
+        self.devices
+            .get(identity)
+            .map(|devices| devices.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the name of the device with the given node ID, if it is one.
+    pub fn name_of(&self, id: &str) -> Option<&str> {
+        let identity = self.identities.get(id)?;
+        Some(self.devices.get(identity)?.get(id)?.name.as_str())
+    }
+
+    /// Returns true if the nodes with the given IDs are, or are devices of, the same identity.
+    pub fn same_identity(&self, a: &str, b: &str) -> bool {
+        self.identity_of(a).unwrap_or(a) == self.identity_of(b).unwrap_or(b)
+    }
+
+    /// Returns every certificate we accepted, to be stored or passed on.
+    pub fn export(&self) -> Vec<crypto::v1::SignedDeviceCertificate> {
+        self.devices
+            .values()
+            .flat_map(|devices| devices.values().map(|device| device.signed.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_devices_of_identity() {
+        let mut devices = Devices::default();
+        let signed = crypto::v1::SignedDeviceCertificate::default();
+        assert!(devices
+            .insert_device("id".into(), "b".into(), "laptop".into(), signed.clone())
+            .unwrap());
+        assert!(devices
+            .insert_device("id".into(), "a".into(), "phone".into(), signed.clone())
+            .unwrap());
+        assert!(!devices
+            .insert_device("id".into(), "a".into(), "phone".into(), signed.clone())
+            .unwrap());
+        assert_eq!(devices.devices_of("id"), vec!["a", "b"]);
+        assert_eq!(devices.name_of("b"), Some("laptop"));
+        assert!(devices.same_identity("a", "b"));
+        assert!(devices.same_identity("a", "id"));
+        assert!(!devices.same_identity("a", "c"));
+
+        // a device cannot be claimed by another identity, nor certify devices of its own
+        assert!(matches!(
+            devices.insert_device("other".into(), "a".into(), "phone".into(), signed.clone()),
+            Err(DeviceError::OtherIdentity)
+        ));
+        assert!(matches!(
+            devices.insert_device("a".into(), "c".into(), "tablet".into(), signed),
+            Err(DeviceError::OtherIdentity)
+        ));
+
+        assert!(devices.remove("a"));
+        assert_eq!(devices.devices_of("id"), vec!["b"]);
+        assert!(devices.remove("id"));
+        assert!(devices.devices_of("id").is_empty());
+        assert_eq!(devices.identity_of("b"), None);
+    }
+
+    #[test]
+    fn test_device_limit() {
+        let mut devices = Devices::default();
+        let signed = crypto::v1::SignedDeviceCertificate::default();
+        for device in 0..MAX_DEVICES {
+            devices
+                .insert_device("id".into(), device.to_string(), "".into(), signed.clone())
+                .unwrap();
+        }
+        assert!(matches!(
+            devices.insert_device("id".into(), "extra".into(), "".into(), signed),
+            Err(DeviceError::TooManyDevices)
+        ));
+    }
+}
@@ -0,0 +1,574 @@
+//! This module defines [Dht], a Kademlia-style distributed hash table that maps fingerprints and
+//! usernames to signed public key, endpoint and prekey records, so nodes can be resolved - and
+//! sessions started with them while they are offline - without the lighthouse.
+//!
+//! Every node has an ID derived from its fingerprint, and records are stored on the nodes whose
+//! IDs are closest to their key by XOR distance. The DHT runs over the existing peer connections:
+//! its messages travel as signed gossip addressed to the node ID of a contact, and carry the
+//! sender's own public key record so they can be verified before any lookup has happened. Our
+//! direct peers seed the contact table, and lookups walk towards the key by asking the closest
+//! contacts they know of for closer ones.
+
+mod store;
+mod table;
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use pgp::composed::SignedPublicKey;
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use string_protocol::{dht, prost::Message, MessageType};
+use thiserror::Error;
+use tokio::{
+    sync::{mpsc, oneshot, Mutex, RwLock},
+    time::{timeout_at, Instant},
+};
+use tracing::{debug, error, trace};
+
+use self::store::verify_record_with_key;
+use crate::{
+    crypto::{node_id, Crypto, SigningError},
+    socket::{Gossip, GossipAction},
+    try_continue,
+};
+
+// re-export types
+pub use self::store::{
+    sign_record, verify_record, DhtRecord, RecordStore, MAX_RECORDS_PER_KEY, MAX_RECORD_TTL,
+    MAX_STORED_RECORDS,
+};
+pub use self::table::{distance, ContactTable, BUCKET_SIZE, STALE_CONTACT};
+
+type Kind = dht::v1::dht_message::Kind;
+
+/// The size of a DHT key, in bytes.
+pub const KEY_SIZE: usize = 32;
+
+/// A key in the DHT. Node IDs are keys too.
+pub type Key = [u8; KEY_SIZE];
+
+/// The number of contacts asked at once during a lookup.
+pub const ALPHA: usize = 3;
+
+/// How long the records of the current node are valid for.
+pub const RECORD_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How often the records of the current node are re-signed and stored again.
+pub const DHT_REPUBLISH: Duration = Duration::from_secs(20 * 60);
+
+/// How long a contact has to reply to a request before it is forgotten.
+pub const DHT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The size of a random request ID, in bytes.
+const REQUEST_ID_SIZE: usize = 16;
+
+/// The most records accepted from a single store request.
+const MAX_STORE_RECORDS: usize = 8;
+
+/// How often the DHT worker runs.
+const DHT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// An enumeration of possible errors that can occur when working with the [Dht].
+#[derive(Error, Debug)]
+pub enum DhtError {
+    /// A record is malformed, or does not hold what its kind says.
+    #[error("Bad record")]
+    BadRecord,
+    /// A record is no longer valid, or claims to be valid for too long.
+    #[error("Expired record")]
+    Expired,
+    /// A message does not carry a valid record of the node that sent it.
+    #[error("Bad sender")]
+    BadSender,
+    /// A signature or public key is invalid.
+    #[error("Failure in signature verification")]
+    SigningFail(#[from] SigningError),
+    /// A message could not be handed to the gossip worker.
+    #[error("Failed to send DHT message")]
+    SendFail,
+    /// No record was found.
+    #[error("Not found")]
+    NotFound,
+}
+
+/// Returns the key the records of the node with the given fingerprint are stored under, which is
+/// also the ID of that node.
+pub fn fingerprint_key(fingerprint: &[u8]) -> Key {
+    hash_key(b"fingerprint:", fingerprint)
+}
+
+/// Returns the key the public key records of nodes with the given username are stored under.
+pub fn username_key(username: &str) -> Key {
+    hash_key(b"username:", username.as_bytes())
+}
+
+fn hash_key(domain: &[u8], data: &[u8]) -> Key {
+    Sha256::new()
+        .chain_update(domain)
+        .chain_update(data)
+        .finalize()
+        .into()
+}
+
+fn new_request_id() -> Vec<u8> {
+    let mut id = vec![0; REQUEST_ID_SIZE];
+    OsRng.fill_bytes(&mut id);
+    id
+}
+
+/// Verify that a DHT message carries a valid public key record of `source`, the node ID that
+/// signed the gossip carrying it. Returns the public key the gossip should be verified with, and
+/// the contact of the sender.
+pub fn verify_sender(
+    message: &dht::v1::DhtMessage,
+    source: &str,
+) -> Result<(SignedPublicKey, dht::v1::Contact), DhtError> {
+    verify_sender_record(message.sender.as_ref(), source)
+}
+
+/// Same as [verify_sender], for any message that carries the public key record of its sender.
+pub fn verify_sender_record(
+    signed: Option<&dht::v1::SignedRecord>,
+    source: &str,
+) -> Result<(SignedPublicKey, dht::v1::Contact), DhtError> {
+    let signed = signed.ok_or(DhtError::BadSender)?;
+    let (record, pubkey) = verify_record_with_key(signed)?;
+    if record.kind != dht::v1::RecordKind::Pubkey || node_id(&record.fingerprint) != source {
+        return Err(DhtError::BadSender);
+    }
+    Ok((
+        pubkey,
+        dht::v1::Contact {
+            fingerprint: record.fingerprint,
+            username: record.username,
+        },
+    ))
+}
+
+/// A request we are waiting on a reply to.
+#[derive(Debug)]
+struct PendingRequest {
+    /// The fingerprint of the contact the request was sent to. Replies from anyone else are
+    /// ignored.
+    to: Vec<u8>,
+    /// Used to hand the reply to the lookup waiting on it.
+    reply: oneshot::Sender<Kind>,
+}
+
+/// A node of the DHT. It stores the records other nodes publish, answers their lookups, and looks
+/// records up on behalf of the [crate::Socket].
+#[derive(Debug)]
+pub struct Dht {
+    /// The contact of the current node.
+    contact: dht::v1::Contact,
+    /// The ID of the current node.
+    id: Key,
+    /// The nodes we know of.
+    pub contacts: RwLock<ContactTable>,
+    /// The records stored on this node.
+    pub store: RwLock<RecordStore>,
+    /// The signed records of the current node, which are republished regularly.
+    own: RwLock<Vec<dht::v1::SignedRecord>>,
+    /// Requests we are waiting on a reply to, by request ID.
+    pending: Mutex<HashMap<Vec<u8>, PendingRequest>>,
+    /// Channel used to send DHT messages as gossip.
+    gossip_tx: mpsc::Sender<Gossip>,
+}
+
+impl Dht {
+    /// Create a node of the DHT for the current node, which sends its messages on `gossip_tx`.
+    pub fn new(username: String, fingerprint: Vec<u8>, gossip_tx: mpsc::Sender<Gossip>) -> Self {
+        let id = fingerprint_key(&fingerprint);
+        Self {
+            contact: dht::v1::Contact {
+                fingerprint,
+                username,
+            },
+            id,
+            contacts: RwLock::new(ContactTable::new(id)),
+            store: RwLock::new(RecordStore::default()),
+            own: RwLock::new(vec![]),
+            pending: Mutex::new(HashMap::new()),
+            gossip_tx,
+        }
+    }
+
+    /// Returns the ID of the current node.
+    pub fn id(&self) -> &Key {
+        &self.id
+    }
+
+    /// Sign the public key, endpoint and prekeys records of the current node, and store them
+    /// locally. They are stored on other nodes by [Dht::publish].
+    pub async fn sign_own_records(
+        &self,
+        crypto: &RwLock<Crypto>,
+        endpoint: SocketAddr,
+    ) -> Result<(), DhtError> {
+        let records = {
+            let mut crypto = crypto.write().await;
+            let bundle = crypto.prekey_bundle()?;
+            // armoring signs the key afresh, so do it once for the records to match
+            let pubkey = crypto.get_self_pubkey()?;
+            let sign = |kind, value| {
+                sign_record(
+                    &crypto,
+                    pubkey.clone(),
+                    self.contact.username.clone(),
+                    self.contact.fingerprint.clone(),
+                    kind,
+                    value,
+                    RECORD_TTL,
+                )
+            };
+            vec![
+                sign(dht::v1::RecordKind::Pubkey, pubkey.clone())?,
+                sign(
+                    dht::v1::RecordKind::Endpoint,
+                    endpoint.to_string().into_bytes(),
+                )?,
+                sign(dht::v1::RecordKind::Prekeys, bundle.encode_to_vec())?,
+            ]
+        };
+        for signed in records.iter() {
+            self.store_record(verify_record(signed)?).await;
+        }
+        *self.own.write().await = records;
+        Ok(())
+    }
+
+    /// Sign a fresh prekeys record of the current node in place of the current one, and store it
+    /// locally. This is done whenever one of our one-time prekeys is used up, so that others stop
+    /// picking it once the record is published.
+    pub async fn sign_prekeys(&self, crypto: &RwLock<Crypto>) -> Result<(), DhtError> {
+        let signed = {
+            let mut crypto = crypto.write().await;
+            let bundle = crypto.prekey_bundle()?;
+            sign_record(
+                &crypto,
+                crypto.get_self_pubkey()?,
+                self.contact.username.clone(),
+                self.contact.fingerprint.clone(),
+                dht::v1::RecordKind::Prekeys,
+                bundle.encode_to_vec(),
+                RECORD_TTL,
+            )?
+        };
+        self.store_record(verify_record(&signed)?).await;
+        let mut own = self.own.write().await;
+        own.retain(|other| {
+            other.record.as_ref().map(|record| record.kind()) != Some(dht::v1::RecordKind::Prekeys)
+        });
+        own.push(signed);
+        Ok(())
+    }
+
+    /// Returns the signed public key record of the current node, which messages that must be
+    /// verifiable without a lookup carry.
+    pub async fn own_pubkey_record(&self) -> Option<dht::v1::SignedRecord> {
+        self.own
+            .read()
+            .await
+            .iter()
+            .find(|signed| {
+                signed.record.as_ref().map(|record| record.kind())
+                    == Some(dht::v1::RecordKind::Pubkey)
+            })
+            .cloned()
+    }
+
+    /// Record that we heard from the given node.
+    pub async fn add_contact(&self, contact: dht::v1::Contact) {
+        if self.contacts.write().await.insert(contact.clone()) {
+            trace!(username = contact.username, "added DHT contact");
+        }
+    }
+
+    /// Store the records of the current node on the nodes closest to their keys.
+    pub async fn publish(&self) {
+        let own = self.own.read().await.clone();
+        let mut by_key: HashMap<Key, Vec<dht::v1::SignedRecord>> = HashMap::new();
+        for signed in own {
+            let record = try_continue!(verify_record(&signed), "Own DHT record is invalid");
+            for key in record.keys() {
+                by_key.entry(key).or_default().push(signed.clone());
+            }
+        }
+
+        for (key, records) in by_key {
+            for contact in self.find_node(key).await {
+                let store = Kind::Store(dht::v1::Store {
+                    records: records.clone(),
+                });
+                if let Err(err) = self.send(&contact, new_request_id(), store).await {
+                    debug!(?err, "failed to store records");
+                }
+            }
+        }
+    }
+
+    /// Returns the [BUCKET_SIZE] nodes closest to the given key.
+    pub async fn find_node(&self, key: Key) -> Vec<dht::v1::Contact> {
+        self.lookup(key, false).await.1
+    }
+
+    /// Returns the valid records stored under the given key, looking them up if we do not hold
+    /// them ourselves. Only the most recently signed record of each kind of each node is kept.
+    pub async fn find_value(&self, key: Key) -> Vec<DhtRecord> {
+        let mut found = RecordStore::default();
+        for record in self.store.read().await.get(&key) {
+            found.insert(key, record);
+        }
+        for record in self.lookup(key, true).await.0 {
+            found.insert(key, record);
+        }
+        found.get(&key)
+    }
+
+    /// Returns the records of the node with the given fingerprint.
+    pub async fn lookup_fingerprint(&self, fingerprint: &[u8]) -> Vec<DhtRecord> {
+        let mut records = self.find_value(fingerprint_key(fingerprint)).await;
+        records.retain(|record| record.fingerprint == fingerprint);
+        records
+    }
+
+    /// Returns the public key records of the nodes with the given username. Usernames are not
+    /// unique, so there may be more than one.
+    pub async fn lookup_username(&self, username: &str) -> Vec<DhtRecord> {
+        let mut records = self.find_value(username_key(username)).await;
+        records.retain(|record| {
+            record.username == username && record.kind == dht::v1::RecordKind::Pubkey
+        });
+        records
+    }
+
+    /// Handle a DHT message from `sender`, which has been checked with [verify_sender].
+    pub async fn handle(&self, sender: dht::v1::Contact, message: dht::v1::DhtMessage) {
+        self.add_contact(sender.clone()).await;
+        let Some(kind) = message.kind else {
+            return;
+        };
+        let reply = match kind {
+            Kind::FindNode(find) => {
+                let Ok(key) = Key::try_from(find.key.as_slice()) else {
+                    return;
+                };
+                Kind::Nodes(dht::v1::Nodes {
+                    contacts: self.closest_for(&key, &sender).await,
+                })
+            }
+            Kind::FindValue(find) => {
+                let Ok(key) = Key::try_from(find.key.as_slice()) else {
+                    return;
+                };
+                let records = self.store.read().await.get(&key);
+                let contacts = match records.is_empty() {
+                    true => self.closest_for(&key, &sender).await,
+                    false => vec![],
+                };
+                Kind::Values(dht::v1::Values {
+                    records: records.into_iter().map(|record| record.signed).collect(),
+                    contacts,
+                })
+            }
+            Kind::Store(store) => {
+                for signed in store.records.iter().take(MAX_STORE_RECORDS) {
+                    match verify_record(signed) {
+                        Ok(record) => self.store_record(record).await,
+                        Err(err) => debug!(?err, "not storing bad DHT record"),
+                    }
+                }
+                return;
+            }
+            Kind::Nodes(_) | Kind::Values(_) => {
+                let mut pending = self.pending.lock().await;
+                match pending.remove(&message.request_id) {
+                    Some(request) if request.to == sender.fingerprint => {
+                        // the lookup may have given up on the reply already
+                        let _ = request.reply.send(kind);
+                    }
+                    Some(request) => {
+                        debug!("ignoring DHT reply from the wrong node");
+                        pending.insert(message.request_id, request);
+                    }
+                    None => {}
+                }
+                return;
+            }
+        };
+        if let Err(err) = self.send(&sender, message.request_id, reply).await {
+            debug!(?err, "failed to reply to DHT request");
+        }
+    }
+
+    /// Walk towards `key` by repeatedly asking the [ALPHA] closest contacts we have not asked yet
+    /// for closer ones, until the [BUCKET_SIZE] closest contacts have all been asked. When
+    /// looking for values, the walk stops as soon as some are found. Contacts that do not reply
+    /// are forgotten.
+    async fn lookup(&self, key: Key, find_value: bool) -> (Vec<DhtRecord>, Vec<dht::v1::Contact>) {
+        let mut shortlist = self.contacts.read().await.closest(&key, BUCKET_SIZE);
+        let mut queried = HashSet::new();
+        let mut records = vec![];
+        loop {
+            shortlist.retain(|contact| contact.fingerprint != self.contact.fingerprint);
+            shortlist.sort_by_key(|contact| distance(&fingerprint_key(&contact.fingerprint), &key));
+            shortlist.dedup_by(|a, b| a.fingerprint == b.fingerprint);
+            shortlist.truncate(BUCKET_SIZE);
+
+            let round: Vec<_> = shortlist
+                .iter()
+                .filter(|contact| !queried.contains(&contact.fingerprint))
+                .take(ALPHA)
+                .cloned()
+                .collect();
+            if round.is_empty() {
+                break;
+            }
+
+            // send every request of the round before waiting on any of them
+            let mut replies = vec![];
+            for contact in round {
+                queried.insert(contact.fingerprint.clone());
+                let request = match find_value {
+                    true => Kind::FindValue(dht::v1::FindValue { key: key.to_vec() }),
+                    false => Kind::FindNode(dht::v1::FindNode { key: key.to_vec() }),
+                };
+                match self.request(&contact, request).await {
+                    Ok((request_id, reply)) => replies.push((contact, request_id, reply)),
+                    Err(err) => debug!(?err, "failed to send DHT request"),
+                }
+            }
+
+            let deadline = Instant::now() + DHT_REQUEST_TIMEOUT;
+            for (contact, request_id, reply) in replies {
+                match timeout_at(deadline, reply).await {
+                    Ok(Ok(Kind::Nodes(nodes))) => shortlist.extend(nodes.contacts),
+                    Ok(Ok(Kind::Values(values))) => {
+                        for signed in values.records {
+                            match verify_record(&signed) {
+                                Ok(record) if record.keys().contains(&key) => records.push(record),
+                                Ok(_) => debug!("ignoring DHT record under the wrong key"),
+                                Err(err) => debug!(?err, "ignoring bad DHT record"),
+                            }
+                        }
+                        shortlist.extend(values.contacts);
+                    }
+                    Ok(Ok(_)) => {}
+                    Ok(Err(_)) | Err(_) => {
+                        debug!(username = contact.username, "DHT contact did not reply");
+                        self.pending.lock().await.remove(&request_id);
+                        self.contacts.write().await.remove(&contact.fingerprint);
+                        shortlist.retain(|other| other.fingerprint != contact.fingerprint);
+                    }
+                }
+            }
+
+            if find_value && !records.is_empty() {
+                break;
+            }
+        }
+        (records, shortlist)
+    }
+
+    /// Returns the contacts closest to `key`, leaving out the node asking for them.
+    async fn closest_for(&self, key: &Key, asking: &dht::v1::Contact) -> Vec<dht::v1::Contact> {
+        let mut contacts = self.contacts.read().await.closest(key, BUCKET_SIZE + 1);
+        contacts.retain(|contact| contact.fingerprint != asking.fingerprint);
+        contacts.truncate(BUCKET_SIZE);
+        contacts
+    }
+
+    /// Store a verified record under every key it belongs under.
+    async fn store_record(&self, record: DhtRecord) {
+        let mut store = self.store.write().await;
+        for key in record.keys() {
+            store.insert(key, record.clone());
+        }
+    }
+
+    /// Send a request to the given contact, returning its ID and a channel the reply is handed
+    /// to.
+    async fn request(
+        &self,
+        to: &dht::v1::Contact,
+        request: Kind,
+    ) -> Result<(Vec<u8>, oneshot::Receiver<Kind>), DhtError> {
+        let request_id = new_request_id();
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.pending.lock().await.insert(
+            request_id.clone(),
+            PendingRequest {
+                to: to.fingerprint.clone(),
+                reply: reply_tx,
+            },
+        );
+        if let Err(err) = self.send(to, request_id.clone(), request).await {
+            self.pending.lock().await.remove(&request_id);
+            return Err(err);
+        }
+        Ok((request_id, reply_rx))
+    }
+
+    /// Send a DHT message to the given contact as gossip, along with our public key record.
+    async fn send(
+        &self,
+        to: &dht::v1::Contact,
+        request_id: Vec<u8>,
+        kind: Kind,
+    ) -> Result<(), DhtError> {
+        let sender = self.own_pubkey_record().await;
+        let message = dht::v1::DhtMessage {
+            request_id,
+            sender,
+            kind: Some(kind),
+        };
+        self.gossip_tx
+            .send(Gossip {
+                action: GossipAction::Send,
+                addr: None,
+                packet: None,
+                message: Some(MessageType::Dht(message)),
+                dest: Some(node_id(&to.fingerprint)),
+                dest_sockaddr: None,
+            })
+            .await
+            .map_err(|_| DhtError::SendFail)
+    }
+}
+
+/// Start the worker that drops expired records, and keeps the records of the current node stored
+/// on the DHT for as long as it is running.
+pub fn start_dht_worker(dht: Arc<Dht>, crypto: Arc<RwLock<Crypto>>, endpoint: SocketAddr) {
+    tokio::spawn(async move {
+        // our records are first signed when the socket is bound
+        let mut signed = Instant::now();
+        let mut published = false;
+        loop {
+            tokio::time::sleep(DHT_INTERVAL).await;
+            trace!("start dht worker loop");
+            dht.store.write().await.expire();
+
+            // re-sign our records, so they never expire while we are around
+            if signed.elapsed() >= DHT_REPUBLISH {
+                if let Err(err) = dht.sign_own_records(&crypto, endpoint).await {
+                    error!(?err, "failed to sign DHT records");
+                    continue;
+                }
+                signed = Instant::now();
+                published = false;
+            }
+
+            // there is nobody to publish to until our first peer connects
+            if published || dht.contacts.read().await.is_empty() {
+                continue;
+            }
+            dht.publish().await;
+            published = true;
+        }
+    });
+}
@@ -0,0 +1,324 @@
+//! Defines [RecordStore], which holds the signed [dht::v1::Record]s a [super::Dht] node is
+//! responsible for, along with how records are signed and verified.
+//!
+//! Records are self-certifying: they carry the public key they are signed with, which must match
+//! their fingerprint and username. Any node can therefore store and serve them without being able
+//! to forge them.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+
+use pgp::composed::SignedPublicKey;
+use string_protocol::{crypto, dht, prost::Message};
+
+use super::{fingerprint_key, username_key, DhtError, Key};
+use crate::{
+    crypto::{Crypto, SigningError},
+    x3dh::decode_bundle,
+};
+
+/// The longest a record may be valid for. Records that claim to be valid for longer are rejected.
+pub const MAX_RECORD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The most records kept under a single key. Only username keys should ever hold more than one
+/// record per kind, if several nodes pick the same username.
+pub const MAX_RECORDS_PER_KEY: usize = 16;
+
+/// The most records a node stores for others.
+pub const MAX_STORED_RECORDS: usize = 4096;
+
+/// How far in the future a record may claim to have been signed, to allow for clock skew.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// A record whose signature has been verified.
+#[derive(Debug, Clone)]
+pub struct DhtRecord {
+    /// The fingerprint of the key the record describes, and is signed with.
+    pub fingerprint: Vec<u8>,
+    /// The username in that key.
+    pub username: String,
+    /// What the record holds.
+    pub kind: dht::v1::RecordKind,
+    /// The armored public key, the endpoint, or the encoded prekey bundle of the node.
+    pub value: Vec<u8>,
+    /// When the record was signed.
+    pub time_signed: SystemTime,
+    /// When the record stops being valid.
+    pub expires: SystemTime,
+    /// The signed record, as it is sent to other nodes.
+    pub signed: dht::v1::SignedRecord,
+}
+
+impl DhtRecord {
+    /// Returns the keys the record is stored under.
+    pub fn keys(&self) -> Vec<Key> {
+        match self.kind {
+            dht::v1::RecordKind::Pubkey => vec![
+                fingerprint_key(&self.fingerprint),
+                username_key(&self.username),
+            ],
+            _ => vec![fingerprint_key(&self.fingerprint)],
+        }
+    }
+
+    /// Returns the endpoint in the record, if it is an endpoint record.
+    pub fn endpoint(&self) -> Option<SocketAddr> {
+        match self.kind {
+            dht::v1::RecordKind::Endpoint => std::str::from_utf8(&self.value).ok()?.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns the prekey bundle in the record, if it is a prekeys record.
+    pub fn prekey_bundle(&self) -> Option<crypto::v1::PrekeyBundle> {
+        match self.kind {
+            dht::v1::RecordKind::Prekeys => decode_bundle(&self.value).ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the record is no longer valid.
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires <= now
+    }
+}
+
+/// Sign a record of the given kind for the current node, valid for `ttl`. `pubkey` is our armored
+/// public key, which is sent along with the record.
+pub fn sign_record(
+    crypto: &Crypto,
+    pubkey: Vec<u8>,
+    username: String,
+    fingerprint: Vec<u8>,
+    kind: dht::v1::RecordKind,
+    value: Vec<u8>,
+    ttl: Duration,
+) -> Result<dht::v1::SignedRecord, SigningError> {
+    let now = SystemTime::now();
+    let record = dht::v1::Record {
+        fingerprint,
+        username,
+        kind: kind.into(),
+        value,
+        time_signed: Some(now.into()),
+        expires: Some((now + ttl.min(MAX_RECORD_TTL)).into()),
+    };
+    let signature = crypto.sign_data(&record.encode_to_vec())?;
+    Ok(dht::v1::SignedRecord {
+        signature,
+        record: Some(record),
+        pubkey,
+    })
+}
+
+/// Check that a signed record is well formed and still valid, without checking its signature.
+fn parse_record(signed: &dht::v1::SignedRecord) -> Result<DhtRecord, DhtError> {
+    let record = signed.record.as_ref().ok_or(DhtError::BadRecord)?;
+    let kind = dht::v1::RecordKind::try_from(record.kind).map_err(|_| DhtError::BadRecord)?;
+    let time = |time: &Option<prost_types::Timestamp>| {
+        time.clone()
+            .and_then(|time| SystemTime::try_from(time).ok())
+            .ok_or(DhtError::BadRecord)
+    };
+    let time_signed = time(&record.time_signed)?;
+    let expires = time(&record.expires)?;
+
+    let parsed = DhtRecord {
+        fingerprint: record.fingerprint.clone(),
+        username: record.username.clone(),
+        kind,
+        value: record.value.clone(),
+        time_signed,
+        expires,
+        signed: signed.clone(),
+    };
+    let valid = match kind {
+        dht::v1::RecordKind::Pubkey => record.value == signed.pubkey,
+        dht::v1::RecordKind::Endpoint => parsed.endpoint().is_some(),
+        dht::v1::RecordKind::Prekeys => parsed.prekey_bundle().is_some(),
+        dht::v1::RecordKind::Unspecified => false,
+    };
+    if !valid {
+        return Err(DhtError::BadRecord);
+    }
+
+    let now = SystemTime::now();
+    if parsed.is_expired(now)
+        || time_signed > now + MAX_CLOCK_SKEW
+        || expires > time_signed + MAX_RECORD_TTL
+    {
+        return Err(DhtError::Expired);
+    }
+    Ok(parsed)
+}
+
+/// Verify a signed record against the public key it carries.
+pub fn verify_record(signed: &dht::v1::SignedRecord) -> Result<DhtRecord, DhtError> {
+    verify_record_with_key(signed).map(|(record, _)| record)
+}
+
+/// Same as [verify_record], but also returns the public key the record is signed with.
+pub(super) fn verify_record_with_key(
+    signed: &dht::v1::SignedRecord,
+) -> Result<(DhtRecord, SignedPublicKey), DhtError> {
+    let parsed = parse_record(signed)?;
+    let pubkey = Crypto::verify_self_certified(
+        &signed.pubkey,
+        &parsed.fingerprint,
+        &parsed.username,
+        &signed.signature,
+        // parse_record made sure the record is there
+        &signed.record.as_ref().unwrap().encode_to_vec(),
+    )?;
+    Ok((parsed, pubkey))
+}
+
+/// The records stored on a node, keyed by DHT key.
+#[derive(Debug, Default)]
+pub struct RecordStore {
+    records: HashMap<Key, Vec<DhtRecord>>,
+}
+
+impl RecordStore {
+    /// Store a verified record under the given key, replacing any older record of the same kind
+    /// from the same node. Returns false if the record was not stored, because we already have a
+    /// newer one or the store is full.
+    pub fn insert(&mut self, key: Key, record: DhtRecord) -> bool {
+        let full = self.len() >= MAX_STORED_RECORDS;
+        let records = self.records.entry(key).or_default();
+        let existing = records
+            .iter()
+            .position(|other| other.fingerprint == record.fingerprint && other.kind == record.kind);
+        match existing {
+            Some(i) if records[i].time_signed >= record.time_signed => false,
+            Some(i) => {
+                records[i] = record;
+                true
+            }
+            None if full || records.len() >= MAX_RECORDS_PER_KEY => false,
+            None => {
+                records.push(record);
+                true
+            }
+        }
+    }
+
+    /// Returns the records under the given key that are still valid.
+    pub fn get(&self, key: &Key) -> Vec<DhtRecord> {
+        let now = SystemTime::now();
+        self.records
+            .get(key)
+            .map(|records| {
+                records
+                    .iter()
+                    .filter(|record| !record.is_expired(now))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Forget records that are no longer valid.
+    pub fn expire(&mut self) {
+        let now = SystemTime::now();
+        for records in self.records.values_mut() {
+            records.retain(|record| !record.is_expired(now));
+        }
+        self.records.retain(|_, records| !records.is_empty());
+    }
+
+    /// Returns the number of stored records.
+    pub fn len(&self) -> usize {
+        self.records.values().map(Vec::len).sum()
+    }
+
+    /// Returns true if no records are stored.
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(kind: dht::v1::RecordKind, value: &[u8], signed_ago: u64) -> DhtRecord {
+        let time_signed = SystemTime::now() - Duration::from_secs(signed_ago);
+        let record = dht::v1::Record {
+            fingerprint: vec![1],
+            username: "bob".to_string(),
+            kind: kind.into(),
+            value: value.to_vec(),
+            time_signed: Some(time_signed.into()),
+            expires: Some((time_signed + Duration::from_secs(60)).into()),
+        };
+        parse_record(&dht::v1::SignedRecord {
+            signature: vec![],
+            record: Some(record),
+            pubkey: b"key".to_vec(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_record() {
+        let endpoint = record(dht::v1::RecordKind::Endpoint, b"127.0.0.1:54321", 0);
+        assert_eq!(endpoint.endpoint(), Some(([127, 0, 0, 1], 54321).into()));
+        assert_eq!(endpoint.keys(), vec![fingerprint_key(&[1])]);
+        assert_eq!(
+            record(dht::v1::RecordKind::Pubkey, b"key", 0).keys().len(),
+            2
+        );
+
+        let mut signed = endpoint.signed.clone();
+        signed.record.as_mut().unwrap().value = b"nowhere".to_vec();
+        assert!(matches!(parse_record(&signed), Err(DhtError::BadRecord)));
+
+        // a pubkey record must hold the key it is signed with
+        let mut signed = record(dht::v1::RecordKind::Pubkey, b"key", 0).signed;
+        signed.pubkey = b"other".to_vec();
+        assert!(matches!(parse_record(&signed), Err(DhtError::BadRecord)));
+
+        let mut signed = endpoint.signed;
+        signed.record.as_mut().unwrap().expires = Some(SystemTime::now().into());
+        assert!(matches!(parse_record(&signed), Err(DhtError::Expired)));
+    }
+
+    #[test]
+    fn test_keeps_latest_record() {
+        let key = fingerprint_key(&[1]);
+        let mut store = RecordStore::default();
+        assert!(store.insert(
+            key,
+            record(dht::v1::RecordKind::Endpoint, b"127.0.0.1:1", 1)
+        ));
+        assert!(store.insert(
+            key,
+            record(dht::v1::RecordKind::Endpoint, b"127.0.0.1:2", 0)
+        ));
+        assert!(!store.insert(
+            key,
+            record(dht::v1::RecordKind::Endpoint, b"127.0.0.1:3", 2)
+        ));
+        assert!(store.insert(key, record(dht::v1::RecordKind::Pubkey, b"key", 0)));
+
+        let records = store.get(&key);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].endpoint(), Some(([127, 0, 0, 1], 2).into()));
+    }
+
+    #[test]
+    fn test_records_expire() {
+        let key = fingerprint_key(&[1]);
+        let mut store = RecordStore::default();
+        let mut expired = record(dht::v1::RecordKind::Endpoint, b"127.0.0.1:1", 0);
+        expired.expires = SystemTime::now();
+        store.insert(key, expired);
+        assert!(store.get(&key).is_empty());
+        store.expire();
+        assert!(store.is_empty());
+    }
+}
@@ -0,0 +1,196 @@
+//! Defines [ContactTable], the k-buckets holding the [dht::v1::Contact]s a [super::Dht] node
+//! knows of, bucketed by their XOR distance from it.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use string_protocol::dht;
+
+use super::{fingerprint_key, Key, KEY_SIZE};
+
+/// The number of contacts kept in every bucket, and the number of nodes a record is stored on.
+pub const BUCKET_SIZE: usize = 8;
+
+/// How long a contact may go unheard from before it is replaced when its bucket is full.
+pub const STALE_CONTACT: Duration = Duration::from_secs(15 * 60);
+
+/// A contact, along with its ID and when we last heard from it.
+#[derive(Debug, Clone)]
+struct ContactEntry {
+    contact: dht::v1::Contact,
+    id: Key,
+    last_seen: Instant,
+}
+
+/// The XOR distance between two keys.
+pub fn distance(left: &Key, right: &Key) -> Key {
+    let mut distance = [0; KEY_SIZE];
+    for (i, byte) in distance.iter_mut().enumerate() {
+        *byte = left[i] ^ right[i];
+    }
+    distance
+}
+
+/// Returns the bucket `id` belongs in, as seen from `own`, which is the number of leading bits
+/// they share. Returns [None] if they are the same.
+fn bucket_index(own: &Key, id: &Key) -> Option<usize> {
+    let distance = distance(own, id);
+    let zeros = distance
+        .iter()
+        .position(|byte| *byte != 0)
+        .map(|i| i * 8 + distance[i].leading_zeros() as usize)?;
+    Some(zeros)
+}
+
+/// A table of the contacts we know of, holding at most [BUCKET_SIZE] contacts for every distance
+/// from us. Contacts we have heard from recently are kept over new ones, since long-lived nodes
+/// are the most likely to stay around.
+#[derive(Debug)]
+pub struct ContactTable {
+    /// The ID of the current node.
+    id: Key,
+    /// Contacts bucketed by the number of leading bits they share with us, least recently seen
+    /// first.
+    buckets: Vec<VecDeque<ContactEntry>>,
+}
+
+impl ContactTable {
+    /// Create an empty table for the node with the given ID.
+    pub fn new(id: Key) -> Self {
+        Self {
+            id,
+            buckets: vec![VecDeque::new(); KEY_SIZE * 8],
+        }
+    }
+
+    /// Record that we heard from the given contact. Returns true if it was added to the table.
+    pub fn insert(&mut self, contact: dht::v1::Contact) -> bool {
+        let id = fingerprint_key(&contact.fingerprint);
+        let Some(index) = bucket_index(&self.id, &id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[index];
+        let now = Instant::now();
+
+        if let Some(position) = bucket.iter().position(|entry| entry.id == id) {
+            bucket.remove(position);
+        } else if bucket.len() >= BUCKET_SIZE {
+            // only make room if the least recently seen contact has gone quiet
+            match bucket.front() {
+                Some(oldest) if now.duration_since(oldest.last_seen) >= STALE_CONTACT => {
+                    bucket.pop_front();
+                }
+                _ => return false,
+            }
+        }
+        bucket.push_back(ContactEntry {
+            contact,
+            id,
+            last_seen: now,
+        });
+        true
+    }
+
+    /// Forget the contact with the given fingerprint, e.g. because it did not reply.
+    pub fn remove(&mut self, fingerprint: &[u8]) {
+        let id = fingerprint_key(fingerprint);
+        if let Some(index) = bucket_index(&self.id, &id) {
+            self.buckets[index].retain(|entry| entry.id != id);
+        }
+    }
+
+    /// Returns up to `count` contacts, closest to `key` first.
+    pub fn closest(&self, key: &Key, count: usize) -> Vec<dht::v1::Contact> {
+        let mut entries: Vec<_> = self.buckets.iter().flatten().collect();
+        entries.sort_by_key(|entry| distance(&entry.id, key));
+        entries
+            .into_iter()
+            .take(count)
+            .map(|entry| entry.contact.clone())
+            .collect()
+    }
+
+    /// Returns the number of known contacts.
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(VecDeque::len).sum()
+    }
+
+    /// Returns true if no contacts are known.
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(VecDeque::is_empty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contact(fingerprint: u16) -> dht::v1::Contact {
+        dht::v1::Contact {
+            fingerprint: fingerprint.to_be_bytes().to_vec(),
+            username: format!("node{fingerprint}"),
+        }
+    }
+
+    #[test]
+    fn test_bucket_index() {
+        let own = [0; KEY_SIZE];
+        let mut id = [0; KEY_SIZE];
+        assert_eq!(bucket_index(&own, &id), None);
+        id[0] = 0x80;
+        assert_eq!(bucket_index(&own, &id), Some(0));
+        id[0] = 0;
+        id[1] = 0x01;
+        assert_eq!(bucket_index(&own, &id), Some(15));
+    }
+
+    #[test]
+    fn test_closest_first() {
+        let own = fingerprint_key(&[0]);
+        let mut table = ContactTable::new(own);
+        for i in 1..=BUCKET_SIZE as u16 {
+            assert!(table.insert(contact(i)));
+        }
+        assert!(!table.insert(dht::v1::Contact {
+            fingerprint: vec![0],
+            username: "me".to_string(),
+        }));
+
+        let key = fingerprint_key(&contact(7).fingerprint);
+        let closest = table.closest(&key, 4);
+        assert_eq!(closest.len(), 4);
+        assert_eq!(closest[0], contact(7));
+        let distances: Vec<_> = closest
+            .iter()
+            .map(|contact| distance(&fingerprint_key(&contact.fingerprint), &key))
+            .collect();
+        assert!(distances.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn test_full_bucket_keeps_live_contacts() {
+        let mut table = ContactTable::new([0; KEY_SIZE]);
+        let mut added = 0;
+        for i in 0..1024 {
+            if table.insert(contact(i)) {
+                added += 1;
+            }
+        }
+        // roughly half of all IDs fall in the first bucket, which only holds a few of them
+        assert!(added < 1024);
+        assert_eq!(table.len(), added);
+        assert!(table
+            .buckets
+            .iter()
+            .all(|bucket| bucket.len() <= BUCKET_SIZE));
+
+        let present = (0..1024)
+            .map(contact)
+            .find(|c| table.closest(&fingerprint_key(&c.fingerprint), 1)[0] == *c)
+            .unwrap();
+        table.remove(&present.fingerprint);
+        assert_eq!(table.len(), added - 1);
+    }
+}
@@ -0,0 +1,637 @@
+//! Implements sender-key group sessions, which encrypt what is broadcast to a channel once for
+//! every member instead of once per member.
+//!
+//! Every member of a channel has its own sender chain: a chain key that is hashed forward for
+//! every packet it broadcasts, deriving a fresh message key each time. A member sends its chain to
+//! every other member in a [crypto::v1::SenderKeyDistribution] over their ratchets, so only
+//! members can read its broadcasts. Broadcasts are signed by their source like any gossip, which
+//! tells receivers whose chain to decrypt with.
+//!
+//! When a member is added, it is sent our chain as it is now, so it cannot read what was sent
+//! before it joined. Distributions carry the members their sender knows of, so the other members
+//! learn of the new one from the chain of whoever added it, and send it theirs in turn; the chain
+//! of the new member is kept until then. When a member is removed, we start a new generation of
+//! our chain and send it to the remaining members only, so the removed member cannot read what is
+//! sent after. Distributions also carry the members their sender removed: every member that
+//! receives one drops them and rekeys in turn, and the removed members are never added back by
+//! the list of another.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, Payload},
+    ChaCha20Poly1305,
+};
+use hkdf::Hkdf;
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+use string_protocol::{crypto, prost::Message, ProtocolPacket, ProtocolPacketType};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// The size of a chain or message key, in bytes.
+const KEY_SIZE: usize = 32;
+
+/// The size of the nonce of a group ciphertext, in bytes.
+const NONCE_SIZE: usize = 12;
+
+/// How many message keys of a sender we derive ahead of the last packet we received from it, to
+/// decrypt packets that arrive out of order. Packets further ahead are dropped.
+pub const MAX_SKIPPED_KEYS: u32 = 1000;
+
+/// How many chains of nodes that are not members yet we keep, waiting for a member to add them.
+pub const MAX_PENDING_SENDERS: usize = 16;
+
+/// An enumeration of possible errors that can occur when using group sessions.
+#[derive(Error, Debug)]
+pub enum GroupKeyError {
+    /// We are not a member of the group, or do not have the key of the sender yet
+    #[error("Missing key for group")]
+    MissingKey,
+    #[error("Group ciphertext is bad")]
+    BadCiphertext,
+    /// Data could not be encrypted with our chain
+    #[error("Failed to encrypt for group")]
+    EncryptFail,
+    /// The sender is not a member of the group
+    #[error("Sender is not a member of the group")]
+    NotMember,
+    /// The packet is from a generation of the sender's chain that has been replaced, or its key
+    /// was already used
+    #[error("Group ciphertext uses a stale key")]
+    StaleKey,
+    /// A stored group session could not be restored
+    #[error("Stored group session is bad")]
+    BadStoredSession,
+}
+
+/// A sender chain, which derives a fresh message key for every packet.
+struct SenderChain {
+    generation: u32,
+    iteration: u32,
+    chain_key: Zeroizing<[u8; KEY_SIZE]>,
+    /// Message keys of iterations that were skipped, by iteration
+    skipped: BTreeMap<u32, Zeroizing<[u8; KEY_SIZE]>>,
+}
+
+impl SenderChain {
+    /// Start a new chain of the given generation from a random chain key.
+    fn generate(generation: u32) -> Self {
+        let mut chain_key = Zeroizing::new([0; KEY_SIZE]);
+        OsRng.fill_bytes(chain_key.as_mut());
+        Self {
+            generation,
+            iteration: 0,
+            chain_key,
+            skipped: BTreeMap::new(),
+        }
+    }
+
+    /// Derive the message key of the current iteration, and move the chain forward.
+    fn advance(&mut self) -> (u32, Zeroizing<[u8; KEY_SIZE]>) {
+        let hkdf = Hkdf::<Sha256>::from_prk(self.chain_key.as_ref())
+            .expect("chain key is long enough to be a PRK");
+        let mut message_key = Zeroizing::new([0; KEY_SIZE]);
+        let mut chain_key = Zeroizing::new([0; KEY_SIZE]);
+        hkdf.expand(b"string group message", message_key.as_mut())
+            .expect("message key is a valid length");
+        hkdf.expand(b"string group chain", chain_key.as_mut())
+            .expect("chain key is a valid length");
+
+        let iteration = self.iteration;
+        self.chain_key = chain_key;
+        self.iteration += 1;
+        (iteration, message_key)
+    }
+
+    /// Returns the message key of the given iteration of a chain we receive with, keeping the
+    /// keys of iterations skipped on the way.
+    fn message_key(&mut self, iteration: u32) -> Result<Zeroizing<[u8; KEY_SIZE]>, GroupKeyError> {
+        if iteration < self.iteration {
+            return self
+                .skipped
+                .remove(&iteration)
+                .ok_or(GroupKeyError::StaleKey);
+        }
+        if iteration - self.iteration > MAX_SKIPPED_KEYS {
+            return Err(GroupKeyError::BadCiphertext);
+        }
+        while self.iteration < iteration {
+            let (skipped, key) = self.advance();
+            self.skipped.insert(skipped, key);
+        }
+        // forget the oldest keys, so a sender cannot make us keep them forever
+        while self.skipped.len() > MAX_SKIPPED_KEYS as usize {
+            self.skipped.pop_first();
+        }
+        Ok(self.advance().1)
+    }
+
+    fn distribution(
+        &self,
+        channel: &str,
+        members: &BTreeSet<String>,
+        removed: &BTreeSet<String>,
+    ) -> crypto::v1::SenderKeyDistribution {
+        crypto::v1::SenderKeyDistribution {
+            channel: channel.to_string(),
+            generation: self.generation,
+            iteration: self.iteration,
+            chain_key: self.chain_key.to_vec(),
+            members: members.iter().cloned().collect(),
+            removed: removed.iter().cloned().collect(),
+        }
+    }
+
+    fn from_distribution(
+        distribution: &crypto::v1::SenderKeyDistribution,
+    ) -> Result<Self, GroupKeyError> {
+        Ok(Self {
+            generation: distribution.generation,
+            iteration: distribution.iteration,
+            chain_key: key(&distribution.chain_key).ok_or(GroupKeyError::BadCiphertext)?,
+            skipped: BTreeMap::new(),
+        })
+    }
+
+    fn export(&self) -> crypto::v1::StoredSenderChain {
+        crypto::v1::StoredSenderChain {
+            generation: self.generation,
+            iteration: self.iteration,
+            chain_key: self.chain_key.to_vec(),
+            skipped: self
+                .skipped
+                .iter()
+                .map(|(iteration, key)| (*iteration, key.to_vec()))
+                .collect(),
+        }
+    }
+
+    fn import(stored: crypto::v1::StoredSenderChain) -> Result<Self, GroupKeyError> {
+        let skipped = stored
+            .skipped
+            .into_iter()
+            .map(|(iteration, bytes)| Some((iteration, key(&bytes)?)))
+            .collect::<Option<_>>()
+            .ok_or(GroupKeyError::BadStoredSession)?;
+        Ok(Self {
+            generation: stored.generation,
+            iteration: stored.iteration,
+            chain_key: key(&stored.chain_key).ok_or(GroupKeyError::BadStoredSession)?,
+            skipped,
+        })
+    }
+}
+
+/// A group session for a channel: the members of the channel, our sender chain, and the sender
+/// chains of the other members that we received. Members are node IDs.
+pub struct GroupSession {
+    members: BTreeSet<String>,
+    own: SenderChain,
+    senders: HashMap<String, SenderChain>,
+    /// Members removed by us or by another member, which are only added back by us
+    removed: BTreeSet<String>,
+    /// Chains of nodes that are not members yet, kept until a member adds them
+    pending: HashMap<String, SenderChain>,
+}
+
+impl GroupSession {
+    /// Start a new group session with the given members. Our own chain must be sent to each of
+    /// them with [GroupSession::distribution].
+    pub fn new(members: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            members: members.into_iter().collect(),
+            own: SenderChain::generate(0),
+            senders: HashMap::new(),
+            removed: BTreeSet::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Returns the members of the group, as we know them.
+    pub fn members(&self) -> &BTreeSet<String> {
+        &self.members
+    }
+
+    /// Add a member to the group, returning false if it already was one. Our chain must be sent
+    /// to it with [GroupSession::distribution].
+    pub fn add_member(&mut self, id: String) -> bool {
+        self.removed.remove(&id);
+        self.members.insert(id)
+    }
+
+    /// Remove a member from the group and start a new generation of our chain, returning false if
+    /// it was not a member. The new chain must be sent to the remaining members with
+    /// [GroupSession::distribution].
+    pub fn remove_member(&mut self, id: &str) -> bool {
+        if !self.members.remove(id) {
+            return false;
+        }
+        self.senders.remove(id);
+        self.removed.insert(id.to_string());
+        self.own = SenderChain::generate(self.own.generation.wrapping_add(1));
+        true
+    }
+
+    /// Returns our chain as it is now, to send to the members of the group.
+    pub fn distribution(&self, channel: &str) -> crypto::v1::SenderKeyDistribution {
+        self.own.distribution(channel, &self.members, &self.removed)
+    }
+
+    /// Store the chain a member sent us, and add the members it knows of that we did not, other
+    /// than those removed. Members the sender removed are removed from our session too, and we
+    /// start a new generation of our chain if any of them was still a member. Returns the members
+    /// that must be sent our chain: every member if we rekeyed, or else those added. Chains of an
+    /// older generation than the one we have are ignored, so a replayed distribution cannot roll
+    /// a member back to a key it replaced, or remove a member again. The chain of a node that is
+    /// not a member yet is kept until a member adds it.
+    pub fn receive_distribution(
+        &mut self,
+        sender: &str,
+        distribution: &crypto::v1::SenderKeyDistribution,
+    ) -> Result<Vec<String>, GroupKeyError> {
+        let chain = SenderChain::from_distribution(distribution)?;
+        if !self.members.contains(sender) {
+            if self.removed.contains(sender)
+                || (self.pending.len() >= MAX_PENDING_SENDERS && !self.pending.contains_key(sender))
+            {
+                return Err(GroupKeyError::NotMember);
+            }
+            self.pending.insert(sender.to_string(), chain);
+            return Ok(vec![]);
+        }
+
+        let newer = match self.senders.get(sender) {
+            Some(current) => distribution.generation > current.generation,
+            None => true,
+        };
+        let mut rekey = false;
+        if newer {
+            for member in distribution.removed.iter() {
+                if self.members.remove(member) {
+                    self.senders.remove(member);
+                    rekey = true;
+                }
+                self.pending.remove(member);
+                self.removed.insert(member.clone());
+            }
+        }
+
+        let mut added = vec![];
+        for member in distribution.members.iter() {
+            if self.removed.contains(member) || !self.members.insert(member.clone()) {
+                continue;
+            }
+            if let Some(chain) = self.pending.remove(member) {
+                self.senders.insert(member.clone(), chain);
+            }
+            added.push(member.clone());
+        }
+
+        if newer {
+            self.senders.insert(sender.to_string(), chain);
+        } else if added.is_empty() {
+            return Err(GroupKeyError::StaleKey);
+        }
+        if rekey {
+            self.own = SenderChain::generate(self.own.generation.wrapping_add(1));
+            return Ok(self.members.iter().cloned().collect());
+        }
+        Ok(added)
+    }
+
+    /// Encrypt data with our chain, returning an encoded [crypto::v1::GroupCiphertext]. The
+    /// channel and our node ID are bound to the ciphertext.
+    pub fn encrypt(
+        &mut self,
+        channel: &str,
+        source: &str,
+        data: &[u8],
+    ) -> Result<Vec<u8>, GroupKeyError> {
+        let generation = self.own.generation;
+        let (iteration, message_key) = self.own.advance();
+        let cipher = ChaCha20Poly1305::new(message_key.as_ref().into());
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(channel, source, generation, iteration);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: data,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| GroupKeyError::EncryptFail)?;
+        Ok(crypto::v1::GroupCiphertext {
+            generation,
+            iteration,
+            nonce: nonce.to_vec(),
+            ciphertext,
+        }
+        .encode_to_vec())
+    }
+
+    /// Decrypt an encoded [crypto::v1::GroupCiphertext] broadcast by `sender` to the channel.
+    pub fn decrypt(
+        &mut self,
+        channel: &str,
+        sender: &str,
+        data: &[u8],
+    ) -> Result<Vec<u8>, GroupKeyError> {
+        let ciphertext =
+            crypto::v1::GroupCiphertext::decode(data).map_err(|_| GroupKeyError::BadCiphertext)?;
+        if !self.members.contains(sender) {
+            return Err(GroupKeyError::NotMember);
+        }
+        let chain = self
+            .senders
+            .get_mut(sender)
+            .ok_or(GroupKeyError::MissingKey)?;
+        if ciphertext.generation < chain.generation {
+            return Err(GroupKeyError::StaleKey);
+        }
+        if ciphertext.generation > chain.generation {
+            // the sender rekeyed, and its new chain has not reached us yet
+            return Err(GroupKeyError::MissingKey);
+        }
+        if ciphertext.nonce.len() != NONCE_SIZE {
+            return Err(GroupKeyError::BadCiphertext);
+        }
+
+        let message_key = chain.message_key(ciphertext.iteration)?;
+        let cipher = ChaCha20Poly1305::new(message_key.as_ref().into());
+        let aad = associated_data(channel, sender, ciphertext.generation, ciphertext.iteration);
+        cipher
+            .decrypt(
+                ciphertext.nonce.as_slice().into(),
+                Payload {
+                    msg: &ciphertext.ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| GroupKeyError::BadCiphertext)
+    }
+
+    /// Export the session in a form that can be stored. The result holds chain keys, so it must
+    /// never leave the device unencrypted.
+    pub fn export(&self) -> crypto::v1::StoredGroupSession {
+        crypto::v1::StoredGroupSession {
+            members: self.members.iter().cloned().collect(),
+            own: Some(self.own.export()),
+            senders: self
+                .senders
+                .iter()
+                .map(|(sender, chain)| (sender.clone(), chain.export()))
+                .collect(),
+            removed: self.removed.iter().cloned().collect(),
+        }
+    }
+
+    /// Restore a session exported with [GroupSession::export].
+    pub fn import(stored: crypto::v1::StoredGroupSession) -> Result<Self, GroupKeyError> {
+        let own = stored.own.ok_or(GroupKeyError::BadStoredSession)?;
+        let senders = stored
+            .senders
+            .into_iter()
+            .map(|(sender, chain)| Ok((sender, SenderChain::import(chain)?)))
+            .collect::<Result<_, GroupKeyError>>()?;
+        Ok(Self {
+            members: stored.members.into_iter().collect(),
+            own: SenderChain::import(own)?,
+            senders,
+            removed: stored.removed.into_iter().collect(),
+            pending: HashMap::new(),
+        })
+    }
+}
+
+/// Build the packet that sends a sender key to a member of its channel. It must be sent encrypted
+/// with the member's ratchet.
+pub fn sender_key_packet(distribution: crypto::v1::SenderKeyDistribution) -> ProtocolPacket {
+    ProtocolPacket {
+        packet_type: Some(ProtocolPacketType::PktSenderKey(distribution)),
+    }
+}
+
+/// Bind a group ciphertext to its channel, its sender and its place in the sender's chain.
+fn associated_data(channel: &str, sender: &str, generation: u32, iteration: u32) -> Vec<u8> {
+    [
+        (channel.len() as u32).to_be_bytes().as_slice(),
+        channel.as_bytes(),
+        (sender.len() as u32).to_be_bytes().as_slice(),
+        sender.as_bytes(),
+        &generation.to_be_bytes(),
+        &iteration.to_be_bytes(),
+    ]
+    .concat()
+}
+
+/// Read a key from bytes of the right length.
+fn key(bytes: &[u8]) -> Option<Zeroizing<[u8; KEY_SIZE]>> {
+    Some(Zeroizing::new(bytes.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start a group of the given members, each of which has the chains of the others.
+    fn group(members: &[&str]) -> HashMap<String, GroupSession> {
+        let mut sessions: HashMap<_, _> = members
+            .iter()
+            .map(|member| {
+                let members = members.iter().map(|member| member.to_string());
+                (member.to_string(), GroupSession::new(members))
+            })
+            .collect();
+        for sender in members {
+            let distribution = sessions[*sender].distribution("channel");
+            for (member, session) in sessions.iter_mut() {
+                if member != sender {
+                    session.receive_distribution(sender, &distribution).unwrap();
+                }
+            }
+        }
+        sessions
+    }
+
+    #[test]
+    fn test_encrypt_once_for_everyone() {
+        let mut sessions = group(&["alice", "bob", "carol"]);
+        let first = sessions
+            .get_mut("alice")
+            .unwrap()
+            .encrypt("channel", "alice", b"first")
+            .unwrap();
+        let second = sessions
+            .get_mut("alice")
+            .unwrap()
+            .encrypt("channel", "alice", b"second")
+            .unwrap();
+
+        for member in ["bob", "carol"] {
+            let session = sessions.get_mut(member).unwrap();
+            // out of order
+            assert_eq!(
+                session.decrypt("channel", "alice", &second).unwrap(),
+                b"second"
+            );
+            assert_eq!(
+                session.decrypt("channel", "alice", &first).unwrap(),
+                b"first"
+            );
+            // but only once
+            assert!(matches!(
+                session.decrypt("channel", "alice", &first),
+                Err(GroupKeyError::StaleKey)
+            ));
+            // and only from alice, in this channel
+            assert!(session.decrypt("channel", "carol", &first).is_err());
+            assert!(session.decrypt("another", "alice", &first).is_err());
+        }
+    }
+
+    #[test]
+    fn test_rekey_on_removal() {
+        let mut sessions = group(&["alice", "bob", "carol"]);
+        let alice = sessions.get_mut("alice").unwrap();
+        assert!(alice.remove_member("carol"));
+        let distribution = alice.distribution("channel");
+        let ciphertext = alice.encrypt("channel", "alice", b"secret").unwrap();
+
+        // carol only has the previous generation
+        let carol = sessions.get_mut("carol").unwrap();
+        assert!(matches!(
+            carol.decrypt("channel", "alice", &ciphertext),
+            Err(GroupKeyError::MissingKey)
+        ));
+
+        let bob = sessions.get_mut("bob").unwrap();
+        bob.receive_distribution("alice", &distribution).unwrap();
+        assert_eq!(
+            bob.decrypt("channel", "alice", &ciphertext).unwrap(),
+            b"secret"
+        );
+        // the previous generation cannot come back
+        let stale = sessions["carol"].distribution("channel");
+        assert!(sessions
+            .get_mut("bob")
+            .unwrap()
+            .receive_distribution("carol", &stale)
+            .is_err());
+    }
+
+    #[test]
+    fn test_removal_reaches_every_member() {
+        let mut sessions = group(&["alice", "bob", "carol"]);
+        let alice = sessions.get_mut("alice").unwrap();
+        alice.remove_member("carol");
+        let from_alice = alice.distribution("channel");
+
+        // bob learns of the removal from alice, and rekeys for the members that remain
+        let bob = sessions.get_mut("bob").unwrap();
+        assert_eq!(
+            bob.receive_distribution("alice", &from_alice).unwrap(),
+            vec!["alice".to_string(), "bob".to_string()]
+        );
+        assert!(!bob.members().contains("carol"));
+        let from_bob = bob.distribution("channel");
+        let ciphertext = bob.encrypt("channel", "bob", b"secret").unwrap();
+        assert!(matches!(
+            sessions
+                .get_mut("carol")
+                .unwrap()
+                .decrypt("channel", "bob", &ciphertext),
+            Err(GroupKeyError::MissingKey)
+        ));
+
+        // alice already removed carol, so she takes the new chain without rekeying again
+        let alice = sessions.get_mut("alice").unwrap();
+        assert!(alice
+            .receive_distribution("bob", &from_bob)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            alice.decrypt("channel", "bob", &ciphertext).unwrap(),
+            b"secret"
+        );
+    }
+
+    #[test]
+    fn test_add_member() {
+        let mut sessions = group(&["alice", "bob", "carol"]);
+        let alice = sessions.get_mut("alice").unwrap();
+        assert!(alice.add_member("dave".to_string()));
+        let from_alice = alice.distribution("channel");
+
+        // dave joins with the members alice knows of, and sends his chain to the others
+        let mut dave = GroupSession::new(from_alice.members.clone());
+        dave.receive_distribution("alice", &from_alice).unwrap();
+        let from_dave = dave.distribution("channel");
+        let alice = sessions.get_mut("alice").unwrap();
+
+        assert!(alice
+            .receive_distribution("dave", &from_dave)
+            .unwrap()
+            .is_empty());
+
+        // bob gets the chain of dave before hearing from alice that he was added
+        let bob = sessions.get_mut("bob").unwrap();
+        assert!(bob
+            .receive_distribution("dave", &from_dave)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            bob.receive_distribution("alice", &from_alice).unwrap(),
+            vec!["dave".to_string()]
+        );
+        // carol hears from alice first
+        let carol = sessions.get_mut("carol").unwrap();
+        assert_eq!(
+            carol.receive_distribution("alice", &from_alice).unwrap(),
+            vec!["dave".to_string()]
+        );
+        carol.receive_distribution("dave", &from_dave).unwrap();
+
+        // both send dave their chains, and everyone reads dave
+        for member in ["bob", "carol"] {
+            let distribution = sessions[member].distribution("channel");
+            assert!(dave
+                .receive_distribution(member, &distribution)
+                .unwrap()
+                .is_empty());
+        }
+        let ciphertext = dave.encrypt("channel", "dave", b"hello").unwrap();
+        for member in ["alice", "bob", "carol"] {
+            let session = sessions.get_mut(member).unwrap();
+            assert_eq!(
+                session.decrypt("channel", "dave", &ciphertext).unwrap(),
+                b"hello"
+            );
+        }
+
+        // a member we removed is not added back by the list of another
+        let bob = sessions.get_mut("bob").unwrap();
+        bob.remove_member("dave");
+        assert!(matches!(
+            bob.receive_distribution("alice", &from_alice),
+            Err(GroupKeyError::StaleKey)
+        ));
+        assert!(!bob.members().contains("dave"));
+    }
+
+    #[test]
+    fn test_export_import() {
+        let mut sessions = group(&["alice", "bob"]);
+        let ciphertext = sessions
+            .get_mut("alice")
+            .unwrap()
+            .encrypt("channel", "alice", b"hello")
+            .unwrap();
+        let mut bob = GroupSession::import(sessions["bob"].export()).unwrap();
+        assert_eq!(
+            bob.decrypt("channel", "alice", &ciphertext).unwrap(),
+            b"hello"
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! Defines [UnlockedKey], a handle to our secret key along with a decrypted copy of it.
+//!
+//! Secret keys are stored encrypted with the user's passphrase, and every signature needs the
+//! key unlocked. The passphrase is asked for once and used to decrypt the key, and is not kept
+//! around. The decrypted parameters of the key are zeroed when the key is locked again or the last
+//! handle is dropped. Handles are cheap to clone and share the unlocked state, so locking one
+//! locks them all.
+
+use std::{
+    fmt,
+    sync::{Arc, RwLock},
+};
+
+use nom::{combinator::map, multi::count};
+use pgp::{
+    composed::{SignedPublicKey, SignedSecretKey},
+    crypto::{hash::HashAlgorithm, public_key::PublicKeyAlgorithm},
+    packet::{self, SecretParams},
+    ser::Serialize,
+    types::{mpi, KeyTrait, Mpi, SecretKeyTrait},
+};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::crypto::SigningError;
+
+/// A secret key, along with a decrypted copy of it while it is unlocked.
+#[derive(Clone)]
+pub struct UnlockedKey {
+    /// The passphrase-encrypted secret key.
+    key: SignedSecretKey,
+    /// The primary key with its secret parameters decrypted, or [None] once it has been locked.
+    /// The parameters are zeroed when they are dropped.
+    decrypted: Arc<RwLock<Option<packet::SecretKey>>>,
+}
+
+impl UnlockedKey {
+    /// Unlock the key with the given passphrase, failing if it is the wrong one. Keys that are not
+    /// encrypted unlock with any passphrase.
+    pub fn unlock(key: SignedSecretKey, passphrase: String) -> Result<Self, SigningError> {
+        let decrypted = decrypt(&key, Zeroizing::new(passphrase))?;
+        Ok(Self {
+            key,
+            decrypted: Arc::new(RwLock::new(Some(decrypted))),
+        })
+    }
+
+    /// Forget the decrypted key, so nothing can be signed until the key is unlocked again.
+    pub fn lock(&self) {
+        // dropping the decrypted parameters zeroes them
+        *self
+            .decrypted
+            .write()
+            .unwrap_or_else(|err| err.into_inner()) = None;
+    }
+
+    /// Unlock a locked key again, failing if the passphrase is the wrong one.
+    pub fn unlock_again(&self, passphrase: String) -> Result<(), SigningError> {
+        let decrypted = decrypt(&self.key, Zeroizing::new(passphrase))?;
+        *self
+            .decrypted
+            .write()
+            .unwrap_or_else(|err| err.into_inner()) = Some(decrypted);
+        Ok(())
+    }
+
+    /// Returns true if the key has been locked.
+    pub fn is_locked(&self) -> bool {
+        self.decrypted
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .is_none()
+    }
+
+    /// Returns the secret key, which is still encrypted.
+    pub fn secret_key(&self) -> &SignedSecretKey {
+        &self.key
+    }
+
+    /// Returns the fingerprint of the key.
+    pub fn fingerprint(&self) -> Vec<u8> {
+        self.key.fingerprint()
+    }
+
+    /// Returns the public half of the key, self-signed so others can check it.
+    pub fn signed_public_key(&self) -> Result<SignedPublicKey, SigningError> {
+        let decrypted = self.decrypted.read().unwrap_or_else(|err| err.into_inner());
+        let decrypted = decrypted.as_ref().ok_or(SigningError::Locked)?;
+        // the decrypted key needs no passphrase
+        Ok(self.key.public_key().sign(decrypted, String::new)?)
+    }
+
+    /// Sign the SHA-256 digest of the data, returning the MPIs of the signature one after the
+    /// other, as [parse_signature] reads them back.
+    pub fn sign<Data: AsRef<[u8]>>(&self, bytes: Data) -> Result<Vec<u8>, SigningError> {
+        // So apparently the official RFC calls for more stuff but this works
+        let digest = Sha256::digest(bytes);
+        let signature = {
+            let decrypted = self.decrypted.read().unwrap_or_else(|err| err.into_inner());
+            let decrypted = decrypted.as_ref().ok_or(SigningError::Locked)?;
+            decrypted.create_signature(String::new, HashAlgorithm::SHA2_256, digest.as_slice())?
+        };
+
+        let mut allbytes: Vec<Vec<u8>> = Vec::new();
+        for mpi in signature {
+            allbytes.push(mpi.to_bytes()?);
+        }
+        Ok(allbytes.concat())
+    }
+}
+
+/// Split a signature made by [UnlockedKey::sign] back into its MPIs, given the algorithm of the
+/// key that made it: RSA signatures are a single MPI, while DSA, ECDSA and EdDSA signatures are
+/// two, `r` and `s`.
+pub fn parse_signature(
+    algorithm: PublicKeyAlgorithm,
+    signature: &[u8],
+) -> Result<Vec<Mpi>, SigningError> {
+    let mpis = match algorithm {
+        PublicKeyAlgorithm::RSA | PublicKeyAlgorithm::RSASign => 1,
+        PublicKeyAlgorithm::DSA | PublicKeyAlgorithm::ECDSA | PublicKeyAlgorithm::EdDSA => 2,
+        _ => return Err(SigningError::UnsupportedAlgorithm),
+    };
+    let (rest, signature) = count(map(mpi, |mpi| mpi.to_owned()), mpis)(signature)
+        .map_err(|_| SigningError::MpiFail)?;
+    if !rest.is_empty() {
+        return Err(SigningError::MpiFail);
+    }
+    Ok(signature)
+}
+
+/// Decrypt the primary key with the passphrase, failing if it is the wrong one.
+fn decrypt(
+    key: &SignedSecretKey,
+    passphrase: Zeroizing<String>,
+) -> Result<packet::SecretKey, SigningError> {
+    let primary = &key.primary_key;
+    let params = match primary.secret_params() {
+        SecretParams::Plain(params) => params.clone(),
+        SecretParams::Encrypted(params) => params
+            .unlock(
+                || passphrase.to_string(),
+                primary.algorithm(),
+                primary.public_params(),
+            )
+            .map_err(|_| SigningError::BadPassphrase)?,
+    };
+    Ok(packet::SecretKey::new(
+        primary.public_key(),
+        SecretParams::Plain(params),
+    ))
+}
+
+impl fmt::Debug for UnlockedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<UnlockedKey {0}>", hex::encode(self.fingerprint()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keygen::{generate_key, KeyAlgorithm};
+
+    fn secret_key() -> SignedSecretKey {
+        generate_key(
+            "alice".to_string(),
+            "passphrase".to_string(),
+            KeyAlgorithm::Ed25519,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_wrong_passphrase() {
+        assert!(matches!(
+            UnlockedKey::unlock(secret_key(), "wrong".to_string()),
+            Err(SigningError::BadPassphrase)
+        ));
+    }
+
+    #[test]
+    fn test_lock_and_unlock_again() {
+        let key = UnlockedKey::unlock(secret_key(), "passphrase".to_string()).unwrap();
+        let other = key.clone();
+        assert!(key.sign(b"data").is_ok());
+
+        // locking one handle locks them all
+        other.lock();
+        assert!(key.is_locked());
+        assert!(matches!(key.sign(b"data"), Err(SigningError::Locked)));
+        assert!(matches!(key.signed_public_key(), Err(SigningError::Locked)));
+
+        assert!(matches!(
+            key.unlock_again("wrong".to_string()),
+            Err(SigningError::BadPassphrase)
+        ));
+        assert!(key.is_locked());
+        key.unlock_again("passphrase".to_string()).unwrap();
+        assert!(!other.is_locked());
+        assert!(other.sign(b"data").is_ok());
+        assert!(other.signed_public_key().is_ok());
+    }
+}
@@ -0,0 +1,149 @@
+//! Generates the secret keys that nodes are identified by.
+//!
+//! Every gossip packet is signed by its source and verified by every node it reaches, so the
+//! algorithm of a key decides much of what gossip costs. Ed25519 signs and verifies far faster
+//! than RSA, with much smaller keys and signatures, and is the default. EdDSA keys can only sign,
+//! so they come with a Cv25519 subkey for encryption, the way OpenPGP pairs them. RSA is still
+//! offered, at sizes that are not being phased out.
+
+use std::{fmt, str::FromStr};
+
+use pgp::{
+    composed::{
+        key::{SecretKeyParamsBuilder, SubkeyParamsBuilder},
+        KeyType, SignedSecretKey,
+    },
+    crypto::{hash::HashAlgorithm, sym::SymmetricKeyAlgorithm},
+    types::CompressionAlgorithm,
+};
+use smallvec::smallvec;
+use thiserror::Error;
+
+/// An enumeration of possible errors that can occur when generating a key.
+#[derive(Error, Debug)]
+pub enum KeyGenError {
+    #[error("Unknown key algorithm {0}")]
+    UnknownAlgorithm(String),
+    #[error("Invalid key parameters: {0}")]
+    BadParams(String),
+    #[error("Failed to generate key")]
+    PgpError(#[from] pgp::errors::Error),
+}
+
+/// The algorithm of a new key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyAlgorithm {
+    /// An Ed25519 signing key, with a Cv25519 encryption subkey.
+    #[default]
+    Ed25519,
+    /// A 3072 bit RSA key.
+    Rsa3072,
+    /// A 4096 bit RSA key.
+    Rsa4096,
+}
+
+impl KeyAlgorithm {
+    /// Every algorithm keys can be generated with.
+    pub const ALL: [KeyAlgorithm; 3] = [
+        KeyAlgorithm::Ed25519,
+        KeyAlgorithm::Rsa3072,
+        KeyAlgorithm::Rsa4096,
+    ];
+
+    /// Returns the name of the algorithm, as it is parsed.
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyAlgorithm::Ed25519 => "ed25519",
+            KeyAlgorithm::Rsa3072 => "rsa3072",
+            KeyAlgorithm::Rsa4096 => "rsa4096",
+        }
+    }
+
+    /// Returns the type of the primary key, which signs.
+    fn key_type(&self) -> KeyType {
+        match self {
+            KeyAlgorithm::Ed25519 => KeyType::EdDSA,
+            KeyAlgorithm::Rsa3072 => KeyType::Rsa(3072),
+            KeyAlgorithm::Rsa4096 => KeyType::Rsa(4096),
+        }
+    }
+
+    /// Returns the type of the encryption subkey, for primary keys that cannot encrypt.
+    fn subkey_type(&self) -> Option<KeyType> {
+        match self {
+            KeyAlgorithm::Ed25519 => Some(KeyType::ECDH),
+            KeyAlgorithm::Rsa3072 | KeyAlgorithm::Rsa4096 => None,
+        }
+    }
+}
+
+impl fmt::Display for KeyAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = KeyGenError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        KeyAlgorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name() == s.to_ascii_lowercase())
+            .ok_or_else(|| KeyGenError::UnknownAlgorithm(s.to_string()))
+    }
+}
+
+/// Generate a secret key for `username` with the given algorithm, encrypted with `passphrase`
+/// and self-signed. Unlock it with [crate::UnlockedKey::unlock] to use it.
+pub fn generate_key(
+    username: String,
+    passphrase: String,
+    algorithm: KeyAlgorithm,
+) -> Result<SignedSecretKey, KeyGenError> {
+    let mut params = SecretKeyParamsBuilder::default();
+    params
+        .key_type(algorithm.key_type())
+        .can_certify(false)
+        .can_sign(true)
+        .primary_user_id(username)
+        .passphrase(Some(passphrase.clone()))
+        .preferred_symmetric_algorithms(smallvec![SymmetricKeyAlgorithm::AES256])
+        .preferred_hash_algorithms(smallvec![HashAlgorithm::SHA2_256])
+        .preferred_compression_algorithms(smallvec![CompressionAlgorithm::ZLIB]);
+    if let Some(subkey_type) = algorithm.subkey_type() {
+        let subkey = SubkeyParamsBuilder::default()
+            .key_type(subkey_type)
+            .can_encrypt(true)
+            .passphrase(Some(passphrase.clone()))
+            .build()
+            .map_err(|err| KeyGenError::BadParams(err.to_string()))?;
+        params.subkey(subkey);
+    }
+
+    let key = params
+        .build()
+        .map_err(|err| KeyGenError::BadParams(err.to_string()))?
+        .generate()?;
+    Ok(key.sign(|| passphrase)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_algorithm_names() {
+        for algorithm in KeyAlgorithm::ALL {
+            assert_eq!(
+                algorithm.to_string().parse::<KeyAlgorithm>().unwrap(),
+                algorithm
+            );
+        }
+        assert_eq!(
+            "Ed25519".parse::<KeyAlgorithm>().unwrap(),
+            KeyAlgorithm::Ed25519
+        );
+        assert!("rsa2048".parse::<KeyAlgorithm>().is_err());
+    }
+}
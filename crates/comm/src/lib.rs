@@ -3,10 +3,21 @@
 //! This crate contains the communication code for string
 
 pub mod crypto;
+pub mod device;
+pub mod dht;
+pub mod group;
+pub mod key;
+pub mod keygen;
 pub mod peer;
+pub mod reset;
+pub mod rotation;
+pub mod safety;
 pub mod socket;
 pub mod util;
+pub mod x3dh;
 
+pub use key::UnlockedKey;
+pub use keygen::{generate_key, KeyAlgorithm};
 pub use peer::Peer;
 pub use socket::Socket;
 
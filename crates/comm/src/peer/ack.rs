@@ -1,4 +1,8 @@
-use std::{collections::HashSet, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use tokio::{
     select,
@@ -11,11 +15,15 @@ use crate::{socket::SocketPacket, try_break};
 
 use super::PeerState;
 
+/// Packets that have not been ACKed yet, keyed by packet and chunk number, along with when they
+/// were sent. The send time is cleared once a packet is retransmitted.
+pub type PendingAcks = Arc<RwLock<HashMap<(u32, u32), Option<Instant>>>>;
+
 /// Periodically checks if we've received an ACK for a packet, and if not, resends the packet.
 /// Times out after 30s and transitions the peer to the dead state.
 pub fn start_ack_timeout_worker(
     state: Arc<RwLock<PeerState>>,
-    packet_acks: Arc<RwLock<HashSet<(u32, u32)>>>,
+    packet_acks: PendingAcks,
     net_outbound_tx: mpsc::Sender<SocketPacket>,
     net_packet: SocketPacket,
 ) {
@@ -35,9 +43,14 @@ pub fn start_ack_timeout_worker(
                 loop {
                     // wait for 1s before checking if we've received an ACK
                     tokio::time::sleep(Duration::from_millis(1000)).await;
-                    let has_packet = { packet_acks.read().await.contains(&(packet_number, chunk_number))};
-                    if !has_packet {
-                        break;
+                    {
+                        let mut packet_acks = packet_acks.write().await;
+                        match packet_acks.get_mut(&(packet_number, chunk_number)) {
+                            // an ACK for a retransmitted packet is ambiguous, so don't use it to
+                            // measure the round trip time
+                            Some(sent) => *sent = None,
+                            None => break,
+                        }
                     }
                     // retransmit
                     try_break!(net_outbound_tx.send(net_packet.clone()).await);
@@ -46,3 +59,11 @@ pub fn start_ack_timeout_worker(
         }
     });
 }
+
+/// Fold a new round trip time sample into the smoothed round trip time.
+pub fn update_rtt(rtt: &mut Option<Duration>, sample: Duration) {
+    *rtt = Some(match *rtt {
+        Some(smoothed) => (smoothed * 7 + sample) / 8,
+        None => sample,
+    });
+}
@@ -1,6 +1,12 @@
 use crate::{
     crypto::{DoubleRatchetError, SigningError},
+    device::DeviceError,
+    dht::DhtError,
+    group::GroupKeyError,
+    reset::SessionResetError,
+    rotation::KeyStatementError,
     socket::SocketPacket,
+    x3dh::X3dhError,
 };
 
 use string_protocol::{PacketDecodeError, PacketEncodeError, ProtocolPacket};
@@ -25,9 +31,27 @@ pub enum PeerError {
     // Failure in double ratchet
     #[error("Failure in double ratchet")]
     DRFail(#[from] DoubleRatchetError),
+    // Failure in group encryption
+    #[error("Failure in group encryption")]
+    GroupFail(#[from] GroupKeyError),
     // Generic error with signature
     #[error("Failure in signature verification")]
     SigFail(#[from] SigningError),
+    // A DHT message could not be verified
+    #[error("Failure in DHT message")]
+    DhtFail(#[from] DhtError),
+    // A prekey message could not be decrypted
+    #[error("Failure in prekey message")]
+    X3dhFail(#[from] X3dhError),
+    // A key rotation or revocation could not be verified
+    #[error("Failure in key statement")]
+    KeyStatementFail(#[from] KeyStatementError),
+    // A device certificate could not be verified
+    #[error("Failure in device certificate")]
+    DeviceFail(#[from] DeviceError),
+    // A session reset could not be accepted
+    #[error("Failure in session reset")]
+    SessionResetFail(#[from] SessionResetError),
     /// The packet we received does not conform to some format
     #[error("Bad packet")]
     BadPacket,
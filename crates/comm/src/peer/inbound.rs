@@ -3,22 +3,26 @@
 
 use std::{
     cmp::Reverse,
-    collections::{BinaryHeap, HashMap, HashSet},
+    collections::{BinaryHeap, HashMap},
     net::SocketAddr,
     sync::Arc,
+    time::Duration,
 };
 
-use string_protocol::{try_decode_packet, ProtocolPacket, ProtocolPacketType};
+use string_protocol::{try_decode_packet, CodecSet, ProtocolPacket, ProtocolPacketType};
 use tokio::sync::{mpsc, RwLock};
 use tracing::{debug, error, trace};
 
 use crate::{
     maybe_break, maybe_continue,
-    socket::{Gossip, GossipAction, SocketPacket, SocketPacketType},
+    socket::{decrement_ttl, Gossip, GossipAction, SocketPacket, SocketPacketType},
     try_break, try_continue, Peer,
 };
 
-use super::PeerState;
+use super::{
+    ack::{update_rtt, PendingAcks},
+    PeerState,
+};
 
 /// Starts the background tasks that handle receiving packets from the network and forwarding their
 /// decoded contents to the application.
@@ -30,8 +34,10 @@ pub fn start_peer_receiver_worker(
     mut net_inbound_rx: mpsc::Receiver<SocketPacket>,
     remote_addr: SocketAddr,
     peers: Arc<RwLock<HashMap<SocketAddr, Peer>>>,
-    packet_acks: Arc<RwLock<HashSet<(u32, u32)>>>,
+    packet_acks: PendingAcks,
     gossip_tx: mpsc::Sender<Gossip>,
+    codecs: Arc<RwLock<CodecSet>>,
+    rtt: Arc<RwLock<Option<Duration>>>,
 ) {
     tokio::task::spawn(async move {
         // priority queue for packets - this guarantees correct sequencing of UDP
@@ -62,13 +68,14 @@ pub fn start_peer_receiver_worker(
                             // initiator never receives SYN or SYNACK
                         }
                         SocketPacketType::Ack => {
+                            negotiate_codecs(&codecs, &packet).await;
                             // write to network
                             try_break!(
                                 net_outbound_tx
-                                    .send(SocketPacket::empty(
+                                    .send(SocketPacket::handshake(
                                         SocketPacketType::SynAck,
                                         packet.packet_number,
-                                        0,
+                                        CodecSet::supported(),
                                     ))
                                     .await
                             );
@@ -101,8 +108,12 @@ pub fn start_peer_receiver_worker(
                             // responder never receives ACK
                         }
                         SocketPacketType::Syn => {
-                            let ack =
-                                SocketPacket::empty(SocketPacketType::Ack, packet.packet_number, 0);
+                            negotiate_codecs(&codecs, &packet).await;
+                            let ack = SocketPacket::handshake(
+                                SocketPacketType::Ack,
+                                packet.packet_number,
+                                CodecSet::supported(),
+                            );
                             // write to network
                             try_break!(
                                 net_outbound_tx.send(ack).await,
@@ -110,6 +121,7 @@ pub fn start_peer_receiver_worker(
                             );
                         }
                         SocketPacketType::SynAck => {
+                            negotiate_codecs(&codecs, &packet).await;
                             debug!(
                                 ?current_state,
                                 next = ?PeerState::Established,
@@ -139,7 +151,10 @@ pub fn start_peer_receiver_worker(
                     | SocketPacketType::Invalid => {}
                     SocketPacketType::Ack => {
                         let mut packets = packet_acks.write().await;
-                        packets.remove(&(packet.packet_number, packet.chunk_number));
+                        let sent = packets.remove(&(packet.packet_number, packet.chunk_number));
+                        if let Some(Some(sent)) = sent {
+                            update_rtt(&mut *rtt.write().await, sent.elapsed());
+                        }
                     }
                     SocketPacketType::Data => {
                         // send ack
@@ -188,6 +203,7 @@ pub fn start_peer_receiver_worker(
                                 }
 
                                 let signed_packet = gossip.packet.as_ref().unwrap();
+                                let ttl = gossip.ttl;
 
                                 let forward = {
                                     let mut peers_write = peers.write().await;
@@ -202,16 +218,18 @@ pub fn start_peer_receiver_worker(
                                     try_continue!(
                                         peer.dispatch_gossip(
                                             signed_packet.clone(),
+                                            gossip.hops.clone(),
                                             app_inbound_tx.clone(),
-                                            remote_addr,
                                             gossip_tx.clone()
                                         )
                                         .await
-                                    )
+                                    ) && peer.check_ttl(ttl, signed_packet)
                                 };
                                 // ..., otherwise, forward it on to our peers
                                 if forward {
                                     debug!("going to forward packet");
+                                    let mut packet = packet;
+                                    decrement_ttl(&mut packet);
                                     let _ = gossip_tx
                                         .send(Gossip {
                                             action: GossipAction::Forward,
@@ -242,11 +260,7 @@ pub fn start_peer_receiver_worker(
                             )) => {
                                 let mut peers_write = peers.write().await;
                                 let peer = maybe_continue!(peers_write.get_mut(&remote_addr));
-                                peer.received_available_peers(
-                                    send_available_peers.peers,
-                                    send_available_peers.time_sent,
-                                )
-                                .await;
+                                peer.received_available_peers(send_available_peers).await;
                             }
                             _ => {}
                         }
@@ -261,3 +275,11 @@ pub fn start_peer_receiver_worker(
         }
     });
 }
+
+/// Record the codecs the remote peer advertised in a handshake packet. We only ever compress with
+/// codecs that both sides support.
+async fn negotiate_codecs(codecs: &RwLock<CodecSet>, packet: &SocketPacket) {
+    let negotiated = CodecSet::supported().intersection(packet.advertised_codecs());
+    debug!(?negotiated, "negotiated codecs");
+    *codecs.write().await = negotiated;
+}
@@ -9,23 +9,35 @@ mod outbound;
 
 use crate::{
     crypto::{Crypto, DoubleRatchet, DoubleRatchetError, SigningError},
-    socket::{Gossip, GossipAction, SocketPacket, MIN_SOCKET_PACKET_SIZE, UDP_MAX_DATAGRAM_SIZE},
+    dht::{verify_sender, verify_sender_record, Dht},
+    group::{sender_key_packet, GroupKeyError},
+    socket::{
+        encrypt_gossip, gossip_ack, hop, receipt_packet, seen_key, sign_gossip, verify_peer_entry,
+        Gossip, GossipAction, GossipLimiter, GossipStats, Outbox, PexTable, RoutingTable,
+        SeenCache, SocketEvent, SocketPacket, GOSSIP_ID_SIZE, MAX_PEX_VERIFY, MAX_TRACE_HOPS,
+        MIN_SOCKET_PACKET_SIZE, UDP_MAX_DATAGRAM_SIZE,
+    },
 };
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashMap,
     fmt,
     net::SocketAddr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use prost_types::Timestamp;
+use sha2::{Digest, Sha256};
 
 use string_protocol::{
-    crypto, gossip, peers, try_decode_packet, try_encode_internal_packet, try_encode_packet,
+    crypto, dht, messages, peers, probe, try_decode_packet, try_encode_internal_packet, CodecSet,
     MessageType, ProtocolPacket, ProtocolPacketType,
 };
 
-use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio::sync::{broadcast, mpsc, Mutex, RwLock};
 
 use tracing::{debug, span, warn, Level};
 
@@ -36,6 +48,10 @@ use self::{
 /// The buffer size of the various channels used for passing data between the network tasks.
 pub const CHANNEL_SIZE: usize = 32;
 
+/// How long before our current time an advertisement of routes may have been sent. Every node
+/// only refreshes its time now and then, so the clocks of two peers are never quite in step.
+pub const AVAILABLE_PEERS_TOLERANCE: Duration = Duration::from_secs(30);
+
 /// The maximum size of an [ProtocolPacket] chunk before it needs to be split into multiple
 /// [SocketPacket]s.
 const MAX_PROTOCOL_PACKET_CHUNK_SIZE: usize = UDP_MAX_DATAGRAM_SIZE - MIN_SOCKET_PACKET_SIZE;
@@ -70,17 +86,41 @@ pub struct Peer {
     pub crypto: Arc<RwLock<Crypto>>,
     /// A reference to the socket's peers.
     pub peers: Arc<RwLock<HashMap<SocketAddr, Peer>>>,
-    /// The username of our current node, not to be confused with name of peer
-    /// TODO: switch to a slightly more rigorous notion of identity.
-    pub username: String,
-    ///
-    pub peername: Option<String>,
-    ///
+    /// The node ID of our current node, not to be confused with the ID of the peer
+    pub id: String,
+    /// The node ID of the peer, once its public key is verified against its fingerprint
+    pub peer_id: Option<String>,
+    /// The fingerprint we expect the key of the peer to have
     pub fingerprint: Vec<u8>,
 	/// Reference to the current time
 	pub curr_time: Arc<RwLock<Timestamp>>,
-    /// Contains the list of usernames of peers that are available to it
-    pub available_peers: HashSet<String>, 
+    /// A reference to the socket's routing table, which is updated with the routes this peer
+    /// advertises.
+    pub routes: Arc<RwLock<RoutingTable>>,
+    /// A reference to the socket's table of nodes learnt through peer exchange, which is updated
+    /// with the entries this peer sends.
+    pub pex: Arc<RwLock<PexTable>>,
+    /// A reference to the socket's node of the DHT, which this peer is a contact of once its key
+    /// is verified.
+    pub dht: Arc<Dht>,
+    /// A reference to the socket's outbox, which is woken when new routes appear.
+    pub outbox: Arc<Outbox>,
+    /// The compression codecs negotiated with the peer during the handshake.
+    pub codecs: Arc<RwLock<CodecSet>>,
+    /// The smoothed round trip time to the peer, measured from ACKs.
+    pub rtt: Arc<RwLock<Option<Duration>>>,
+    /// A reference to the socket's cache of recently seen gossip IDs.
+    pub seen: Arc<Mutex<SeenCache>>,
+    /// A reference to the socket's rate limits for received gossip.
+    pub limiter: Arc<Mutex<GossipLimiter>>,
+    /// A reference to the socket's gossip counters.
+    pub stats: Arc<GossipStats>,
+    /// Channel used to publish [SocketEvent]s.
+    pub events: broadcast::Sender<SocketEvent>,
+    /// The number of hops gossip sent by this node may travel.
+    pub gossip_ttl: u32,
+    /// Set while peer entries sent by this peer are being verified.
+    pex_verifying: Arc<AtomicBool>,
 }
 
 impl fmt::Debug for Peer {
@@ -91,15 +131,25 @@ impl fmt::Debug for Peer {
 
 impl Peer {
     /// Create a new connection to the given destination.
+    #[allow(clippy::too_many_arguments)]
     #[tracing::instrument(name = "peer", skip(initiate))]
     pub fn new(
         remote_addr: SocketAddr,
         crypto: Arc<RwLock<Crypto>>,
         peers: Arc<RwLock<HashMap<SocketAddr, Peer>>>,
-        username: String,
+        id: String,
         gossip_tx: mpsc::Sender<Gossip>,
         fingerprint: Vec<u8>,
 		curr_time: Arc<RwLock<Timestamp>>, 
+        routes: Arc<RwLock<RoutingTable>>,
+        pex: Arc<RwLock<PexTable>>,
+        dht: Arc<Dht>,
+        outbox: Arc<Outbox>,
+        seen: Arc<Mutex<SeenCache>>,
+        limiter: Arc<Mutex<GossipLimiter>>,
+        stats: Arc<GossipStats>,
+        events: broadcast::Sender<SocketEvent>,
+        gossip_ttl: u32,
         initiate: bool,
     ) -> Result<
         (
@@ -124,7 +174,10 @@ impl Peer {
         }));
 
         let packet_number = Arc::new(Mutex::new(0));
-        let pending_acks = Arc::new(RwLock::new(HashSet::new()));
+        let pending_acks = Arc::new(RwLock::new(HashMap::new()));
+        let rtt = Arc::new(RwLock::new(None));
+        // until the handshake completes, we can only assume the peer reads uncompressed packets
+        let codecs = Arc::new(RwLock::new(CodecSet::none()));
 
         span!(Level::TRACE, "peer::receiver", %remote_addr).in_scope(|| {
             start_peer_receiver_worker(
@@ -136,6 +189,8 @@ impl Peer {
                 peers.clone(),
                 pending_acks.clone(),
                 gossip_tx.clone(),
+                codecs.clone(),
+                rtt.clone(),
             )
         });
 
@@ -147,6 +202,7 @@ impl Peer {
                 crypto.clone(),
                 packet_number.clone(),
                 pending_acks.clone(),
+                codecs.clone(),
             )
         });
 
@@ -158,12 +214,22 @@ impl Peer {
                 state,
                 crypto,
                 peers,
-                username: username.clone(),
-                peername: None,
+                id,
+                peer_id: None,
                 fingerprint,
 				curr_time,
-				// peers contains itself
-                available_peers: HashSet::from_iter(vec![username]),
+                routes,
+                pex,
+                dht,
+                outbox,
+                codecs,
+                rtt,
+                seen,
+                limiter,
+                stats,
+                events,
+                gossip_ttl,
+                pex_verifying: Arc::new(AtomicBool::new(false)),
             },
             app_inbound_rx,
             net_outbound_rx,
@@ -179,12 +245,16 @@ impl Peer {
         Ok(())
     }
 
-    /// Send the peers that we can see available right now
+    /// Send the routes that we know of right now, along with the entries of the nodes we know of
     pub async fn send_available_peers(&mut self) -> Result<(), PeerError> {
+        let routes = self.routes.read().await.advertise(self.remote_addr);
+        let entries = self.pex.read().await.advertise(&self.fingerprint);
         let send_available_peers =
             ProtocolPacketType::PktSendAvailablePeers(peers::v1::SendAvailablePeers {
-                peers: self.available_peers.clone().into_iter().collect(),
+                peers: routes.iter().map(|route| route.node_id.clone()).collect(),
                 time_sent: Some(self.curr_time.read().await.clone()),
+                routes,
+                entries,
             });
 
         let packet_tosend = ProtocolPacket {
@@ -195,45 +265,119 @@ impl Peer {
         Ok(())
     }
 
-    /// Now that we've received peers from another person, we check if we can expand our own set of peers
-    pub async fn received_available_peers(&mut self, peers: Vec<String>, time_sent: Option<Timestamp>) {
-		if let Some(time_sent) = time_sent{
-			if compare_timestamps(self.curr_time.read().await.clone(), time_sent){
-				self.available_peers = self.available_peers.union(&HashSet::from_iter(peers)).cloned().collect();
-			}
+    /// Now that we've received routes from another person, we check if we can reach more
+    /// destinations, or reach them in fewer hops. Advertisements sent more than
+    /// [AVAILABLE_PEERS_TOLERANCE] before our current time are stale and ignored. The peer entries
+    /// that come with them are verified in the background and recorded, no matter how old the
+    /// advertisement is, since every entry carries its own signing time.
+    pub async fn received_available_peers(&mut self, available: peers::v1::SendAvailablePeers) {
+        self.received_peer_entries(&available.entries).await;
+
+        let Some(mut time_sent) = available.time_sent else {
+            return;
+        };
+        time_sent.seconds += AVAILABLE_PEERS_TOLERANCE.as_secs() as i64;
+        if compare_timestamps(time_sent, self.curr_time.read().await.clone()) {
+            debug!("ignoring stale available peers");
+            return;
+        }
+
+        let reachable = {
+            let mut routes = self.routes.write().await;
+            if available.routes.is_empty() {
+                // the peer does not understand routes, so all we know is that it can reach these
+                routes.update(
+                    self.remote_addr,
+                    available.peers.into_iter().map(|id| (id, 1)),
+                )
+            } else {
+                routes.update(
+                    self.remote_addr,
+                    available
+                        .routes
+                        .into_iter()
+                        .map(|route| (route.node_id, route.hops)),
+                )
+            }
+        };
+        if reachable {
+            self.outbox.wake().await;
         }
     }
 
+    /// Record the given peer entries that we did not have yet. We are called with the socket's
+    /// peers locked, so verifying the signatures is left to a task, of which each peer gets one
+    /// at a time, with at most [MAX_PEX_VERIFY] entries.
+    async fn received_peer_entries(&mut self, entries: &[peers::v1::SignedPeerEntry]) {
+        let unknown: Vec<_> = {
+            let mut pex = self.pex.write().await;
+            entries
+                .iter()
+                .filter(|signed| !pex.refresh_known(signed))
+                .take(MAX_PEX_VERIFY)
+                .cloned()
+                .collect()
+        };
+        if unknown.is_empty() {
+            return;
+        }
+        if self.pex_verifying.swap(true, Ordering::AcqRel) {
+            debug!("still verifying peer entries, ignoring new ones");
+            return;
+        }
+
+        let pex = self.pex.clone();
+        let events = self.events.clone();
+        let verifying = self.pex_verifying.clone();
+        tokio::spawn(async move {
+            let verified = tokio::task::spawn_blocking(move || {
+                unknown
+                    .iter()
+                    .filter_map(|signed| match verify_peer_entry(signed) {
+                        Ok(peer) => Some(peer),
+                        Err(err) => {
+                            debug!(?err, "ignoring bad peer entry");
+                            None
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .await
+            .unwrap_or_default();
+            verifying.store(false, Ordering::Release);
+
+            let mut pex = pex.write().await;
+            for peer in verified {
+                let discovered = SocketEvent::PeerDiscovered {
+                    fingerprint: peer.fingerprint.clone(),
+                    username: peer.username.clone(),
+                    addr: peer.addr,
+                };
+                if pex.insert(peer) {
+                    // nobody may be listening, which is fine
+                    let _ = events.send(discovered);
+                }
+            }
+        });
+    }
+
     /// Helper function to package and sign a [MessageType] as [Gossip] packet and send to this peer only
     /// For distributing gossip check Socket class instead.
-    /// TODO: Sign the gossip packet's contents with our private key
     pub async fn send_gossip_single(
         &mut self,
         message: crypto::v1::signed_packet_internal::MessageType,
         destination: String,
     ) -> Result<(), PeerError> {
-        let weaken = destination == "*";
-        let internal = crypto::v1::SignedPacketInternal {
+        let tosend = sign_gossip(
+            &self.crypto,
+            &self.seen,
+            &self.limiter,
+            self.id.clone(),
+            message,
             destination,
-            source: self.username.clone(),
-            message_type: Some(message),
-        };
-        let signature = {
-            if !weaken {
-                let crypto = self.crypto.read().await;
-                crypto.sign_data(&try_encode_internal_packet(&internal)?)?
-            }
-            else { vec![] }
-        };
-        let gossip = ProtocolPacketType::PktGossip(gossip::v1::Gossip {
-            packet: Some(crypto::v1::SignedPacket {
-                signature,
-                signed_data: Some(internal),
-            }),
-        });
-        let tosend = ProtocolPacket {
-            packet_type: Some(gossip),
-        };
+            self.gossip_ttl,
+        )
+        .await?;
         self.send_packet(tosend).await?;
         Ok(())
     }
@@ -245,78 +389,174 @@ impl Peer {
         packet: ProtocolPacket,
         destination: String,
     ) -> Result<(), PeerError> {
-        let bytes = try_encode_packet(&packet).map_err(PeerError::EncodeFail)?;
-        // encrypt message contents
-        let content = {
-            if destination == "*" {
-                let mut crypto = self.crypto.write().await;
-                let ratchet = crypto
-                    .ratchets
-                    .get_mut(&destination)
-                    .ok_or(PeerError::DRFail(DoubleRatchetError::MissingRatchet))?;
-                ratchet.encrypt(&bytes).map_err(PeerError::DRFail)?
-            }
-            else { bytes }
-        };
-        self.send_gossip_single(
-            MessageType::EncryptedPacket(crypto::v1::EncryptedPacket { content }),
-            destination,
-        )
-        .await?;
+        let message = encrypt_gossip(&self.crypto, &packet, &destination).await?;
+        self.send_gossip_single(message, destination).await?;
         Ok(())
     }
 
     /// Dispatches gossip packet based on the following logic:
+    /// 0. If we have already seen this packet's gossip ID and signature, or our peer is over its
+    ///    rate limit, drop it before verifying anything. Once verified, gossip from a source over
+    ///    its rate limit is dropped too
     /// 1. If this packet is not intended for our node as destination, return true
-    ///    so the caller can forward it on
+    ///    so the caller can forward it on. Broadcasts are intended for every node, and are
+    ///    forwarded once they are verified, or undelivered if we do not know their source
     ///
     /// Otherwise, check the message inside the gossip packet:
     ///    2. if it's a [KeyExchange] try to establish the DR ratchet
-    ///    3. if it's an [EncryptedPacket] decrypt and forward it to the app
+    ///    3. if it's an [EncryptedPacket] decrypt and forward it to the app, then acknowledge it
+    ///    4. if it's a [GossipAck] clear the acknowledged gossip from our outbox, if the source is
+    ///       the node it was sent to
+    ///    5. if it's a [DhtMessage] hand it to the DHT
+    ///    6. if it's a [Probe] reply to it, along with the `hops` it took if it is a trace
+    ///    7. if it's a [ProbeReply] publish it for whoever sent the probe
+    ///    8. if it's a [PrekeyMessage] start a DR ratchet from our prekeys, reply with a
+    ///       [KeyExchange] so the source can complete it, and deliver the packet inside
+    ///    9. if it's a [SignedKeyStatement] apply the rotation or revocation, and forward it if it
+    ///       was broadcast and new to us
+    ///    10. if it's a [SignedDeviceCertificate] add the device to its identity, and forward it
+    ///       the same way
+    ///    11. if it's a [SessionReset] replace our ratchet with the source with a responder to
+    ///       its key exchange, and reply with a [KeyExchange]
 
     async fn dispatch_gossip(
         &mut self,
         signed_packet: crypto::v1::SignedPacket,
+        hops: Vec<probe::v1::Hop>,
         app_inbound_tx: mpsc::Sender<ProtocolPacket>,
-        remote_addr: SocketAddr,
         gossip_tx: mpsc::Sender<Gossip>,
     ) -> Result<bool, PeerError> {
         let signature = signed_packet.signature;
         let signed_data = signed_packet.signed_data.ok_or(PeerError::BadPacket)?;
         debug!("dispatching gossip {:?}", signed_data.clone());
 
+        // drop gossip we have already delivered or forwarded
+        if signed_data.id.len() != GOSSIP_ID_SIZE {
+            return Err(PeerError::BadPacket);
+        }
+        let seen = seen_key(&signed_data.id, &signature);
+        if self.seen.lock().await.contains(&seen) {
+            debug!("dropping duplicate gossip");
+            self.stats.duplicates.fetch_add(1, Ordering::Relaxed);
+            return Ok(false);
+        }
+        // rate limited gossip is not marked as seen, so a copy through a quieter peer still counts
+        if let Err(err) = self.limiter.lock().await.admit(self.remote_addr) {
+            debug!(
+                ?err,
+                source = signed_data.source,
+                "dropping rate limited gossip"
+            );
+            self.stats.rate_limited.fetch_add(1, Ordering::Relaxed);
+            return Ok(false);
+        }
+        self.seen.lock().await.insert(&seen);
+        self.stats.received.fetch_add(1, Ordering::Relaxed);
+
         let cloned_signed_data = signed_data.clone();
         let source = signed_data.source;
         let mut forward = false;
         let dest = signed_data.destination;
-        let weaken = dest == "*";
-
-        if dest == self.username || dest == "*" {
-            if !weaken {
-                let crypto_obj = self.crypto.read().await;
-                crypto_obj.verify_data(
-                    &source,
-                    &signature,
-                    &try_encode_internal_packet(&cloned_signed_data)?,
-                )?;
+        let broadcast = dest == "*";
+
+        if dest == self.id || broadcast {
+            // key statements carry the keys they are about and are verified on their own, since
+            // their source may well be the key being revoked, and so are device certificates
+            match signed_data.message_type {
+                Some(MessageType::KeyStatement(ref signed)) => {
+                    let applied = self.crypto.write().await.apply_key_statement(signed)?;
+                    return Ok(applied && broadcast);
+                }
+                Some(MessageType::DeviceCertificate(ref signed)) => {
+                    let applied = self.crypto.write().await.apply_device_certificate(signed)?;
+                    return Ok(applied && broadcast);
+                }
+                _ => {}
+            }
+            if self.crypto.read().await.statements.is_revoked(&source) {
+                return Err(PeerError::SigFail(SigningError::Revoked));
+            }
+            // every gossip for us must be signed by its source, broadcasts included - we neither
+            // deliver nor pass on a broadcast with a bad signature. One from a node we do not
+            // know the key of is passed on undelivered, since others may know it. DHT messages
+            // carry the key of their source, since they are how we learn keys in the first place,
+            // and so do prekey messages, which may come from a node we have never heard of
+            let bytes = try_encode_internal_packet(&cloned_signed_data)?;
+            let sender = match signed_data.message_type {
+                Some(MessageType::Dht(ref message)) if !broadcast => {
+                    let (pubkey, contact) = verify_sender(message, &source)?;
+                    Crypto::verify_data_static(&pubkey, &signature, &bytes)?;
+                    Some((pubkey, contact))
+                }
+                Some(MessageType::PrekeyMessage(ref message)) if !broadcast => {
+                    let (pubkey, contact) = verify_sender_record(message.sender.as_ref(), &source)?;
+                    Crypto::verify_data_static(&pubkey, &signature, &bytes)?;
+                    Some((pubkey, contact))
+                }
+                _ => {
+                    let verified = self
+                        .crypto
+                        .read()
+                        .await
+                        .verify_data(&source, &signature, &bytes);
+                    match verified {
+                        Err(SigningError::MissingPubKey)
+                            if broadcast
+                                && matches!(
+                                    signed_data.message_type,
+                                    Some(MessageType::EncryptedPacket(_))
+                                ) =>
+                        {
+                            debug!(source, "forwarding broadcast from unknown source");
+                            return Ok(true);
+                        }
+                        verified => verified?,
+                    }
+                    None
+                }
+            };
+            // only now that the source is known to have sent it does the gossip count against
+            // the source
+            if let Err(err) = self.limiter.lock().await.admit_source(&cloned_signed_data) {
+                debug!(?err, source, "dropping rate limited gossip");
+                self.stats.rate_limited.fetch_add(1, Ordering::Relaxed);
+                return Ok(false);
+            }
+            // broadcasts only ever carry packets for the application, and keep spreading once
+            // we have read them
+            if broadcast {
+                if !matches!(
+                    signed_data.message_type,
+                    Some(MessageType::EncryptedPacket(_))
+                ) {
+                    return Err(PeerError::BadPacket);
+                }
+                forward = true;
             }
             match signed_data.message_type {
                 Some(MessageType::KeyExchange(dr)) => {
                     let mut crypto_obj = self.crypto.write().await;
+                    let local = crypto_obj.key.fingerprint();
+                    let remote = crypto_obj.fingerprint_of(&source)?;
                     let ratchet = crypto_obj
                         .ratchets
                         .entry(source.clone())
                         .or_insert_with(DoubleRatchet::new_responder);
+                    // both nodes started a key exchange at the same time
+                    if ratchet.resolve_glare(&dr, &local, &remote) {
+                        debug!(source, "keeping our key exchange over the one of the node");
+                        return Ok(false);
+                    }
                     match ratchet {
                         DoubleRatchet::Responder { .. } => {
-                            if ratchet.handle_kex(dr).is_ok() {};
-                            let kex = ratchet.generate_kex_message();
+                            ratchet.handle_kex(dr, &local, &remote)?;
+                            let kex = ratchet.generate_kex_message()?;
                             drop(crypto_obj);
                             self.send_gossip_single(MessageType::KeyExchange(kex), source)
                                 .await?;
                         }
-                        DoubleRatchet::Initiator { .. } => {
-                            if ratchet.handle_kex(dr).is_ok() {};
+                        DoubleRatchet::Initiator { .. } | DoubleRatchet::PrekeyInitiator { .. } => {
+                            ratchet.handle_kex(dr, &local, &remote)?;
                             drop(crypto_obj);
                             self.send_gossip_single_encrypted(
                                 ProtocolPacket { packet_type: None },
@@ -324,100 +564,383 @@ impl Peer {
                             )
                             .await?;
                         }
+                        // a late copy of the key exchange that started the session: an established
+                        // session is only replaced by a reset
                         DoubleRatchet::AlmostInitialized { .. }
                         | DoubleRatchet::Initialized { .. } => {
-                            unreachable!()
+                            debug!(source, "ignoring key exchange for an established ratchet");
                         }
                     }
                 }
                 Some(MessageType::EncryptedPacket(enc)) => {
+                    // a retry of a packet we already delivered: our acknowledgement must have
+                    // been lost, so send it again instead of decrypting it a second time. Content
+                    // digests are longer than gossip IDs, so they share the seen-cache safely.
+                    let digest = Sha256::digest(&enc.content).to_vec();
+                    if !broadcast && self.seen.lock().await.contains(&digest) {
+                        debug!("acknowledging redelivered gossip");
+                        self.send_gossip_ack(&gossip_tx, signed_data.id, source)
+                            .await;
+                        return Ok(false);
+                    }
                     let bytes = {
-                        if !weaken {
+                        if !broadcast {
+                            // unicast is always encrypted with the ratchet
+                            if !enc.group.is_empty() {
+                                return Err(PeerError::BadPacket);
+                            }
                             let mut crypto = self.crypto.write().await;
-                            let ratchet = crypto
-                                .ratchets
-                                .get_mut(&source)
-                                .ok_or(PeerError::DRFail(DoubleRatchetError::MissingRatchet))?;
-                            ratchet.decrypt(&enc.content).map_err(PeerError::DRFail)?
+                            let decrypted = match crypto.ratchets.get_mut(&source) {
+                                Some(ratchet) => ratchet.decrypt(&enc.content),
+                                None => Err(DoubleRatchetError::MissingRatchet),
+                            };
+                            match decrypted {
+                                Ok(bytes) => {
+                                    crypto.decrypt_succeeded(&source);
+                                    bytes
+                                }
+                                Err(err) => {
+                                    // only the source can sign packets that fail, so a ratchet
+                                    // that keeps failing has diverged from its own
+                                    if let Some(reset) =
+                                        crypto.decrypt_failed(&source, &enc.content)?
+                                    {
+                                        drop(crypto);
+                                        self.outbox.discard(&source, &self.events).await;
+                                        self.send_gossip_single(
+                                            MessageType::SessionReset(reset),
+                                            source,
+                                        )
+                                        .await?;
+                                    }
+                                    return Err(PeerError::DRFail(err));
+                                }
+                            }
+                        } else if !enc.group.is_empty() {
+                            match self.crypto.write().await.group_decrypt(
+                                &enc.group,
+                                &source,
+                                &enc.content,
+                            ) {
+                                Ok(bytes) => bytes,
+                                // not a member of the channel, or not one with the source, just
+                                // pass it on
+                                Err(
+                                    GroupKeyError::MissingKey
+                                    | GroupKeyError::NotMember
+                                    | GroupKeyError::StaleKey,
+                                ) => return Ok(true),
+                                Err(err) => return Err(err.into()),
+                            }
+                        } else {
+                            enc.content
                         }
-                        else { enc.content }
                     };
                     let packet = try_decode_packet(bytes).map_err(PeerError::DecodeFail)?;
-                    app_inbound_tx.send(packet).await?;
+                    self.deliver(packet, &source, broadcast, &app_inbound_tx, &gossip_tx)
+                        .await?;
+                    if !broadcast {
+                        self.seen.lock().await.insert(&digest);
+                        self.send_gossip_ack(&gossip_tx, signed_data.id, source)
+                            .await;
+                    }
                 }
-                Some(MessageType::PubKeyRequest(_)) => unreachable!(),
-                Some(MessageType::PubKeyReply(reply)) => {
-                    // TODO: forward reply to those asking in reply_to
-                    let tosend = {
-                        let mut crypto_obj = self.crypto.write().await;
-                        crypto_obj.insert_pubkey_reply_to(dest.clone(), None)
-                    };
-                    if tosend.is_some() {
-                        for asking in tosend.unwrap() {
-                            let _ = gossip_tx
-                                .send(Gossip {
-                                    action: GossipAction::SendDirect,
-                                    addr: None,
-                                    packet: None,
-                                    message: Some(MessageType::PubKeyReply(reply.clone())),
-                                    dest: None,
-                                    dest_sockaddr: Some(asking),
-                                })
+                Some(MessageType::GossipAck(ack)) => {
+                    self.outbox.ack(&source, &ack.id).await;
+                }
+                Some(MessageType::Dht(message)) => {
+                    if let Some((_, contact)) = sender {
+                        self.dht.handle(contact, message).await;
+                    }
+                }
+                Some(MessageType::PrekeyMessage(message)) => {
+                    // the same redelivery check as for encrypted packets
+                    let digest = Sha256::digest(&message.content).to_vec();
+                    if self.seen.lock().await.contains(&digest) {
+                        debug!("acknowledging redelivered prekey message");
+                        self.send_gossip_ack(&gossip_tx, signed_data.id, source)
+                            .await;
+                        return Ok(false);
+                    }
+                    let (pubkey, _) = sender.ok_or(PeerError::BadPacket)?;
+                    let (kex, bytes) = {
+                        let mut crypto = self.crypto.write().await;
+                        if matches!(
+                            crypto.ratchets.get(&source),
+                            Some(DoubleRatchet::Initialized { .. })
+                        ) {
+                            // a ratchet replaced behind our back would lose every packet the
+                            // source already encrypted with it
+                            warn!(source, "dropping prekey message, ratchet already exists");
+                            drop(crypto);
+                            self.send_gossip_ack(&gossip_tx, signed_data.id, source)
                                 .await;
+                            return Ok(false);
                         }
+                        let (secrets, bytes) = crypto.prekeys.respond(&message)?;
+                        crypto.add_pubkey(pubkey)?;
+                        let remote = crypto.fingerprint_of(&source)?;
+                        let mut ratchet = DoubleRatchet::new_prekey_responder(
+                            &secrets.root,
+                            secrets.session_id(),
+                            &crypto.key.fingerprint(),
+                            &remote,
+                        );
+                        let kex = ratchet.generate_kex_message()?;
+                        crypto.ratchets.insert(source.clone(), ratchet);
+                        (kex, bytes)
                     };
-                    {
-                        let mut crypto_obj = self.crypto.write().await;
-                        let _ = crypto_obj.add_pubkey_raw(&reply.pubkey);
+                    self.send_gossip_single(MessageType::KeyExchange(kex), source.clone())
+                        .await?;
+
+                    let packet = try_decode_packet(bytes).map_err(PeerError::DecodeFail)?;
+                    self.deliver(packet, &source, false, &app_inbound_tx, &gossip_tx)
+                        .await?;
+                    self.seen.lock().await.insert(&digest);
+                    self.send_gossip_ack(&gossip_tx, signed_data.id, source)
+                        .await;
+
+                    // a one-time prekey was used up, so republish our bundle without it
+                    let (dht, crypto) = (self.dht.clone(), self.crypto.clone());
+                    tokio::spawn(async move {
+                        match dht.sign_prekeys(&crypto).await {
+                            Ok(()) => dht.publish().await,
+                            Err(err) => warn!(?err, "failed to sign prekeys"),
+                        }
+                    });
+                }
+                Some(MessageType::SessionReset(reset)) => {
+                    let kex = self
+                        .crypto
+                        .write()
+                        .await
+                        .accept_session_reset(&source, reset)?;
+                    if let Some(kex) = kex {
+                        self.outbox.discard(&source, &self.events).await;
+                        self.send_gossip_single(MessageType::KeyExchange(kex), source)
+                            .await?;
                     }
                 }
+                Some(MessageType::Probe(probe)) => {
+                    let path = match probe.trace {
+                        true => hops
+                            .into_iter()
+                            .take(MAX_TRACE_HOPS)
+                            .chain([hop(&self.id)])
+                            .collect(),
+                        false => vec![],
+                    };
+                    let reply = probe::v1::ProbeReply {
+                        probe_id: probe.probe_id,
+                        path,
+                    };
+                    let _ = gossip_tx
+                        .send(Gossip {
+                            action: GossipAction::Send,
+                            addr: None,
+                            packet: None,
+                            message: Some(MessageType::ProbeReply(reply)),
+                            dest: Some(source),
+                            dest_sockaddr: None,
+                        })
+                        .await;
+                }
+                Some(MessageType::ProbeReply(reply)) => {
+                    // nobody may be listening, which is fine
+                    let _ = self.events.send(SocketEvent::ProbeReply {
+                        from: source,
+                        probe_id: reply.probe_id,
+                        path: reply.path,
+                        return_path: hops,
+                    });
+                }
+                // public keys are looked up in the DHT now
+                Some(MessageType::PubKeyRequest(_)) | Some(MessageType::PubKeyReply(_)) => {
+                    debug!("ignoring public key gossip");
+                }
+                // handled before verification
+                Some(MessageType::KeyStatement(_)) | Some(MessageType::DeviceCertificate(_)) => {}
                 None => {}
             }
         } else {
             forward = true;
             match signed_data.message_type {
-                Some(MessageType::KeyExchange(_)) | Some(MessageType::EncryptedPacket(_)) => {}
-                Some(MessageType::PubKeyRequest(..)) => {
+                Some(MessageType::KeyExchange(_))
+                | Some(MessageType::EncryptedPacket(_))
+                | Some(MessageType::GossipAck(_))
+                | Some(MessageType::Dht(_))
+                | Some(MessageType::Probe(_))
+                | Some(MessageType::ProbeReply(_))
+                | Some(MessageType::PrekeyMessage(_))
+                | Some(MessageType::KeyStatement(_))
+                | Some(MessageType::DeviceCertificate(_))
+                | Some(MessageType::SessionReset(_)) => {}
+                // nodes that have not moved to the DHT yet still flood these
+                Some(MessageType::PubKeyRequest(_)) | Some(MessageType::PubKeyReply(_)) => {
                     forward = false;
-                    let mut crypto_obj = self.crypto.write().await;
-                    let pubkey = crypto_obj.lookup_pubkey(dest.clone());
-                    if pubkey.is_some() {
-                        let armored = pubkey
-                            .unwrap()
-                            .to_armored_bytes(None)
-                            .map_err(SigningError::PgpError)?;
-                        drop(crypto_obj);
-                        if self.peername.is_some() {
-                            self.send_gossip_single(
-                                MessageType::PubKeyReply(crypto::v1::PubKeyReply {
-                                    owner: dest.clone(),
-                                    pubkey: armored,
-                                }),
-                                self.peername.clone().unwrap(),
-                            )
-                            .await?;
-                        }
-                    } else {
-                        let tosend =
-                            crypto_obj.insert_pubkey_reply_to(dest.clone(), Some(remote_addr));
-                        drop(crypto_obj);
-                        // len == 1 means only our remote_addr
-                        if tosend.is_some() && tosend.unwrap().len() == 1 {
-                            self.send_gossip_single(
-                                MessageType::PubKeyRequest(crypto::v1::PubKeyRequest {}),
-                                dest,
-                            )
-                            .await?;
-                        }
-                    }
                 }
-                Some(MessageType::PubKeyReply(_)) => unreachable!(),
                 None => {}
             }
         }
         Ok(forward)
     }
 
+    /// Deliver a decrypted packet from `source` to the application. Receipts are published as
+    /// [SocketEvent::Receipt] instead, and messages are answered with a delivery receipt, unless
+    /// they were broadcast. Transcripts are only delivered from our other devices.
+    async fn deliver(
+        &self,
+        packet: ProtocolPacket,
+        source: &str,
+        broadcast: bool,
+        app_inbound_tx: &mpsc::Sender<ProtocolPacket>,
+        gossip_tx: &mpsc::Sender<Gossip>,
+    ) -> Result<(), PeerError> {
+        match packet.packet_type {
+            // receipts are for us, not the application
+            Some(ProtocolPacketType::PktReceipt(receipt)) if !broadcast => {
+                debug!(source, "received receipt");
+                // nobody may be listening, which is fine
+                let _ = self.events.send(SocketEvent::Receipt {
+                    from: source.to_string(),
+                    receipt_type: receipt.r#type(),
+                    message_ids: receipt.message_ids,
+                    time: receipt.time,
+                });
+            }
+            // so are sender keys of the channels we are a member of
+            Some(ProtocolPacketType::PktSenderKey(distribution)) if !broadcast => {
+                debug!(
+                    source,
+                    channel = distribution.channel,
+                    "received sender key"
+                );
+                let reply =
+                    self.crypto
+                        .write()
+                        .await
+                        .receive_sender_key(&self.id, source, &distribution);
+                match reply {
+                    // we were just added to the channel, or learnt of new members, which need
+                    // our key too
+                    Ok(Some((own, members))) => {
+                        for member in members {
+                            self.send_sender_key(gossip_tx, own.clone(), member).await;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(err) => warn!(?err, source, "dropping sender key"),
+                }
+            }
+            // only our own devices can tell us what we sent
+            Some(ProtocolPacketType::PktSentTranscript(_)) if !broadcast => {
+                let own = {
+                    let crypto = self.crypto.read().await;
+                    crypto.devices.identity_of(source).is_some()
+                        && crypto.devices.same_identity(source, &self.id)
+                };
+                match own {
+                    true => app_inbound_tx.send(packet).await?,
+                    false => warn!(source, "dropping transcript from another identity"),
+                }
+            }
+            Some(ProtocolPacketType::PktMessage(ref message)) if !broadcast => {
+                let id = message.id.clone();
+                app_inbound_tx.send(packet).await?;
+                self.send_receipt(
+                    gossip_tx,
+                    source.to_string(),
+                    messages::v1::ReceiptType::Delivered,
+                    vec![id],
+                )
+                .await;
+            }
+            _ => app_inbound_tx.send(packet).await?,
+        }
+        Ok(())
+    }
+
+    /// Acknowledge the delivery of the gossip with the given ID to its source, so it is cleared
+    /// from the source's outbox.
+    async fn send_gossip_ack(&self, gossip_tx: &mpsc::Sender<Gossip>, id: Vec<u8>, source: String) {
+        let _ = gossip_tx
+            .send(Gossip {
+                action: GossipAction::Send,
+                addr: None,
+                packet: None,
+                message: Some(gossip_ack(id)),
+                dest: Some(source),
+                dest_sockaddr: None,
+            })
+            .await;
+    }
+
+    /// Send our sender key for a channel to a member of it.
+    async fn send_sender_key(
+        &self,
+        gossip_tx: &mpsc::Sender<Gossip>,
+        distribution: crypto::v1::SenderKeyDistribution,
+        destination: String,
+    ) {
+        let _ = gossip_tx
+            .send(Gossip {
+                action: GossipAction::SendEncrypted,
+                addr: None,
+                packet: Some(sender_key_packet(distribution)),
+                message: None,
+                dest: Some(destination),
+                dest_sockaddr: None,
+            })
+            .await;
+    }
+
+    /// Send a receipt for messages we received from `destination` back to it.
+    async fn send_receipt(
+        &self,
+        gossip_tx: &mpsc::Sender<Gossip>,
+        destination: String,
+        receipt_type: messages::v1::ReceiptType,
+        message_ids: Vec<String>,
+    ) {
+        let _ = gossip_tx
+            .send(Gossip {
+                action: GossipAction::SendEncrypted,
+                addr: None,
+                packet: Some(receipt_packet(receipt_type, message_ids)),
+                message: None,
+                dest: Some(destination),
+                dest_sockaddr: None,
+            })
+            .await;
+    }
+
+    /// Check whether gossip with the given TTL may be forwarded another hop. If it has run out
+    /// of hops, the drop is counted and published as a [SocketEvent::GossipExpired].
+    fn check_ttl(&self, ttl: u32, signed_packet: &crypto::v1::SignedPacket) -> bool {
+        if ttl > 1 {
+            return true;
+        }
+        self.stats.ttl_expired.fetch_add(1, Ordering::Relaxed);
+
+        let (id, source, destination) = match signed_packet.signed_data {
+            Some(ref data) => (
+                data.id.clone(),
+                data.source.clone(),
+                data.destination.clone(),
+            ),
+            None => Default::default(),
+        };
+        debug!(?id, source, destination, "dropping gossip, TTL expired");
+        // nobody may be listening, which is fine
+        let _ = self.events.send(SocketEvent::GossipExpired {
+            id,
+            source,
+            destination,
+            from: self.remote_addr,
+        });
+        false
+    }
+
     async fn send_pubkey(&mut self) -> Result<(), PeerError> {
         let armored = self.crypto.read().await.get_self_pubkey()?;
         self.send_packet(ProtocolPacket {
@@ -430,16 +953,31 @@ impl Peer {
     }
 
     async fn add_peer_pubkey(&mut self, pubkey_bytes: &Vec<u8>) -> Result<(), PeerError> {
-        let peername = self.crypto.write().await.try_add_peer_pubkey(
-            self.remote_addr,
-            pubkey_bytes,
-            &self.fingerprint,
-        )?;
+        let (peer_id, username) = {
+            let mut crypto = self.crypto.write().await;
+            let peer_id =
+                crypto.try_add_peer_pubkey(self.remote_addr, pubkey_bytes, &self.fingerprint)?;
+            let username = crypto.username_of(&peer_id).unwrap_or_default();
+            (peer_id, username)
+        };
         // If we get here, fingerprint verified peer's pubkey
-        self.peername = Some(peername);
+        let reachable = self
+            .routes
+            .write()
+            .await
+            .add_neighbour(peer_id.clone(), self.remote_addr);
+        if reachable {
+            self.outbox.wake().await;
+        }
+        self.dht
+            .add_contact(dht::v1::Contact {
+                fingerprint: self.fingerprint.clone(),
+                username,
+            })
+            .await;
+        self.peer_id = Some(peer_id);
         Ok(())
     }
-
 }
 
 /// compares timestamps
@@ -1,16 +1,22 @@
 //! This module contains the background task for sending packets to the network, taking packets from
 //! the application, encoding them as [SocketPacket]s, then sending them to the network.
 
-use std::{collections::HashSet, sync::Arc, time::Duration};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use string_protocol::{try_encode_packet, ProtocolPacket, ProtocolPacketType};
+use string_protocol::{try_encode_packet_with, CodecSet, ProtocolPacket, ProtocolPacketType};
 use tokio::sync::{mpsc, Mutex, RwLock};
 use tracing::{debug, error, trace, warn};
 
 use crate::{
     crypto::Crypto,
     maybe_break,
-    peer::{ack::start_ack_timeout_worker, MAX_PROTOCOL_PACKET_CHUNK_SIZE},
+    peer::{
+        ack::{start_ack_timeout_worker, PendingAcks},
+        MAX_PROTOCOL_PACKET_CHUNK_SIZE,
+    },
     socket::{SocketPacket, SocketPacketType},
     try_break, try_continue,
 };
@@ -25,7 +31,8 @@ pub fn start_peer_sender_worker(
     mut app_outbound_rx: mpsc::Receiver<ProtocolPacket>,
     _crypto: Arc<RwLock<Crypto>>,
     packet_number: Arc<Mutex<u32>>,
-    pending_acks: Arc<RwLock<HashSet<(u32, u32)>>>,
+    pending_acks: PendingAcks,
+    codecs: Arc<RwLock<CodecSet>>,
 ) {
     tokio::task::spawn(async move {
         let mut syns_sent: u32 = 0;
@@ -42,10 +49,11 @@ pub fn start_peer_sender_worker(
             if current_state == PeerState::Init || current_state == PeerState::Connect {
                 try_break!(
                     net_outbound_tx
-                        .send(
-                            SocketPacket::new(SocketPacketType::Syn, syns_sent, 0, vec![])
-                                .expect("failed to create packet")
-                        )
+                        .send(SocketPacket::handshake(
+                            SocketPacketType::Syn,
+                            syns_sent,
+                            CodecSet::supported()
+                        ))
                         .await
                 );
                 syns_sent += 1;
@@ -66,9 +74,13 @@ pub fn start_peer_sender_worker(
             let mut packet_number = packet_number.lock().await;
             let mut pending_acks_write = pending_acks.write().await;
 
-            // encode packet
+            // encode packet, compressing only with codecs the peer can decode
             trace!("encode packet: {:?}", packet);
-            let buf = try_continue!(try_encode_packet(&packet), "Failed to encode packet");
+            let codecs = { *codecs.read().await };
+            let buf = try_continue!(
+                try_encode_packet_with(&packet, codecs),
+                "Failed to encode packet"
+            );
 
             // split packet into network packets and send
             for net_packet in
@@ -96,8 +108,10 @@ pub fn start_peer_sender_worker(
                 match net_outbound_tx.send(net_packet.clone()).await {
                     Ok(_) => {
                         // add the packet to hashmap of packets that we don't have a ACK to
-                        pending_acks_write
-                            .insert((net_packet.packet_number, net_packet.chunk_number));
+                        pending_acks_write.insert(
+                            (net_packet.packet_number, net_packet.chunk_number),
+                            Some(Instant::now()),
+                        );
 
                         // start a task that will wait for an ACK for this packet
                         start_ack_timeout_worker(
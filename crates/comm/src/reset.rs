@@ -0,0 +1,209 @@
+//! Defines session resets, which replace a ratchet that no longer decrypts what its peer sends.
+//!
+//! Two ratchets diverge when one side loses its state, restores an old copy of it, or misses the
+//! key exchange that completed it. Every packet then fails to decrypt, and keeps failing. Gossip
+//! is signed by its source, but a relay can replay what the source sent once it has left our seen
+//! cache, so only ciphertexts on a chain we still expect count as failures: after
+//! [MAX_DECRYPT_FAILURES] of them in a row we replace our ratchet with a new initiator, and send
+//! the peer a [crypto::v1::SessionReset] carrying its key exchange. The peer replaces its own
+//! ratchet with a responder and answers with a key exchange, which completes the new session the
+//! usual way. Packets waiting in either outbox were encrypted with the old ratchets, so they are
+//! dropped. We start at most one reset with a node every [RESET_COOLDOWN], so that packets
+//! already on their way when the session was reset do not start another, and accept each reset
+//! once, so a replayed one cannot break the session it started.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    time::{Duration, Instant, SystemTime},
+};
+
+use string_protocol::crypto;
+use thiserror::Error;
+
+use crate::crypto::{DoubleRatchetError, SigningError};
+
+/// How many packets from a node must fail to decrypt in a row before we reset the session.
+pub const MAX_DECRYPT_FAILURES: u32 = 3;
+
+/// The least amount of time between two resets we start with the same node.
+pub const RESET_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// How long after it was sent a reset is still accepted, so an old one cannot be replayed to
+/// break a session. Resets dated further ahead of our clock than this are refused too.
+pub const MAX_RESET_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// How long we remember the resets we accepted. A reset is only accepted within
+/// [MAX_RESET_AGE] of its sending time, on either side, so this covers every replay of it.
+pub const ACCEPTED_RESET_TTL: Duration = Duration::from_secs(2 * MAX_RESET_AGE.as_secs());
+
+/// An enumeration of possible errors that can occur when accepting a session reset.
+#[derive(Error, Debug)]
+pub enum SessionResetError {
+    #[error("Session reset carries no key exchange")]
+    MissingKeyExchange,
+    #[error("Session reset has no sending time")]
+    MissingTime,
+    #[error("Session reset is too old")]
+    Stale,
+    #[error("Session reset was already accepted")]
+    Replayed,
+    #[error("Missing public key for node")]
+    SigningFail(#[from] SigningError),
+    #[error("Failed to start the new session")]
+    RatchetFail(#[from] DoubleRatchetError),
+}
+
+/// Build the reset that starts a new session with the given key exchange.
+pub fn session_reset(key_exchange: crypto::v1::DrKeyExchange) -> crypto::v1::SessionReset {
+    crypto::v1::SessionReset {
+        key_exchange: Some(key_exchange),
+        time_sent: Some(SystemTime::now().into()),
+    }
+}
+
+/// Check that a reset was sent recently, returning the key exchange it carries.
+pub fn check_reset(
+    reset: crypto::v1::SessionReset,
+    now: SystemTime,
+) -> Result<crypto::v1::DrKeyExchange, SessionResetError> {
+    let time_sent = reset
+        .time_sent
+        .and_then(|time| SystemTime::try_from(time).ok())
+        .ok_or(SessionResetError::MissingTime)?;
+    // a clock somewhat ahead of ours is not a replay
+    let age = match now.duration_since(time_sent) {
+        Ok(age) => age,
+        Err(err) => err.duration(),
+    };
+    if age > MAX_RESET_AGE {
+        return Err(SessionResetError::Stale);
+    }
+    reset
+        .key_exchange
+        .ok_or(SessionResetError::MissingKeyExchange)
+}
+
+/// Tracks how the ratchet with each node is doing, to tell when it should be reset.
+#[derive(Debug, Default)]
+pub struct SessionHealth {
+    /// Decrypt failures in a row, by node ID.
+    failures: HashMap<String, u32>,
+    /// When we last reset the session, by node ID.
+    resets: HashMap<String, Instant>,
+    /// When we accepted a reset, by the DH pubkey of its key exchange.
+    accepted: HashMap<Vec<u8>, Instant>,
+}
+
+impl SessionHealth {
+    /// Record that a packet from the node failed to decrypt. Returns true if the session should
+    /// be reset.
+    pub fn record_failure(&mut self, node: &str, now: Instant) -> bool {
+        let failures = self.failures.entry(node.to_string()).or_default();
+        *failures = failures.saturating_add(1);
+        if *failures < MAX_DECRYPT_FAILURES {
+            return false;
+        }
+        match self.resets.get(node) {
+            Some(reset) => now.duration_since(*reset) >= RESET_COOLDOWN,
+            None => true,
+        }
+    }
+
+    /// Record that a packet from the node decrypted.
+    pub fn record_success(&mut self, node: &str) {
+        self.failures.remove(node);
+    }
+
+    /// Record that the session with the node was reset, by either side.
+    pub fn record_reset(&mut self, node: &str, now: Instant) {
+        self.failures.remove(node);
+        self.resets.insert(node.to_string(), now);
+    }
+
+    /// Record that we are accepting a reset with the given key exchange. Returns false if we
+    /// already accepted it.
+    pub fn record_accepted(
+        &mut self,
+        key_exchange: &crypto::v1::DrKeyExchange,
+        now: Instant,
+    ) -> bool {
+        self.accepted
+            .retain(|_, accepted| now.duration_since(*accepted) < ACCEPTED_RESET_TTL);
+        match self.accepted.entry(key_exchange.dh_pubkey.clone()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reset_after_failures() {
+        let mut health = SessionHealth::default();
+        let now = Instant::now();
+        for _ in 1..MAX_DECRYPT_FAILURES {
+            assert!(!health.record_failure("bob", now));
+        }
+        // a packet that decrypts starts the count over
+        health.record_success("bob");
+        for _ in 1..MAX_DECRYPT_FAILURES {
+            assert!(!health.record_failure("bob", now));
+        }
+        assert!(health.record_failure("bob", now));
+
+        // packets sent before the reset keep failing for a while
+        health.record_reset("bob", now);
+        for _ in 0..MAX_DECRYPT_FAILURES * 2 {
+            assert!(!health.record_failure("bob", now));
+        }
+        assert!(health.record_failure("bob", now + RESET_COOLDOWN));
+        assert!(!health.record_failure("carol", now));
+    }
+
+    #[test]
+    fn test_accept_reset_once() {
+        let mut health = SessionHealth::default();
+        let now = Instant::now();
+        let key_exchange = crypto::v1::DrKeyExchange {
+            dh_pubkey: vec![1; 32],
+            dr_pubkey: vec![],
+        };
+        assert!(health.record_accepted(&key_exchange, now));
+        assert!(!health.record_accepted(&key_exchange, now + MAX_RESET_AGE));
+        // forgotten once it can no longer pass [check_reset]
+        assert!(health.record_accepted(&key_exchange, now + ACCEPTED_RESET_TTL));
+    }
+
+    #[test]
+    fn test_check_reset() {
+        let now = SystemTime::now();
+        let reset = session_reset(crypto::v1::DrKeyExchange::default());
+        assert!(check_reset(reset.clone(), now).is_ok());
+        assert!(check_reset(reset.clone(), now - Duration::from_secs(60)).is_ok());
+        assert!(matches!(
+            check_reset(reset.clone(), now + MAX_RESET_AGE * 2),
+            Err(SessionResetError::Stale)
+        ));
+        assert!(check_reset(reset.clone(), now - MAX_RESET_AGE / 2).is_ok());
+        assert!(matches!(
+            check_reset(reset.clone(), now - MAX_RESET_AGE * 2),
+            Err(SessionResetError::Stale)
+        ));
+        assert!(matches!(
+            check_reset(
+                crypto::v1::SessionReset {
+                    key_exchange: None,
+                    ..reset
+                },
+                now
+            ),
+            Err(SessionResetError::MissingKeyExchange)
+        ));
+    }
+}
@@ -0,0 +1,314 @@
+//! Defines key rotation and revocation statements, which let a user retire a key.
+//!
+//! A rotation is signed by the key it retires and certifies the key that replaces it, so contacts
+//! follow the node to its new node ID without having to verify it again. A revocation is signed by
+//! the key it revokes, and tells every node to stop trusting it. Whoever compromised a key can sign
+//! a rotation with it dated whenever they like, so once a key is revoked we accept no new rotation
+//! of it, and no node ID is resolved through it. Both carry the keys they are about, so any node
+//! can verify them and pass them on. They are broadcast as gossip, and registered with the
+//! lighthouse for the nodes that were offline.
+
+use std::{collections::HashMap, io::Cursor, time::SystemTime};
+
+use pgp::{composed::SignedPublicKey, types::KeyTrait, Deserializable};
+use string_protocol::{crypto, prost::Message};
+use thiserror::Error;
+
+use crate::{
+    crypto::{node_id, Crypto, SigningError},
+    key::UnlockedKey,
+};
+
+/// The most rotations followed when resolving a node ID, which stops rotation loops.
+pub const MAX_ROTATION_CHAIN: usize = 16;
+
+/// An enumeration of possible errors that can occur when handling key statements.
+#[derive(Error, Debug)]
+pub enum KeyStatementError {
+    #[error("Key statement is missing")]
+    MissingStatement,
+    #[error("Key statement has no signing time")]
+    MissingTime,
+    #[error("Key rotates to itself")]
+    SelfRotation,
+    #[error("Failed to verify key statement")]
+    SigningFail(#[from] SigningError),
+    #[error("Malformed key in key statement")]
+    PgpError(#[from] pgp::errors::Error),
+    #[error("Key has been revoked")]
+    Revoked,
+    #[error("Too many key rotations")]
+    RotationLoop,
+}
+
+/// A key statement whose signature has been verified.
+#[derive(Debug, Clone)]
+pub enum VerifiedStatement {
+    /// `previous` is replaced by `next`.
+    Rotation {
+        previous: SignedPublicKey,
+        next: SignedPublicKey,
+        time_signed: SystemTime,
+    },
+    /// `pubkey` must no longer be trusted.
+    Revocation {
+        pubkey: SignedPublicKey,
+        reason: String,
+        time_signed: SystemTime,
+    },
+}
+
+impl VerifiedStatement {
+    /// Returns the key the statement is about, which signed it.
+    pub fn subject(&self) -> &SignedPublicKey {
+        match self {
+            VerifiedStatement::Rotation { previous, .. } => previous,
+            VerifiedStatement::Revocation { pubkey, .. } => pubkey,
+        }
+    }
+}
+
+/// Sign a statement that our key is replaced by `next`.
+pub fn sign_rotation(
+    key: &UnlockedKey,
+    next: &SignedPublicKey,
+) -> Result<crypto::v1::SignedKeyStatement, SigningError> {
+    sign_statement(
+        key,
+        crypto::v1::key_statement::Statement::Rotation(crypto::v1::KeyRotation {
+            previous_pubkey: key.signed_public_key()?.to_armored_bytes(None)?,
+            pubkey: next.to_armored_bytes(None)?,
+            time_signed: Some(SystemTime::now().into()),
+        }),
+    )
+}
+
+/// Sign a statement that our key must no longer be trusted.
+pub fn sign_revocation(
+    key: &UnlockedKey,
+    reason: String,
+) -> Result<crypto::v1::SignedKeyStatement, SigningError> {
+    sign_statement(
+        key,
+        crypto::v1::key_statement::Statement::Revocation(crypto::v1::KeyRevocation {
+            pubkey: key.signed_public_key()?.to_armored_bytes(None)?,
+            reason,
+            time_signed: Some(SystemTime::now().into()),
+        }),
+    )
+}
+
+fn sign_statement(
+    key: &UnlockedKey,
+    statement: crypto::v1::key_statement::Statement,
+) -> Result<crypto::v1::SignedKeyStatement, SigningError> {
+    let statement = crypto::v1::KeyStatement {
+        statement: Some(statement),
+    };
+    Ok(crypto::v1::SignedKeyStatement {
+        signature: key.sign(statement.encode_to_vec())?,
+        statement: Some(statement),
+    })
+}
+
+/// Verify a signed statement against the key it is about.
+pub fn verify_statement(
+    signed: &crypto::v1::SignedKeyStatement,
+) -> Result<VerifiedStatement, KeyStatementError> {
+    let statement = signed
+        .statement
+        .as_ref()
+        .ok_or(KeyStatementError::MissingStatement)?;
+    let verified = match statement.statement {
+        Some(crypto::v1::key_statement::Statement::Rotation(ref rotation)) => {
+            let previous = parse_pubkey(&rotation.previous_pubkey)?;
+            let next = parse_pubkey(&rotation.pubkey)?;
+            if previous.fingerprint() == next.fingerprint() {
+                return Err(KeyStatementError::SelfRotation);
+            }
+            VerifiedStatement::Rotation {
+                previous,
+                next,
+                time_signed: parse_time(&rotation.time_signed)?,
+            }
+        }
+        Some(crypto::v1::key_statement::Statement::Revocation(ref revocation)) => {
+            VerifiedStatement::Revocation {
+                pubkey: parse_pubkey(&revocation.pubkey)?,
+                reason: revocation.reason.clone(),
+                time_signed: parse_time(&revocation.time_signed)?,
+            }
+        }
+        None => return Err(KeyStatementError::MissingStatement),
+    };
+    Crypto::verify_data_static(
+        verified.subject(),
+        &signed.signature,
+        &statement.encode_to_vec(),
+    )?;
+    Ok(verified)
+}
+
+fn parse_pubkey(bytes: &[u8]) -> Result<SignedPublicKey, KeyStatementError> {
+    let (pubkey, _headers) = SignedPublicKey::from_armor_single(Cursor::new(bytes))?;
+    Ok(pubkey)
+}
+
+fn parse_time(time: &Option<prost_types::Timestamp>) -> Result<SystemTime, KeyStatementError> {
+    time.clone()
+        .and_then(|time| SystemTime::try_from(time).ok())
+        .ok_or(KeyStatementError::MissingTime)
+}
+
+/// A rotation we accepted.
+#[derive(Debug, Clone)]
+struct Rotation {
+    next: String,
+    signed: crypto::v1::SignedKeyStatement,
+}
+
+/// The rotations and revocations we know of, by the node ID of the key they are about.
+#[derive(Debug, Default)]
+pub struct KeyStatements {
+    rotations: HashMap<String, Rotation>,
+    revocations: HashMap<String, crypto::v1::SignedKeyStatement>,
+}
+
+impl KeyStatements {
+    /// Record a verified statement. Returns false if it taught us nothing: we already had it, the
+    /// key was already rotated to another one, or the key was revoked before we learned of the
+    /// rotation.
+    pub fn insert(
+        &mut self,
+        statement: &VerifiedStatement,
+        signed: crypto::v1::SignedKeyStatement,
+    ) -> bool {
+        match statement {
+            VerifiedStatement::Rotation { previous, next, .. } => self.insert_rotation(
+                node_id(&previous.fingerprint()),
+                node_id(&next.fingerprint()),
+                signed,
+            ),
+            VerifiedStatement::Revocation { pubkey, .. } => {
+                self.insert_revocation(node_id(&pubkey.fingerprint()), signed)
+            }
+        }
+    }
+
+    fn insert_rotation(
+        &mut self,
+        previous: String,
+        next: String,
+        signed: crypto::v1::SignedKeyStatement,
+    ) -> bool {
+        // the signing time of a rotation is chosen by whoever holds the key
+        if self.rotations.contains_key(&previous) || self.revocations.contains_key(&previous) {
+            return false;
+        }
+        self.rotations.insert(previous, Rotation { next, signed });
+        true
+    }
+
+    fn insert_revocation(&mut self, id: String, signed: crypto::v1::SignedKeyStatement) -> bool {
+        if self.revocations.contains_key(&id) {
+            return false;
+        }
+        self.revocations.insert(id, signed);
+        true
+    }
+
+    /// Returns true if the key with the given node ID was revoked.
+    pub fn is_revoked(&self, id: &str) -> bool {
+        self.revocations.contains_key(id)
+    }
+
+    /// Returns the node ID the key with the given node ID was rotated to, if it was.
+    pub fn rotated_to(&self, id: &str) -> Option<&str> {
+        self.rotations
+            .get(id)
+            .map(|rotation| rotation.next.as_str())
+    }
+
+    /// Returns the node ID of the current key of the node with the given node ID, following its
+    /// rotations. Fails if any key along the way was revoked.
+    pub fn resolve(&self, id: &str) -> Result<String, KeyStatementError> {
+        let mut current = id;
+        for _ in 0..MAX_ROTATION_CHAIN {
+            if self.is_revoked(current) {
+                return Err(KeyStatementError::Revoked);
+            }
+            match self.rotated_to(current) {
+                Some(next) => current = next,
+                None => return Ok(current.to_string()),
+            }
+        }
+        Err(KeyStatementError::RotationLoop)
+    }
+
+    /// Returns every statement we accepted, to be stored or passed on.
+    pub fn export(&self) -> Vec<crypto::v1::SignedKeyStatement> {
+        self.rotations
+            .values()
+            .map(|rotation| rotation.signed.clone())
+            .chain(self.revocations.values().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_follows_rotations() {
+        let mut statements = KeyStatements::default();
+        let signed = crypto::v1::SignedKeyStatement::default();
+        assert!(statements.insert_rotation("a".into(), "b".into(), signed.clone()));
+        assert!(statements.insert_rotation("b".into(), "c".into(), signed.clone()));
+        assert_eq!(statements.resolve("a").unwrap(), "c");
+
+        // the first rotation of a key wins
+        assert!(!statements.insert_rotation("a".into(), "d".into(), signed.clone()));
+        assert_eq!(statements.resolve("a").unwrap(), "c");
+
+        assert!(statements.insert_revocation("c".into(), signed.clone()));
+        assert!(matches!(
+            statements.resolve("a"),
+            Err(KeyStatementError::Revoked)
+        ));
+
+        // a loop does not hang
+        assert!(statements.insert_rotation("x".into(), "y".into(), signed.clone()));
+        assert!(statements.insert_rotation("y".into(), "x".into(), signed));
+        assert!(matches!(
+            statements.resolve("x"),
+            Err(KeyStatementError::RotationLoop)
+        ));
+    }
+
+    #[test]
+    fn test_revocation_voids_rotations() {
+        let signed = crypto::v1::SignedKeyStatement::default();
+
+        // no rotation of a revoked key is accepted, whenever it claims to be signed
+        let mut statements = KeyStatements::default();
+        assert!(statements.insert_revocation("a".into(), signed.clone()));
+        assert!(!statements.insert_rotation("a".into(), "b".into(), signed.clone()));
+        assert!(matches!(
+            statements.resolve("a"),
+            Err(KeyStatementError::Revoked)
+        ));
+
+        // a revoked key in the middle of the chain breaks it
+        let mut statements = KeyStatements::default();
+        assert!(statements.insert_rotation("a".into(), "b".into(), signed.clone()));
+        assert!(statements.insert_rotation("b".into(), "c".into(), signed.clone()));
+        assert!(statements.insert_revocation("b".into(), signed));
+        assert!(statements.is_revoked("b"));
+        assert!(matches!(
+            statements.resolve("a"),
+            Err(KeyStatementError::Revoked)
+        ));
+        assert_eq!(statements.resolve("c").unwrap(), "c");
+    }
+}
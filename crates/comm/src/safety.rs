@@ -0,0 +1,147 @@
+//! Computes safety numbers, which let two users check out of band that the keys their devices use
+//! for each other are the right ones.
+//!
+//! Each party's half of the number is an iterated hash of their fingerprint and username, so both
+//! sides compute the same number no matter who asks. It is shown either as [SAFETY_NUMBER_DIGITS]
+//! digits to read out, or as a [crypto::v1::SafetyNumberPayload] to scan as a QR code. Once a user
+//! has compared them, the contact can be marked verified with
+//! [crate::crypto::Crypto::set_verified].
+
+use sha2::{Digest, Sha512};
+use string_protocol::{
+    crypto,
+    prost::{DecodeError, Message},
+};
+use thiserror::Error;
+
+/// The version of the safety number computation.
+pub const SAFETY_NUMBER_VERSION: u32 = 1;
+
+/// The number of digits in a safety number, half of which come from each party.
+pub const SAFETY_NUMBER_DIGITS: usize = 60;
+
+/// How many times each half is hashed, to make finding a key with a matching number expensive.
+const ITERATIONS: usize = 5200;
+
+/// The number of bytes of each iterated hash that are used, five per group of five digits.
+const HASH_SIZE: usize = 30;
+
+/// An enumeration of possible errors that can occur when comparing a scanned safety number.
+#[derive(Error, Debug)]
+pub enum SafetyNumberError {
+    /// The scanned payload could not be decoded.
+    #[error("Malformed safety number payload")]
+    DecodeFail(#[from] DecodeError),
+    /// The scanned payload was computed differently, so it cannot be compared.
+    #[error("Unsupported safety number version")]
+    UnsupportedVersion,
+}
+
+/// The safety number of a pair of nodes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafetyNumber {
+    /// The iterated hash of our fingerprint.
+    local: Vec<u8>,
+    /// The iterated hash of the fingerprint of the other node.
+    remote: Vec<u8>,
+}
+
+impl SafetyNumber {
+    /// Compute the safety number between us and another node, from both fingerprints and
+    /// usernames.
+    pub fn new(
+        local_fingerprint: &[u8],
+        local_username: &str,
+        remote_fingerprint: &[u8],
+        remote_username: &str,
+    ) -> Self {
+        Self {
+            local: iterated_hash(local_fingerprint, local_username),
+            remote: iterated_hash(remote_fingerprint, remote_username),
+        }
+    }
+
+    /// Returns the number as [SAFETY_NUMBER_DIGITS] digits, in groups of five separated by
+    /// spaces. The lower half comes first, so both nodes show the same digits.
+    pub fn digits(&self) -> String {
+        let (first, second) = match self.local <= self.remote {
+            true => (&self.local, &self.remote),
+            false => (&self.remote, &self.local),
+        };
+        first
+            .chunks(5)
+            .chain(second.chunks(5))
+            .map(|chunk| {
+                let value = chunk.iter().fold(0u64, |acc, byte| acc << 8 | *byte as u64);
+                format!("{:05}", value % 100000)
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Returns the payload to show as a QR code, for the other node to scan.
+    pub fn qr_payload(&self) -> Vec<u8> {
+        crypto::v1::SafetyNumberPayload {
+            version: SAFETY_NUMBER_VERSION,
+            local: self.local.clone(),
+            remote: self.remote.clone(),
+        }
+        .encode_to_vec()
+    }
+
+    /// Compare a payload scanned from the other node's QR code with our own safety number,
+    /// returning true if they match.
+    pub fn matches_scanned(&self, payload: &[u8]) -> Result<bool, SafetyNumberError> {
+        let scanned = crypto::v1::SafetyNumberPayload::decode(payload)?;
+        if scanned.version != SAFETY_NUMBER_VERSION {
+            return Err(SafetyNumberError::UnsupportedVersion);
+        }
+        // their local half is our remote one
+        Ok(scanned.local == self.remote && scanned.remote == self.local)
+    }
+}
+
+/// Hash a fingerprint and username [ITERATIONS] times, keeping the first [HASH_SIZE] bytes.
+fn iterated_hash(fingerprint: &[u8], username: &str) -> Vec<u8> {
+    let mut hash = Sha512::new()
+        .chain_update((SAFETY_NUMBER_VERSION as u16).to_be_bytes())
+        .chain_update(fingerprint)
+        .chain_update(username.as_bytes())
+        .finalize();
+    for _ in 0..ITERATIONS {
+        hash = Sha512::new()
+            .chain_update(hash)
+            .chain_update(fingerprint)
+            .finalize();
+    }
+    hash[..HASH_SIZE].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_both_sides_agree() {
+        let alice = SafetyNumber::new(&[1; 20], "alice", &[2; 20], "bob");
+        let bob = SafetyNumber::new(&[2; 20], "bob", &[1; 20], "alice");
+
+        let digits = alice.digits();
+        assert_eq!(digits, bob.digits());
+        assert_eq!(digits.replace(' ', "").len(), SAFETY_NUMBER_DIGITS);
+
+        assert!(alice.matches_scanned(&bob.qr_payload()).unwrap());
+        assert!(bob.matches_scanned(&alice.qr_payload()).unwrap());
+        // our own code is not theirs
+        assert!(!alice.matches_scanned(&alice.qr_payload()).unwrap());
+    }
+
+    #[test]
+    fn test_key_change_changes_number() {
+        let before = SafetyNumber::new(&[1; 20], "alice", &[2; 20], "bob");
+        let after = SafetyNumber::new(&[1; 20], "alice", &[3; 20], "bob");
+
+        assert_ne!(before.digits(), after.digits());
+        assert!(!before.matches_scanned(&after.qr_payload()).unwrap());
+    }
+}
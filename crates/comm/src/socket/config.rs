@@ -0,0 +1,36 @@
+//! Defines [SocketConfig], which tunes the behaviour of a [crate::Socket].
+
+use std::time::Duration;
+
+use super::{fanout::FanoutKind, limit::LimitConfig, outbox::DEFAULT_OUTBOX_EXPIRY};
+
+/// The default number of hops gossip may travel before it is dropped.
+pub const DEFAULT_GOSSIP_TTL: u32 = 8;
+
+/// Configuration for a [crate::Socket].
+#[derive(Debug, Clone)]
+pub struct SocketConfig {
+    /// The number of hops gossip sent by this node may travel before it is dropped.
+    pub gossip_ttl: u32,
+    /// The strategy used to pick the peers gossip is sent to when there is no known route.
+    pub fanout: FanoutKind,
+    /// How long encrypted gossip is retried for before it is dropped from the outbox.
+    pub outbox_expiry: Duration,
+    /// When set, the socket connects to nodes learnt through peer exchange until it has this
+    /// many peers. Off by default.
+    pub pex_target_degree: Option<usize>,
+    /// Rate limits applied to received gossip, and the proof of work put on our own.
+    pub limits: LimitConfig,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self {
+            gossip_ttl: DEFAULT_GOSSIP_TTL,
+            fanout: FanoutKind::default(),
+            outbox_expiry: DEFAULT_OUTBOX_EXPIRY,
+            pex_target_degree: None,
+            limits: LimitConfig::default(),
+        }
+    }
+}
@@ -3,7 +3,15 @@
 use thiserror::Error;
 use tokio::sync::mpsc;
 
-use crate::peer::error::PeerError;
+use crate::{
+    crypto::{DoubleRatchetError, SigningError},
+    device::DeviceError,
+    dht::DhtError,
+    group::GroupKeyError,
+    peer::error::PeerError,
+    rotation::KeyStatementError,
+    x3dh::X3dhError,
+};
 use prost_types::TimestampError;
 use rsntp::{ConversionError, SynchronizationError};
 use string_protocol::PacketDecodeError;
@@ -37,6 +45,9 @@ pub enum SocketError {
     /// Trying to start a ratchet when it exists
     #[error("Ratchet exists")]
     RatchetExists,
+    /// A DR ratchet could not be used
+    #[error("Failure in DR ratchet")]
+    RatchetError(#[from] DoubleRatchetError),
     /// Tried to send gossip, but 0 peers connected
     #[error("No peer for gossip")]
     NoPeer,
@@ -55,6 +66,33 @@ pub enum SocketError {
     /// STUN error
     #[error("STUN error")]
     StunError,
+    /// A DHT lookup failed
+    #[error("DHT error")]
+    DhtError(#[from] DhtError),
+    /// A ping or trace got no reply in time
+    #[error("Probe timed out")]
+    ProbeTimeout,
+    /// A session could not be started from a prekey bundle
+    #[error("Failure in prekey session establishment")]
+    X3dhError(#[from] X3dhError),
+    /// We do not have the public key of a node
+    #[error("Missing public key for node")]
+    MissingPubKey,
+    /// Our key could not be used
+    #[error("Failure in signing")]
+    SigningFail(#[from] SigningError),
+    /// A group session could not be used
+    #[error("Failure in group session")]
+    GroupError(#[from] GroupKeyError),
+    /// A key rotation or revocation could not be used
+    #[error("Failure in key statement")]
+    KeyStatementError(#[from] KeyStatementError),
+    /// A device certificate could not be used
+    #[error("Failure in device certificate")]
+    DeviceError(#[from] DeviceError),
+    /// A node ID is not a hex encoded fingerprint
+    #[error("Malformed node ID")]
+    BadNodeId,
 }
 
 /// An enumeration of possible errors that can occur when working with [ProtocolPacket]s.
@@ -0,0 +1,117 @@
+//! Defines [SocketEvent], which is used to observe what a [crate::Socket] is doing.
+
+use std::net::SocketAddr;
+
+use prost_types::Timestamp;
+use string_protocol::{messages, probe};
+
+/// An enumeration of events emitted by a [crate::Socket]. Subscribe to them with
+/// [crate::Socket::subscribe].
+#[derive(Debug, Clone)]
+pub enum SocketEvent {
+    /// A gossip packet was dropped instead of being forwarded, because it ran out of hops.
+    GossipExpired {
+        /// The gossip ID of the dropped packet.
+        id: Vec<u8>,
+        /// The node ID of the source of the dropped packet.
+        source: String,
+        /// The node ID of the intended destination of the dropped packet.
+        destination: String,
+        /// The peer we received the packet from.
+        from: SocketAddr,
+    },
+    /// An encrypted gossip was dropped from the outbox, because its destination did not
+    /// acknowledge it in time.
+    OutboxExpired {
+        /// The gossip ID of the last attempt.
+        id: Vec<u8>,
+        /// The node ID of the destination of the dropped gossip.
+        destination: String,
+        /// The number of times the gossip was sent.
+        attempts: u32,
+    },
+    /// A node we did not know of was learnt through peer exchange.
+    PeerDiscovered {
+        /// The fingerprint of the node's public key.
+        fingerprint: Vec<u8>,
+        /// The username of the node.
+        username: String,
+        /// The reflexive address of the node.
+        addr: SocketAddr,
+    },
+    /// A delivery or read receipt was received for messages we sent.
+    Receipt {
+        /// The node ID of the node the messages were delivered to, or read by.
+        from: String,
+        /// Whether the messages were delivered or read.
+        receipt_type: messages::v1::ReceiptType,
+        /// The IDs of the messages, as in [messages::v1::Message::id].
+        message_ids: Vec<String>,
+        /// When the messages were delivered or read, according to the sender of the receipt.
+        time: Option<Timestamp>,
+    },
+    /// The reply to a ping or trace we sent.
+    ProbeReply {
+        /// The node ID of the node that replied.
+        from: String,
+        /// The ID of the probe.
+        probe_id: Vec<u8>,
+        /// For traces, the hops the probe took to reach the node, ending with the node.
+        path: Vec<probe::v1::Hop>,
+        /// For traces, the hops the reply took to reach us.
+        return_path: Vec<probe::v1::Hop>,
+    },
+    /// A new key claims the username of a contact we already have a key for. Either the contact
+    /// changed keys, or someone is impersonating it; gossip is addressed by fingerprint, so the
+    /// new key is a different node until the user verifies it.
+    KeyChanged {
+        /// The username both keys claim.
+        username: String,
+        /// The fingerprint of the key we already had.
+        previous: Vec<u8>,
+        /// The fingerprint of the new key.
+        fingerprint: Vec<u8>,
+        /// Whether the user had verified the key we already had.
+        verified: bool,
+    },
+    /// A contact replaced its key with a new one, which the old key vouched for.
+    KeyRotated {
+        /// The username of the old key.
+        username: String,
+        /// The fingerprint of the old key.
+        previous: Vec<u8>,
+        /// The fingerprint of the new key.
+        fingerprint: Vec<u8>,
+        /// Whether the user had verified the old key, in which case the new one is verified too.
+        verified: bool,
+    },
+    /// A key was revoked, and is no longer trusted.
+    KeyRevoked {
+        /// The username of the key.
+        username: String,
+        /// The fingerprint of the key.
+        fingerprint: Vec<u8>,
+        /// Why the key was revoked, as given by its owner.
+        reason: String,
+    },
+    /// An identity certified the key of one of its devices, which gets a copy of everything sent
+    /// to the identity from now on.
+    DeviceAdded {
+        /// The username of the device's key.
+        username: String,
+        /// The fingerprint of the identity key.
+        identity: Vec<u8>,
+        /// The fingerprint of the device's key.
+        fingerprint: Vec<u8>,
+        /// The name of the device, as given by its owner.
+        name: String,
+    },
+    /// The ratchet with a node was replaced with a new session, because one side could no longer
+    /// decrypt with it. Packets waiting for the node in the outbox are dropped.
+    SessionReset {
+        /// The node ID of the node.
+        node: String,
+        /// Whether we started the reset, or the node did.
+        initiated: bool,
+    },
+}
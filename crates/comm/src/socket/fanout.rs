@@ -0,0 +1,255 @@
+//! Defines [FanoutStrategy], which decides the peers a gossip is sent to when there is no known
+//! route to its destination, along with the strategies that ship with the [crate::Socket].
+//!
+//! Every strategy sends to [fanout_size] peers by default, which grows with the logarithm of the
+//! peer count - this keeps the probability that a gossip reaches every node high as the network
+//! grows, without flooding it.
+
+use std::{fmt, net::SocketAddr, time::Duration};
+
+use rand::{
+    rngs::OsRng,
+    seq::{IteratorRandom, SliceRandom},
+};
+
+use super::stats::GossipStats;
+
+/// The number of peers sent to on top of the logarithm of the peer count.
+pub const FANOUT_REDUNDANCY: usize = 3;
+
+/// The round trip time assumed for peers that have not been measured yet.
+pub const DEFAULT_RTT: Duration = Duration::from_millis(200);
+
+/// The number of gossip packets the [AdaptiveFanout] waits for before adjusting its fan-out.
+const ADAPTIVE_WINDOW: u64 = 32;
+
+/// Returns the number of peers to gossip to, out of `peers` candidates.
+pub fn fanout_size(peers: usize) -> usize {
+    if peers == 0 {
+        return 0;
+    }
+    let log = (peers as f64).ln().ceil() as usize;
+    (log + FANOUT_REDUNDANCY).min(peers)
+}
+
+/// A peer that gossip can be sent to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FanoutPeer {
+    /// The address of the peer.
+    pub addr: SocketAddr,
+    /// The smoothed round trip time to the peer, if it has been measured.
+    pub rtt: Option<Duration>,
+}
+
+/// A strategy for selecting the peers a gossip is sent to.
+pub trait FanoutStrategy: fmt::Debug + Send + Sync {
+    /// Select the peers to send a gossip to out of the given candidates. The `stats` of the
+    /// socket can be used to adapt to how the gossip is spreading.
+    fn select(&mut self, candidates: &[FanoutPeer], stats: &GossipStats) -> Vec<SocketAddr>;
+}
+
+/// An enumeration of the [FanoutStrategy]s that ship with the [crate::Socket].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FanoutKind {
+    /// See [RandomFanout].
+    #[default]
+    Random,
+    /// See [RoundRobinFanout].
+    RoundRobin,
+    /// See [LatencyWeightedFanout].
+    LatencyWeighted,
+    /// See [AdaptiveFanout].
+    Adaptive,
+}
+
+impl FanoutKind {
+    /// Create a new instance of the strategy.
+    pub fn strategy(self) -> Box<dyn FanoutStrategy> {
+        match self {
+            FanoutKind::Random => Box::<RandomFanout>::default(),
+            FanoutKind::RoundRobin => Box::<RoundRobinFanout>::default(),
+            FanoutKind::LatencyWeighted => Box::<LatencyWeightedFanout>::default(),
+            FanoutKind::Adaptive => Box::<AdaptiveFanout>::default(),
+        }
+    }
+}
+
+/// Selects peers uniformly at random.
+#[derive(Debug, Default)]
+pub struct RandomFanout;
+
+impl FanoutStrategy for RandomFanout {
+    fn select(&mut self, candidates: &[FanoutPeer], _stats: &GossipStats) -> Vec<SocketAddr> {
+        candidates
+            .iter()
+            .map(|peer| peer.addr)
+            .choose_multiple(&mut OsRng, fanout_size(candidates.len()))
+    }
+}
+
+/// Cycles through the peers in address order, so that load is spread evenly between them.
+#[derive(Debug, Default)]
+pub struct RoundRobinFanout {
+    /// The position in the peer list to start the next selection from.
+    cursor: usize,
+}
+
+impl FanoutStrategy for RoundRobinFanout {
+    fn select(&mut self, candidates: &[FanoutPeer], _stats: &GossipStats) -> Vec<SocketAddr> {
+        if candidates.is_empty() {
+            return vec![];
+        }
+        let mut addrs: Vec<_> = candidates.iter().map(|peer| peer.addr).collect();
+        addrs.sort();
+
+        let count = fanout_size(addrs.len());
+        let start = self.cursor % addrs.len();
+        self.cursor = (start + count) % addrs.len();
+        addrs
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(count)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Selects peers at random, favouring those with a lower round trip time.
+#[derive(Debug, Default)]
+pub struct LatencyWeightedFanout;
+
+impl FanoutStrategy for LatencyWeightedFanout {
+    fn select(&mut self, candidates: &[FanoutPeer], stats: &GossipStats) -> Vec<SocketAddr> {
+        let count = fanout_size(candidates.len());
+        let weight = |peer: &FanoutPeer| {
+            let rtt = peer.rtt.unwrap_or(DEFAULT_RTT);
+            1.0 / rtt.as_secs_f64().max(0.001)
+        };
+        match candidates.choose_multiple_weighted(&mut OsRng, count, weight) {
+            Ok(selected) => selected.map(|peer| peer.addr).collect(),
+            // weights are always positive and finite, but fall back to uniform just in case
+            Err(_) => RandomFanout.select(candidates, stats),
+        }
+    }
+}
+
+/// Selects peers at random, adjusting the fan-out to how much of the gossip we receive is
+/// duplicated. Lots of duplicates mean gossip is spreading with more redundancy than needed, so
+/// fewer peers are selected; few duplicates mean gossip may be failing to reach everyone, so more
+/// peers are selected.
+#[derive(Debug, Default)]
+pub struct AdaptiveFanout {
+    /// The number of peers added to (or removed from) [fanout_size].
+    adjustment: isize,
+    /// The number of new gossip received at the last adjustment.
+    received: u64,
+    /// The number of duplicate gossip received at the last adjustment.
+    duplicates: u64,
+}
+
+impl AdaptiveFanout {
+    /// Adjust the fan-out based on the duplicates received since the last adjustment.
+    fn adapt(&mut self, stats: &GossipStats) {
+        let received = stats.received();
+        let duplicates = stats.duplicates();
+        let new = received.saturating_sub(self.received);
+        let dups = duplicates.saturating_sub(self.duplicates);
+        if new + dups < ADAPTIVE_WINDOW {
+            return;
+        }
+        self.received = received;
+        self.duplicates = duplicates;
+
+        // more than three quarters duplicates: back off, less than a quarter: push harder
+        let max = FANOUT_REDUNDANCY as isize;
+        if dups * 4 > (new + dups) * 3 {
+            self.adjustment = (self.adjustment - 1).max(-max);
+        } else if dups * 4 < new + dups {
+            self.adjustment = (self.adjustment + 1).min(max);
+        }
+    }
+}
+
+impl FanoutStrategy for AdaptiveFanout {
+    fn select(&mut self, candidates: &[FanoutPeer], stats: &GossipStats) -> Vec<SocketAddr> {
+        self.adapt(stats);
+        if candidates.is_empty() {
+            return vec![];
+        }
+        let count = (fanout_size(candidates.len()) as isize + self.adjustment)
+            .clamp(1, candidates.len() as isize) as usize;
+        candidates
+            .iter()
+            .map(|peer| peer.addr)
+            .choose_multiple(&mut OsRng, count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::atomic::Ordering};
+
+    use super::*;
+
+    fn candidates(count: u16) -> Vec<FanoutPeer> {
+        (0..count)
+            .map(|port| FanoutPeer {
+                addr: ([127, 0, 0, 1], port).into(),
+                rtt: Some(Duration::from_millis(port as u64 + 1)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fanout_size() {
+        assert_eq!(fanout_size(0), 0);
+        assert_eq!(fanout_size(2), 2);
+        assert_eq!(fanout_size(10), 6);
+        assert_eq!(fanout_size(1000), 10);
+    }
+
+    #[test]
+    fn test_round_robin_covers_all_peers() {
+        let peers = candidates(12);
+        let stats = GossipStats::default();
+        let mut strategy = RoundRobinFanout::default();
+
+        let mut seen = HashSet::new();
+        for _ in 0..2 {
+            seen.extend(strategy.select(&peers, &stats));
+        }
+        assert_eq!(seen.len(), peers.len());
+    }
+
+    #[test]
+    fn test_strategies_select_distinct_peers() {
+        let peers = candidates(20);
+        let stats = GossipStats::default();
+        for kind in [
+            FanoutKind::Random,
+            FanoutKind::RoundRobin,
+            FanoutKind::LatencyWeighted,
+            FanoutKind::Adaptive,
+        ] {
+            let selected = kind.strategy().select(&peers, &stats);
+            let unique: HashSet<_> = selected.iter().collect();
+            assert_eq!(selected.len(), fanout_size(peers.len()));
+            assert_eq!(unique.len(), selected.len());
+        }
+    }
+
+    #[test]
+    fn test_adaptive_backs_off_on_duplicates() {
+        let peers = candidates(20);
+        let stats = GossipStats::default();
+        let mut strategy = AdaptiveFanout::default();
+        stats
+            .duplicates
+            .fetch_add(ADAPTIVE_WINDOW, Ordering::Relaxed);
+        assert_eq!(
+            strategy.select(&peers, &stats).len(),
+            fanout_size(peers.len()) - 1
+        );
+    }
+}
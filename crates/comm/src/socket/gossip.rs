@@ -1,14 +1,26 @@
 use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
-use rand::{rngs::OsRng, seq::IteratorRandom};
-use string_protocol::{MessageType, ProtocolPacket};
-use tokio::sync::{mpsc, RwLock};
-use tracing::trace;
+use string_protocol::{
+    crypto, gossip, try_encode_internal_packet, try_encode_packet_with, CodecSet, MessageType,
+    ProtocolPacket, ProtocolPacketType,
+};
+use tokio::sync::{mpsc, Mutex, RwLock};
+use tracing::{error, trace};
 
-use crate::Peer;
-
-/// Number of peers to send gossip to
-const GOSSIP_COUNT: usize = 3;
+use super::{
+    fanout::{FanoutPeer, FanoutStrategy},
+    limit::{stamp_pow, GossipLimiter},
+    outbox::Outbox,
+    route::RoutingTable,
+    seen::{new_gossip_id, seen_key, SeenCache},
+    stats::GossipStats,
+    trace::{record_hop, reply_next_hop, start_trace},
+};
+use crate::{
+    crypto::{Crypto, DoubleRatchetError},
+    peer::{error::PeerError, PeerState},
+    try_continue, Peer,
+};
 
 /// Enumeration of gossip action types.
 pub enum GossipAction {
@@ -16,7 +28,9 @@ pub enum GossipAction {
     Send,
     /// Same as above, but encrypted. This should be the common case
     SendEncrypted,
-    /// We received a gossip packet, please forward it
+    /// Broadcast a packet encrypted with our sender key for the channel in `dest`
+    SendGroupEncrypted,
+    /// We received a gossip packet (or are retrying one from the outbox), please forward it
     Forward,
     /// Actually not a gossip, just send directly
     SendDirect,
@@ -31,15 +45,198 @@ pub struct Gossip {
     pub packet: Option<ProtocolPacket>,
     /// Gossip message to forward (either this or the one above)
     pub message: Option<MessageType>,
-    /// Destination to send to; not needed when forwarding
+    /// Node ID to send to; not needed when forwarding
     pub dest: Option<String>,
     ///
     pub dest_sockaddr: Option<SocketAddr>,
 }
 
+/// Package and sign a [MessageType] as a [Gossip] packet under a fresh gossip ID, allowed to
+/// travel `ttl` hops. The ID and signature are recorded as seen, so that copies echoed back to us
+/// are dropped.
+/// Every gossip is signed, broadcasts included, so receivers can verify its source. If the
+/// limiter asks for it, the gossip is stamped with a proof of work first.
+pub async fn sign_gossip(
+    crypto: &RwLock<Crypto>,
+    seen: &Mutex<SeenCache>,
+    limiter: &Mutex<GossipLimiter>,
+    source: String,
+    message: MessageType,
+    destination: String,
+    ttl: u32,
+) -> Result<ProtocolPacket, PeerError> {
+    let mut internal = crypto::v1::SignedPacketInternal {
+        destination,
+        source,
+        message_type: Some(message),
+        id: new_gossip_id(),
+        pow: None,
+    };
+    let difficulty = limiter.lock().await.stamp_difficulty();
+    if let Some(difficulty) = difficulty {
+        // the stamp does not cover the message, so there is no need to hand all of it over
+        let stamped = crypto::v1::SignedPacketInternal {
+            message_type: None,
+            ..internal.clone()
+        };
+        // an unstamped gossip may still get through, so send it anyway if this fails
+        internal.pow = tokio::task::spawn_blocking(move || stamp_pow(&stamped, difficulty))
+            .await
+            .ok();
+    }
+    let signature = {
+        let crypto = crypto.read().await;
+        crypto.sign_data(&try_encode_internal_packet(&internal)?)?
+    };
+    seen.lock()
+        .await
+        .insert(&seen_key(&internal.id, &signature));
+
+    let gossip = ProtocolPacketType::PktGossip(gossip::v1::Gossip {
+        packet: Some(crypto::v1::SignedPacket {
+            signature,
+            signed_data: Some(internal),
+        }),
+        ttl,
+        hops: vec![],
+    });
+    Ok(ProtocolPacket {
+        packet_type: Some(gossip),
+    })
+}
+
+/// Encrypt a [ProtocolPacket] for the given destination, packaging it as an [EncryptedPacket]
+/// ready to be signed with [sign_gossip]. Packets for a single destination are always encrypted
+/// with its ratchet; broadcasts are only signed, so their content is left as is. The content is
+/// never compressed, since the destination may not support the codecs our peers do.
+pub async fn encrypt_gossip(
+    crypto: &RwLock<Crypto>,
+    packet: &ProtocolPacket,
+    destination: &String,
+) -> Result<MessageType, PeerError> {
+    let bytes = try_encode_packet_with(packet, CodecSet::none()).map_err(PeerError::EncodeFail)?;
+    // encrypt message contents
+    let content = {
+        if destination != "*" {
+            let mut crypto = crypto.write().await;
+            let ratchet = crypto
+                .ratchets
+                .get_mut(destination)
+                .ok_or(PeerError::DRFail(DoubleRatchetError::MissingRatchet))?;
+            ratchet.encrypt(&bytes).map_err(PeerError::DRFail)?
+        } else {
+            bytes
+        }
+    };
+    Ok(MessageType::EncryptedPacket(crypto::v1::EncryptedPacket {
+        content,
+        group: String::new(),
+    }))
+}
+
+/// Encrypt a [ProtocolPacket] with our sender key for the given channel, packaging it as an
+/// [EncryptedPacket] ready to be signed with [sign_gossip] by `source` and broadcast.
+pub async fn encrypt_gossip_group(
+    crypto: &RwLock<Crypto>,
+    packet: &ProtocolPacket,
+    source: &str,
+    channel: &str,
+) -> Result<MessageType, PeerError> {
+    let bytes = try_encode_packet_with(packet, CodecSet::none()).map_err(PeerError::EncodeFail)?;
+    let content = crypto
+        .write()
+        .await
+        .group_encrypt(channel, source, &bytes)?;
+    Ok(MessageType::EncryptedPacket(crypto::v1::EncryptedPacket {
+        content,
+        group: channel.to_string(),
+    }))
+}
+
+/// Returns the signed contents of the packet, if it is a gossip packet.
+pub(super) fn gossip_data(packet: &ProtocolPacket) -> Option<&crypto::v1::SignedPacketInternal> {
+    match packet.packet_type {
+        Some(ProtocolPacketType::PktGossip(ref gossip)) => gossip
+            .packet
+            .as_ref()
+            .and_then(|packet| packet.signed_data.as_ref()),
+        _ => None,
+    }
+}
+
+/// Returns the gossip ID of the packet, if it is a gossip packet.
+pub fn gossip_id(packet: &ProtocolPacket) -> Option<&[u8]> {
+    gossip_data(packet).map(|data| data.id.as_slice())
+}
+
+/// Returns the destination of the packet, if it is a gossip packet.
+pub fn gossip_destination(packet: &ProtocolPacket) -> Option<&str> {
+    gossip_data(packet).map(|data| data.destination.as_str())
+}
+
+/// Returns the neighbour to send gossip to the given destination through, if we know a route to
+/// it and the neighbour is still alive. We never route gossip back to the peer it came from.
+async fn route_gossip(
+    routes: &RwLock<RoutingTable>,
+    peers: &RwLock<HashMap<SocketAddr, Peer>>,
+    destination: &str,
+    skip: Option<SocketAddr>,
+) -> Option<SocketAddr> {
+    if destination == "*" {
+        return None;
+    }
+    let next_hop = routes.read().await.next_hop(destination)?;
+    if Some(next_hop) == skip {
+        return None;
+    }
+    let peers = peers.read().await;
+    let state = *peers.get(&next_hop)?.state.read().await;
+    match state {
+        PeerState::Dead => None,
+        _ => Some(next_hop),
+    }
+}
+
+/// Consume one hop of a gossip packet that is about to be forwarded.
+pub fn decrement_ttl(packet: &mut ProtocolPacket) {
+    if let Some(ProtocolPacketType::PktGossip(ref mut gossip)) = packet.packet_type {
+        gossip.ttl = gossip.ttl.saturating_sub(1);
+    }
+}
+
+/// Returns the live peers gossip can be fanned out to, skipping the peer it came from.
+async fn fanout_candidates(
+    peers: &RwLock<HashMap<SocketAddr, Peer>>,
+    skip: Option<SocketAddr>,
+) -> Vec<FanoutPeer> {
+    let peers = peers.read().await;
+    let mut candidates = Vec::with_capacity(peers.len());
+    for (addr, peer) in peers.iter() {
+        // skip if included
+        if Some(*addr) == skip || *peer.state.read().await == PeerState::Dead {
+            continue;
+        }
+        candidates.push(FanoutPeer {
+            addr: *addr,
+            rtt: *peer.rtt.read().await,
+        });
+    }
+    candidates
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn start_gossip_worker(
     mut gossip_rx: mpsc::Receiver<Gossip>,
     peers: Arc<RwLock<HashMap<SocketAddr, Peer>>>,
+    crypto: Arc<RwLock<Crypto>>,
+    node_id: String,
+    routes: Arc<RwLock<RoutingTable>>,
+    seen: Arc<Mutex<SeenCache>>,
+    limiter: Arc<Mutex<GossipLimiter>>,
+    stats: Arc<GossipStats>,
+    fanout: Arc<Mutex<Box<dyn FanoutStrategy>>>,
+    outbox: Arc<Outbox>,
+    ttl: u32,
 ) {
     tokio::spawn(async move {
         loop {
@@ -65,23 +262,116 @@ pub fn start_gossip_worker(
                     continue;
                 }
                 let peer_ = peer.unwrap();
-                let peername = peer_.peername.clone();
+                let peer_id = peer_.peer_id.clone();
                 let _ = peer_
-                    .send_gossip_single(message.unwrap().clone(), peername.unwrap())
+                    .send_gossip_single(message.unwrap().clone(), peer_id.unwrap())
                     .await;
                 continue;
             }
 
-            // Selects at most 3 peers randomly from list of peers - should
-            // probably employ round robin here.
-            let targets: Vec<_> = peers
-                .read()
-                .await
-                .keys()
-                // skip if included
-                .filter(|addr| skip.map(|skip_addr| skip_addr != **addr).unwrap_or(true))
-                .cloned()
-                .choose_multiple(&mut OsRng, GOSSIP_COUNT);
+            // build the packet once, so every target receives the same gossip ID
+            let packet = match action {
+                GossipAction::Send => {
+                    trace!("sending gossip {:?}", message);
+                    // a node that rotated its key is only reachable under its new one
+                    let dest = try_continue!(
+                        crypto.read().await.resolve_id(&dest.unwrap()),
+                        "Cannot send gossip to destination"
+                    );
+                    let mut packet = try_continue!(
+                        sign_gossip(
+                            &crypto,
+                            &seen,
+                            &limiter,
+                            node_id.clone(),
+                            message.unwrap(),
+                            dest,
+                            ttl
+                        )
+                        .await,
+                        "Failed to sign gossip"
+                    );
+                    start_trace(&mut packet, &node_id);
+                    packet
+                }
+                GossipAction::SendEncrypted => {
+                    trace!("sending encrypted gossip {:?}", packet);
+                    let dest = try_continue!(
+                        crypto.read().await.resolve_id(&dest.unwrap()),
+                        "Cannot send gossip to destination"
+                    );
+                    let message = try_continue!(
+                        encrypt_gossip(&crypto, &packet.unwrap(), &dest).await,
+                        "Failed to encrypt gossip"
+                    );
+                    let packet = try_continue!(
+                        sign_gossip(
+                            &crypto,
+                            &seen,
+                            &limiter,
+                            node_id.clone(),
+                            message.clone(),
+                            dest.clone(),
+                            ttl
+                        )
+                        .await,
+                        "Failed to sign gossip"
+                    );
+                    // keep unicast gossip around until the destination acknowledges it
+                    if dest != "*" {
+                        let id = gossip_id(&packet).unwrap_or_default().to_vec();
+                        outbox.insert(dest, id, message).await;
+                    }
+                    packet
+                }
+                GossipAction::SendGroupEncrypted => {
+                    trace!("sending group encrypted gossip {:?}", packet);
+                    let message = try_continue!(
+                        encrypt_gossip_group(&crypto, &packet.unwrap(), &node_id, &dest.unwrap())
+                            .await,
+                        "Failed to encrypt gossip"
+                    );
+                    try_continue!(
+                        sign_gossip(
+                            &crypto,
+                            &seen,
+                            &limiter,
+                            node_id.clone(),
+                            message,
+                            "*".to_string(),
+                            ttl
+                        )
+                        .await,
+                        "Failed to sign gossip"
+                    )
+                }
+                GossipAction::Forward => {
+                    trace!("forwarding gossip {:?}", packet);
+                    let mut packet = packet.unwrap();
+                    record_hop(&mut packet, &node_id);
+                    packet
+                }
+                GossipAction::SendDirect => unreachable!(),
+            };
+
+            // Send along the route to the destination if we know one, otherwise let the fan-out
+            // strategy pick from our live peers. Replies to traces go back the way the trace came
+            let destination =
+                reply_next_hop(&packet, &node_id).or_else(|| gossip_destination(&packet));
+            let route = match destination {
+                Some(destination) => route_gossip(&routes, &peers, destination, skip).await,
+                None => None,
+            };
+            let targets: Vec<_> = match route {
+                Some(next_hop) => {
+                    trace!(?next_hop, "routing gossip");
+                    vec![next_hop]
+                }
+                None => {
+                    let candidates = fanout_candidates(&peers, skip).await;
+                    fanout.lock().await.select(&candidates, &stats)
+                }
+            };
 
             // we have no targets!
             if targets.is_empty() {
@@ -90,31 +380,13 @@ pub fn start_gossip_worker(
 
             for target in targets {
                 let mut peers_write = peers.write().await;
-                let target_peer = peers_write.get_mut(&target);
-                let target_peer_ = target_peer.expect("No such peer");
-                let res = match action {
-                    GossipAction::Send => {
-                        trace!("sending gossip {:?}", message);
-                        target_peer_
-                            .send_gossip_single(message.clone().unwrap(), dest.clone().unwrap())
-                            .await
-                    }
-                    GossipAction::SendEncrypted => {
-                        trace!("sending encrypted gossip {:?}", packet);
-                        target_peer_
-                            .send_gossip_single_encrypted(
-                                packet.clone().unwrap(),
-                                dest.clone().unwrap(),
-                            )
-                            .await
-                    }
-                    GossipAction::Forward => {
-                        trace!("forwarding gossip {:?}", packet);
-                        target_peer_.send_packet(packet.clone().unwrap()).await
-                    }
-                    GossipAction::SendDirect => unreachable!(),
+                // the peer may have been dropped since it was picked
+                let Some(target_peer) = peers_write.get_mut(&target) else {
+                    continue;
                 };
-                if res.is_ok() {}
+                if let Err(err) = target_peer.send_packet(packet.clone()).await {
+                    error!(?target, "Failed to send gossip: {:?}", err);
+                }
             }
         }
     });
@@ -0,0 +1,407 @@
+//! Defines [GossipLimiter], which drops gossip from neighbours and sources that send more than
+//! their share, along with the [crypto::v1::ProofOfWork] stamps that let gossip from a busy source
+//! through anyway.
+//!
+//! Sources are only claimed until the signature is checked, so anyone can send gossip under any
+//! source. The per-neighbour limit is applied before anything is verified, and bounds the work a
+//! single peer can make us do. The per-source limit is only applied once the signature checks
+//! out, so nobody can use up the budget of a source by claiming to be it; it stops a single source
+//! from taking all of our attention. Each stamp is only spent once. The difficulty of the stamps
+//! we accept rises with the amount of gossip we drop, so making gossip through a loaded node costs
+//! more.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+use string_protocol::crypto;
+use thiserror::Error;
+
+use super::seen::SeenCache;
+
+/// The default rate, in gossip per second, allowed from a single source.
+pub const DEFAULT_SOURCE_RATE: f64 = 10.0;
+
+/// The default number of gossip a single source may send in a burst.
+pub const DEFAULT_SOURCE_BURST: f64 = 50.0;
+
+/// The default rate, in gossip per second, allowed from a single neighbour.
+pub const DEFAULT_NEIGHBOUR_RATE: f64 = 100.0;
+
+/// The default number of gossip a single neighbour may send in a burst.
+pub const DEFAULT_NEIGHBOUR_BURST: f64 = 500.0;
+
+/// The most sources rate limits are tracked for at once.
+pub const MAX_TRACKED_SOURCES: usize = 4096;
+
+/// The least difficulty of a stamp that lets gossip past the per-source limit.
+pub const MIN_POW_DIFFICULTY: u32 = 8;
+
+/// The most difficulty ever asked of a stamp.
+pub const MAX_POW_DIFFICULTY: u32 = 20;
+
+/// How long dropped gossip counts towards the load.
+const LOAD_WINDOW: Duration = Duration::from_secs(10);
+
+/// The largest nonce accepted in a stamp.
+const MAX_NONCE_SIZE: usize = 16;
+
+/// A token bucket rate: `rate` tokens are added every second, up to `burst`.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    /// Tokens added every second.
+    pub rate: f64,
+    /// The most tokens held at once.
+    pub burst: f64,
+}
+
+/// Configuration for a [GossipLimiter].
+#[derive(Debug, Clone)]
+pub struct LimitConfig {
+    /// The limit applied to gossip from each source.
+    pub per_source: RateLimit,
+    /// The limit applied to gossip received from each neighbour.
+    pub per_neighbour: RateLimit,
+    /// The difficulty of the stamps put on our own gossip, which is raised when we are under
+    /// load ourselves. Zero, the default, sends gossip without stamps.
+    pub pow_difficulty: u32,
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        Self {
+            per_source: RateLimit {
+                rate: DEFAULT_SOURCE_RATE,
+                burst: DEFAULT_SOURCE_BURST,
+            },
+            per_neighbour: RateLimit {
+                rate: DEFAULT_NEIGHBOUR_RATE,
+                burst: DEFAULT_NEIGHBOUR_BURST,
+            },
+            pow_difficulty: 0,
+        }
+    }
+}
+
+/// Why gossip was not admitted.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum LimitError {
+    /// The neighbour the gossip came from has sent too much
+    #[error("Neighbour is over its rate limit")]
+    Neighbour,
+    /// The source of the gossip has sent too much, and the gossip has no good enough stamp
+    #[error("Source is over its rate limit")]
+    Source,
+    /// The stamp of the gossip was already spent on another copy of it
+    #[error("Stamp was already spent")]
+    SpentStamp,
+}
+
+/// A bucket of tokens, refilled over time.
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket.
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst,
+            updated: now,
+        }
+    }
+
+    /// Add the tokens earned since the last update.
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.updated = now;
+    }
+
+    /// Take a token, returning false if there are none left.
+    fn try_take(&mut self, limit: RateLimit, now: Instant) -> bool {
+        self.refill(limit, now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Returns true if the bucket would be full at `now`, so forgetting it changes nothing.
+    fn is_full(&self, limit: RateLimit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * limit.rate >= limit.burst
+    }
+}
+
+/// Rate limits for gossip, per source and per neighbour.
+#[derive(Debug)]
+pub struct GossipLimiter {
+    config: LimitConfig,
+    sources: HashMap<String, TokenBucket>,
+    neighbours: HashMap<SocketAddr, TokenBucket>,
+    /// The IDs of the gossip whose stamps were spent recently.
+    spent: SeenCache,
+    /// When the current load window started.
+    window_start: Instant,
+    /// Gossip dropped in the current load window.
+    dropped: u64,
+    /// Gossip dropped in the previous load window.
+    last_dropped: u64,
+}
+
+impl GossipLimiter {
+    /// Create a limiter with the given configuration.
+    pub fn new(config: LimitConfig) -> Self {
+        Self {
+            config,
+            sources: HashMap::new(),
+            neighbours: HashMap::new(),
+            spent: SeenCache::default(),
+            window_start: Instant::now(),
+            dropped: 0,
+            last_dropped: 0,
+        }
+    }
+
+    /// Decide whether to handle gossip received from `neighbour`. This is meant to be called
+    /// before the gossip is verified, so nothing in it is looked at.
+    pub fn admit(&mut self, neighbour: SocketAddr) -> Result<(), LimitError> {
+        let now = Instant::now();
+        self.roll_window(now);
+
+        let per_neighbour = self.config.per_neighbour;
+        let admitted = self
+            .neighbours
+            .entry(neighbour)
+            .or_insert_with(|| TokenBucket::new(per_neighbour, now))
+            .try_take(per_neighbour, now);
+        if !admitted {
+            self.dropped += 1;
+            return Err(LimitError::Neighbour);
+        }
+        Ok(())
+    }
+
+    /// Decide whether to handle gossip from its source. This is meant to be called once the
+    /// signature of the gossip is verified, so that only the source itself can use up its budget.
+    pub fn admit_source(
+        &mut self,
+        data: &crypto::v1::SignedPacketInternal,
+    ) -> Result<(), LimitError> {
+        let now = Instant::now();
+        self.roll_window(now);
+
+        if !self.sources.contains_key(&data.source) && self.sources.len() >= MAX_TRACKED_SOURCES {
+            self.prune(now);
+        }
+        let per_source = self.config.per_source;
+        let full = self.sources.len() >= MAX_TRACKED_SOURCES;
+        let admitted = match self.sources.get_mut(&data.source) {
+            Some(bucket) => bucket.try_take(per_source, now),
+            // still full of sources we have heard from recently, so newcomers have to pay
+            None if full => false,
+            None => {
+                let mut bucket = TokenBucket::new(per_source, now);
+                let admitted = bucket.try_take(per_source, now);
+                self.sources.insert(data.source.clone(), bucket);
+                admitted
+            }
+        };
+        if admitted {
+            return Ok(());
+        }
+
+        let required = self.required_difficulty();
+        match data.pow {
+            Some(ref pow) if pow.difficulty >= required && verify_pow(data, pow) => {
+                if !self.spent.insert(&data.id) {
+                    self.dropped += 1;
+                    return Err(LimitError::SpentStamp);
+                }
+                Ok(())
+            }
+            _ => {
+                self.dropped += 1;
+                Err(LimitError::Source)
+            }
+        }
+    }
+
+    /// Returns the least difficulty of a stamp that lets gossip past the per-source limit, which
+    /// grows with the amount of gossip dropped recently.
+    pub fn required_difficulty(&self) -> u32 {
+        let load = self.dropped.max(self.last_dropped);
+        let extra = u64::BITS - load.leading_zeros();
+        (MIN_POW_DIFFICULTY + extra).min(MAX_POW_DIFFICULTY)
+    }
+
+    /// Returns the difficulty to stamp our own gossip with, if we stamp it at all.
+    pub fn stamp_difficulty(&self) -> Option<u32> {
+        match self.config.pow_difficulty {
+            0 => None,
+            difficulty => Some(
+                difficulty
+                    .max(self.required_difficulty())
+                    .min(MAX_POW_DIFFICULTY),
+            ),
+        }
+    }
+
+    /// Start a new load window if the current one is over.
+    fn roll_window(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.window_start) >= LOAD_WINDOW {
+            self.last_dropped = self.dropped;
+            self.dropped = 0;
+            self.window_start = now;
+        }
+    }
+
+    /// Forget buckets that have refilled, since they behave exactly like new ones.
+    fn prune(&mut self, now: Instant) {
+        let (per_source, per_neighbour) = (self.config.per_source, self.config.per_neighbour);
+        self.sources
+            .retain(|_, bucket| !bucket.is_full(per_source, now));
+        self.neighbours
+            .retain(|_, bucket| !bucket.is_full(per_neighbour, now));
+    }
+}
+
+impl Default for GossipLimiter {
+    fn default() -> Self {
+        Self::new(LimitConfig::default())
+    }
+}
+
+/// Hash the parts of the gossip a stamp is bound to, along with the stamp itself.
+fn pow_hash(data: &crypto::v1::SignedPacketInternal, difficulty: u32, nonce: &[u8]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(&data.id)
+        .chain_update((data.source.len() as u64).to_be_bytes())
+        .chain_update(data.source.as_bytes())
+        .chain_update((data.destination.len() as u64).to_be_bytes())
+        .chain_update(data.destination.as_bytes())
+        .chain_update(difficulty.to_be_bytes())
+        .chain_update(nonce)
+        .finalize()
+        .into()
+}
+
+/// Returns the number of leading zero bits in the hash.
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in hash {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+/// Check that a stamp is valid for the given gossip.
+pub fn verify_pow(data: &crypto::v1::SignedPacketInternal, pow: &crypto::v1::ProofOfWork) -> bool {
+    pow.difficulty <= MAX_POW_DIFFICULTY
+        && pow.nonce.len() <= MAX_NONCE_SIZE
+        && leading_zero_bits(&pow_hash(data, pow.difficulty, &pow.nonce)) >= pow.difficulty
+}
+
+/// Find a stamp of the given difficulty for the gossip. This takes about `2^difficulty` hashes,
+/// so it should not be run on the async runtime for large difficulties.
+pub fn stamp_pow(
+    data: &crypto::v1::SignedPacketInternal,
+    difficulty: u32,
+) -> crypto::v1::ProofOfWork {
+    let difficulty = difficulty.min(MAX_POW_DIFFICULTY);
+    let mut counter: u64 = 0;
+    loop {
+        let nonce = counter.to_be_bytes();
+        if leading_zero_bits(&pow_hash(data, difficulty, &nonce)) >= difficulty {
+            return crypto::v1::ProofOfWork {
+                difficulty,
+                nonce: nonce.to_vec(),
+            };
+        }
+        counter += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gossip(source: &str) -> crypto::v1::SignedPacketInternal {
+        crypto::v1::SignedPacketInternal {
+            source: source.to_string(),
+            destination: "bob".to_string(),
+            id: vec![1; 16],
+            ..Default::default()
+        }
+    }
+
+    fn limiter(source_burst: f64, neighbour_burst: f64) -> GossipLimiter {
+        GossipLimiter::new(LimitConfig {
+            per_source: RateLimit {
+                rate: 0.0,
+                burst: source_burst,
+            },
+            per_neighbour: RateLimit {
+                rate: 0.0,
+                burst: neighbour_burst,
+            },
+            pow_difficulty: 0,
+        })
+    }
+
+    #[test]
+    fn test_limits_each_source() {
+        let mut limiter = limiter(2.0, 100.0);
+        assert_eq!(limiter.admit_source(&gossip("alice")), Ok(()));
+        assert_eq!(limiter.admit_source(&gossip("alice")), Ok(()));
+        assert_eq!(
+            limiter.admit_source(&gossip("alice")),
+            Err(LimitError::Source)
+        );
+        assert_eq!(limiter.admit_source(&gossip("carol")), Ok(()));
+        assert!(limiter.required_difficulty() > MIN_POW_DIFFICULTY);
+    }
+
+    #[test]
+    fn test_limits_each_neighbour() {
+        let mut limiter = limiter(0.0, 1.0);
+        assert_eq!(limiter.admit(([127, 0, 0, 1], 1).into()), Ok(()));
+        assert_eq!(
+            limiter.admit(([127, 0, 0, 1], 1).into()),
+            Err(LimitError::Neighbour)
+        );
+        assert_eq!(limiter.admit(([127, 0, 0, 1], 2).into()), Ok(()));
+        // admitting from a neighbour charges no source
+        assert_eq!(
+            limiter.admit_source(&gossip("alice")),
+            Err(LimitError::Source)
+        );
+    }
+
+    #[test]
+    fn test_stamp_passes_source_limit() {
+        let mut limiter = limiter(0.0, 100.0);
+        let mut data = gossip("alice");
+        data.pow = Some(stamp_pow(&data, limiter.required_difficulty()));
+        assert!(verify_pow(&data, data.pow.as_ref().unwrap()));
+        assert_eq!(limiter.admit_source(&data), Ok(()));
+        // each stamp is spent once
+        assert_eq!(limiter.admit_source(&data), Err(LimitError::SpentStamp));
+
+        // a stamp only counts for the gossip it was made for
+        let mut other = gossip("alice");
+        other.id = vec![2; 16];
+        other.pow = data.pow.clone();
+        assert_eq!(limiter.admit_source(&other), Err(LimitError::Source));
+    }
+}
@@ -1,8 +1,19 @@
 //! Defines the UDP socket abstraction and first-layer packet format used for communication between peers.
 
+mod config;
 pub mod error;
+mod event;
+mod fanout;
 mod gossip;
+mod limit;
+mod outbox;
 mod packet;
+mod pex;
+mod receipt;
+mod route;
+mod seen;
+mod stats;
+mod trace;
 
 use std::{
     collections::{hash_map::Entry, HashMap},
@@ -12,38 +23,81 @@ use std::{
 };
 
 use chrono::Datelike;
+use pgp::types::KeyTrait;
 use prost_types::Timestamp;
 use rsntp::AsyncSntpClient;
 
-use pgp::composed::SignedSecretKey;
-use rand::{rngs::OsRng, seq::IteratorRandom};
-use string_protocol::crypto;
-use string_protocol::{MessageType, ProtocolPacket};
+use string_protocol::{
+    crypto as proto_crypto, dht, messages, probe, try_encode_packet_with, CodecSet, MessageType,
+    ProtocolPacket, ProtocolPacketType,
+};
 use stunclient::StunClient;
 use tokio::{
     net::UdpSocket,
-    sync::{mpsc, RwLock},
+    sync::{broadcast, mpsc, Mutex, RwLock},
+    time::{timeout_at, Instant},
 };
-use tracing::{debug, error, span, trace};
+use tracing::{debug, error, span, trace, warn};
 
-use self::gossip::start_gossip_worker;
+use self::{
+    gossip::start_gossip_worker,
+    outbox::start_outbox_worker,
+    pex::{sign_peer_entry, start_pex_worker},
+};
 use crate::{
-    crypto::{Crypto, DoubleRatchet},
+    crypto::{node_id, Crypto, DoubleRatchet, SigningError},
+    device::{transcript_packet, verify_certificate, DeviceError},
+    dht::{start_dht_worker, Dht, DhtError},
+    group::{sender_key_packet, GroupKeyError},
+    key::UnlockedKey,
     maybe_break, maybe_continue,
     peer::{Peer, PeerState, CHANNEL_SIZE},
-    try_break, try_continue,
+    rotation::{sign_revocation, sign_rotation},
+    safety::SafetyNumber,
+    try_break, try_continue, x3dh,
 };
 
 // re-export types
+pub use self::config::{SocketConfig, DEFAULT_GOSSIP_TTL};
 pub use self::error::{SocketError, SocketPacketDecodeError};
-pub use self::gossip::{Gossip, GossipAction};
+pub use self::event::SocketEvent;
+pub use self::fanout::{
+    fanout_size, AdaptiveFanout, FanoutKind, FanoutPeer, FanoutStrategy, LatencyWeightedFanout,
+    RandomFanout, RoundRobinFanout, DEFAULT_RTT, FANOUT_REDUNDANCY,
+};
+pub use self::gossip::{
+    decrement_ttl, encrypt_gossip, encrypt_gossip_group, gossip_destination, gossip_id,
+    sign_gossip, Gossip, GossipAction,
+};
+pub use self::limit::{
+    stamp_pow, verify_pow, GossipLimiter, LimitConfig, LimitError, RateLimit,
+    DEFAULT_NEIGHBOUR_BURST, DEFAULT_NEIGHBOUR_RATE, DEFAULT_SOURCE_BURST, DEFAULT_SOURCE_RATE,
+    MAX_POW_DIFFICULTY, MAX_TRACKED_SOURCES, MIN_POW_DIFFICULTY,
+};
+pub use self::outbox::{
+    gossip_ack, Outbox, OutboxEntries, OutboxEntry, DEFAULT_OUTBOX_EXPIRY, OUTBOX_RETRY_BASE,
+    OUTBOX_RETRY_MAX,
+};
 pub use self::packet::{
     SocketPacket, SocketPacketType, MIN_SOCKET_PACKET_SIZE, UDP_MAX_DATAGRAM_SIZE,
 };
+pub use self::pex::{
+    verify_peer_entry, PexError, PexPeer, PexTable, MAX_PEX_ENTRIES, MAX_PEX_VERIFY,
+    PEX_CONNECT_TIMEOUT, PEX_ENTRY_TTL, PEX_REFRESH,
+};
+pub use self::receipt::{new_message_id, receipt_packet};
+pub use self::route::{Route, RoutingTable, MAX_ROUTE_HOPS, ROUTE_TTL};
+pub use self::seen::{
+    new_gossip_id, seen_key, SeenCache, GOSSIP_ID_SIZE, SEEN_CACHE_CAPACITY, SEEN_CACHE_TTL,
+};
+pub use self::stats::GossipStats;
+pub use self::trace::{
+    hop, record_hop, reply_next_hop, start_trace, Trace, TraceHop, MAX_TRACE_HOPS, PROBE_TIMEOUT,
+};
 
 /// A wrapper around the [UdpSocket] type that provides a higher-level interface for sending and
 /// receiving packets from multiple peers.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Socket {
     /// The inner [UdpSocket] used for sending and receiving packets.
     pub inner: Arc<UdpSocket>,
@@ -51,7 +105,10 @@ pub struct Socket {
     pub peers: Arc<RwLock<HashMap<SocketAddr, Peer>>>,
     /// Crypto object, contains ratchets for other nodes
     pub crypto: Arc<RwLock<Crypto>>,
-    /// Username used to identify this current node
+    /// Node ID of this current node, the hex encoded fingerprint of our key, which gossip is
+    /// addressed to
+    pub id: String,
+    /// Username of this current node, only used for display
     pub username: String,
     /// Channel used to send gossip
     pub gossip_tx: mpsc::Sender<Gossip>,
@@ -61,14 +118,55 @@ pub struct Socket {
     pub external: SocketAddr,
     /// Channel used to unify inbound packets
     pub unified_inbound_tx: mpsc::Sender<(Vec<u8>, ProtocolPacket)>,
+    /// Encrypted gossip that has not been acknowledged by its destination yet
+    pub outbox: Arc<Outbox>,
+    /// Routes to other nodes, built from the routes our peers advertise
+    pub routes: Arc<RwLock<RoutingTable>>,
+    /// Nodes learnt through peer exchange, along with our own signed entry
+    pub pex: Arc<RwLock<PexTable>>,
+    /// Our node of the DHT, used to resolve other nodes
+    pub dht: Arc<Dht>,
+    /// Cache of recently seen gossip IDs, shared with every peer
+    pub seen: Arc<Mutex<SeenCache>>,
+    /// Rate limits for received gossip, shared with every peer
+    pub limiter: Arc<Mutex<GossipLimiter>>,
+    /// The configuration this socket was bound with
+    pub config: SocketConfig,
+    /// Counters describing the gossip handled by this socket
+    pub stats: Arc<GossipStats>,
+    /// Strategy used to pick the peers gossip is sent to when there is no known route
+    pub fanout: Arc<Mutex<Box<dyn FanoutStrategy>>>,
+    /// Channel used to publish [SocketEvent]s
+    pub events: broadcast::Sender<SocketEvent>,
 }
 
 impl Socket {
     /// Create a new `Socket` that is bound to the given address. This method also
-    /// starts the background tasks that handle sending and receiving packets.
+    /// starts the background tasks that handle sending and receiving packets. The key must stay
+    /// unlocked for the socket to sign anything.
     pub async fn bind(
         addr: SocketAddr,
-        secret_key: SignedSecretKey,
+        key: UnlockedKey,
+    ) -> Result<(Self, mpsc::Receiver<(Vec<u8>, ProtocolPacket)>), SocketError> {
+        Self::bind_with_config(addr, key, SocketConfig::default()).await
+    }
+
+    /// Same as [Socket::bind], but with the given [SocketConfig].
+    pub async fn bind_with_config(
+        addr: SocketAddr,
+        key: UnlockedKey,
+        config: SocketConfig,
+    ) -> Result<(Self, mpsc::Receiver<(Vec<u8>, ProtocolPacket)>), SocketError> {
+        Self::bind_with_crypto(addr, Crypto::new(key), config).await
+    }
+
+    /// Same as [Socket::bind_with_config], but with the given [Crypto], which may hold sessions
+    /// and prekeys restored with [Crypto::load]. Our prekey bundle is signed while binding, so
+    /// prekeys restored later would not match the one we published.
+    pub async fn bind_with_crypto(
+        addr: SocketAddr,
+        mut crypto: Crypto,
+        config: SocketConfig,
     ) -> Result<(Self, mpsc::Receiver<(Vec<u8>, ProtocolPacket)>), SocketError> {
         // bind socket
 
@@ -85,47 +183,138 @@ impl Socket {
         // create peers map
         let peers = Arc::new(RwLock::new(HashMap::new()));
 
-        let crypto = Arc::new(RwLock::new(Crypto::new(secret_key.clone())));
+        let secret_key = crypto.key.secret_key().clone();
+        let (events, _) = broadcast::channel(CHANNEL_SIZE);
+
+        crypto.set_event_sender(events.clone());
+        let crypto = Arc::new(RwLock::new(crypto));
 
         let (gossip_tx, gossip_rx) = mpsc::channel(CHANNEL_SIZE);
 
         let curr_time = Arc::new(RwLock::new(Self::get_utc_time().await?));
 
+        let seen = Arc::new(Mutex::new(SeenCache::default()));
+
+        let limiter = Arc::new(Mutex::new(GossipLimiter::new(config.limits.clone())));
+
+        let stats = Arc::new(GossipStats::default());
+
+        let fanout = Arc::new(Mutex::new(config.fanout.strategy()));
+
+        let outbox = Arc::new(Outbox::new(config.outbox_expiry));
+
+        if secret_key.details.users.len() != 1 {
+            // Why do we have a weird number of users
+            panic!("Invalid number of users in secret key - programming error")
+        }
+
+        let username = Crypto::get_pubkey_username(secret_key.into());
+        let id = node_id(&crypto.read().await.key.fingerprint());
+
+        let routes = Arc::new(RwLock::new(RoutingTable::new(id.clone(), ROUTE_TTL)));
+
+        let pex = {
+            let crypto = crypto.read().await;
+            let mut pex = PexTable::new(crypto.key.fingerprint(), PEX_ENTRY_TTL);
+            match sign_peer_entry(&crypto, username.clone(), external) {
+                Ok(entry) => pex.set_own(entry),
+                Err(err) => error!(?err, "failed to sign peer entry"),
+            }
+            Arc::new(RwLock::new(pex))
+        };
+
+        let dht = {
+            let fingerprint = crypto.read().await.key.fingerprint();
+            Arc::new(Dht::new(username.clone(), fingerprint, gossip_tx.clone()))
+        };
+        if let Err(err) = dht.sign_own_records(&crypto, external).await {
+            error!(?err, "failed to sign DHT records");
+        }
+
         // start the outbound worker
         span!(tracing::Level::INFO, "socket::outbound")
             .in_scope(|| start_outbound_worker(socket.clone(), peers.clone()));
 
         // start the gossip worker
-        span!(tracing::Level::INFO, "socket::gossip")
-            .in_scope(|| start_gossip_worker(gossip_rx, peers.clone()));
+        span!(tracing::Level::INFO, "socket::gossip").in_scope(|| {
+            start_gossip_worker(
+                gossip_rx,
+                peers.clone(),
+                crypto.clone(),
+                id.clone(),
+                routes.clone(),
+                seen.clone(),
+                limiter.clone(),
+                stats.clone(),
+                fanout.clone(),
+                outbox.clone(),
+                config.gossip_ttl,
+            )
+        });
+
+        // start the outbox worker
+        span!(tracing::Level::INFO, "socket::outbox").in_scope(|| {
+            start_outbox_worker(
+                outbox.clone(),
+                gossip_tx.clone(),
+                crypto.clone(),
+                seen.clone(),
+                limiter.clone(),
+                events.clone(),
+                id.clone(),
+                config.gossip_ttl,
+            )
+        });
+
+        // start the dht worker
+        span!(tracing::Level::INFO, "socket::dht")
+            .in_scope(|| start_dht_worker(dht.clone(), crypto.clone(), external));
 
         // create the unified inbound channel
         let (unified_inbound_tx, unified_inbound_rx) = mpsc::channel(CHANNEL_SIZE);
 
         // start the perodic worker
-        span!(tracing::Level::INFO, "socket::periodic")
-            .in_scope(|| start_periodic_worker(peers.clone(), curr_time.clone()));
+        span!(tracing::Level::INFO, "socket::periodic").in_scope(|| {
+            start_periodic_worker(
+                peers.clone(),
+                curr_time.clone(),
+                routes.clone(),
+                pex.clone(),
+                crypto.clone(),
+                username.clone(),
+                external,
+            )
+        });
 
-        if secret_key.details.users.len() != 1 {
-            // Why do we have a weird number of users
-            panic!("Invalid number of users in secret key - programming error")
-        }
+        let socket = Self {
+            inner: socket,
+            peers,
+            crypto,
+            id,
+            username,
+            gossip_tx,
+            curr_time,
+            external,
+            unified_inbound_tx,
+            outbox,
+            routes,
+            pex,
+            dht,
+            seen,
+            limiter,
+            config,
+            stats,
+            fanout,
+            events,
+        };
 
-        let username = Crypto::get_pubkey_username(secret_key.into());
+        // start the pex worker, if the socket should connect to the nodes it learns about
+        if let Some(target_degree) = socket.config.pex_target_degree {
+            span!(tracing::Level::INFO, "socket::pex")
+                .in_scope(|| start_pex_worker(socket.clone(), target_degree));
+        }
 
-        Ok((
-            Self {
-                inner: socket,
-                peers,
-                crypto,
-                username,
-                gossip_tx,
-                curr_time,
-                external,
-                unified_inbound_tx,
-            },
-            unified_inbound_rx,
-        ))
+        Ok((socket, unified_inbound_rx))
     }
 
     /// Add a new peer to the list of connections, returning a channel for receiving
@@ -140,10 +329,19 @@ impl Socket {
             addr,
             self.crypto.clone(),
             self.peers.clone(),
-            self.username.clone(),
+            self.id.clone(),
             self.gossip_tx.clone(),
             fingerprint.clone(),
             self.curr_time.clone(),
+            self.routes.clone(),
+            self.pex.clone(),
+            self.dht.clone(),
+            self.outbox.clone(),
+            self.seen.clone(),
+            self.limiter.clone(),
+            self.stats.clone(),
+            self.events.clone(),
+            self.config.gossip_ttl,
             initiate,
         )?;
 
@@ -176,9 +374,22 @@ impl Socket {
             connections.insert(addr, peer);
         }
 
+        // a new peer may give a way to reach destinations we could not reach before
+        self.outbox.wake().await;
+
         Ok(app_outbound_tx)
     }
 
+    /// Replace the strategy used to pick the peers gossip is sent to when there is no known route.
+    pub async fn set_fanout_strategy(&self, strategy: impl FanoutStrategy + 'static) {
+        *self.fanout.lock().await = Box::new(strategy);
+    }
+
+    /// Subscribe to the [SocketEvent]s emitted by this socket.
+    pub fn subscribe(&self) -> broadcast::Receiver<SocketEvent> {
+        self.events.subscribe()
+    }
+
     pub async fn get_peer_state(&mut self, addr: SocketAddr) -> Option<PeerState> {
         let connections = self.peers.read().await;
         if !connections.contains_key(&addr) {
@@ -215,7 +426,8 @@ impl Socket {
         Ok(())
     }
 
-    /// Sends non-encrypted message to a random group of peers
+    /// Sends non-encrypted message along the route to the destination node ID, or to a random
+    /// group of peers if no route is known.
     /// Use this to send key exchange messages
     pub async fn send_gossip(
         &self,
@@ -235,7 +447,9 @@ impl Socket {
             .map_err(|err| SocketError::GossipSendError(Box::new(err)))
     }
 
-    /// Sends encrypted packet to random group of peers
+    /// Sends encrypted packet along the route to the destination node ID, or to a random group of
+    /// peers if no route is known. The packet is kept in the [Outbox] and retried until the
+    /// destination acknowledges it.
     /// Use this to send a ProtocolPacket containing a PktMessage,
     /// which contains the message data
     pub async fn send_gossip_encrypted(
@@ -256,29 +470,517 @@ impl Socket {
             .map_err(|err| SocketError::GossipSendError(Box::new(err)))
     }
 
-    pub async fn get_node_cert(&mut self, destination: String) -> Result<(), SocketError> {
-        {
-            let mut crypto_obj = self.crypto.write().await;
-            crypto_obj.insert_pubkey_reply_to(destination.clone(), None);
+    /// Broadcasts a packet encrypted with our sender key for the given channel, once for every
+    /// member. Only members of the channel, who have our key, can read it; every other node just
+    /// forwards it.
+    pub async fn send_gossip_group(
+        &self,
+        packet: ProtocolPacket,
+        channel: String,
+    ) -> Result<(), SocketError> {
+        self.gossip_tx
+            .send(Gossip {
+                action: GossipAction::SendGroupEncrypted,
+                addr: None,
+                packet: Some(packet),
+                message: None,
+                dest: Some(channel),
+                dest_sockaddr: None,
+            })
+            .await
+            .map_err(|err| SocketError::GossipSendError(Box::new(err)))
+    }
+
+    /// Start a group session for the given channel with the given members, and send our sender
+    /// key to each of them. Members are node IDs. Whoever receives it joins the channel and sends
+    /// us theirs. We need a ratchet with every member.
+    pub async fn create_group(
+        &self,
+        channel: String,
+        members: Vec<String>,
+    ) -> Result<(), SocketError> {
+        let members: Vec<String> = members.into_iter().chain([self.id.clone()]).collect();
+        let distribution = self
+            .crypto
+            .write()
+            .await
+            .create_group(&channel, members.clone());
+        self.send_sender_key(distribution, members).await
+    }
+
+    /// Add a member to the group session of the given channel, and send our sender key to every
+    /// member. The other members learn of the new one from it, and send it their keys.
+    pub async fn add_group_member(&self, channel: &str, id: String) -> Result<(), SocketError> {
+        let (distribution, members) = {
+            let mut crypto = self.crypto.write().await;
+            let session = crypto
+                .groups
+                .get_mut(channel)
+                .ok_or(GroupKeyError::MissingKey)?;
+            session.add_member(id);
+            (session.distribution(channel), session.members().clone())
+        };
+        self.send_sender_key(distribution, members).await
+    }
+
+    /// Remove a member from the group session of the given channel. We rekey, and send our new
+    /// sender key to the remaining members, so the removed member cannot read what we broadcast
+    /// after. The key carries the removal, so every other member removes it and rekeys as well.
+    pub async fn remove_group_member(&self, channel: &str, id: &str) -> Result<(), SocketError> {
+        let (distribution, members) = {
+            let mut crypto = self.crypto.write().await;
+            let session = crypto
+                .groups
+                .get_mut(channel)
+                .ok_or(GroupKeyError::MissingKey)?;
+            if !session.remove_member(id) {
+                return Err(GroupKeyError::NotMember.into());
+            }
+            (session.distribution(channel), session.members().clone())
+        };
+        self.send_sender_key(distribution, members).await
+    }
+
+    /// Forget the group session of the given channel.
+    pub async fn leave_group(&self, channel: &str) {
+        self.crypto.write().await.groups.remove(channel);
+    }
+
+    /// Send our sender key to every member of its channel other than us.
+    async fn send_sender_key(
+        &self,
+        distribution: proto_crypto::v1::SenderKeyDistribution,
+        members: impl IntoIterator<Item = String>,
+    ) -> Result<(), SocketError> {
+        for member in members {
+            if member != self.id {
+                self.send_gossip_encrypted(sender_key_packet(distribution.clone()), member)
+                    .await?;
+            }
         }
+        Ok(())
+    }
+
+    /// Send a receipt of the given type for messages we received from `destination`. Delivery
+    /// receipts are sent automatically; use this to send read receipts once the user has seen
+    /// the messages.
+    pub async fn send_receipt(
+        &self,
+        destination: String,
+        receipt_type: messages::v1::ReceiptType,
+        message_ids: Vec<String>,
+    ) -> Result<(), SocketError> {
+        self.send_gossip_encrypted(receipt_packet(receipt_type, message_ids), destination)
+            .await
+    }
+
+    /// Check whether the node with the given ID is reachable through the mesh, returning
+    /// the round trip time of a probe to it. We need its public key to verify the reply.
+    pub async fn ping(&self, destination: String) -> Result<Duration, SocketError> {
+        let (rtt, _, _) = self.probe(destination, false).await?;
+        Ok(rtt)
+    }
+
+    /// Same as [Socket::ping], but also returns the path the probe took to the node, and the
+    /// path its reply took back. Every node along the way records its hop with a timestamp,
+    /// which shows which nodes gossip to the node goes through and where it is held up.
+    pub async fn trace(&self, destination: String) -> Result<Trace, SocketError> {
+        let (rtt, path, return_path) = self.probe(destination, true).await?;
+        Ok(Trace {
+            rtt,
+            path: path.into_iter().map(TraceHop::from).collect(),
+            return_path: return_path.into_iter().map(TraceHop::from).collect(),
+        })
+    }
+
+    /// Send a probe to the given node and wait for its reply.
+    async fn probe(
+        &self,
+        destination: String,
+        trace: bool,
+    ) -> Result<(Duration, Vec<probe::v1::Hop>, Vec<probe::v1::Hop>), SocketError> {
+        let probe_id = new_gossip_id();
+        // subscribe before sending, so the reply cannot be missed
+        let mut events = self.subscribe();
+        let sent = Instant::now();
         self.send_gossip(
-            MessageType::PubKeyRequest(crypto::v1::PubKeyRequest {}),
+            MessageType::Probe(probe::v1::Probe {
+                probe_id: probe_id.clone(),
+                trace,
+            }),
             destination,
         )
         .await?;
+
+        let deadline = sent + PROBE_TIMEOUT;
+        loop {
+            let event = match timeout_at(deadline, events.recv()).await {
+                Ok(Ok(event)) => event,
+                // we fell behind on events, the reply may still come
+                Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+                Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => {
+                    return Err(SocketError::ProbeTimeout)
+                }
+            };
+            if let SocketEvent::ProbeReply {
+                probe_id: id,
+                path,
+                return_path,
+                ..
+            } = event
+            {
+                if id == probe_id {
+                    return Ok((sent.elapsed(), path, return_path));
+                }
+            }
+        }
+    }
+
+    /// Look up the public keys of the nodes with the given username in the DHT, and add them to
+    /// our known keys. Returns their node IDs, most recently signed first. Anyone can claim any
+    /// username, so compare safety numbers before trusting one of them.
+    pub async fn get_node_cert(&mut self, username: String) -> Result<Vec<String>, SocketError> {
+        let mut records = self.dht.lookup_username(&username).await;
+        if records.is_empty() {
+            return Err(DhtError::NotFound.into());
+        }
+        records.sort_by_key(|record| std::cmp::Reverse(record.time_signed));
+        let mut crypto = self.crypto.write().await;
+        records
+            .iter()
+            .map(|record| {
+                Ok(crypto
+                    .add_pubkey_raw(&record.value)
+                    .map_err(DhtError::from)?)
+            })
+            .collect()
+    }
+
+    /// Look up the node with the given fingerprint in the DHT. Its public key is added to our
+    /// known keys, and its username is returned along with the endpoint it last published, if
+    /// any.
+    pub async fn lookup_node(
+        &self,
+        fingerprint: &[u8],
+    ) -> Result<(String, Option<SocketAddr>), SocketError> {
+        let records = self.dht.lookup_fingerprint(fingerprint).await;
+        let pubkey = records
+            .iter()
+            .find(|record| record.kind == dht::v1::RecordKind::Pubkey)
+            .ok_or(DhtError::NotFound)?;
+        let username = {
+            let mut crypto = self.crypto.write().await;
+            let id = crypto
+                .add_pubkey_raw(&pubkey.value)
+                .map_err(DhtError::from)?;
+            crypto.username_of(&id).unwrap_or_default()
+        };
+        let endpoint = records.iter().find_map(|record| record.endpoint());
+        Ok((username, endpoint))
+    }
+
+    /// Compute the safety number between us and the node with the given ID, for the users to
+    /// compare out of band. We need the public key of the node.
+    pub async fn safety_number(&self, id: &str) -> Result<SafetyNumber, SocketError> {
+        Ok(self.crypto.read().await.safety_number(id)?)
+    }
+
+    /// Mark the node with the given ID as verified once the user compared safety numbers, or
+    /// forget that it was. If another key ever claims its username, a [SocketEvent::KeyChanged]
+    /// is published.
+    pub async fn set_verified(&self, id: &str, verified: bool) -> Result<(), SocketError> {
+        Ok(self.crypto.write().await.set_verified(id, verified)?)
+    }
+
+    /// Returns true if the node with the given ID is verified, and its key has not changed since.
+    pub async fn is_verified(&self, id: &str) -> bool {
+        self.crypto.read().await.is_verified(id)
+    }
+
+    /// Replace our key with `next`: a rotation statement signed with our current key is broadcast,
+    /// so our contacts follow us to the new key. This socket keeps using the current key; bind a
+    /// new one with `next` once the statement is out. Returns the statement, to be registered
+    /// with the lighthouse for the nodes that are offline.
+    pub async fn rotate_key(
+        &self,
+        next: &UnlockedKey,
+    ) -> Result<proto_crypto::v1::SignedKeyStatement, SocketError> {
+        let statement = {
+            let crypto = self.crypto.read().await;
+            sign_rotation(&crypto.key, &next.signed_public_key()?)?
+        };
+        self.send_gossip(
+            MessageType::KeyStatement(statement.clone()),
+            "*".to_string(),
+        )
+        .await?;
+        Ok(statement)
+    }
+
+    /// Revoke our key, for instance because it was compromised: a revocation statement is
+    /// broadcast, after which no node trusts anything signed with the key. The socket is of no
+    /// further use. Returns the statement, to be registered with the lighthouse for the nodes
+    /// that are offline.
+    pub async fn revoke_key(
+        &self,
+        reason: String,
+    ) -> Result<proto_crypto::v1::SignedKeyStatement, SocketError> {
+        let statement = sign_revocation(&self.crypto.read().await.key, reason)?;
+        self.send_gossip(
+            MessageType::KeyStatement(statement.clone()),
+            "*".to_string(),
+        )
+        .await?;
+        Ok(statement)
+    }
+
+    /// Apply a key rotation or revocation learnt elsewhere, such as from the lighthouse. Returns
+    /// false if we already knew of it.
+    pub async fn apply_key_statement(
+        &self,
+        statement: &proto_crypto::v1::SignedKeyStatement,
+    ) -> Result<bool, SocketError> {
+        Ok(self.crypto.write().await.apply_key_statement(statement)?)
+    }
+
+    /// Announce that our key is a device of the identity that signed `certificate`: it is applied,
+    /// then broadcast, so that whoever sends to the identity sends to us too. Register it with the
+    /// lighthouse as well, for the nodes that are offline.
+    pub async fn announce_device(
+        &self,
+        certificate: &proto_crypto::v1::SignedDeviceCertificate,
+    ) -> Result<(), SocketError> {
+        {
+            let mut crypto = self.crypto.write().await;
+            let verified = verify_certificate(certificate)?;
+            if verified.device.fingerprint() != crypto.key.fingerprint() {
+                return Err(DeviceError::NotOurDevice.into());
+            }
+            crypto.apply_device_certificate(certificate)?;
+        }
+        self.send_gossip(
+            MessageType::DeviceCertificate(certificate.clone()),
+            "*".to_string(),
+        )
+        .await
+    }
+
+    /// Apply a device certificate learnt elsewhere, such as from the lighthouse. Returns false if
+    /// we already knew of it.
+    pub async fn apply_device_certificate(
+        &self,
+        certificate: &proto_crypto::v1::SignedDeviceCertificate,
+    ) -> Result<bool, SocketError> {
+        Ok(self
+            .crypto
+            .write()
+            .await
+            .apply_device_certificate(certificate)?)
+    }
+
+    /// Returns the node ID of our identity, which is our own node ID unless we announced that we
+    /// are a device of another.
+    pub async fn identity(&self) -> String {
+        self.crypto.read().await.own_identity()
+    }
+
+    /// Returns the node IDs of the devices of the identity with the given node ID.
+    pub async fn devices_of(&self, identity: &str) -> Result<Vec<String>, SocketError> {
+        Ok(self.crypto.read().await.devices_of(identity)?)
+    }
+
+    /// Send an encrypted packet to every device of the identity with the given node ID, each over
+    /// its own ratchet. A device we have no ratchet with yet gets the packet as the first of a
+    /// session started from its prekey bundle. If the packet is a message, our other devices get
+    /// a copy of it as a transcript, so that they show the same history. Fails only if no device
+    /// could be sent to.
+    pub async fn send_to_identity(
+        &self,
+        packet: ProtocolPacket,
+        identity: String,
+    ) -> Result<(), SocketError> {
+        let (devices, own) = {
+            let crypto = self.crypto.read().await;
+            (
+                crypto.devices_of(&identity)?,
+                crypto.devices_of(&crypto.own_identity())?,
+            )
+        };
+        let transcript = match packet.packet_type {
+            Some(ProtocolPacketType::PktMessage(ref message)) => {
+                Some(transcript_packet(identity.clone(), message.clone()))
+            }
+            _ => None,
+        };
+
+        let mut result = Err(SocketError::MissingPubKey);
+        for device in devices.iter().filter(|device| **device != self.id) {
+            match self.send_to_device(packet.clone(), device.clone()).await {
+                Ok(()) => result = Ok(()),
+                Err(err) => {
+                    warn!(?err, device, "failed to send to device");
+                    if result.is_err() {
+                        result = Err(err);
+                    }
+                }
+            }
+        }
+        if let Some(transcript) = transcript {
+            for device in own
+                .into_iter()
+                .filter(|device| *device != self.id && !devices.contains(device))
+            {
+                if let Err(err) = self
+                    .send_to_device(transcript.clone(), device.clone())
+                    .await
+                {
+                    warn!(?err, device, "failed to send transcript to device");
+                }
+            }
+        }
+        result
+    }
+
+    /// Send an encrypted packet to a single device, starting a session from its prekey bundle if
+    /// we have no ratchet with it.
+    async fn send_to_device(
+        &self,
+        packet: ProtocolPacket,
+        device: String,
+    ) -> Result<(), SocketError> {
+        if self.crypto.read().await.ratchets.contains_key(&device) {
+            return self.send_gossip_encrypted(packet, device).await;
+        }
+        let bundle = self.fetch_prekey_bundle(&device).await?;
+        self.start_dr_with_bundle(device, &bundle, packet).await
+    }
+
+    /// Returns our current prekey bundle, rotating and replenishing our prekeys first, so that it
+    /// can be published somewhere other than the DHT.
+    pub async fn prekey_bundle(&self) -> Result<proto_crypto::v1::PrekeyBundle, SocketError> {
+        let bundle = self
+            .crypto
+            .write()
+            .await
+            .prekey_bundle()
+            .map_err(x3dh::X3dhError::from)?;
+        Ok(bundle)
+    }
+
+    /// Look up the prekey bundle of the node with the given ID in the DHT, which
+    /// [Socket::start_dr_with_bundle] starts a session from. Its public key is added to our known
+    /// keys, so the bundle can be verified.
+    pub async fn fetch_prekey_bundle(
+        &self,
+        destination: &str,
+    ) -> Result<proto_crypto::v1::PrekeyBundle, SocketError> {
+        let fingerprint = hex::decode(destination).map_err(|_| SocketError::BadNodeId)?;
+        let records = self.dht.lookup_fingerprint(&fingerprint).await;
+        let pubkey = records
+            .iter()
+            .find(|record| record.kind == dht::v1::RecordKind::Pubkey)
+            .ok_or(DhtError::NotFound)?;
+        self.crypto
+            .write()
+            .await
+            .add_pubkey_raw(&pubkey.value)
+            .map_err(DhtError::from)?;
+        let bundle = records
+            .iter()
+            .find_map(|record| record.prekey_bundle())
+            .ok_or(DhtError::NotFound)?;
+        Ok(bundle)
+    }
+
+    /// Start a DR ratchet with the destination node from its prekey bundle, sending it `packet`
+    /// as the first encrypted packet. Unlike [Socket::start_dr], the node does not need to be
+    /// online: the packet is kept in the [Outbox] until the node comes online and acknowledges
+    /// it, and the ratchet is completed once the node replies. We need the public key of the node
+    /// to verify the bundle.
+    pub async fn start_dr_with_bundle(
+        &self,
+        destination: String,
+        bundle: &proto_crypto::v1::PrekeyBundle,
+        packet: ProtocolPacket,
+    ) -> Result<(), SocketError> {
+        let bytes = try_encode_packet_with(&packet, CodecSet::none())?;
+        let sender = self.dht.own_pubkey_record().await;
+        let message = {
+            let mut crypto = self.crypto.write().await;
+            if crypto.statements.is_revoked(&destination) {
+                return Err(SigningError::Revoked.into());
+            }
+            if crypto.ratchets.contains_key(&destination) {
+                return Err(SocketError::RatchetExists);
+            }
+            let pubkey = crypto
+                .lookup_pubkey(destination.clone())
+                .ok_or(SocketError::MissingPubKey)?;
+            let (secrets, mut message) = x3dh::initiate(&crypto.prekeys, bundle, &pubkey)?;
+            message.content = secrets.seal(&bytes)?;
+            message.sender = sender;
+            crypto.ratchets.insert(
+                destination.clone(),
+                DoubleRatchet::new_prekey_initiator(
+                    secrets.root.clone(),
+                    secrets.session_id().to_vec(),
+                ),
+            );
+            MessageType::PrekeyMessage(message)
+        };
+
+        let packet = sign_gossip(
+            &self.crypto,
+            &self.seen,
+            &self.limiter,
+            self.id.clone(),
+            message.clone(),
+            destination.clone(),
+            self.config.gossip_ttl,
+        )
+        .await?;
+        let id = gossip_id(&packet).unwrap_or_default().to_vec();
+        self.outbox.insert(destination, id, message).await;
+        self.gossip_tx
+            .send(Gossip {
+                action: GossipAction::Forward,
+                addr: None,
+                packet: Some(packet),
+                message: None,
+                dest: None,
+                dest_sockaddr: None,
+            })
+            .await
+            .map_err(|err| SocketError::GossipSendError(Box::new(err)))
+    }
+
+    /// Replace the DR ratchet with the destination node with a new session, dropping the packets
+    /// for it still in the [Outbox]. This happens on its own when packets from the node keep
+    /// failing to decrypt, see [crate::reset].
+    pub async fn reset_session(&mut self, destination: String) -> Result<(), SocketError> {
+        let (destination, reset) = {
+            let mut crypto = self.crypto.write().await;
+            let destination = crypto.resolve_id(&destination)?;
+            let reset = crypto.reset_session(&destination)?;
+            (destination, reset)
+        };
+        self.outbox.discard(&destination, &self.events).await;
+        self.send_gossip(MessageType::SessionReset(reset), destination)
+            .await?;
         Ok(())
     }
 
     /// Attempt to establish a DR ratchet with destination node
     /// Since this is done by gossip, it may not succeed if the node is down
-    /// or inexistent
+    /// or inexistent. Fails if there is a ratchet already, which [Socket::reset_session] replaces
     pub async fn start_dr(&mut self, destination: String) -> Result<(), SocketError> {
         let mut crypto = self.crypto.write().await;
+        let destination = crypto.resolve_id(&destination)?;
         match crypto.ratchets.entry(destination.clone()) {
             Entry::Occupied(_) => Err(SocketError::RatchetExists),
             Entry::Vacant(entry) => {
                 let mut dr = DoubleRatchet::new_initiator();
-                let kex_msg = dr.generate_kex_message();
+                let kex_msg = dr.generate_kex_message()?;
                 entry.insert(dr);
                 drop(crypto);
                 self.send_gossip(MessageType::KeyExchange(kex_msg), destination)
@@ -366,13 +1068,39 @@ fn spawn_inbound_peer_task(
 fn start_periodic_worker(
     peers: Arc<RwLock<HashMap<SocketAddr, Peer>>>,
     curr_time: Arc<RwLock<Timestamp>>,
+    routes: Arc<RwLock<RoutingTable>>,
+    pex: Arc<RwLock<PexTable>>,
+    crypto: Arc<RwLock<Crypto>>,
+    username: String,
+    external: SocketAddr,
 ) {
     tokio::spawn(async move {
         loop {
             tokio::time::sleep(Duration::from_millis(5000)).await;
-            // periodically send all the peers you can see right now
+            // forget routes that are no longer being advertised
+            routes.write().await.expire();
+
+            // forget nodes that are no longer being advertised, and keep our own entry fresh
+            {
+                let mut pex = pex.write().await;
+                pex.expire();
+                if pex.needs_refresh() {
+                    let crypto = crypto.read().await;
+                    match sign_peer_entry(&crypto, username.clone(), external) {
+                        Ok(entry) => pex.set_own(entry),
+                        Err(err) => error!(?err, "failed to sign peer entry"),
+                    }
+                }
+            }
+
+            // periodically send all the peers you can see right now, and forget the routes
+            // through peers that died
             let mut peers_write = peers.write().await;
             for peer in peers_write.values_mut() {
+                if *peer.state.read().await == PeerState::Dead {
+                    routes.write().await.remove_neighbour(peer.remote_addr);
+                    continue;
+                }
                 try_continue!(peer.send_available_peers().await);
             }
 
@@ -0,0 +1,469 @@
+//! Defines [Outbox], which holds encrypted gossip until its destination acknowledges it.
+//!
+//! Every encrypted gossip we send is kept in the outbox under its destination node ID. The
+//! destination replies with a [crypto::v1::GossipAck] once the packet has been delivered, which
+//! clears the entry. Acknowledgements are only taken from the destination itself, since every node
+//! that forwarded the packet knows its gossip ID. Until then, the packet is resent with
+//! exponential backoff - and straight away whenever a new peer or route appears. Each attempt is
+//! signed under a fresh gossip ID so that nodes which saw an earlier attempt do not drop it as a
+//! duplicate. Entries that are not acknowledged in time are dropped, and so are the oldest entries
+//! for a destination once it has [MAX_OUTBOX_ENTRIES] of them.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use string_protocol::{crypto, MessageType};
+use tokio::{
+    select,
+    sync::{broadcast, mpsc, Mutex, Notify, RwLock},
+    time::sleep,
+};
+use tracing::{debug, error, trace};
+
+use super::{
+    event::SocketEvent,
+    gossip::{gossip_id, sign_gossip, Gossip, GossipAction},
+    limit::GossipLimiter,
+    seen::SeenCache,
+};
+use crate::{crypto::Crypto, try_continue};
+
+/// The default amount of time an unacknowledged gossip is kept in the outbox.
+pub const DEFAULT_OUTBOX_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The delay before the first retry of an unacknowledged gossip.
+pub const OUTBOX_RETRY_BASE: Duration = Duration::from_secs(2);
+
+/// The longest delay between retries of an unacknowledged gossip.
+pub const OUTBOX_RETRY_MAX: Duration = Duration::from_secs(60);
+
+/// The most unacknowledged entries kept for a single destination.
+pub const MAX_OUTBOX_ENTRIES: usize = 256;
+
+/// How long the outbox worker sleeps when there is nothing to retry.
+const OUTBOX_IDLE: Duration = Duration::from_secs(60);
+
+/// An encrypted gossip that has not been acknowledged yet.
+#[derive(Debug, Clone)]
+pub struct OutboxEntry {
+    /// The encrypted message. It is re-signed under a fresh gossip ID on every attempt.
+    pub message: MessageType,
+    /// The gossip IDs of every attempt so far. An acknowledgement of any of them clears the entry.
+    pub ids: Vec<Vec<u8>>,
+    /// When the entry was added to the outbox.
+    pub created: Instant,
+    /// When the entry should next be retried.
+    pub next_attempt: Instant,
+    /// The number of attempts so far.
+    pub attempts: u32,
+}
+
+/// The pending entries of an [Outbox], grouped by destination node ID.
+#[derive(Debug, Default)]
+pub struct OutboxEntries {
+    /// The unacknowledged entries for each destination.
+    entries: HashMap<String, Vec<OutboxEntry>>,
+    /// The destination each gossip ID was sent to.
+    destinations: HashMap<Vec<u8>, String>,
+}
+
+impl OutboxEntries {
+    /// Add a gossip that was just sent under the given ID. Returns the oldest entry for the
+    /// destination if it had to be dropped to make room.
+    pub fn insert(
+        &mut self,
+        destination: String,
+        id: Vec<u8>,
+        message: MessageType,
+    ) -> Option<OutboxEntry> {
+        let now = Instant::now();
+        self.destinations.insert(id.clone(), destination.clone());
+        let entries = self.entries.entry(destination).or_default();
+        entries.push(OutboxEntry {
+            message,
+            ids: vec![id],
+            created: now,
+            next_attempt: now + backoff(1),
+            attempts: 1,
+        });
+        if entries.len() <= MAX_OUTBOX_ENTRIES {
+            return None;
+        }
+        let dropped = entries.remove(0);
+        for id in dropped.ids.iter() {
+            self.destinations.remove(id);
+        }
+        Some(dropped)
+    }
+
+    /// Clear the entry the given gossip ID belongs to, if `source` is the node it was sent to,
+    /// returning its destination if there was one.
+    pub fn ack(&mut self, source: &str, id: &[u8]) -> Option<String> {
+        if self.destinations.get(id)? != source {
+            return None;
+        }
+        let destination = self.destinations.remove(id)?;
+        let entries = self.entries.get_mut(&destination)?;
+        let position = entries
+            .iter()
+            .position(|entry| entry.ids.iter().any(|other| other == id))?;
+        let entry = entries.remove(position);
+        if entries.is_empty() {
+            self.entries.remove(&destination);
+        }
+        for other in entry.ids {
+            self.destinations.remove(&other);
+        }
+        Some(destination)
+    }
+
+    /// Record that the entry with the given gossip ID was retried, and the new gossip ID it was
+    /// sent under, if it could be sent at all.
+    fn record_attempt(&mut self, destination: &str, previous: &[u8], id: Option<Vec<u8>>) {
+        let Some(entry) = self.entries.get_mut(destination).and_then(|entries| {
+            entries
+                .iter_mut()
+                .find(|entry| entry.ids.iter().any(|other| other == previous))
+        }) else {
+            return;
+        };
+        entry.attempts += 1;
+        entry.next_attempt = Instant::now() + backoff(entry.attempts);
+        if let Some(id) = id {
+            entry.ids.push(id.clone());
+            self.destinations.insert(id, destination.to_string());
+        }
+    }
+
+    /// Returns the entries that are due to be retried, as (destination, latest gossip ID, message).
+    pub fn due(&self, now: Instant) -> Vec<(String, Vec<u8>, MessageType)> {
+        self.entries
+            .iter()
+            .flat_map(|(destination, entries)| {
+                entries
+                    .iter()
+                    .filter(move |entry| entry.next_attempt <= now)
+                    .filter_map(move |entry| {
+                        let id = entry.ids.last()?.clone();
+                        Some((destination.clone(), id, entry.message.clone()))
+                    })
+            })
+            .collect()
+    }
+
+    /// Returns when the next entry is due to be retried.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.entries
+            .values()
+            .flatten()
+            .map(|entry| entry.next_attempt)
+            .min()
+    }
+
+    /// Make every entry due to be retried straight away.
+    pub fn retry_now(&mut self) {
+        let now = Instant::now();
+        for entry in self.entries.values_mut().flatten() {
+            entry.next_attempt = now;
+        }
+    }
+
+    /// Remove the entries older than `expiry`, returning them along with their destination.
+    pub fn expire(&mut self, now: Instant, expiry: Duration) -> Vec<(String, OutboxEntry)> {
+        let mut expired = vec![];
+        for (destination, entries) in self.entries.iter_mut() {
+            let (old, fresh) = entries
+                .drain(..)
+                .partition(|entry| now.duration_since(entry.created) >= expiry);
+            *entries = fresh;
+            expired.extend(old.into_iter().map(|entry| (destination.clone(), entry)));
+        }
+        self.entries.retain(|_, entries| !entries.is_empty());
+        for (_, entry) in expired.iter() {
+            for id in entry.ids.iter() {
+                self.destinations.remove(id);
+            }
+        }
+        expired
+    }
+
+    /// Remove every entry for the given destination, returning them.
+    pub fn discard(&mut self, destination: &str) -> Vec<OutboxEntry> {
+        let discarded = self.entries.remove(destination).unwrap_or_default();
+        for entry in discarded.iter() {
+            for id in entry.ids.iter() {
+                self.destinations.remove(id);
+            }
+        }
+        discarded
+    }
+
+    /// Returns the number of unacknowledged entries for the given destination.
+    pub fn pending(&self, destination: &str) -> usize {
+        self.entries.get(destination).map_or(0, Vec::len)
+    }
+
+    /// Returns the total number of unacknowledged entries.
+    pub fn len(&self) -> usize {
+        self.entries.values().map(Vec::len).sum()
+    }
+
+    /// Returns true if there are no unacknowledged entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Holds encrypted gossip until it is acknowledged by its destination, and wakes the outbox
+/// worker when it should retry.
+#[derive(Debug)]
+pub struct Outbox {
+    /// The unacknowledged entries.
+    pub entries: Mutex<OutboxEntries>,
+    /// How long entries are kept before they are dropped.
+    pub expiry: Duration,
+    /// Used to wake the outbox worker.
+    notify: Notify,
+}
+
+impl Outbox {
+    /// Create an empty outbox that drops entries after `expiry`.
+    pub fn new(expiry: Duration) -> Self {
+        Self {
+            entries: Mutex::new(OutboxEntries::default()),
+            expiry,
+            notify: Notify::new(),
+        }
+    }
+
+    /// Add a gossip that was just sent under the given ID.
+    pub async fn insert(&self, destination: String, id: Vec<u8>, message: MessageType) {
+        let dropped = self
+            .entries
+            .lock()
+            .await
+            .insert(destination.clone(), id, message);
+        if dropped.is_some() {
+            debug!(destination, "outbox full, dropping oldest gossip");
+        }
+        self.notify.notify_one();
+    }
+
+    /// Handle an acknowledgement from `source` for the given gossip ID.
+    pub async fn ack(&self, source: &str, id: &[u8]) {
+        if let Some(destination) = self.entries.lock().await.ack(source, id) {
+            debug!(destination, "gossip acknowledged");
+        }
+    }
+
+    /// Retry every entry straight away. This is called when a new peer or route appears.
+    pub async fn wake(&self) {
+        let mut entries = self.entries.lock().await;
+        if !entries.is_empty() {
+            entries.retry_now();
+            self.notify.notify_one();
+        }
+    }
+
+    /// Drop every entry for the given destination, which was encrypted with a ratchet that has
+    /// been reset, publishing a [SocketEvent::OutboxExpired] for each.
+    pub async fn discard(&self, destination: &str, events: &broadcast::Sender<SocketEvent>) {
+        let discarded = self.entries.lock().await.discard(destination);
+        for entry in discarded {
+            debug!(destination, "dropping gossip of a reset session");
+            // nobody may be listening, which is fine
+            let _ = events.send(SocketEvent::OutboxExpired {
+                id: entry.ids.last().cloned().unwrap_or_default(),
+                destination: destination.to_string(),
+                attempts: entry.attempts,
+            });
+        }
+    }
+
+    /// Returns the number of unacknowledged entries for the given destination.
+    pub async fn pending(&self, destination: &str) -> usize {
+        self.entries.lock().await.pending(destination)
+    }
+}
+
+/// Returns the delay before the given attempt is retried.
+fn backoff(attempts: u32) -> Duration {
+    OUTBOX_RETRY_BASE
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(OUTBOX_RETRY_MAX)
+}
+
+/// Start the worker that resends unacknowledged gossip and drops expired entries.
+#[allow(clippy::too_many_arguments)]
+pub fn start_outbox_worker(
+    outbox: Arc<Outbox>,
+    gossip_tx: mpsc::Sender<Gossip>,
+    crypto: Arc<RwLock<Crypto>>,
+    seen: Arc<Mutex<SeenCache>>,
+    limiter: Arc<Mutex<GossipLimiter>>,
+    events: broadcast::Sender<SocketEvent>,
+    node_id: String,
+    ttl: u32,
+) {
+    tokio::spawn(async move {
+        loop {
+            trace!("start outbox worker loop");
+
+            // wait until the next entry is due, or until we are woken up
+            let next_due = { outbox.entries.lock().await.next_due() };
+            let wait = next_due
+                .map(|due| due.saturating_duration_since(Instant::now()))
+                .unwrap_or(OUTBOX_IDLE);
+            select! {
+                _ = sleep(wait) => {}
+                _ = outbox.notify.notified() => {}
+            }
+
+            let now = Instant::now();
+            let (expired, due) = {
+                let mut entries = outbox.entries.lock().await;
+                (entries.expire(now, outbox.expiry), entries.due(now))
+            };
+
+            for (destination, entry) in expired {
+                debug!(destination, "dropping unacknowledged gossip");
+                // nobody may be listening, which is fine
+                let _ = events.send(SocketEvent::OutboxExpired {
+                    id: entry.ids.last().cloned().unwrap_or_default(),
+                    destination,
+                    attempts: entry.attempts,
+                });
+            }
+
+            for (destination, previous, message) in due {
+                debug!(destination, "retrying unacknowledged gossip");
+                let packet = sign_gossip(
+                    &crypto,
+                    &seen,
+                    &limiter,
+                    node_id.clone(),
+                    message,
+                    destination.clone(),
+                    ttl,
+                )
+                .await;
+                // back off even if signing failed, so we do not spin on a broken entry
+                let id = packet
+                    .as_ref()
+                    .ok()
+                    .and_then(gossip_id)
+                    .map(|id| id.to_vec());
+                outbox
+                    .entries
+                    .lock()
+                    .await
+                    .record_attempt(&destination, &previous, id);
+                let packet = try_continue!(packet, "Failed to sign gossip");
+
+                if gossip_tx
+                    .send(Gossip {
+                        action: GossipAction::Forward,
+                        addr: None,
+                        packet: Some(packet),
+                        message: None,
+                        dest: None,
+                        dest_sockaddr: None,
+                    })
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/// Build the acknowledgement for the gossip with the given ID.
+pub fn gossip_ack(id: Vec<u8>) -> MessageType {
+    MessageType::GossipAck(crypto::v1::GossipAck { id })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> MessageType {
+        MessageType::EncryptedPacket(crypto::v1::EncryptedPacket {
+            content: vec![1, 2, 3],
+            group: String::new(),
+        })
+    }
+
+    #[test]
+    fn test_ack_clears_every_attempt() {
+        let mut entries = OutboxEntries::default();
+        entries.insert("bob".to_string(), vec![1], message());
+        entries.record_attempt("bob", &[1], Some(vec![2]));
+        assert_eq!(entries.pending("bob"), 1);
+
+        // only the destination can acknowledge the entry
+        assert_eq!(entries.ack("mallory", &[1]), None);
+        assert_eq!(entries.pending("bob"), 1);
+
+        // acknowledging the first attempt clears the entry
+        assert_eq!(entries.ack("bob", &[1]), Some("bob".to_string()));
+        assert!(entries.is_empty());
+        assert_eq!(entries.ack("bob", &[2]), None);
+    }
+
+    #[test]
+    fn test_retry_and_expiry() {
+        let mut entries = OutboxEntries::default();
+        entries.insert("bob".to_string(), vec![1], message());
+        assert!(entries.due(Instant::now()).is_empty());
+
+        entries.retry_now();
+        let due = entries.due(Instant::now());
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].1, vec![1]);
+
+        let expired = entries.expire(Instant::now(), Duration::ZERO);
+        assert_eq!(expired.len(), 1);
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn test_discard_destination() {
+        let mut entries = OutboxEntries::default();
+        entries.insert("bob".to_string(), vec![1], message());
+        entries.insert("bob".to_string(), vec![2], message());
+        entries.insert("carol".to_string(), vec![3], message());
+
+        assert_eq!(entries.discard("bob").len(), 2);
+        assert_eq!(entries.pending("bob"), 0);
+        assert_eq!(entries.ack("bob", &[1]), None);
+        assert_eq!(entries.pending("carol"), 1);
+    }
+
+    #[test]
+    fn test_entries_per_destination_are_capped() {
+        let mut entries = OutboxEntries::default();
+        for id in 0..MAX_OUTBOX_ENTRIES as u32 {
+            assert!(entries
+                .insert("bob".to_string(), id.to_be_bytes().to_vec(), message())
+                .is_none());
+        }
+        let dropped = entries
+            .insert("bob".to_string(), vec![1, 2, 3, 4, 5], message())
+            .unwrap();
+        assert_eq!(dropped.ids, vec![0u32.to_be_bytes().to_vec()]);
+        assert_eq!(entries.pending("bob"), MAX_OUTBOX_ENTRIES);
+        assert_eq!(entries.ack("bob", &0u32.to_be_bytes()), None);
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), OUTBOX_RETRY_BASE);
+        assert_eq!(backoff(2), OUTBOX_RETRY_BASE * 2);
+        assert_eq!(backoff(100), OUTBOX_RETRY_MAX);
+    }
+}
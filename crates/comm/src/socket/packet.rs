@@ -6,6 +6,7 @@ use std::{
 };
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use string_protocol::CodecSet;
 // use flate2::read::GzDecoder;
 // use string_protocol::{try_decode_packet, ProtocolPacket};
 
@@ -29,7 +30,8 @@ pub const UDP_MAX_DATAGRAM_SIZE: usize = 40_000;
 /// - 4 bytes: Sequence number
 /// - 4 bytes: Length of the data
 ///
-/// Then arbitrary-length data, as defined by the protocol.
+/// Then arbitrary-length data, as defined by the protocol. SYN, ACK and SYNACK packets carry a
+/// single byte of data: the bitmask of compression codecs supported by the sender.
 #[derive(Debug, Clone)]
 pub struct SocketPacket {
     /// The type of packet.
@@ -40,7 +42,8 @@ pub struct SocketPacket {
     pub chunk_number: u32,
     /// The length of the data within the packet.
     pub data_length: u32,
-    /// The packet data. This is empty for SYN, ACK, SYNACK, and HEARTBEAT packets.
+    /// The packet data. This is the supported codec bitmask for SYN, ACK and SYNACK packets, and
+    /// empty for HEARTBEAT packets.
     pub data: Vec<u8>,
 }
 
@@ -158,6 +161,23 @@ impl SocketPacket {
         }
     }
 
+    /// Create a handshake packet advertising the given codecs.
+    pub fn handshake(packet_type: SocketPacketType, packet_number: u32, codecs: CodecSet) -> Self {
+        Self {
+            packet_type,
+            packet_number,
+            chunk_number: 0,
+            data: vec![codecs.bits()],
+            data_length: 1,
+        }
+    }
+
+    /// Returns the codecs advertised by a handshake packet. Peers that do not advertise any
+    /// codecs can only receive uncompressed packets.
+    pub fn advertised_codecs(&self) -> CodecSet {
+        CodecSet::from_bits(self.data.first().copied().unwrap_or_default())
+    }
+
     /// Decode a packet from the given byte buffer.
     pub fn decode<Data>(bytes: Data) -> Result<SocketPacket, SocketPacketDecodeError>
     where
@@ -0,0 +1,423 @@
+//! Defines [PexTable], which holds the signed [peers::v1::PeerEntry]s learnt through peer
+//! exchange (PEX), along with the worker that grows the mesh from them.
+//!
+//! Every node signs an entry with its fingerprint, username and reflexive address, and sends it
+//! to its peers along with the entries it has learnt from others. Entries are self-certifying -
+//! they carry the public key they are signed with, which must match the fingerprint - so they can
+//! be relayed by anyone without being forged. A node that opts in with
+//! [super::SocketConfig::pex_target_degree] connects to the nodes it learns about until it has
+//! enough peers, so the mesh heals without going back to the lighthouse.
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant, SystemTime},
+};
+
+use string_protocol::{peers, prost::Message};
+use thiserror::Error;
+use tracing::{debug, trace, warn};
+
+use super::Socket;
+use crate::{
+    crypto::{Crypto, SigningError},
+    peer::PeerState,
+};
+
+/// How long an entry is kept without being re-advertised.
+pub const PEX_ENTRY_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// How often a node re-signs its own entry, so that it never goes stale for its peers.
+pub const PEX_REFRESH: Duration = Duration::from_secs(5 * 60);
+
+/// The maximum number of entries sent to a peer at once.
+pub const MAX_PEX_ENTRIES: usize = 32;
+
+/// The maximum number of unknown entries verified from a single advertisement. Only one batch is
+/// verified per peer at a time, and advertisements that arrive in the meantime are not verified.
+pub const MAX_PEX_VERIFY: usize = 8;
+
+/// How long a connection made from an entry has to be established before it is dropped.
+pub const PEX_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a node that could not be connected to is left alone.
+const PEX_RETRY_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// How often the PEX worker checks whether more peers are needed.
+const PEX_INTERVAL: Duration = Duration::from_secs(10);
+
+/// An enumeration of the reasons a [peers::v1::SignedPeerEntry] can be rejected.
+#[derive(Error, Debug)]
+pub enum PexError {
+    /// The signed entry is missing its contents.
+    #[error("Missing entry")]
+    MissingEntry,
+    /// The address could not be parsed.
+    #[error("Bad address")]
+    BadAddress,
+    /// The entry was signed too long ago, or claims to be signed in the future.
+    #[error("Stale entry")]
+    Stale,
+    /// The signature or public key is invalid, or the key does not belong to the node.
+    #[error("Invalid signature")]
+    SigningFail(#[from] SigningError),
+}
+
+/// A node learnt through peer exchange, whose entry has been verified.
+#[derive(Debug, Clone)]
+pub struct PexPeer {
+    /// The fingerprint of the node's public key.
+    pub fingerprint: Vec<u8>,
+    /// The username of the node.
+    pub username: String,
+    /// The reflexive address of the node.
+    pub addr: SocketAddr,
+    /// When the node signed its entry.
+    pub time_signed: SystemTime,
+    /// The signed entry, as it is relayed to other peers.
+    pub signed: peers::v1::SignedPeerEntry,
+    /// When we received the entry.
+    pub received: Instant,
+}
+
+/// A table of the nodes learnt through peer exchange, keyed by fingerprint.
+#[derive(Debug)]
+pub struct PexTable {
+    /// The fingerprint of the current node, which is never stored.
+    fingerprint: Vec<u8>,
+    /// How long an entry is kept without being re-advertised.
+    ttl: Duration,
+    /// The entry of the current node, and when it was signed.
+    own: Option<(peers::v1::SignedPeerEntry, Instant)>,
+    /// The latest verified entry of every node.
+    entries: HashMap<Vec<u8>, PexPeer>,
+}
+
+impl PexTable {
+    /// Create an empty table for the node with the given fingerprint.
+    pub fn new(fingerprint: Vec<u8>, ttl: Duration) -> Self {
+        Self {
+            fingerprint,
+            ttl,
+            own: None,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns the fingerprint of the current node.
+    pub fn fingerprint(&self) -> &[u8] {
+        &self.fingerprint
+    }
+
+    /// Replace the entry of the current node.
+    pub fn set_own(&mut self, entry: peers::v1::SignedPeerEntry) {
+        self.own = Some((entry, Instant::now()));
+    }
+
+    /// Returns true if the entry of the current node should be re-signed.
+    pub fn needs_refresh(&self) -> bool {
+        match self.own {
+            Some((_, signed)) => signed.elapsed() >= PEX_REFRESH,
+            None => true,
+        }
+    }
+
+    /// Returns true if the given entry is ours, or the one we already hold for its node, in which
+    /// case it is kept for longer. Known entries do not need to be verified again.
+    pub fn refresh_known(&mut self, signed: &peers::v1::SignedPeerEntry) -> bool {
+        let Some(entry) = signed.entry.as_ref() else {
+            return false;
+        };
+        if entry.fingerprint == self.fingerprint {
+            return true;
+        }
+        match self.entries.get_mut(&entry.fingerprint) {
+            Some(peer) if peer.signed.signature == signed.signature => {
+                peer.received = Instant::now();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Add a verified entry, keeping the most recently signed entry of every node. Returns true
+    /// if we did not know of the node before.
+    pub fn insert(&mut self, peer: PexPeer) -> bool {
+        if peer.fingerprint == self.fingerprint {
+            return false;
+        }
+        match self.entries.get(&peer.fingerprint) {
+            Some(existing) if existing.time_signed >= peer.time_signed => false,
+            Some(_) => {
+                self.entries.insert(peer.fingerprint.clone(), peer);
+                false
+            }
+            None => {
+                self.entries.insert(peer.fingerprint.clone(), peer);
+                true
+            }
+        }
+    }
+
+    /// Returns the entries to send to the peer with the given fingerprint: ours first, then the
+    /// most recently signed entries we know of, leaving out the peer's own.
+    pub fn advertise(&self, to: &[u8]) -> Vec<peers::v1::SignedPeerEntry> {
+        let own = self.own.iter().map(|(entry, _)| entry.clone());
+        let others = self
+            .candidates()
+            .into_iter()
+            .filter(|peer| peer.fingerprint != to)
+            .map(|peer| peer.signed);
+        own.chain(others).take(MAX_PEX_ENTRIES).collect()
+    }
+
+    /// Returns the nodes we know of, most recently signed first.
+    pub fn candidates(&self) -> Vec<PexPeer> {
+        let now = Instant::now();
+        let mut candidates: Vec<_> = self
+            .entries
+            .values()
+            .filter(|peer| now.duration_since(peer.received) < self.ttl)
+            .cloned()
+            .collect();
+        candidates.sort_by_key(|peer| Reverse(peer.time_signed));
+        candidates
+    }
+
+    /// Forget entries that have not been advertised recently.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        let ttl = self.ttl;
+        self.entries
+            .retain(|_, peer| now.duration_since(peer.received) < ttl);
+    }
+
+    /// Returns the number of known nodes.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no nodes are known.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Sign the entry of the current node, advertising the given reflexive address.
+pub fn sign_peer_entry(
+    crypto: &Crypto,
+    username: String,
+    addr: SocketAddr,
+) -> Result<peers::v1::SignedPeerEntry, SigningError> {
+    let entry = peers::v1::PeerEntry {
+        fingerprint: crypto.key.fingerprint(),
+        username,
+        addr: addr.to_string(),
+        time_signed: Some(SystemTime::now().into()),
+        pubkey: crypto.get_self_pubkey()?,
+    };
+    let signature = crypto.sign_data(&entry.encode_to_vec())?;
+    Ok(peers::v1::SignedPeerEntry {
+        signature,
+        entry: Some(entry),
+    })
+}
+
+/// Verify a signed entry against the public key it carries.
+pub fn verify_peer_entry(signed: &peers::v1::SignedPeerEntry) -> Result<PexPeer, PexError> {
+    let entry = signed.entry.as_ref().ok_or(PexError::MissingEntry)?;
+    let addr = entry.addr.parse().map_err(|_| PexError::BadAddress)?;
+
+    // entries signed in the future are only tolerated within the clock skew we allow for
+    let time_signed = entry
+        .time_signed
+        .clone()
+        .and_then(|time| SystemTime::try_from(time).ok())
+        .ok_or(PexError::Stale)?;
+    let now = SystemTime::now();
+    let fresh = match now.duration_since(time_signed) {
+        Ok(age) => age < PEX_ENTRY_TTL,
+        Err(ahead) => ahead.duration() < PEX_REFRESH,
+    };
+    if !fresh {
+        return Err(PexError::Stale);
+    }
+
+    Crypto::verify_self_certified(
+        &entry.pubkey,
+        &entry.fingerprint,
+        &entry.username,
+        &signed.signature,
+        &entry.encode_to_vec(),
+    )?;
+    Ok(PexPeer {
+        fingerprint: entry.fingerprint.clone(),
+        username: entry.username.clone(),
+        addr,
+        time_signed,
+        signed: signed.clone(),
+        received: Instant::now(),
+    })
+}
+
+/// Start the worker that connects to nodes learnt through peer exchange until the socket has
+/// `target_degree` peers. Connections that are not established in time are dropped, and the node
+/// is left alone for a while.
+///
+/// Both ends have to add each other for a connection to be established, so the node with the
+/// lower fingerprint initiates the handshake and the other responds.
+pub fn start_pex_worker(mut socket: Socket, target_degree: usize) {
+    tokio::spawn(async move {
+        let mut pending: HashMap<SocketAddr, Instant> = HashMap::new();
+        let mut failed: HashMap<Vec<u8>, Instant> = HashMap::new();
+        loop {
+            tokio::time::sleep(PEX_INTERVAL).await;
+            trace!("start pex worker loop");
+
+            // drop connections that did not get established in time
+            let now = Instant::now();
+            failed.retain(|_, since| now.duration_since(*since) < PEX_RETRY_AFTER);
+            let (degree, connected) = {
+                let mut peers = socket.peers.write().await;
+                let mut settled = vec![];
+                for (addr, since) in pending.iter() {
+                    let state = match peers.get(addr) {
+                        Some(peer) => *peer.state.read().await,
+                        None => PeerState::Dead,
+                    };
+                    if state == PeerState::Established
+                        || now.duration_since(*since) >= PEX_CONNECT_TIMEOUT
+                    {
+                        settled.push((*addr, state));
+                    }
+                }
+                for (addr, state) in settled {
+                    pending.remove(&addr);
+                    if state == PeerState::Established {
+                        continue;
+                    }
+                    debug!(?addr, "dropping peer that did not connect");
+                    if let Some(peer) = peers.remove(&addr) {
+                        failed.insert(peer.fingerprint, now);
+                    }
+                    socket.routes.write().await.remove_neighbour(addr);
+                }
+
+                let mut degree = 0;
+                for peer in peers.values() {
+                    if *peer.state.read().await != PeerState::Dead {
+                        degree += 1;
+                    }
+                }
+                let connected: Vec<_> = peers
+                    .values()
+                    .map(|peer| (peer.remote_addr, peer.fingerprint.clone()))
+                    .collect();
+                (degree, connected)
+            };
+            if degree >= target_degree {
+                continue;
+            }
+
+            let (ours, candidates) = {
+                let pex = socket.pex.read().await;
+                (pex.fingerprint().to_vec(), pex.candidates())
+            };
+            let candidates: Vec<_> = candidates
+                .into_iter()
+                .filter(|peer| {
+                    peer.addr != socket.external
+                        && !failed.contains_key(&peer.fingerprint)
+                        && !connected.iter().any(|(addr, fingerprint)| {
+                            *addr == peer.addr || *fingerprint == peer.fingerprint
+                        })
+                })
+                .take(target_degree - degree)
+                .collect();
+            for peer in candidates {
+                debug!(
+                    username = peer.username,
+                    addr = ?peer.addr,
+                    "connecting to peer learnt through pex"
+                );
+                let initiate = ours < peer.fingerprint;
+                match socket.add_peer(peer.addr, peer.fingerprint, initiate).await {
+                    Ok(_) => {
+                        pending.insert(peer.addr, Instant::now());
+                    }
+                    Err(err) => warn!(?err, "failed to add peer learnt through pex"),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(fingerprint: u8, time_signed: SystemTime) -> PexPeer {
+        let entry = peers::v1::PeerEntry {
+            fingerprint: vec![fingerprint],
+            username: format!("node{fingerprint}"),
+            addr: format!("127.0.0.1:{fingerprint}"),
+            time_signed: Some(time_signed.into()),
+            pubkey: vec![],
+        };
+        PexPeer {
+            fingerprint: vec![fingerprint],
+            username: entry.username.clone(),
+            addr: entry.addr.parse().unwrap(),
+            time_signed,
+            signed: peers::v1::SignedPeerEntry {
+                signature: vec![fingerprint],
+                entry: Some(entry),
+            },
+            received: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn test_keeps_latest_entry() {
+        let now = SystemTime::now();
+        let mut table = PexTable::new(vec![0], PEX_ENTRY_TTL);
+        assert!(table.insert(peer(1, now)));
+        assert!(!table.insert(peer(1, now - Duration::from_secs(1))));
+        assert_eq!(table.candidates()[0].time_signed, now);
+
+        // our own entry is never stored
+        assert!(!table.insert(peer(0, now)));
+        assert!(table.refresh_known(&peer(0, now).signed));
+        assert!(table.refresh_known(&peer(1, now).signed));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn test_advertise() {
+        let now = SystemTime::now();
+        let mut table = PexTable::new(vec![0], PEX_ENTRY_TTL);
+        assert!(table.needs_refresh());
+        table.set_own(peer(0, now).signed);
+        assert!(!table.needs_refresh());
+        table.insert(peer(1, now - Duration::from_secs(1)));
+        table.insert(peer(2, now));
+
+        // ours first, then the newest, leaving out the recipient's own
+        let entries = table.advertise(&[2]);
+        let fingerprints: Vec<_> = entries
+            .iter()
+            .map(|signed| signed.entry.as_ref().unwrap().fingerprint.clone())
+            .collect();
+        assert_eq!(fingerprints, vec![vec![0], vec![1]]);
+    }
+
+    #[test]
+    fn test_entries_expire() {
+        let mut table = PexTable::new(vec![0], Duration::ZERO);
+        table.insert(peer(1, SystemTime::now()));
+        assert!(table.candidates().is_empty());
+        table.expire();
+        assert!(table.is_empty());
+    }
+}
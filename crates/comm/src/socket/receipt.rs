@@ -0,0 +1,28 @@
+//! Defines helpers for delivery and read receipts, which are sent back to the author of a
+//! message to tell them how far it got. Receipts travel as encrypted gossip like any other
+//! packet, and are surfaced to the author as [super::SocketEvent::Receipt]s.
+
+use std::time::SystemTime;
+
+use string_protocol::{messages, ProtocolPacket, ProtocolPacketType};
+
+use super::seen::new_gossip_id;
+
+/// Returns a new random ID for a [messages::v1::Message], which receipts refer to it by.
+pub fn new_message_id() -> String {
+    hex::encode(new_gossip_id())
+}
+
+/// Build a receipt of the given type for the messages with the given IDs, timestamped now.
+pub fn receipt_packet(
+    receipt_type: messages::v1::ReceiptType,
+    message_ids: Vec<String>,
+) -> ProtocolPacket {
+    ProtocolPacket {
+        packet_type: Some(ProtocolPacketType::PktReceipt(messages::v1::Receipt {
+            message_ids,
+            r#type: receipt_type.into(),
+            time: Some(SystemTime::now().into()),
+        })),
+    }
+}
@@ -0,0 +1,226 @@
+//! Defines [RoutingTable], a distance-vector table mapping destination node IDs to the
+//! neighbouring peer that gossip should be sent through to reach them.
+//!
+//! Each peer periodically advertises the destinations it can reach along with their hop count.
+//! We keep the shortest route to every destination, and refresh it whenever the neighbour it
+//! goes through re-advertises it. Routes that are not refreshed expire.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use string_protocol::peers;
+
+/// Routes longer than this are treated as unreachable, which stops count-to-infinity loops.
+pub const MAX_ROUTE_HOPS: u32 = 16;
+
+/// How long a route is kept without being re-advertised.
+pub const ROUTE_TTL: Duration = Duration::from_secs(30);
+
+/// A route to a destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    /// The neighbouring peer that gossip to the destination should be sent to.
+    pub next_hop: SocketAddr,
+    /// The number of hops from us to the destination.
+    pub hops: u32,
+    /// When the route was last advertised.
+    pub updated: Instant,
+}
+
+/// A table of the best known [Route] to every reachable destination.
+#[derive(Debug)]
+pub struct RoutingTable {
+    /// The node ID of the current node, which is never routed to.
+    id: String,
+    /// How long a route is kept without being re-advertised.
+    ttl: Duration,
+    /// The best known route to each destination.
+    routes: HashMap<String, Route>,
+}
+
+impl RoutingTable {
+    /// Create an empty routing table for the node with the given ID.
+    pub fn new(id: String, ttl: Duration) -> Self {
+        Self {
+            id,
+            ttl,
+            routes: HashMap::new(),
+        }
+    }
+
+    /// Record that the peer with the given ID is a direct neighbour. Returns true if we did
+    /// not have a route to it before.
+    pub fn add_neighbour(&mut self, id: String, addr: SocketAddr) -> bool {
+        self.update(addr, [(id, 0)])
+    }
+
+    /// Merge the routes advertised by the neighbour at `from`. Each advertised route is one hop
+    /// longer for us than it is for the neighbour. Returns true if a destination we did not have
+    /// a route to became reachable.
+    pub fn update(
+        &mut self,
+        from: SocketAddr,
+        advertised: impl IntoIterator<Item = (String, u32)>,
+    ) -> bool {
+        let now = Instant::now();
+        let mut reachable = false;
+        for (id, hops) in advertised {
+            if id == self.id {
+                continue;
+            }
+            let hops = hops.saturating_add(1);
+            let candidate = Route {
+                next_hop: from,
+                hops,
+                updated: now,
+            };
+
+            let replace = match self.routes.get(&id) {
+                // the neighbour we route through always has the latest word on its route,
+                // including when the destination has become unreachable
+                Some(route) if route.next_hop == from => true,
+                Some(route) => hops < route.hops || self.is_expired(route, now),
+                None => true,
+            };
+            if !replace {
+                continue;
+            }
+            if hops > MAX_ROUTE_HOPS {
+                if self.next_hop_of(&id) == Some(from) {
+                    self.routes.remove(&id);
+                }
+            } else {
+                reachable |= self.lookup(&id).is_none();
+                self.routes.insert(id, candidate);
+            }
+        }
+        reachable
+    }
+
+    /// Returns the route to the given destination, if one is known.
+    pub fn lookup(&self, id: &str) -> Option<Route> {
+        self.routes
+            .get(id)
+            .filter(|route| !self.is_expired(route, Instant::now()))
+            .copied()
+    }
+
+    /// Returns the neighbour that gossip to the given destination should be sent to.
+    pub fn next_hop(&self, id: &str) -> Option<SocketAddr> {
+        self.lookup(id).map(|route| route.next_hop)
+    }
+
+    /// Forget every route that goes through the given neighbour.
+    pub fn remove_neighbour(&mut self, addr: SocketAddr) {
+        self.routes.retain(|_, route| route.next_hop != addr);
+    }
+
+    /// Forget routes that have not been advertised recently.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        let ttl = self.ttl;
+        self.routes
+            .retain(|_, route| now.duration_since(route.updated) < ttl);
+    }
+
+    /// Returns the routes to advertise to the neighbour at `to`, including the route to
+    /// ourselves. Routes that go through `to` are left out (split horizon), since they are of no
+    /// use to it.
+    pub fn advertise(&self, to: SocketAddr) -> Vec<peers::v1::Route> {
+        let now = Instant::now();
+        let ours = peers::v1::Route {
+            node_id: self.id.clone(),
+            hops: 0,
+        };
+        std::iter::once(ours)
+            .chain(
+                self.routes
+                    .iter()
+                    .filter(|(_, route)| route.next_hop != to && !self.is_expired(route, now))
+                    .map(|(id, route)| peers::v1::Route {
+                        node_id: id.clone(),
+                        hops: route.hops,
+                    }),
+            )
+            .collect()
+    }
+
+    /// Returns the number of known routes.
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    /// Returns true if no routes are known.
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    fn next_hop_of(&self, id: &str) -> Option<SocketAddr> {
+        self.routes.get(id).map(|route| route.next_hop)
+    }
+
+    fn is_expired(&self, route: &Route, now: Instant) -> bool {
+        now.duration_since(route.updated) >= self.ttl
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        ([127, 0, 0, 1], port).into()
+    }
+
+    #[test]
+    fn test_prefers_shorter_routes() {
+        let mut table = RoutingTable::new("alice".to_string(), ROUTE_TTL);
+        table.update(addr(1), [("carol".to_string(), 3)]);
+        table.update(addr(2), [("carol".to_string(), 1)]);
+        assert_eq!(table.next_hop("carol"), Some(addr(2)));
+        assert_eq!(table.lookup("carol").unwrap().hops, 2);
+
+        // a longer route through a different neighbour is ignored
+        table.update(addr(1), [("carol".to_string(), 2)]);
+        assert_eq!(table.next_hop("carol"), Some(addr(2)));
+    }
+
+    #[test]
+    fn test_next_hop_is_authoritative() {
+        let mut table = RoutingTable::new("alice".to_string(), ROUTE_TTL);
+        table.update(addr(1), [("carol".to_string(), 1)]);
+        table.update(addr(1), [("carol".to_string(), 4)]);
+        assert_eq!(table.lookup("carol").unwrap().hops, 5);
+
+        table.update(addr(1), [("carol".to_string(), MAX_ROUTE_HOPS)]);
+        assert_eq!(table.next_hop("carol"), None);
+    }
+
+    #[test]
+    fn test_advertise_split_horizon() {
+        let mut table = RoutingTable::new("alice".to_string(), ROUTE_TTL);
+        table.add_neighbour("bob".to_string(), addr(1));
+        table.update(
+            addr(1),
+            [("carol".to_string(), 1), ("alice".to_string(), 1)],
+        );
+        assert_eq!(table.len(), 2);
+
+        let to_bob = table.advertise(addr(1));
+        assert_eq!(to_bob.len(), 1);
+        assert_eq!(to_bob[0].node_id, "alice");
+        assert_eq!(table.advertise(addr(2)).len(), 3);
+    }
+
+    #[test]
+    fn test_routes_expire() {
+        let mut table = RoutingTable::new("alice".to_string(), Duration::ZERO);
+        table.add_neighbour("bob".to_string(), addr(1));
+        assert_eq!(table.next_hop("bob"), None);
+        table.expire();
+        assert!(table.is_empty());
+    }
+}
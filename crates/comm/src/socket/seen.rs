@@ -0,0 +1,155 @@
+//! Defines [SeenCache], which remembers the IDs of recently seen gossip so that duplicates can be
+//! dropped instead of being delivered or forwarded again.
+//!
+//! Gossip is only verified once it reaches its destination, so the cache is keyed on the ID along
+//! with a digest of the signature. Otherwise anyone could copy the ID of gossip they have seen
+//! into a forgery and get the genuine gossip dropped by every node the forgery reaches first.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+/// The size of a gossip ID, in bytes.
+pub const GOSSIP_ID_SIZE: usize = 16;
+
+/// The default number of gossip IDs remembered by a [SeenCache].
+pub const SEEN_CACHE_CAPACITY: usize = 8192;
+
+/// The default amount of time a gossip ID is remembered by a [SeenCache].
+pub const SEEN_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Generate a new random gossip ID.
+pub fn new_gossip_id() -> Vec<u8> {
+    let mut id = vec![0; GOSSIP_ID_SIZE];
+    OsRng.fill_bytes(&mut id);
+    id
+}
+
+/// Returns the key gossip with the given ID and signature is remembered under.
+pub fn seen_key(id: &[u8], signature: &[u8]) -> Vec<u8> {
+    let mut key = id.to_vec();
+    key.extend_from_slice(&Sha256::digest(signature));
+    key
+}
+
+/// A bounded, time-expiring set of gossip IDs. Once the cache is full, the oldest IDs are evicted
+/// first.
+#[derive(Debug)]
+pub struct SeenCache {
+    /// The maximum number of IDs to remember.
+    capacity: usize,
+    /// How long each ID is remembered for.
+    ttl: Duration,
+    /// The set of remembered IDs.
+    ids: HashSet<Vec<u8>>,
+    /// The remembered IDs, ordered by insertion time.
+    order: VecDeque<(Instant, Vec<u8>)>,
+}
+
+impl SeenCache {
+    /// Create a new cache with the given capacity and time-to-live.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            ids: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Record the given ID, returning `true` if it had not been seen before.
+    pub fn insert(&mut self, id: &[u8]) -> bool {
+        let now = Instant::now();
+        self.expire(now);
+        if self.ids.contains(id) {
+            return false;
+        }
+
+        // make room for the new ID
+        while self.order.len() >= self.capacity {
+            match self.order.pop_front() {
+                Some((_, oldest)) => self.ids.remove(&oldest),
+                None => break,
+            };
+        }
+
+        self.ids.insert(id.to_vec());
+        self.order.push_back((now, id.to_vec()));
+        true
+    }
+
+    /// Check whether the given ID has been seen recently.
+    pub fn contains(&self, id: &[u8]) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// Returns the number of remembered IDs.
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    /// Returns true if no IDs are remembered.
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Forget IDs that are older than the time-to-live.
+    fn expire(&mut self, now: Instant) {
+        while let Some((inserted, _)) = self.order.front() {
+            if now.duration_since(*inserted) < self.ttl {
+                break;
+            }
+            if let Some((_, id)) = self.order.pop_front() {
+                self.ids.remove(&id);
+            }
+        }
+    }
+}
+
+impl Default for SeenCache {
+    fn default() -> Self {
+        Self::new(SEEN_CACHE_CAPACITY, SEEN_CACHE_TTL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_oldest_when_full() {
+        let mut cache = SeenCache::new(2, SEEN_CACHE_TTL);
+        assert!(cache.insert(&[1]));
+        assert!(cache.insert(&[2]));
+        assert!(!cache.insert(&[2]));
+        assert!(cache.insert(&[3]));
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains(&[1]));
+        assert!(cache.contains(&[2]));
+        assert!(cache.contains(&[3]));
+    }
+
+    #[test]
+    fn test_ids_expire() {
+        let mut cache = SeenCache::new(SEEN_CACHE_CAPACITY, Duration::ZERO);
+        assert!(cache.insert(&[1]));
+        // the ID is expired by the next insert, so it is new again
+        assert!(cache.insert(&[1]));
+        assert!(cache.insert(&[2]));
+        assert!(!cache.contains(&[1]));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_key_covers_signature() {
+        let id = new_gossip_id();
+        let mut cache = SeenCache::default();
+        assert!(cache.insert(&seen_key(&id, b"forged")));
+        assert!(!cache.contains(&seen_key(&id, b"genuine")));
+        assert!(cache.contains(&seen_key(&id, b"forged")));
+    }
+}
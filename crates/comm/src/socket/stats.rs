@@ -0,0 +1,39 @@
+//! Defines [GossipStats], a set of counters describing the gossip handled by a [crate::Socket].
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters describing the gossip handled by a [crate::Socket].
+#[derive(Debug, Default)]
+pub struct GossipStats {
+    /// The number of gossip packets dropped because they ran out of hops.
+    pub ttl_expired: AtomicU64,
+    /// The number of gossip packets received that had not been seen before.
+    pub received: AtomicU64,
+    /// The number of gossip packets received that had already been seen.
+    pub duplicates: AtomicU64,
+    /// The number of gossip packets dropped because their source or neighbour was rate limited.
+    pub rate_limited: AtomicU64,
+}
+
+impl GossipStats {
+    /// Returns the number of gossip packets dropped because they ran out of hops.
+    pub fn ttl_expired(&self) -> u64 {
+        self.ttl_expired.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of gossip packets received that had not been seen before.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of gossip packets received that had already been seen.
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of gossip packets dropped because their source or neighbour was rate
+    /// limited.
+    pub fn rate_limited(&self) -> u64 {
+        self.rate_limited.load(Ordering::Relaxed)
+    }
+}
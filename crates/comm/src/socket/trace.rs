@@ -0,0 +1,166 @@
+//! Defines helpers for pings and traces, which check whether a node is reachable through the
+//! mesh and along which path. Both are [probe::v1::Probe]s sent as signed gossip, which the
+//! destination answers with a [probe::v1::ProbeReply].
+//!
+//! A trace also asks every node it passes through to append its hop to the gossip. The reply
+//! carries the hops back, and is sent back along them instead of being routed, so both ways can
+//! be compared. Hops are outside the signature of the gossip and cannot be trusted; they are only
+//! meant for debugging.
+
+use std::time::{Duration, SystemTime};
+
+use string_protocol::{gossip, probe, MessageType, ProtocolPacket, ProtocolPacketType};
+
+use super::gossip::gossip_data;
+
+/// How long to wait for the reply to a ping or trace.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most hops recorded on a traced gossip. Gossip does not travel further than this anyway.
+pub const MAX_TRACE_HOPS: usize = 64;
+
+/// A node a trace passed through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceHop {
+    /// The node ID of the node.
+    pub node_id: String,
+    /// When the node handled the trace, by its own clock.
+    pub time: Option<SystemTime>,
+}
+
+impl From<probe::v1::Hop> for TraceHop {
+    fn from(hop: probe::v1::Hop) -> Self {
+        Self {
+            node_id: hop.node_id,
+            time: hop.time.and_then(|time| time.try_into().ok()),
+        }
+    }
+}
+
+/// The result of [crate::Socket::trace].
+#[derive(Debug, Clone)]
+pub struct Trace {
+    /// The time between sending the trace and receiving its reply.
+    pub rtt: Duration,
+    /// The nodes the trace passed through, starting with us and ending with its destination.
+    pub path: Vec<TraceHop>,
+    /// The nodes the reply passed through, starting with the destination.
+    pub return_path: Vec<TraceHop>,
+}
+
+/// Returns a hop for the given node, timestamped now.
+pub fn hop(node_id: &str) -> probe::v1::Hop {
+    probe::v1::Hop {
+        node_id: node_id.to_string(),
+        time: Some(SystemTime::now().into()),
+    }
+}
+
+/// Returns the gossip inside the packet, if it is a gossip packet.
+fn gossip_mut(packet: &mut ProtocolPacket) -> Option<&mut gossip::v1::Gossip> {
+    match packet.packet_type {
+        Some(ProtocolPacketType::PktGossip(ref mut gossip)) => Some(gossip),
+        _ => None,
+    }
+}
+
+/// Returns the message inside the packet, if it is a gossip packet.
+fn gossip_message(packet: &ProtocolPacket) -> Option<&MessageType> {
+    gossip_data(packet).and_then(|data| data.message_type.as_ref())
+}
+
+/// Start recording hops on gossip we are about to send, if it is a trace or the reply to one.
+pub fn start_trace(packet: &mut ProtocolPacket, node_id: &str) {
+    let traced = match gossip_message(packet) {
+        Some(MessageType::Probe(probe)) => probe.trace,
+        Some(MessageType::ProbeReply(reply)) => !reply.path.is_empty(),
+        _ => false,
+    };
+    if let (true, Some(gossip)) = (traced, gossip_mut(packet)) {
+        gossip.hops = vec![hop(node_id)];
+    }
+}
+
+/// Append our hop to gossip we are about to forward, if it is being traced.
+pub fn record_hop(packet: &mut ProtocolPacket, node_id: &str) {
+    if let Some(gossip) = gossip_mut(packet) {
+        if !gossip.hops.is_empty() && gossip.hops.len() < MAX_TRACE_HOPS {
+            gossip.hops.push(hop(node_id));
+        }
+    }
+}
+
+/// Returns the node to send the reply to a trace to next, which is the one before us on the path
+/// the trace took.
+pub fn reply_next_hop<'a>(packet: &'a ProtocolPacket, node_id: &str) -> Option<&'a str> {
+    let Some(MessageType::ProbeReply(reply)) = gossip_message(packet) else {
+        return None;
+    };
+    let position = reply.path.iter().rposition(|hop| hop.node_id == node_id)?;
+    position
+        .checked_sub(1)
+        .map(|previous| reply.path[previous].node_id.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use string_protocol::crypto;
+
+    use super::*;
+
+    fn packet(message: MessageType) -> ProtocolPacket {
+        ProtocolPacket {
+            packet_type: Some(ProtocolPacketType::PktGossip(gossip::v1::Gossip {
+                packet: Some(crypto::v1::SignedPacket {
+                    signature: vec![],
+                    signed_data: Some(crypto::v1::SignedPacketInternal {
+                        message_type: Some(message),
+                        ..Default::default()
+                    }),
+                }),
+                ttl: 8,
+                hops: vec![],
+            })),
+        }
+    }
+
+    fn hops(packet: &ProtocolPacket) -> Vec<String> {
+        match packet.packet_type {
+            Some(ProtocolPacketType::PktGossip(ref gossip)) => {
+                gossip.hops.iter().map(|hop| hop.node_id.clone()).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    #[test]
+    fn test_only_traces_record_hops() {
+        let mut ping = packet(MessageType::Probe(probe::v1::Probe {
+            probe_id: vec![1],
+            trace: false,
+        }));
+        start_trace(&mut ping, "alice");
+        record_hop(&mut ping, "bob");
+        assert!(hops(&ping).is_empty());
+
+        let mut trace = packet(MessageType::Probe(probe::v1::Probe {
+            probe_id: vec![1],
+            trace: true,
+        }));
+        start_trace(&mut trace, "alice");
+        record_hop(&mut trace, "bob");
+        assert_eq!(hops(&trace), vec!["alice", "bob"]);
+    }
+
+    #[test]
+    fn test_reply_follows_path_back() {
+        let reply = packet(MessageType::ProbeReply(probe::v1::ProbeReply {
+            probe_id: vec![1],
+            path: vec![hop("alice"), hop("bob"), hop("carol")],
+        }));
+        assert_eq!(reply_next_hop(&reply, "carol"), Some("bob"));
+        assert_eq!(reply_next_hop(&reply, "bob"), Some("alice"));
+        assert_eq!(reply_next_hop(&reply, "alice"), None);
+        assert_eq!(reply_next_hop(&reply, "dave"), None);
+    }
+}
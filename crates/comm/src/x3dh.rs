@@ -0,0 +1,485 @@
+//! Implements X3DH-style asynchronous session establishment, so that a ratchet can be started with
+//! a node that is offline.
+//!
+//! Every node has a long-term X25519 identity key, a signed prekey that is rotated every
+//! [SIGNED_PREKEY_ROTATION], and a pool of one-time prekeys. Together they form a
+//! [crypto::v1::PrekeyBundle], which is signed with the node's PGP key and published to the DHT
+//! and the lighthouse. An initiator derives a shared secret from the bundle and a fresh ephemeral
+//! key, and sends its first packet encrypted under it in a [crypto::v1::PrekeyMessage]. Whenever
+//! the responder comes online, it derives the same secret from its private prekeys and starts the
+//! ratchet from it.
+//!
+//! One-time prekeys are forgotten as soon as they are used, so a prekey message can only ever be
+//! accepted once. The DHT hands the same bundle to everyone, so initiators pick one of its
+//! one-time prekeys at random; a message using a prekey that someone else got to first is
+//! rejected.
+
+use std::{
+    collections::BTreeMap,
+    io::Cursor,
+    time::{Duration, SystemTime},
+};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, Payload},
+    ChaCha20Poly1305,
+};
+use hkdf::Hkdf;
+use pgp::composed::SignedPublicKey;
+use rand::{rngs::OsRng, seq::SliceRandom};
+use sha2::Sha256;
+use string_protocol::{crypto, prost::Message};
+use thiserror::Error;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::{
+    crypto::{Crypto, SigningError},
+    key::UnlockedKey,
+};
+
+/// The number of one-time prekeys we keep published.
+pub const ONE_TIME_PREKEYS: usize = 32;
+
+/// How long a signed prekey is used before it is replaced. The previous one is still accepted
+/// until the next rotation, for bundles that were fetched before it.
+pub const SIGNED_PREKEY_ROTATION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Domain separation for the derived secrets.
+const KDF_INFO: &[u8] = b"string X3DH";
+
+/// The size of the nonce prepended to the content of a prekey message, in bytes.
+const NONCE_SIZE: usize = 12;
+
+/// An enumeration of possible errors that can occur when establishing a session from prekeys.
+#[derive(Error, Debug)]
+pub enum X3dhError {
+    /// A bundle or prekey message is malformed.
+    #[error("Malformed prekeys")]
+    BadPrekeys,
+    /// The signature of a bundle is invalid.
+    #[error("Failure in prekey bundle signature")]
+    SigningFail(#[from] SigningError),
+    /// A prekey message uses a prekey we do not have, or no longer have.
+    #[error("Unknown or already used prekey")]
+    UnknownPrekey,
+    /// The content of a prekey message could not be encrypted or decrypted.
+    #[error("Failed to encrypt or decrypt prekey message")]
+    CipherFail,
+}
+
+/// The secrets derived from a prekey bundle.
+pub struct SharedSecrets {
+    /// The shared secret the ratchet is started from.
+    pub root: Zeroizing<[u8; 32]>,
+    /// The key the content of the prekey message is encrypted with.
+    message_key: Zeroizing<[u8; 32]>,
+    /// Bound to the content of the prekey message: both identity keys and the ephemeral key.
+    associated_data: Vec<u8>,
+}
+
+impl SharedSecrets {
+    /// Returns the ID of the session, which both sides bind the ratchet to: both identity keys and
+    /// the ephemeral key.
+    pub fn session_id(&self) -> &[u8] {
+        &self.associated_data
+    }
+
+    /// Encrypt the content of a prekey message, prepending a random nonce to it.
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, X3dhError> {
+        let cipher = ChaCha20Poly1305::new(self.message_key.as_ref().into());
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext,
+            aad: &self.associated_data,
+        };
+        let encrypted = cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| X3dhError::CipherFail)?;
+        Ok([nonce.to_vec(), encrypted].concat())
+    }
+
+    /// Decrypt the content of a prekey message encrypted with [SharedSecrets::seal].
+    fn open(&self, content: &[u8]) -> Result<Vec<u8>, X3dhError> {
+        if content.len() < NONCE_SIZE {
+            return Err(X3dhError::CipherFail);
+        }
+        let (nonce, encrypted) = content.split_at(NONCE_SIZE);
+        let cipher = ChaCha20Poly1305::new(self.message_key.as_ref().into());
+        let payload = Payload {
+            msg: encrypted,
+            aad: &self.associated_data,
+        };
+        cipher
+            .decrypt(nonce.into(), payload)
+            .map_err(|_| X3dhError::CipherFail)
+    }
+}
+
+/// A private prekey.
+struct PrivatePrekey {
+    id: u32,
+    secret: StaticSecret,
+    created: SystemTime,
+}
+
+impl PrivatePrekey {
+    fn public(&self) -> crypto::v1::Prekey {
+        crypto::v1::Prekey {
+            id: self.id,
+            key: PublicKey::from(&self.secret).as_bytes().to_vec(),
+        }
+    }
+
+    fn export(&self) -> crypto::v1::StoredPrekey {
+        crypto::v1::StoredPrekey {
+            id: self.id,
+            key: self.secret.to_bytes().to_vec(),
+            created: Some(self.created.into()),
+        }
+    }
+
+    fn import(stored: crypto::v1::StoredPrekey) -> Result<Self, X3dhError> {
+        Ok(Self {
+            id: stored.id,
+            secret: StaticSecret::from(key_bytes(&stored.key)?),
+            created: stored
+                .created
+                .and_then(|time| SystemTime::try_from(time).ok())
+                .ok_or(X3dhError::BadPrekeys)?,
+        })
+    }
+}
+
+/// Our identity key and private prekeys.
+pub struct Prekeys {
+    /// Our long-term X25519 identity key.
+    identity: StaticSecret,
+    /// The prekey in the bundles we publish now.
+    signed: PrivatePrekey,
+    /// The signed prekey we used before the last rotation.
+    previous: Option<PrivatePrekey>,
+    /// The one-time prekeys that have not been used yet, by ID.
+    one_time: BTreeMap<u32, PrivatePrekey>,
+    /// The ID of the next prekey. IDs start at 1, since 0 means no one-time prekey.
+    next_id: u32,
+}
+
+impl Prekeys {
+    /// Generate a new identity key, signed prekey and pool of one-time prekeys.
+    pub fn generate() -> Self {
+        let mut prekeys = Self {
+            identity: StaticSecret::random_from_rng(OsRng),
+            signed: PrivatePrekey {
+                id: 1,
+                secret: StaticSecret::random_from_rng(OsRng),
+                created: SystemTime::now(),
+            },
+            previous: None,
+            one_time: BTreeMap::new(),
+            next_id: 2,
+        };
+        prekeys.replenish();
+        prekeys
+    }
+
+    /// Returns our X25519 identity key.
+    pub fn identity_key(&self) -> PublicKey {
+        PublicKey::from(&self.identity)
+    }
+
+    /// Replace the signed prekey if it is due, returning true if it was.
+    pub fn rotate(&mut self, now: SystemTime) -> bool {
+        let age = now.duration_since(self.signed.created).unwrap_or_default();
+        if age < SIGNED_PREKEY_ROTATION {
+            return false;
+        }
+        let signed = self.new_prekey(now);
+        self.previous = Some(std::mem::replace(&mut self.signed, signed));
+        true
+    }
+
+    /// Top the one-time prekeys back up to [ONE_TIME_PREKEYS], returning true if any were added.
+    pub fn replenish(&mut self) -> bool {
+        let missing = ONE_TIME_PREKEYS.saturating_sub(self.one_time.len());
+        let now = SystemTime::now();
+        for _ in 0..missing {
+            let prekey = self.new_prekey(now);
+            self.one_time.insert(prekey.id, prekey);
+        }
+        missing > 0
+    }
+
+    /// Build the bundle others start sessions with us from, signed with our key.
+    pub fn bundle(&self, key: &UnlockedKey) -> Result<crypto::v1::PrekeyBundle, SigningError> {
+        let signed_prekey = crypto::v1::SignedPrekey {
+            identity_key: self.identity_key().as_bytes().to_vec(),
+            prekey: Some(self.signed.public()),
+        };
+        Ok(crypto::v1::PrekeyBundle {
+            signature: key.sign(signed_prekey.encode_to_vec())?,
+            signed_prekey: Some(signed_prekey),
+            one_time_prekeys: self.one_time.values().map(PrivatePrekey::public).collect(),
+        })
+    }
+
+    /// Derive the secrets of a prekey message sent to us and decrypt its content, using up its
+    /// one-time prekey.
+    pub fn respond(
+        &mut self,
+        message: &crypto::v1::PrekeyMessage,
+    ) -> Result<(SharedSecrets, Vec<u8>), X3dhError> {
+        let their_identity = PublicKey::from(key_bytes(&message.identity_key)?);
+        let ephemeral = PublicKey::from(key_bytes(&message.ephemeral_key)?);
+
+        let signed = [Some(&self.signed), self.previous.as_ref()]
+            .into_iter()
+            .flatten()
+            .find(|prekey| prekey.id == message.signed_prekey_id)
+            .ok_or(X3dhError::UnknownPrekey)?;
+        let one_time = match message.one_time_prekey_id {
+            0 => None,
+            id => Some(self.one_time.get(&id).ok_or(X3dhError::UnknownPrekey)?),
+        };
+
+        let mut dh = vec![
+            signed.secret.diffie_hellman(&their_identity).to_bytes(),
+            self.identity.diffie_hellman(&ephemeral).to_bytes(),
+            signed.secret.diffie_hellman(&ephemeral).to_bytes(),
+        ];
+        if let Some(one_time) = one_time {
+            dh.push(one_time.secret.diffie_hellman(&ephemeral).to_bytes());
+        }
+        let secrets = derive(&dh, &their_identity, &self.identity_key(), &ephemeral);
+
+        // only use up the prekey once we know the message is genuine
+        let content = secrets.open(&message.content)?;
+        self.one_time.remove(&message.one_time_prekey_id);
+        Ok((secrets, content))
+    }
+
+    /// Export the prekeys in a form that can be stored. The result holds private keys, so it must
+    /// never leave the device unencrypted.
+    pub fn export(&self) -> crypto::v1::StoredPrekeys {
+        crypto::v1::StoredPrekeys {
+            identity_key: self.identity.to_bytes().to_vec(),
+            signed_prekey: Some(self.signed.export()),
+            previous_signed_prekey: self.previous.as_ref().map(PrivatePrekey::export),
+            one_time_prekeys: self.one_time.values().map(PrivatePrekey::export).collect(),
+            next_id: self.next_id,
+        }
+    }
+
+    /// Restore prekeys exported with [Prekeys::export].
+    pub fn import(stored: crypto::v1::StoredPrekeys) -> Result<Self, X3dhError> {
+        let one_time = stored
+            .one_time_prekeys
+            .into_iter()
+            .map(|stored| PrivatePrekey::import(stored).map(|prekey| (prekey.id, prekey)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            identity: StaticSecret::from(key_bytes(&stored.identity_key)?),
+            signed: PrivatePrekey::import(stored.signed_prekey.ok_or(X3dhError::BadPrekeys)?)?,
+            previous: stored
+                .previous_signed_prekey
+                .map(PrivatePrekey::import)
+                .transpose()?,
+            one_time,
+            next_id: stored.next_id,
+        })
+    }
+
+    fn new_prekey(&mut self, now: SystemTime) -> PrivatePrekey {
+        let id = self.next_id;
+        // wrap around to 1, since 0 means no one-time prekey
+        self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+        PrivatePrekey {
+            id,
+            secret: StaticSecret::random_from_rng(OsRng),
+            created: now,
+        }
+    }
+}
+
+/// Verify the signature of a bundle with the PGP key of the node it claims to belong to.
+pub fn verify_bundle(
+    bundle: &crypto::v1::PrekeyBundle,
+    pubkey: &SignedPublicKey,
+) -> Result<(), X3dhError> {
+    let signed_prekey = bundle.signed_prekey.as_ref().ok_or(X3dhError::BadPrekeys)?;
+    Crypto::verify_data_static(pubkey, &bundle.signature, &signed_prekey.encode_to_vec())?;
+    Ok(())
+}
+
+/// Derive the secrets of a session with the node whose bundle this is, after verifying it with
+/// its PGP key. Returns them along with the prekey message to send the node, whose content is
+/// left for the caller to fill in with [SharedSecrets::seal].
+pub fn initiate(
+    identity: &Prekeys,
+    bundle: &crypto::v1::PrekeyBundle,
+    pubkey: &SignedPublicKey,
+) -> Result<(SharedSecrets, crypto::v1::PrekeyMessage), X3dhError> {
+    verify_bundle(bundle, pubkey)?;
+    let signed_prekey = bundle.signed_prekey.as_ref().ok_or(X3dhError::BadPrekeys)?;
+    let prekey = signed_prekey.prekey.as_ref().ok_or(X3dhError::BadPrekeys)?;
+    let one_time = bundle.one_time_prekeys.choose(&mut OsRng);
+    agree(
+        &identity.identity,
+        &signed_prekey.identity_key,
+        prekey,
+        one_time,
+    )
+}
+
+/// Derive the secrets of a session from the keys of a verified bundle, as the initiator.
+fn agree(
+    identity: &StaticSecret,
+    their_identity: &[u8],
+    signed_prekey: &crypto::v1::Prekey,
+    one_time_prekey: Option<&crypto::v1::Prekey>,
+) -> Result<(SharedSecrets, crypto::v1::PrekeyMessage), X3dhError> {
+    let their_identity = PublicKey::from(key_bytes(their_identity)?);
+    let signed = PublicKey::from(key_bytes(&signed_prekey.key)?);
+    let ephemeral = StaticSecret::random_from_rng(OsRng);
+
+    let mut dh = vec![
+        identity.diffie_hellman(&signed).to_bytes(),
+        ephemeral.diffie_hellman(&their_identity).to_bytes(),
+        ephemeral.diffie_hellman(&signed).to_bytes(),
+    ];
+    if let Some(one_time) = one_time_prekey {
+        let one_time = PublicKey::from(key_bytes(&one_time.key)?);
+        dh.push(ephemeral.diffie_hellman(&one_time).to_bytes());
+    }
+
+    let identity = PublicKey::from(identity);
+    let ephemeral = PublicKey::from(&ephemeral);
+    let secrets = derive(&dh, &identity, &their_identity, &ephemeral);
+    let message = crypto::v1::PrekeyMessage {
+        identity_key: identity.as_bytes().to_vec(),
+        ephemeral_key: ephemeral.as_bytes().to_vec(),
+        signed_prekey_id: signed_prekey.id,
+        one_time_prekey_id: one_time_prekey.map_or(0, |prekey| prekey.id),
+        content: vec![],
+        sender: None,
+    };
+    Ok((secrets, message))
+}
+
+/// Derive the shared secrets from the Diffie-Hellman outputs, in the order both sides compute
+/// them in.
+fn derive(
+    dh: &[[u8; 32]],
+    initiator: &PublicKey,
+    responder: &PublicKey,
+    ephemeral: &PublicKey,
+) -> SharedSecrets {
+    // as in X3DH, prepend a block of 0xFF bytes to the key material
+    let mut ikm = Zeroizing::new(vec![0xFF; 32]);
+    for output in dh {
+        ikm.extend_from_slice(output);
+    }
+    let mut okm = Zeroizing::new([0; 64]);
+    Hkdf::<Sha256>::new(Some(&[0; 32]), &ikm)
+        .expand(KDF_INFO, okm.as_mut())
+        .expect("64 bytes is a valid HKDF-SHA256 output length");
+
+    let mut root = Zeroizing::new([0; 32]);
+    let mut message_key = Zeroizing::new([0; 32]);
+    root.copy_from_slice(&okm[..32]);
+    message_key.copy_from_slice(&okm[32..]);
+    SharedSecrets {
+        root,
+        message_key,
+        associated_data: [
+            initiator.as_bytes().as_slice(),
+            responder.as_bytes(),
+            ephemeral.as_bytes(),
+        ]
+        .concat(),
+    }
+}
+
+/// Decode a prekey bundle, as it is stored in the DHT or on the lighthouse.
+pub fn decode_bundle(bytes: &[u8]) -> Result<crypto::v1::PrekeyBundle, X3dhError> {
+    crypto::v1::PrekeyBundle::decode(Cursor::new(bytes)).map_err(|_| X3dhError::BadPrekeys)
+}
+
+fn key_bytes(bytes: &[u8]) -> Result<[u8; 32], X3dhError> {
+    bytes.try_into().map_err(|_| X3dhError::BadPrekeys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start a session with the given prekeys, as if from their bundle.
+    fn start(
+        initiator: &Prekeys,
+        responder: &Prekeys,
+        one_time: bool,
+    ) -> (SharedSecrets, crypto::v1::PrekeyMessage) {
+        let one_time = one_time.then(|| responder.one_time.values().next().unwrap().public());
+        let (secrets, mut message) = agree(
+            &initiator.identity,
+            responder.identity_key().as_bytes(),
+            &responder.signed.public(),
+            one_time.as_ref(),
+        )
+        .unwrap();
+        message.content = secrets.seal(b"hello").unwrap();
+        (secrets, message)
+    }
+
+    #[test]
+    fn test_agree_and_respond() {
+        let alice = Prekeys::generate();
+        let mut bob = Prekeys::generate();
+
+        let (secrets, message) = start(&alice, &bob, true);
+        let (received, content) = bob.respond(&message).unwrap();
+        assert_eq!(*received.root, *secrets.root);
+        assert_eq!(content, b"hello");
+
+        // the one-time prekey is used up, so the message cannot be replayed
+        assert_eq!(bob.one_time.len(), ONE_TIME_PREKEYS - 1);
+        assert!(matches!(
+            bob.respond(&message),
+            Err(X3dhError::UnknownPrekey)
+        ));
+
+        // sessions can also start without a one-time prekey
+        let (secrets, message) = start(&alice, &bob, false);
+        assert_eq!(*bob.respond(&message).unwrap().0.root, *secrets.root);
+    }
+
+    #[test]
+    fn test_rejects_tampered_message() {
+        let alice = Prekeys::generate();
+        let mut bob = Prekeys::generate();
+        let (_, mut message) = start(&alice, &bob, true);
+        message.identity_key = Prekeys::generate().identity_key().as_bytes().to_vec();
+        assert!(matches!(bob.respond(&message), Err(X3dhError::CipherFail)));
+        // a forged message does not use up the prekey
+        assert_eq!(bob.one_time.len(), ONE_TIME_PREKEYS);
+    }
+
+    #[test]
+    fn test_rotate_and_restore() {
+        let alice = Prekeys::generate();
+        let mut bob = Prekeys::generate();
+        let (secrets, message) = start(&alice, &bob, false);
+
+        // messages from bundles fetched before the rotation are still accepted
+        assert!(!bob.rotate(SystemTime::now()));
+        assert!(bob.rotate(SystemTime::now() + SIGNED_PREKEY_ROTATION));
+        let mut bob = Prekeys::import(bob.export()).unwrap();
+        assert_eq!(*bob.respond(&message).unwrap().0.root, *secrets.root);
+
+        bob.rotate(SystemTime::now() + SIGNED_PREKEY_ROTATION * 2);
+        assert!(matches!(
+            bob.respond(&message),
+            Err(X3dhError::UnknownPrekey)
+        ));
+    }
+}
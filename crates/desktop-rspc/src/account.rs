@@ -4,14 +4,10 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use pgp::{
-    crypto::{hash::HashAlgorithm, sym::SymmetricKeyAlgorithm},
-    types::CompressionAlgorithm,
-    Deserializable, KeyType, SecretKeyParamsBuilder, SignedSecretKey,
-};
+use pgp::{Deserializable, SignedSecretKey};
 use rspc::{RouterBuilder, Type};
 use serde::Deserialize;
-use smallvec::smallvec;
+use string_comm::{generate_key, KeyAlgorithm, UnlockedKey};
 use tokio::sync::RwLock;
 use tracing::info;
 
@@ -34,6 +30,11 @@ impl AccountContext {
             fingerprint: None.into(),
         }
     }
+
+    /// Returns the path of the session store of the given user, which sits next to their key.
+    pub fn session_store(&self, username: &str) -> PathBuf {
+        self.key_dir.join(format!("{}.sessions", username))
+    }
 }
 
 /// Attach the channel cache queries to the router.
@@ -43,12 +44,16 @@ pub fn attach_account_queries<TMeta: Send>(
     builder
         .query("account.fingerprint", |t| t(get_fingerprint))
         .mutation("account.login", |t| t(login_account))
+        .mutation("account.logout", |t| t(logout_account))
         .mutation("account.create", |t| t(create_account))
 }
 
 #[derive(Debug, Type, Deserialize)]
 struct LoginArgs {
+    /// The username of the account.
     username: String,
+    /// The passphrase for the key.
+    passphrase: String,
 }
 
 /// Test if the user has a private key.
@@ -83,8 +88,15 @@ async fn login_account(ctx: Ctx, args: LoginArgs) -> Result<(), rspc::Error> {
             err,
         )
     })?;
+    let key = UnlockedKey::unlock(secret_key, args.passphrase.clone()).map_err(|err| {
+        rspc::Error::with_cause(
+            rspc::ErrorCode::Unauthorized,
+            "Incorrect passphrase".to_string(),
+            err,
+        )
+    })?;
 
-    ctx.setup_socket(args.username, secret_key)
+    ctx.setup_socket(args.username, key, &args.passphrase)
         .await
         .map_err(|err| {
             rspc::Error::with_cause(
@@ -96,6 +108,8 @@ async fn login_account(ctx: Ctx, args: LoginArgs) -> Result<(), rspc::Error> {
                     ContextError::PrismaError(_) => "encountered prisma query error",
                     ContextError::SocketActive => "socket already active",
                     ContextError::SocketError(_) => "error setting up socket",
+                    ContextError::SocketInactive => "socket not active",
+                    ContextError::SessionStoreError(_) => "failed to store sessions",
                 }
                 .to_string(),
                 err,
@@ -105,12 +119,45 @@ async fn login_account(ctx: Ctx, args: LoginArgs) -> Result<(), rspc::Error> {
     Ok(())
 }
 
+/// Store the sessions of the active user and log them out.
+#[tracing::instrument]
+async fn logout_account(ctx: Ctx, _: ()) -> Result<(), rspc::Error> {
+    ctx.teardown_socket().await.map_err(|err| {
+        rspc::Error::with_cause(
+            rspc::ErrorCode::InternalServerError,
+            "failed to log out".to_string(),
+            err,
+        )
+    })
+}
+
+/// The algorithm of the key of a new account.
+#[derive(Default, Clone, Copy, Debug, Type, Deserialize)]
+enum AccountKeyAlgorithm {
+    #[default]
+    Ed25519,
+    Rsa3072,
+    Rsa4096,
+}
+
+impl From<AccountKeyAlgorithm> for KeyAlgorithm {
+    fn from(algorithm: AccountKeyAlgorithm) -> Self {
+        match algorithm {
+            AccountKeyAlgorithm::Ed25519 => KeyAlgorithm::Ed25519,
+            AccountKeyAlgorithm::Rsa3072 => KeyAlgorithm::Rsa3072,
+            AccountKeyAlgorithm::Rsa4096 => KeyAlgorithm::Rsa4096,
+        }
+    }
+}
+
 #[derive(Debug, Type, Deserialize)]
 struct CreateAccountArgs {
     /// The username of the account.
     username: String,
     /// The passphrase for the key.
     passphrase: String,
+    /// The algorithm of the key.
+    algorithm: AccountKeyAlgorithm,
 }
 
 /// Test if the user has a private key.
@@ -130,27 +177,18 @@ async fn create_account(ctx: Ctx, args: CreateAccountArgs) -> Result<(), rspc::E
         ));
     }
 
-    let mut key_params = SecretKeyParamsBuilder::default();
-    key_params
-        .key_type(KeyType::Rsa(2048))
-        .can_certify(false)
-        .can_sign(true)
-        .primary_user_id(args.username.clone())
-        .preferred_symmetric_algorithms(smallvec![SymmetricKeyAlgorithm::AES256])
-        .preferred_hash_algorithms(smallvec![HashAlgorithm::SHA2_256])
-        .preferred_compression_algorithms(smallvec![CompressionAlgorithm::ZLIB]);
-
-    let secret_key_params = key_params
-        .build()
-        .expect("Must be able to create secret key params");
-    let secret_key = secret_key_params
-        .generate()
-        .expect("Failed to generate a plain key.");
-    let passwd_fn = || args.passphrase;
-
-    let secret_key = secret_key
-        .sign(passwd_fn)
-        .expect("must be able to sign its own metadata");
+    let secret_key = generate_key(
+        args.username.clone(),
+        args.passphrase.clone(),
+        args.algorithm.into(),
+    )
+    .map_err(|err| {
+        rspc::Error::with_cause(
+            rspc::ErrorCode::InternalServerError,
+            "Failed to generate key".to_string(),
+            err,
+        )
+    })?;
 
     // write key to file
     let mut file = File::create(key_path).expect("Error opening privkey file");
@@ -168,7 +206,15 @@ async fn create_account(ctx: Ctx, args: CreateAccountArgs) -> Result<(), rspc::E
         )
     })?;
 
-    ctx.setup_socket(args.username, secret_key)
+    let key = UnlockedKey::unlock(secret_key, args.passphrase.clone()).map_err(|err| {
+        rspc::Error::with_cause(
+            rspc::ErrorCode::InternalServerError,
+            "Failed to unlock new key".to_string(),
+            err,
+        )
+    })?;
+
+    ctx.setup_socket(args.username, key, &args.passphrase)
         .await
         .map_err(|err| {
             rspc::Error::with_cause(
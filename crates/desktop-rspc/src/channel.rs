@@ -1,5 +1,9 @@
+use std::time::SystemTime;
+
 use rspc::{ErrorCode, RouterBuilder, Type};
 use serde::Deserialize;
+use string_comm::socket::new_message_id;
+use string_protocol::{messages, ProtocolPacket, ProtocolPacketType};
 
 use crate::{context::StatefulSocket, Ctx};
 
@@ -12,6 +16,7 @@ pub fn attach_channel_queries<TMeta: Send>(
         .query("channel.messages", |t| t(get_channel_messages))
         .mutation("channel.create", |t| t(create_channel))
         .mutation("channel.send", |t| t(send_message))
+        .mutation("channel.read", |t| t(read_messages))
 }
 
 /// Fetch a list of channels from the cache.
@@ -83,6 +88,8 @@ pub async fn create_channel(
 pub struct SendMessageArgs {
     channel_id: i32,
     content: String,
+    /// Node ID of the identity to send the message to. Without one, the message is only stored.
+    recipient: Option<String>,
 }
 
 /// Send a message to the network.
@@ -101,7 +108,6 @@ async fn send_message(ctx: Ctx, args: SendMessageArgs) -> Result<(), rspc::Error
         .clone()
     };
 
-    // TODO: send on socket
     let socket = ctx.socket.read().await;
     let socket = match *socket {
         StatefulSocket::Active(ref socket) => socket,
@@ -112,7 +118,32 @@ async fn send_message(ctx: Ctx, args: SendMessageArgs) -> Result<(), rspc::Error
             ));
         }
     };
-    // socket.send_gossip_encrypted(ms, destination);
+
+    // receipts for the message refer to it by its ID
+    let message_id = new_message_id();
+    if let Some(recipient) = args.recipient {
+        let message = messages::v1::Message {
+            id: message_id.clone(),
+            channel_id: args.channel_id.to_string(),
+            username: socket.username.clone(),
+            content: args.content.clone(),
+            attachments: vec![],
+            time_sent: Some(SystemTime::now().into()),
+        };
+        let packet = ProtocolPacket {
+            packet_type: Some(ProtocolPacketType::PktMessage(message)),
+        };
+        socket
+            .send_to_identity(packet, recipient)
+            .await
+            .map_err(|err| {
+                rspc::Error::with_cause(
+                    ErrorCode::InternalServerError,
+                    "failed to send message".into(),
+                    err,
+                )
+            })?;
+    }
 
     // push message to cache - maybe wait for response from socket?
     ctx.cache
@@ -121,7 +152,7 @@ async fn send_message(ctx: Ctx, args: SendMessageArgs) -> Result<(), rspc::Error
             args.content,
             cache_prisma::user::id::equals(fingerprint.clone()),
             cache_prisma::channel::id::equals(args.channel_id),
-            vec![],
+            vec![cache_prisma::message::message_id::set(Some(message_id))],
         )
         .exec()
         .await
@@ -134,3 +165,40 @@ async fn send_message(ctx: Ctx, args: SendMessageArgs) -> Result<(), rspc::Error
         })?;
     Ok(())
 }
+
+/// Tell the author of messages that they were read.
+#[derive(Debug, Type, Deserialize)]
+pub struct ReadMessagesArgs {
+    /// Node ID of the author of the messages.
+    author: String,
+    /// IDs of the messages, as they were received.
+    message_ids: Vec<String>,
+}
+
+/// Send a read receipt for messages to their author.
+async fn read_messages(ctx: Ctx, args: ReadMessagesArgs) -> Result<(), rspc::Error> {
+    let socket = ctx.socket.read().await;
+    let socket = match *socket {
+        StatefulSocket::Active(ref socket) => socket,
+        StatefulSocket::Inactive => {
+            return Err(rspc::Error::new(
+                ErrorCode::Unauthorized,
+                "not logged in".to_string(),
+            ));
+        }
+    };
+    socket
+        .send_receipt(
+            args.author,
+            messages::v1::ReceiptType::Read,
+            args.message_ids,
+        )
+        .await
+        .map_err(|err| {
+            rspc::Error::with_cause(
+                ErrorCode::InternalServerError,
+                "failed to send read receipt".into(),
+                err,
+            )
+        })
+}
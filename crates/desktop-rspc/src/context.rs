@@ -1,14 +1,18 @@
-use std::path::{Path, PathBuf};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
 
-use pgp::{
-    types::{KeyTrait, SecretKeyTrait},
-    SignedSecretKey,
+use string_comm::{
+    crypto::{Crypto, SessionStoreError},
+    socket::SocketConfig,
+    try_continue, Socket, UnlockedKey, DEFAULT_PORT,
 };
-use string_comm::{try_continue, Socket, DEFAULT_PORT};
 use string_protocol::ProtocolPacket;
 use thiserror::Error;
 use tokio::sync::{mpsc, RwLock};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
+use zeroize::Zeroizing;
 
 use crate::{
     account::AccountContext,
@@ -35,6 +39,22 @@ pub struct Context {
     pub account_ctx: AccountContext,
     /// The lighthouse context.
     pub lighthouse_ctx: LighthouseContext,
+    /// Where the sessions of the active user are stored.
+    pub session_store: RwLock<Option<SessionStore>>,
+}
+
+/// The session store of the active user, along with the passphrase it is encrypted with.
+pub struct SessionStore {
+    /// The path of the store.
+    pub path: PathBuf,
+    /// The passphrase of the key of the user.
+    passphrase: Zeroizing<String>,
+}
+
+impl fmt::Debug for SessionStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<SessionStore {:?}>", self.path)
+    }
 }
 
 /// Wrapper type for the socket to account for pre-login users.
@@ -67,6 +87,12 @@ pub enum ContextError {
     /// Socket error.
     #[error("socket error")]
     SocketError(#[from] string_comm::socket::SocketError),
+    /// The socket is not active.
+    #[error("socket not active")]
+    SocketInactive,
+    /// The sessions could not be stored.
+    #[error("failed to store sessions")]
+    SessionStoreError(#[from] SessionStoreError),
 }
 
 impl Context {
@@ -96,15 +122,18 @@ impl Context {
             settings_ctx: SettingsContext::from_data_dir(&data_dir).await?,
             lighthouse_ctx: LighthouseContext::from_data_dir(&data_dir).await?,
             inbound_channels: RwLock::new(Vec::new()),
+            session_store: None.into(),
         })
     }
 
-    /// Setup the socket for the context.
-    #[tracing::instrument]
+    /// Setup the socket for the context, restoring the sessions and prekeys stored for the user
+    /// with the passphrase of their key.
+    #[tracing::instrument(skip(passphrase))]
     pub async fn setup_socket(
         &self,
         username: String,
-        secret_key: SignedSecretKey,
+        key: UnlockedKey,
+        passphrase: &str,
     ) -> Result<(), ContextError> {
         // check if socket is active
         debug!("Checking if socket is active...");
@@ -117,7 +146,7 @@ impl Context {
         // store user fingerprint
         debug!("Storing user fingerprint...");
         let mut fingerprint = self.account_ctx.fingerprint.write().await;
-        *fingerprint = Some(secret_key.public_key().fingerprint());
+        *fingerprint = Some(key.fingerprint());
 
         // prepare database
         self.cache
@@ -129,17 +158,46 @@ impl Context {
         self.cache
             .user()
             .upsert(
-                cache_prisma::user::id::equals(secret_key.public_key().fingerprint()),
-                cache_prisma::user::create(secret_key.public_key().fingerprint(), username, vec![]),
+                cache_prisma::user::id::equals(key.fingerprint()),
+                cache_prisma::user::create(key.fingerprint(), username, vec![]),
                 vec![],
             )
             .exec()
             .await?;
 
+        // restore our sessions before binding, which signs our prekey bundle
+        let session_store = self.account_ctx.session_store(&username);
+        let mut crypto = Crypto::new(key.clone());
+        if session_store.exists() {
+            match crypto.load(&session_store, passphrase) {
+                Ok(count) => info!("Restored {} sessions", count),
+                Err(err) => warn!(?err, "failed to restore sessions"),
+            }
+        }
+
         // create new socket
         debug!("Creating new socket... binding to 0.0.0.0:{}", DEFAULT_PORT);
-        let (mut inner, packets) =
-            Socket::bind(([0, 0, 0, 0], DEFAULT_PORT).into(), secret_key).await?;
+        let (mut inner, packets) = Socket::bind_with_crypto(
+            ([0, 0, 0, 0], DEFAULT_PORT).into(),
+            crypto,
+            SocketConfig::default(),
+        )
+        .await?;
+
+        // publish our prekeys, so that peers can reach us while we are offline
+        match inner.prekey_bundle().await {
+            Ok(bundle) => {
+                if let Err(err) = self.lighthouse_ctx.publish_prekeys(&key, &bundle).await {
+                    warn!(?err, "failed to publish prekeys");
+                }
+            }
+            Err(err) => warn!(?err, "failed to create prekey bundle"),
+        }
+
+        // store the prekeys we published, which peers may use while we are offline
+        if let Err(err) = inner.crypto.read().await.save(&session_store, passphrase) {
+            warn!(?err, "failed to store sessions");
+        }
 
         // look for initial peers
         let peers = self.cache.peer().find_many(vec![]).exec().await?;
@@ -156,7 +214,45 @@ impl Context {
 
         self.inbound_app_rx.write().await.replace(packets);
         *socket = StatefulSocket::Active(inner);
+        self.session_store.write().await.replace(SessionStore {
+            path: session_store,
+            passphrase: Zeroizing::new(passphrase.to_string()),
+        });
+
+        Ok(())
+    }
+
+    /// Store the sessions of the active user, so they can be restored on the next login.
+    pub async fn save_sessions(&self) -> Result<(), ContextError> {
+        let socket = self.socket.read().await;
+        let StatefulSocket::Active(ref inner) = *socket else {
+            return Err(ContextError::SocketInactive);
+        };
+        let session_store = self.session_store.read().await;
+        let Some(ref store) = *session_store else {
+            return Err(ContextError::SocketInactive);
+        };
+        inner
+            .crypto
+            .read()
+            .await
+            .save(&store.path, &store.passphrase)?;
+        Ok(())
+    }
 
+    /// Store the sessions of the active user and deactivate the socket. The key is locked, so
+    /// the workers of the socket can no longer sign anything.
+    #[tracing::instrument]
+    pub async fn teardown_socket(&self) -> Result<(), ContextError> {
+        self.save_sessions().await?;
+        let mut socket = self.socket.write().await;
+        if let StatefulSocket::Active(ref inner) = *socket {
+            inner.crypto.read().await.key.lock();
+        }
+        *socket = StatefulSocket::Inactive;
+        self.inbound_app_rx.write().await.take();
+        self.session_store.write().await.take();
+        *self.account_ctx.fingerprint.write().await = None;
         Ok(())
     }
 }
@@ -1,10 +1,15 @@
+use std::time::SystemTime;
+
+use cache_prisma::client::chrono::{DateTime, FixedOffset, Utc};
 use futures::Stream;
 use rspc::{RouterBuilder, Type};
 use serde::{Deserialize, Serialize};
-use string_comm::maybe_break;
-use string_protocol::packet::v1::packet::PacketType;
+use string_comm::{maybe_break, maybe_continue, socket::SocketEvent};
+use string_protocol::{messages::v1::ReceiptType, packet::v1::packet::PacketType, ProtocolPacket};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::error;
 
-use crate::Ctx;
+use crate::{context::StatefulSocket, Ctx};
 
 #[derive(Serialize, Deserialize, Type)]
 pub enum Event {
@@ -14,9 +19,29 @@ pub enum Event {
         author: String,
         channel_id: String,
         content: String,
+        /// ID of the message, which read receipts refer to it by.
+        message_id: String,
+    },
+    MessageDelivered {
+        message_id: String,
+    },
+    MessageRead {
+        message_id: String,
+    },
+    /// A message sent from another of our devices.
+    MessageSynced {
+        recipient: String,
+        channel_id: String,
+        content: String,
     },
 }
 
+/// Something received from the socket, either a packet or an event.
+enum Incoming {
+    Packet(Option<(Vec<u8>, ProtocolPacket)>),
+    Event(Result<SocketEvent, RecvError>),
+}
+
 /// Attach the message cache queries to the router.
 pub fn attach_event_queries<TMeta: Send>(
     builder: RouterBuilder<Ctx, TMeta>,
@@ -35,16 +60,61 @@ fn event_stream(ctx: Ctx, _: ()) -> impl Stream<Item = Event> {
                 return;
             }
         };
+        let mut socket_events = match *ctx.socket.read().await {
+            StatefulSocket::Active(ref socket) => socket.subscribe(),
+            StatefulSocket::Inactive => {
+                yield Event::NotConnected;
+                return;
+            }
+        };
         loop {
-            let (_, packet) = match unified_inbound_rx.recv().await {
-                Some(packet) => packet,
-                None => break,
+            let incoming = tokio::select! {
+                packet = unified_inbound_rx.recv() => Incoming::Packet(packet),
+                event = socket_events.recv() => Incoming::Event(event),
+            };
+            let packet = match incoming {
+                Incoming::Packet(Some((_, packet))) => packet,
+                Incoming::Packet(None) | Incoming::Event(Err(RecvError::Closed)) => break,
+                Incoming::Event(Err(RecvError::Lagged(_))) => continue,
+                Incoming::Event(Ok(SocketEvent::Receipt {
+                    receipt_type,
+                    message_ids,
+                    time,
+                    ..
+                })) => {
+                    let time = time
+                        .and_then(|time| SystemTime::try_from(time).ok())
+                        .unwrap_or_else(SystemTime::now);
+                    for message_id in message_ids {
+                        if let Err(err) =
+                            record_receipt(&ctx, &message_id, receipt_type, time).await
+                        {
+                            error!("failed to record receipt: {:?}", err);
+                        }
+                        match receipt_type {
+                            ReceiptType::Delivered => yield Event::MessageDelivered { message_id },
+                            ReceiptType::Read => yield Event::MessageRead { message_id },
+                            ReceiptType::Unspecified => {}
+                        }
+                    }
+                    continue;
+                }
+                Incoming::Event(Ok(_)) => continue,
             };
 
             // match on packet type
             let packet_type = maybe_break!(packet.packet_type);
             let message = match packet_type {
                 PacketType::PktMessage(message) => message,
+                PacketType::PktSentTranscript(transcript) => {
+                    let message = maybe_continue!(transcript.message);
+                    yield Event::MessageSynced {
+                        recipient: transcript.destination,
+                        channel_id: message.channel_id,
+                        content: message.content,
+                    };
+                    continue;
+                }
                 _ => unreachable!("unexpected packet type - yichen lied")
             };
 
@@ -52,7 +122,34 @@ fn event_stream(ctx: Ctx, _: ()) -> impl Stream<Item = Event> {
                 author: message.username,
                 channel_id: message.channel_id,
                 content: message.content,
+                message_id: message.id,
             };
         }
     }
 }
+
+/// Record the delivery state reported by a receipt in the message cache.
+async fn record_receipt(
+    ctx: &Ctx,
+    message_id: &str,
+    receipt_type: ReceiptType,
+    time: SystemTime,
+) -> Result<(), cache_prisma::client::QueryError> {
+    let time: DateTime<FixedOffset> = DateTime::<Utc>::from(time).into();
+    let update = match receipt_type {
+        ReceiptType::Delivered => cache_prisma::message::delivered_at::set(Some(time)),
+        ReceiptType::Read => cache_prisma::message::read_at::set(Some(time)),
+        ReceiptType::Unspecified => return Ok(()),
+    };
+    ctx.cache
+        .message()
+        .update_many(
+            vec![cache_prisma::message::message_id::equals(Some(
+                message_id.to_string(),
+            ))],
+            vec![update],
+        )
+        .exec()
+        .await?;
+    Ok(())
+}
@@ -1,8 +1,8 @@
 use std::{collections::HashMap, net::SocketAddr, path::Path};
 
-use pgp::SignedSecretKey;
 use serde::{Deserialize, Serialize};
-use string_comm::DEFAULT_PORT;
+use string_comm::{UnlockedKey, DEFAULT_PORT};
+use string_protocol::crypto::v1::{PrekeyBundle, SignedDeviceCertificate};
 use thiserror::Error;
 use tokio::sync::RwLock;
 use tracing::info;
@@ -45,11 +45,43 @@ impl LighthouseContext {
     /// List potential peers.
     pub async fn list_potential_peers(
         &self,
-        secret_key: SignedSecretKey,
+        key: UnlockedKey,
     ) -> Result<HashMap<String, SocketAddr>, LighthouseError> {
         let settings = self.settings.read().await;
-        let results =
-            lighthouse_client::list_potential_peers(&settings.endpoint, &secret_key).await?;
+        let results = lighthouse_client::list_potential_peers(&settings.endpoint, &key).await?;
+        Ok(results)
+    }
+
+    /// Publish our prekey bundle, so other nodes can start a session with us while we are offline.
+    pub async fn publish_prekeys(
+        &self,
+        key: &UnlockedKey,
+        bundle: &PrekeyBundle,
+    ) -> Result<(), LighthouseError> {
+        let settings = self.settings.read().await;
+        lighthouse_client::publish_prekeys(&settings.endpoint, key, bundle).await?;
+        Ok(())
+    }
+
+    /// Register the certificate of our key, so other nodes find us among the devices of our
+    /// identity.
+    pub async fn publish_device(
+        &self,
+        key: &UnlockedKey,
+        certificate: &SignedDeviceCertificate,
+    ) -> Result<(), LighthouseError> {
+        let settings = self.settings.read().await;
+        lighthouse_client::publish_device(&settings.endpoint, key, certificate).await?;
+        Ok(())
+    }
+
+    /// Fetch the certificates of the devices of an identity.
+    pub async fn fetch_devices<F: AsRef<[u8]>>(
+        &self,
+        fingerprint: F,
+    ) -> Result<Vec<SignedDeviceCertificate>, LighthouseError> {
+        let settings = self.settings.read().await;
+        let results = lighthouse_client::fetch_devices(&settings.endpoint, fingerprint).await?;
         Ok(results)
     }
 
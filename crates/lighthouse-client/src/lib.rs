@@ -4,13 +4,12 @@
 
 use base64::prelude::*;
 use lighthouse_protocol::{
-    GetNodeAddrPayload, GetNodeAddrResponse, ListPotentialPeersPayload, ListPotentialPeersResponse,
+    GetDevicesResponse, GetKeyStatementsResponse, GetNodeAddrPayload, GetNodeAddrResponse,
+    GetPrekeysResponse, KeyStatementKind, ListPotentialPeersPayload, ListPotentialPeersResponse,
+    PublishDevicePayload, PublishKeyStatementPayload, PublishPrekeysPayload,
     RegisterNodeAddrPayload,
 };
-use pgp::{
-    composed::SignedSecretKey,
-    types::{KeyTrait, SecretKeyTrait},
-};
+use prost::Message;
 use serde::{Deserialize, Serialize};
 
 use std::{
@@ -18,7 +17,10 @@ use std::{
     net::{Ipv4Addr, SocketAddr},
     str::{from_utf8, FromStr},
 };
-use string_comm::crypto::{Crypto, SigningError};
+use string_comm::{crypto::SigningError, UnlockedKey};
+use string_protocol::crypto::v1::{
+    key_statement, Prekey, PrekeyBundle, SignedDeviceCertificate, SignedKeyStatement,
+};
 use thiserror::Error;
 
 /// An enumeration of errors that can occur when using the lighthouse client.
@@ -40,6 +42,15 @@ pub enum LighthouseClientError {
     /// An error occured while decoding base64.
     #[error("failed to decode base64 info string")]
     Base64Error(#[from] base64::DecodeError),
+    /// An error occured while decoding hex.
+    #[error("failed to decode hex string")]
+    HexError(#[from] hex::FromHexError),
+    /// An error occured while decoding prekeys, key statements or device certificates.
+    #[error("failed to decode prekeys")]
+    PrekeyDecodeError(#[from] prost::DecodeError),
+    /// A key statement without a statement was provided.
+    #[error("key statement is missing")]
+    MissingKeyStatement,
     /// An invalid info string format was provided.
     #[error("invalid info string format")]
     InfoStringError,
@@ -64,7 +75,7 @@ pub async fn register_node_address(
     lighthouse_url: &String,
     addr: Option<Ipv4Addr>,
     port: u16,
-    secret_key: SignedSecretKey,
+    key: UnlockedKey,
 ) -> Result<(), LighthouseClientError> {
     // fetch ip from STUN if not provided
     let addr = match addr {
@@ -75,14 +86,8 @@ pub async fn register_node_address(
     let now: u32 = chrono::Utc::now().timestamp() as u32;
 
     // sign the data - include timestamp to prevent replay attacks
-    let signature = hex::encode(Crypto::sign_data_static(
-        &secret_key.clone(),
-        &format!("{}-{}", port, now).into_bytes(),
-    )?);
-    let pubkey = secret_key
-        .public_key()
-        .sign(&secret_key, || "".to_string())?
-        .to_armored_string(None)?;
+    let signature = hex::encode(key.sign(format!("{}-{}", port, now).into_bytes())?);
+    let pubkey = key.signed_public_key()?.to_armored_string(None)?;
 
     let client = reqwest::Client::new();
     client
@@ -135,13 +140,10 @@ pub async fn get_node_address<F: AsRef<[u8]>>(
 /// with the given secret key.
 pub async fn list_potential_peers(
     lighthouse_url: &String,
-    secret_key: &SignedSecretKey,
+    key: &UnlockedKey,
 ) -> Result<HashMap<String, SocketAddr>, LighthouseClientError> {
     let timestamp: u32 = chrono::Utc::now().timestamp() as u32;
-    let signature = hex::encode(Crypto::sign_data_static(
-        &secret_key,
-        &timestamp.to_le_bytes(),
-    )?);
+    let signature = hex::encode(key.sign(timestamp.to_le_bytes())?);
 
     let client = reqwest::Client::new();
     Ok(client
@@ -150,11 +152,8 @@ pub async fn list_potential_peers(
             &(ListPotentialPeersPayload {
                 signature,
                 timestamp,
-                fingerprint: hex::encode(secret_key.public_key().fingerprint()),
-                public_key: secret_key
-                    .public_key()
-                    .sign(secret_key, || "".to_string())?
-                    .to_armored_string(None)?,
+                fingerprint: hex::encode(key.fingerprint()),
+                public_key: key.signed_public_key()?.to_armored_string(None)?,
             }),
         )
         .send()
@@ -164,6 +163,215 @@ pub async fn list_potential_peers(
         .addrs)
 }
 
+/// Publish this node's prekey bundle to a lighthouse server, so that other nodes can start a
+/// session with it while it is offline. The one-time prekeys of the bundle are handed out one at
+/// a time.
+pub async fn publish_prekeys(
+    lighthouse_url: &String,
+    key: &UnlockedKey,
+    bundle: &PrekeyBundle,
+) -> Result<(), LighthouseClientError> {
+    let one_time_prekeys: Vec<String> = bundle
+        .one_time_prekeys
+        .iter()
+        .map(|prekey| hex::encode(prekey.encode_to_vec()))
+        .collect();
+    let bundle = hex::encode(
+        PrekeyBundle {
+            one_time_prekeys: vec![],
+            ..bundle.clone()
+        }
+        .encode_to_vec(),
+    );
+
+    let timestamp: u32 = chrono::Utc::now().timestamp() as u32;
+    let signature = hex::encode(key.sign(format!(
+        "{}-{}-{}",
+        bundle,
+        one_time_prekeys.join(","),
+        timestamp
+    ))?);
+
+    let client = reqwest::Client::new();
+    client
+        .post(format!("{}/prekeys", lighthouse_url))
+        .json(
+            &(PublishPrekeysPayload {
+                fingerprint: hex::encode(key.fingerprint()),
+                public_key: key.signed_public_key()?.to_armored_string(None)?,
+                bundle,
+                one_time_prekeys,
+                signature,
+                timestamp,
+            }),
+        )
+        .send()
+        .await?
+        .json::<()>()
+        .await?;
+    Ok(())
+}
+
+/// Fetch the prekey bundle of the node with the given fingerprint from a lighthouse server, with
+/// at most one one-time prekey. The bundle must still be verified against the node's public key.
+pub async fn fetch_prekey_bundle<F: AsRef<[u8]>>(
+    lighthouse_url: &String,
+    fingerprint: F,
+) -> Result<PrekeyBundle, LighthouseClientError> {
+    let client = reqwest::Client::new();
+    let response = client
+        .get(format!(
+            "{}/prekeys/{}",
+            lighthouse_url,
+            hex::encode(fingerprint)
+        ))
+        .send()
+        .await?
+        .json::<GetPrekeysResponse>()
+        .await?;
+
+    let mut bundle = PrekeyBundle::decode(hex::decode(response.bundle)?.as_slice())?;
+    bundle.one_time_prekeys = match response.one_time_prekey {
+        Some(prekey) => vec![Prekey::decode(hex::decode(prekey)?.as_slice())?],
+        None => vec![],
+    };
+    Ok(bundle)
+}
+
+/// Register a rotation or revocation of this node's key with a lighthouse server, so that nodes
+/// that were offline when it was broadcast still learn of it. The statement must be signed with
+/// `key`.
+pub async fn publish_key_statement(
+    lighthouse_url: &String,
+    key: &UnlockedKey,
+    statement: &SignedKeyStatement,
+) -> Result<(), LighthouseClientError> {
+    let kind = match statement
+        .statement
+        .as_ref()
+        .and_then(|statement| statement.statement.as_ref())
+    {
+        Some(key_statement::Statement::Rotation(_)) => KeyStatementKind::Rotation,
+        Some(key_statement::Statement::Revocation(_)) => KeyStatementKind::Revocation,
+        None => return Err(LighthouseClientError::MissingKeyStatement),
+    };
+    let statement = hex::encode(statement.encode_to_vec());
+
+    let timestamp: u32 = chrono::Utc::now().timestamp() as u32;
+    let signature =
+        hex::encode(key.sign(format!("{}-{}-{}", kind.as_str(), statement, timestamp))?);
+
+    let client = reqwest::Client::new();
+    client
+        .post(format!("{}/keys/statements", lighthouse_url))
+        .json(
+            &(PublishKeyStatementPayload {
+                fingerprint: hex::encode(key.fingerprint()),
+                public_key: key.signed_public_key()?.to_armored_string(None)?,
+                kind,
+                statement,
+                signature,
+                timestamp,
+            }),
+        )
+        .send()
+        .await?
+        .json::<()>()
+        .await?;
+    Ok(())
+}
+
+/// Fetch the rotations and revocations registered for the key with the given fingerprint from a
+/// lighthouse server. Each statement must still be verified, and applied with
+/// [string_comm::Socket::apply_key_statement].
+pub async fn fetch_key_statements<F: AsRef<[u8]>>(
+    lighthouse_url: &String,
+    fingerprint: F,
+) -> Result<Vec<SignedKeyStatement>, LighthouseClientError> {
+    let client = reqwest::Client::new();
+    let response = client
+        .get(format!(
+            "{}/keys/{}/statements",
+            lighthouse_url,
+            hex::encode(fingerprint)
+        ))
+        .send()
+        .await?
+        .json::<GetKeyStatementsResponse>()
+        .await?;
+
+    response
+        .statements
+        .into_iter()
+        .map(|statement| {
+            Ok(SignedKeyStatement::decode(
+                hex::decode(statement)?.as_slice(),
+            )?)
+        })
+        .collect()
+}
+
+/// Register the certificate of this node's key with a lighthouse server, so that nodes can find
+/// it among the devices of its identity. The certificate must be for `key`.
+pub async fn publish_device(
+    lighthouse_url: &String,
+    key: &UnlockedKey,
+    certificate: &SignedDeviceCertificate,
+) -> Result<(), LighthouseClientError> {
+    let certificate = hex::encode(certificate.encode_to_vec());
+
+    let timestamp: u32 = chrono::Utc::now().timestamp() as u32;
+    let signature = hex::encode(key.sign(format!("{}-{}", certificate, timestamp))?);
+
+    let client = reqwest::Client::new();
+    client
+        .post(format!("{}/devices", lighthouse_url))
+        .json(
+            &(PublishDevicePayload {
+                fingerprint: hex::encode(key.fingerprint()),
+                public_key: key.signed_public_key()?.to_armored_string(None)?,
+                certificate,
+                signature,
+                timestamp,
+            }),
+        )
+        .send()
+        .await?
+        .json::<()>()
+        .await?;
+    Ok(())
+}
+
+/// Fetch the certificates of the devices of the identity with the given fingerprint from a
+/// lighthouse server. Each certificate must still be verified, and applied with
+/// [string_comm::Socket::apply_device_certificate].
+pub async fn fetch_devices<F: AsRef<[u8]>>(
+    lighthouse_url: &String,
+    fingerprint: F,
+) -> Result<Vec<SignedDeviceCertificate>, LighthouseClientError> {
+    let client = reqwest::Client::new();
+    let response = client
+        .get(format!(
+            "{}/identities/{}/devices",
+            lighthouse_url,
+            hex::encode(fingerprint)
+        ))
+        .send()
+        .await?
+        .json::<GetDevicesResponse>()
+        .await?;
+
+    response
+        .certificates
+        .into_iter()
+        .map(|certificate| {
+            Ok(SignedDeviceCertificate::decode(
+                hex::decode(certificate)?.as_slice(),
+            )?)
+        })
+        .collect()
+}
+
 /// A struct to hold encoded information.
 #[derive(Serialize, Deserialize)]
 struct EncodedInfo {
@@ -0,0 +1,20 @@
+-- CreateTable
+CREATE TABLE "PrekeyBundle" (
+    "fingerprint" TEXT NOT NULL,
+    "bundle" TEXT NOT NULL,
+    "updatedAt" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT "PrekeyBundle_pkey" PRIMARY KEY ("fingerprint")
+);
+
+-- CreateTable
+CREATE TABLE "OneTimePrekey" (
+    "id" SERIAL NOT NULL,
+    "fingerprint" TEXT NOT NULL,
+    "prekey" TEXT NOT NULL,
+
+    CONSTRAINT "OneTimePrekey_pkey" PRIMARY KEY ("id")
+);
+
+-- CreateIndex
+CREATE INDEX "OneTimePrekey_fingerprint_idx" ON "OneTimePrekey"("fingerprint");
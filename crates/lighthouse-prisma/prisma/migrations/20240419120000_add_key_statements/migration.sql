@@ -0,0 +1,13 @@
+-- CreateTable
+CREATE TABLE "KeyStatement" (
+    "id" SERIAL NOT NULL,
+    "fingerprint" TEXT NOT NULL,
+    "kind" TEXT NOT NULL,
+    "statement" TEXT NOT NULL,
+    "createdAt" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT "KeyStatement_pkey" PRIMARY KEY ("id")
+);
+
+-- CreateIndex
+CREATE INDEX "KeyStatement_fingerprint_idx" ON "KeyStatement"("fingerprint");
@@ -0,0 +1,12 @@
+-- CreateTable
+CREATE TABLE "Device" (
+    "fingerprint" TEXT NOT NULL,
+    "identity" TEXT NOT NULL,
+    "certificate" TEXT NOT NULL,
+    "updatedAt" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT "Device_pkey" PRIMARY KEY ("fingerprint")
+);
+
+-- CreateIndex
+CREATE INDEX "Device_identity_idx" ON "Device"("identity");
@@ -3,10 +3,13 @@
 
 use std::{collections::HashMap, net::SocketAddr};
 
-use nom::combinator::map;
+use nom::{
+    combinator::{all_consuming, map},
+    multi::count,
+};
 use pgp::{
-    crypto::hash::HashAlgorithm,
-    types::{mpi, PublicKeyTrait},
+    crypto::{hash::HashAlgorithm, public_key::PublicKeyAlgorithm},
+    types::{mpi, KeyTrait, PublicKeyTrait},
     Deserializable, SignedPublicKey,
 };
 use serde::{Deserialize, Serialize};
@@ -33,13 +36,24 @@ pub trait Sign {
         };
         let digest = digest.as_slice();
 
-        // do something?
-        let signature = self.signature();
-        let (_, mpi_signature) = map(mpi, |v| vec![v.to_owned()])(&signature)?;
-
         // access the public key
         let (public_key, _headers) = SignedPublicKey::from_string(&self.public_key())?;
 
+        // RSA signatures are one MPI, DSA, ECDSA and EdDSA signatures are two
+        let mpis = match public_key.algorithm() {
+            PublicKeyAlgorithm::RSA | PublicKeyAlgorithm::RSASign => 1,
+            PublicKeyAlgorithm::DSA | PublicKeyAlgorithm::ECDSA | PublicKeyAlgorithm::EdDSA => 2,
+            algorithm => {
+                return Err(pgp::errors::Error::Unsupported(format!(
+                    "signature algorithm {:?}",
+                    algorithm
+                )))
+            }
+        };
+        let signature = self.signature();
+        let (_, mpi_signature) =
+            all_consuming(count(map(mpi, |v| v.to_owned()), mpis))(&signature)?;
+
         // verify
         public_key.verify_signature(HashAlgorithm::SHA2_256, digest, &mpi_signature)
     }
@@ -124,3 +138,154 @@ impl Sign for ListPotentialPeersPayload {
 pub struct ListPotentialPeersResponse {
     pub addrs: HashMap<String, SocketAddr>,
 }
+
+/// Used to publish a node's prekey bundle, so that other nodes can start a session with it while
+/// it is offline.
+#[derive(Serialize, Deserialize)]
+pub struct PublishPrekeysPayload {
+    /// The fingerprint of the node publishing its prekeys.
+    pub fingerprint: String,
+    /// The public key of the node publishing its prekeys.
+    pub public_key: String,
+    /// The hex-encoded prekey bundle, without its one-time prekeys.
+    pub bundle: String,
+    /// The hex-encoded one-time prekeys, each of which is handed out once.
+    pub one_time_prekeys: Vec<String>,
+    /// The signature of the payload, constructed from the prekeys and the timestamp.
+    pub signature: String,
+    /// The timestamp of the request.
+    pub timestamp: u32,
+}
+
+impl Sign for PublishPrekeysPayload {
+    fn signature(&self) -> Vec<u8> {
+        hex::decode(&self.signature).unwrap()
+    }
+
+    fn public_key(&self) -> &String {
+        &self.public_key
+    }
+
+    fn data(&self) -> Vec<u8> {
+        format!(
+            "{}-{}-{}",
+            self.bundle,
+            self.one_time_prekeys.join(","),
+            self.timestamp
+        )
+        .into_bytes()
+    }
+}
+
+/// The response to a prekey bundle request.
+#[derive(Serialize, Deserialize)]
+pub struct GetPrekeysResponse {
+    /// The hex-encoded prekey bundle, without its one-time prekeys.
+    pub bundle: String,
+    /// A hex-encoded one-time prekey, if the node has any left.
+    pub one_time_prekey: Option<String>,
+}
+
+/// The kind of a key statement registered with the lighthouse.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyStatementKind {
+    /// The key is replaced by a new one.
+    Rotation,
+    /// The key must no longer be trusted.
+    Revocation,
+}
+
+impl KeyStatementKind {
+    /// Returns the name of the kind, as it is signed and stored.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyStatementKind::Rotation => "rotation",
+            KeyStatementKind::Revocation => "revocation",
+        }
+    }
+}
+
+/// Used to register a rotation or revocation of a node's key, so that nodes which were offline
+/// when it was broadcast still learn of it. It must be signed by the key it is about.
+#[derive(Serialize, Deserialize)]
+pub struct PublishKeyStatementPayload {
+    /// The fingerprint of the key the statement is about.
+    pub fingerprint: String,
+    /// The public key the statement is about.
+    pub public_key: String,
+    /// Whether the statement rotates or revokes the key.
+    pub kind: KeyStatementKind,
+    /// The hex-encoded signed key statement.
+    pub statement: String,
+    /// The signature of the payload, constructed from the kind, the statement and the timestamp.
+    pub signature: String,
+    /// The timestamp of the request.
+    pub timestamp: u32,
+}
+
+impl Sign for PublishKeyStatementPayload {
+    fn signature(&self) -> Vec<u8> {
+        hex::decode(&self.signature).unwrap()
+    }
+
+    fn public_key(&self) -> &String {
+        &self.public_key
+    }
+
+    fn data(&self) -> Vec<u8> {
+        format!(
+            "{}-{}-{}",
+            self.kind.as_str(),
+            self.statement,
+            self.timestamp
+        )
+        .into_bytes()
+    }
+}
+
+/// The response to a key statement request.
+#[derive(Serialize, Deserialize)]
+pub struct GetKeyStatementsResponse {
+    /// The hex-encoded signed key statements registered for the key, which must still be
+    /// verified.
+    pub statements: Vec<String>,
+}
+
+/// Used to register a device certificate, so that nodes can look up the devices of an identity.
+/// It must be signed by the key of the device, the certificate by the identity key.
+#[derive(Serialize, Deserialize)]
+pub struct PublishDevicePayload {
+    /// The fingerprint of the key of the device.
+    pub fingerprint: String,
+    /// The public key of the device.
+    pub public_key: String,
+    /// The hex-encoded signed device certificate.
+    pub certificate: String,
+    /// The signature of the payload, constructed from the certificate and the timestamp.
+    pub signature: String,
+    /// The timestamp of the request.
+    pub timestamp: u32,
+}
+
+impl Sign for PublishDevicePayload {
+    fn signature(&self) -> Vec<u8> {
+        hex::decode(&self.signature).unwrap()
+    }
+
+    fn public_key(&self) -> &String {
+        &self.public_key
+    }
+
+    fn data(&self) -> Vec<u8> {
+        format!("{}-{}", self.certificate, self.timestamp).into_bytes()
+    }
+}
+
+/// The response to a device request.
+#[derive(Serialize, Deserialize)]
+pub struct GetDevicesResponse {
+    /// The hex-encoded signed certificates of the devices of the identity, which must still be
+    /// verified.
+    pub certificates: Vec<String>,
+}
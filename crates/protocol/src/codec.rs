@@ -0,0 +1,310 @@
+//! Defines the compression envelope that wraps every encoded [ProtocolPacket].
+//!
+//! An envelope has the following format:
+//! - 1 byte: Codec ([Codec::None], [Codec::Gzip] or [Codec::Zstd])
+//! - 4 bytes: Length of the uncompressed protobuf payload
+//!
+//! Followed by the (possibly compressed) protobuf payload.
+
+use std::io::{self, Read, Write};
+
+use prost::Message;
+
+use crate::{
+    crypto, messages, packet, AttachmentType, MessageType, PacketDecodeError, PacketEncodeError,
+    ProtocolPacket, ProtocolPacketType,
+};
+
+/// The size of the envelope header.
+pub const ENVELOPE_HEADER_SIZE: usize = 1 + 4;
+
+/// Payloads smaller than this are never compressed - the codec overhead outweighs any gain.
+pub const MIN_COMPRESSION_SIZE: usize = 512;
+
+/// The largest payload we are willing to decompress. This protects against decompression bombs.
+pub const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+/// The most bytes set aside up front for a decompressed payload, since the length the envelope
+/// claims is not to be trusted. The buffer grows as the payload actually decompresses.
+const MAX_INITIAL_CAPACITY: usize = 64 * 1024;
+
+/// The zstd compression level used when encoding.
+const ZSTD_LEVEL: i32 = 3;
+
+/// An enumeration of the compression codecs that can be used to encode a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Codec {
+    /// The payload is not compressed.
+    None = 0,
+    /// The payload is compressed with gzip.
+    Gzip = 1,
+    /// The payload is compressed with zstd.
+    Zstd = 2,
+}
+
+impl TryFrom<u8> for Codec {
+    type Error = PacketDecodeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Gzip),
+            2 => Ok(Codec::Zstd),
+            other => Err(PacketDecodeError::UnknownCodec(other)),
+        }
+    }
+}
+
+impl Codec {
+    /// The bit used to represent this codec in a [CodecSet].
+    const fn bit(self) -> u8 {
+        1 << (self as u8)
+    }
+}
+
+/// A set of [Codec]s, stored as a bitmask. This is exchanged by peers during the handshake so
+/// that each side only uses codecs the other can decode. [Codec::None] is always a member.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodecSet(u8);
+
+impl CodecSet {
+    /// The set of codecs supported by this build.
+    pub const fn supported() -> Self {
+        Self(Codec::None.bit() | Codec::Gzip.bit() | Codec::Zstd.bit())
+    }
+
+    /// The smallest possible set, containing only [Codec::None].
+    pub const fn none() -> Self {
+        Self(Codec::None.bit())
+    }
+
+    /// Create a set from a bitmask received from a peer. Unknown bits are discarded.
+    pub const fn from_bits(bits: u8) -> Self {
+        Self((bits & Self::supported().0) | Codec::None.bit())
+    }
+
+    /// Returns the bitmask representation of this set.
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Check whether the given codec is a member of this set.
+    pub const fn contains(self, codec: Codec) -> bool {
+        self.0 & codec.bit() != 0
+    }
+
+    /// Returns the codecs that are members of both sets.
+    pub const fn intersection(self, other: Self) -> Self {
+        Self::from_bits(self.0 & other.0)
+    }
+
+    /// The codec we prefer to compress with, out of the codecs in this set.
+    pub fn preferred(self) -> Codec {
+        [Codec::Zstd, Codec::Gzip]
+            .into_iter()
+            .find(|codec| self.contains(*codec))
+            .unwrap_or(Codec::None)
+    }
+}
+
+impl Default for CodecSet {
+    fn default() -> Self {
+        Self::supported()
+    }
+}
+
+/// Attempt to encode a packet into an envelope, compressing with one of the given codecs if it is
+/// worthwhile.
+pub fn try_encode_packet_with(
+    packet: &ProtocolPacket,
+    codecs: CodecSet,
+) -> Result<Vec<u8>, PacketEncodeError> {
+    // encode packet
+    let mut raw = Vec::with_capacity(packet.encoded_len());
+    packet.encode(&mut raw)?;
+    let raw_len: u32 = raw
+        .len()
+        .try_into()
+        .map_err(|_| PacketEncodeError::TooLarge)?;
+
+    // pick a codec, and fall back to none if compression did not help
+    let codec = match raw.len() < MIN_COMPRESSION_SIZE || is_incompressible(packet) {
+        true => Codec::None,
+        false => codecs.preferred(),
+    };
+    let (codec, payload) = match compress(codec, &raw)? {
+        Some(compressed) if compressed.len() < raw.len() => (codec, compressed),
+        _ => (Codec::None, raw),
+    };
+
+    let mut buf = Vec::with_capacity(ENVELOPE_HEADER_SIZE + payload.len());
+    buf.push(codec as u8);
+    buf.extend_from_slice(&raw_len.to_be_bytes());
+    buf.extend_from_slice(&payload);
+    Ok(buf)
+}
+
+/// Attempt to decode a packet from an envelope.
+pub fn try_decode_packet_envelope(buf: &[u8]) -> Result<ProtocolPacket, PacketDecodeError> {
+    if buf.len() < ENVELOPE_HEADER_SIZE {
+        return Err(PacketDecodeError::BadEnvelope);
+    }
+    let codec = Codec::try_from(buf[0])?;
+    let raw_len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+    if raw_len > MAX_DECOMPRESSED_SIZE {
+        return Err(PacketDecodeError::BadEnvelope);
+    }
+    let payload = &buf[ENVELOPE_HEADER_SIZE..];
+
+    let raw = match codec {
+        Codec::None => payload.to_vec(),
+        Codec::Gzip => read_bounded(flate2::read::GzDecoder::new(payload), raw_len)?,
+        Codec::Zstd => read_bounded(zstd::stream::read::Decoder::new(payload)?, raw_len)?,
+    };
+    if raw.len() != raw_len {
+        return Err(PacketDecodeError::BadEnvelope);
+    }
+
+    Ok(packet::v1::Packet::decode(&*raw)?)
+}
+
+/// Compress the data with the given codec, returning `None` if no compression was requested.
+fn compress(codec: Codec, raw: &[u8]) -> Result<Option<Vec<u8>>, PacketEncodeError> {
+    match codec {
+        Codec::None => Ok(None),
+        Codec::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(raw)?;
+            Ok(Some(encoder.finish()?))
+        }
+        Codec::Zstd => Ok(Some(zstd::stream::encode_all(raw, ZSTD_LEVEL)?)),
+    }
+}
+
+/// Read at most `expected + 1` bytes from the decoder, so oversized payloads are detected without
+/// decompressing all of them.
+fn read_bounded<R: Read>(decoder: R, expected: usize) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(expected.min(MAX_INITIAL_CAPACITY));
+    decoder.take(expected as u64 + 1).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Returns true if most of the packet is made up of data that will not compress - encrypted
+/// content, or attachments in an already-compressed format.
+fn is_incompressible(packet: &ProtocolPacket) -> bool {
+    let total = packet.encoded_len();
+    let opaque = match &packet.packet_type {
+        Some(ProtocolPacketType::PktMessage(message)) => message
+            .attachments
+            .iter()
+            .filter_map(|attachment| attachment.attachment_type.as_ref())
+            .filter(|attachment| is_precompressed(attachment))
+            .map(attachment_len)
+            .sum(),
+        Some(ProtocolPacketType::PktGossip(gossip)) => match gossip
+            .packet
+            .as_ref()
+            .and_then(|packet| packet.signed_data.as_ref())
+            .and_then(|data| data.message_type.as_ref())
+        {
+            Some(MessageType::EncryptedPacket(crypto::v1::EncryptedPacket { content, .. })) => {
+                content.len()
+            }
+            _ => 0,
+        },
+        _ => 0,
+    };
+    opaque * 2 >= total
+}
+
+/// Returns true if the attachment is stored in a format that is already compressed.
+fn is_precompressed(attachment: &AttachmentType) -> bool {
+    match attachment {
+        AttachmentType::Image(image) => matches!(
+            image.format(),
+            messages::v1::ImageFormat::Png
+                | messages::v1::ImageFormat::Jpeg
+                | messages::v1::ImageFormat::Webp
+                | messages::v1::ImageFormat::Gif
+        ),
+        AttachmentType::Audio(audio) => matches!(
+            audio.format(),
+            messages::v1::AudioFormat::Mp3 | messages::v1::AudioFormat::Ogg
+        ),
+        AttachmentType::Video(video) => matches!(
+            video.format(),
+            messages::v1::VideoFormat::Mp4 | messages::v1::VideoFormat::Webm
+        ),
+    }
+}
+
+/// Returns the size of the attachment data.
+fn attachment_len(attachment: &AttachmentType) -> usize {
+    match attachment {
+        AttachmentType::Image(image) => image.data.len(),
+        AttachmentType::Audio(audio) => audio.data.len(),
+        AttachmentType::Video(video) => video.data.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_packet(content: String) -> ProtocolPacket {
+        ProtocolPacket {
+            packet_type: Some(ProtocolPacketType::PktMessage(messages::v1::Message {
+                content,
+                ..Default::default()
+            })),
+        }
+    }
+
+    #[test]
+    fn test_small_packets_are_not_compressed() {
+        let packet = text_packet("hello".to_string());
+        let buf = try_encode_packet_with(&packet, CodecSet::supported()).unwrap();
+        assert_eq!(buf[0], Codec::None as u8);
+        assert_eq!(try_decode_packet_envelope(&buf).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_codec_roundtrip() {
+        let packet = text_packet("string ".repeat(1000));
+        for codecs in [
+            CodecSet::none(),
+            CodecSet::from_bits(Codec::Gzip.bit()),
+            CodecSet::supported(),
+        ] {
+            let buf = try_encode_packet_with(&packet, codecs).unwrap();
+            assert_eq!(buf[0], codecs.preferred() as u8);
+            assert_eq!(try_decode_packet_envelope(&buf).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn test_precompressed_attachments_are_not_compressed() {
+        let packet = ProtocolPacket {
+            packet_type: Some(ProtocolPacketType::PktMessage(messages::v1::Message {
+                attachments: vec![messages::v1::MessageAttachment {
+                    attachment_type: Some(AttachmentType::Image(messages::v1::ImageAttachment {
+                        format: messages::v1::ImageFormat::Jpeg.into(),
+                        data: vec![0; 4096],
+                    })),
+                }],
+                ..Default::default()
+            })),
+        };
+        let buf = try_encode_packet_with(&packet, CodecSet::supported()).unwrap();
+        assert_eq!(buf[0], Codec::None as u8);
+    }
+
+    #[test]
+    fn test_bad_envelope() {
+        assert!(try_decode_packet_envelope(&[]).is_err());
+        assert!(try_decode_packet_envelope(&[7, 0, 0, 0, 0]).is_err());
+        assert!(try_decode_packet_envelope(&[0, 0, 0, 0, 9, 1]).is_err());
+    }
+}
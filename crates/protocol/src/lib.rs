@@ -2,11 +2,18 @@
 //!
 //! This crate contains the protocol definition for the string protocol.
 
-use std::io::{self, Read, Write};
+mod codec;
+
+use std::io;
 
 use prost::{DecodeError, EncodeError, Message};
 use thiserror::Error;
 
+pub use codec::{
+    try_encode_packet_with, Codec, CodecSet, ENVELOPE_HEADER_SIZE, MAX_DECOMPRESSED_SIZE,
+    MIN_COMPRESSION_SIZE,
+};
+
 /// Utility macro to quickly define a module for a protocol.
 macro_rules! include_protocol {
     ($name:literal, $version:ident) => {
@@ -59,6 +66,16 @@ pub mod gossip {
     include_protocol!("gossip", v1);
 }
 
+/// Defines the DHT records and messages
+pub mod dht {
+    include_protocol!("dht", v1);
+}
+
+/// Defines the ping and trace probes
+pub mod probe {
+    include_protocol!("probe", v1);
+}
+
 /// Defines the SendAvailablePeers + RequestAvailablePeers
 pub mod peers {
     include_protocol!("peers", v1);
@@ -86,6 +103,10 @@ pub enum PacketDecodeError {
     DecodeError(#[from] DecodeError),
     #[error("encountered an IO error while decoding packet")]
     IoError(#[from] io::Error),
+    #[error("unknown compression codec {0}")]
+    UnknownCodec(u8),
+    #[error("malformed packet envelope")]
+    BadEnvelope,
 }
 
 /// An error that can occur when encoding a packet.
@@ -95,6 +116,8 @@ pub enum PacketEncodeError {
     EncodeError(#[from] EncodeError),
     #[error("encountered an IO error while encoding packet")]
     IoError(#[from] io::Error),
+    #[error("packet is too large to encode")]
+    TooLarge,
 }
 
 /// Attempt to decode a packet from the given buffer.
@@ -102,23 +125,12 @@ pub fn try_decode_packet<Data>(buf: Data) -> Result<ProtocolPacket, PacketDecode
 where
     Data: AsRef<[u8]>,
 {
-    // decompress data - generously allocate 2x the size of the compressed data
-    let mut decoder = flate2::read::GzDecoder::new(buf.as_ref());
-    let mut buf = Vec::with_capacity(buf.as_ref().len() * 2);
-    decoder.read_to_end(&mut buf)?;
-    // decode packet
-    Ok(packet::v1::Packet::decode(&*buf)?)
+    codec::try_decode_packet_envelope(buf.as_ref())
 }
 
-/// Attempt to encode a packet into a buffer.
+/// Attempt to encode a packet into a buffer, using any of the codecs supported by this build.
 pub fn try_encode_packet(packet: &ProtocolPacket) -> Result<Vec<u8>, PacketEncodeError> {
-    // encode packet
-    let mut buf = Vec::new();
-    packet.encode(&mut buf)?;
-    // compress data
-    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
-    encoder.write_all(&buf)?;
-    Ok(encoder.finish()?)
+    try_encode_packet_with(packet, CodecSet::supported())
 }
 
 /// Attempt to encode a SignedPacketInternal for signing purposes
@@ -16,11 +16,15 @@ use axum::{
 use axum_macros::debug_handler;
 use lighthouse_prisma::PrismaClient;
 use lighthouse_protocol::{
-    GetNodeAddrPayload, GetNodeAddrResponse, ListPotentialPeersPayload, ListPotentialPeersResponse,
+    GetDevicesResponse, GetKeyStatementsResponse, GetNodeAddrPayload, GetNodeAddrResponse,
+    GetPrekeysResponse, KeyStatementKind, ListPotentialPeersPayload, ListPotentialPeersResponse,
+    PublishDevicePayload, PublishKeyStatementPayload, PublishPrekeysPayload,
     RegisterNodeAddrPayload, RegisterNodeAddrResponse, Sign,
 };
+use pgp::{types::KeyTrait, Deserializable, SignedPublicKey};
 
 use serde::Serialize;
+use string_comm::device::verify_encoded_certificate;
 use thiserror::Error;
 use tokio::{net::TcpListener, sync::RwLock};
 use tower::ServiceBuilder;
@@ -46,6 +50,12 @@ enum LighthouseError {
     InvalidId,
     #[error("invalid fingerprint")]
     InvalidFingerprint(#[from] hex::FromHexError),
+    #[error("fingerprint does not match public key")]
+    FingerprintMismatch,
+    #[error("key has been revoked")]
+    KeyRevoked,
+    #[error("invalid device certificate")]
+    InvalidCertificate,
 }
 
 #[derive(Serialize)]
@@ -96,6 +106,10 @@ async fn wipe_node_entries(
     db.pubkey().delete_many(vec![]).exec().await?;
     db.pending_connection().delete_many(vec![]).exec().await?;
     db.endpoint().delete_many(vec![]).exec().await?;
+    db.prekey_bundle().delete_many(vec![]).exec().await?;
+    db.one_time_prekey().delete_many(vec![]).exec().await?;
+    db.key_statement().delete_many(vec![]).exec().await?;
+    db.device().delete_many(vec![]).exec().await?;
 
     let sql_cmd = "ALTER SEQUENCE \"Pubkey_id_seq\" RESTART WITH 1";
     db._execute_raw(prisma_client_rust::Raw::new(sql_cmd, vec![]))
@@ -105,6 +119,14 @@ async fn wipe_node_entries(
     db._execute_raw(prisma_client_rust::Raw::new(sql_cmd1, vec![]))
         .exec()
         .await?;
+    let sql_cmd2 = "ALTER SEQUENCE \"OneTimePrekey_id_seq\" RESTART WITH 1";
+    db._execute_raw(prisma_client_rust::Raw::new(sql_cmd2, vec![]))
+        .exec()
+        .await?;
+    let sql_cmd3 = "ALTER SEQUENCE \"KeyStatement_id_seq\" RESTART WITH 1";
+    db._execute_raw(prisma_client_rust::Raw::new(sql_cmd3, vec![]))
+        .exec()
+        .await?;
 
     Ok(())
 }
@@ -117,8 +139,12 @@ async fn register_node_addr(
 ) -> Result<Response, LighthouseError> {
     let db = ctx.db.write().await;
 
-    // verify the payload
+    // verify the payload, and that the key is still trusted
     payload.verify()?;
+    let (public_key, _headers) = SignedPublicKey::from_string(&payload.public_key)?;
+    if is_revoked(&db, &hex::encode(public_key.fingerprint())).await? {
+        return Err(LighthouseError::KeyRevoked);
+    }
 
     let existing_rec = db
         .endpoint()
@@ -240,6 +266,265 @@ async fn list_potential_peers(
     .into_response())
 }
 
+/// This endpoint handles the publishing of a node's prekeys, replacing any it published before.
+#[debug_handler]
+async fn publish_prekeys(
+    Extension(ctx): Extension<Arc<LighthouseCtx>>,
+    Json(payload): Json<PublishPrekeysPayload>,
+) -> Result<Response, LighthouseError> {
+    let db = ctx.db.write().await;
+
+    // verify the payload, and that nobody else is publishing prekeys for the node
+    payload.verify()?;
+    let (public_key, _headers) = SignedPublicKey::from_string(&payload.public_key)?;
+    if hex::encode(public_key.fingerprint()) != payload.fingerprint {
+        return Err(LighthouseError::FingerprintMismatch);
+    }
+    if is_revoked(&db, &payload.fingerprint).await? {
+        return Err(LighthouseError::KeyRevoked);
+    }
+
+    db.prekey_bundle()
+        .upsert(
+            lighthouse_prisma::prekey_bundle::fingerprint::equals(payload.fingerprint.clone()),
+            lighthouse_prisma::prekey_bundle::create(
+                payload.fingerprint.clone(),
+                payload.bundle.clone(),
+                vec![],
+            ),
+            vec![
+                lighthouse_prisma::prekey_bundle::bundle::set(payload.bundle),
+                lighthouse_prisma::prekey_bundle::updated_at::set(
+                    chrono::Utc::now().fixed_offset(),
+                ),
+            ],
+        )
+        .exec()
+        .await?;
+
+    // one-time prekeys that were not handed out yet are replaced too
+    db.one_time_prekey()
+        .delete_many(vec![
+            lighthouse_prisma::one_time_prekey::fingerprint::equals(payload.fingerprint.clone()),
+        ])
+        .exec()
+        .await?;
+    for prekey in payload.one_time_prekeys {
+        db.one_time_prekey()
+            .create(payload.fingerprint.clone(), prekey, vec![])
+            .exec()
+            .await?;
+    }
+
+    Ok(Json(()).into_response())
+}
+
+/// This endpoint handles the lookup of a node's prekey bundle. Each one-time prekey is only ever
+/// handed out once.
+#[debug_handler]
+async fn get_prekeys(
+    Path(fingerprint): Path<String>,
+    Extension(ctx): Extension<Arc<LighthouseCtx>>,
+) -> Result<Response, LighthouseError> {
+    let db = ctx.db.write().await;
+
+    let bundle = db
+        .prekey_bundle()
+        .find_unique(lighthouse_prisma::prekey_bundle::fingerprint::equals(
+            fingerprint.clone(),
+        ))
+        .exec()
+        .await?
+        .ok_or(LighthouseError::InvalidId)?;
+
+    let one_time_prekey = db
+        .one_time_prekey()
+        .find_first(vec![
+            lighthouse_prisma::one_time_prekey::fingerprint::equals(fingerprint),
+        ])
+        .exec()
+        .await?;
+    if let Some(ref prekey) = one_time_prekey {
+        db.one_time_prekey()
+            .delete(lighthouse_prisma::one_time_prekey::id::equals(prekey.id))
+            .exec()
+            .await?;
+    }
+
+    Ok(Json(GetPrekeysResponse {
+        bundle: bundle.bundle,
+        one_time_prekey: one_time_prekey.map(|prekey| prekey.prekey),
+    })
+    .into_response())
+}
+
+/// This endpoint handles the registration of a rotation or revocation of a node's key. Once a
+/// key is revoked, its prekeys are dropped and it can no longer register anything else.
+#[debug_handler]
+async fn publish_key_statement(
+    Extension(ctx): Extension<Arc<LighthouseCtx>>,
+    Json(payload): Json<PublishKeyStatementPayload>,
+) -> Result<Response, LighthouseError> {
+    let db = ctx.db.write().await;
+
+    // verify the payload, and that it is signed by the key it is about
+    payload.verify()?;
+    let (public_key, _headers) = SignedPublicKey::from_string(&payload.public_key)?;
+    if hex::encode(public_key.fingerprint()) != payload.fingerprint {
+        return Err(LighthouseError::FingerprintMismatch);
+    }
+
+    db.key_statement()
+        .create(
+            payload.fingerprint.clone(),
+            payload.kind.as_str().to_string(),
+            payload.statement,
+            vec![],
+        )
+        .exec()
+        .await?;
+
+    if payload.kind == KeyStatementKind::Revocation {
+        db.prekey_bundle()
+            .delete_many(vec![lighthouse_prisma::prekey_bundle::fingerprint::equals(
+                payload.fingerprint.clone(),
+            )])
+            .exec()
+            .await?;
+        db.one_time_prekey()
+            .delete_many(vec![
+                lighthouse_prisma::one_time_prekey::fingerprint::equals(
+                    payload.fingerprint.clone(),
+                ),
+            ])
+            .exec()
+            .await?;
+        // a revoked device is retired, and a revoked identity takes its devices with it
+        db.device()
+            .delete_many(vec![prisma_client_rust::operator::or(vec![
+                lighthouse_prisma::device::fingerprint::equals(payload.fingerprint.clone()),
+                lighthouse_prisma::device::identity::equals(payload.fingerprint),
+            ])])
+            .exec()
+            .await?;
+    }
+
+    Ok(Json(()).into_response())
+}
+
+/// This endpoint handles the lookup of the rotations and revocations registered for a node's key.
+#[debug_handler]
+async fn get_key_statements(
+    Path(fingerprint): Path<String>,
+    Extension(ctx): Extension<Arc<LighthouseCtx>>,
+) -> Result<Response, LighthouseError> {
+    let db = ctx.db.write().await;
+
+    let statements = db
+        .key_statement()
+        .find_many(vec![lighthouse_prisma::key_statement::fingerprint::equals(
+            fingerprint,
+        )])
+        .exec()
+        .await?;
+
+    Ok(Json(GetKeyStatementsResponse {
+        statements: statements
+            .into_iter()
+            .map(|statement| statement.statement)
+            .collect(),
+    })
+    .into_response())
+}
+
+/// This endpoint handles the registration of a device certificate, by the device it certifies.
+/// Neither the device nor its identity may have been revoked.
+#[debug_handler]
+async fn publish_device(
+    Extension(ctx): Extension<Arc<LighthouseCtx>>,
+    Json(payload): Json<PublishDevicePayload>,
+) -> Result<Response, LighthouseError> {
+    let db = ctx.db.write().await;
+
+    // verify the payload, and that the certificate is for the key that signed it
+    payload.verify()?;
+    let (public_key, _headers) = SignedPublicKey::from_string(&payload.public_key)?;
+    if hex::encode(public_key.fingerprint()) != payload.fingerprint {
+        return Err(LighthouseError::FingerprintMismatch);
+    }
+    let certificate =
+        hex::decode(&payload.certificate).map_err(|_| LighthouseError::InvalidCertificate)?;
+    let (device, _) = verify_encoded_certificate(&certificate)
+        .map_err(|_| LighthouseError::InvalidCertificate)?;
+    if hex::encode(device.device.fingerprint()) != payload.fingerprint {
+        return Err(LighthouseError::FingerprintMismatch);
+    }
+    let identity = hex::encode(device.identity.fingerprint());
+    if is_revoked(&db, &payload.fingerprint).await? || is_revoked(&db, &identity).await? {
+        return Err(LighthouseError::KeyRevoked);
+    }
+
+    db.device()
+        .upsert(
+            lighthouse_prisma::device::fingerprint::equals(payload.fingerprint.clone()),
+            lighthouse_prisma::device::create(
+                payload.fingerprint,
+                identity.clone(),
+                payload.certificate.clone(),
+                vec![],
+            ),
+            vec![
+                lighthouse_prisma::device::identity::set(identity),
+                lighthouse_prisma::device::certificate::set(payload.certificate),
+                lighthouse_prisma::device::updated_at::set(chrono::Utc::now().fixed_offset()),
+            ],
+        )
+        .exec()
+        .await?;
+
+    Ok(Json(()).into_response())
+}
+
+/// This endpoint handles the lookup of the devices of an identity.
+#[debug_handler]
+async fn get_devices(
+    Path(fingerprint): Path<String>,
+    Extension(ctx): Extension<Arc<LighthouseCtx>>,
+) -> Result<Response, LighthouseError> {
+    let db = ctx.db.write().await;
+
+    let devices = db
+        .device()
+        .find_many(vec![lighthouse_prisma::device::identity::equals(
+            fingerprint,
+        )])
+        .exec()
+        .await?;
+
+    Ok(Json(GetDevicesResponse {
+        certificates: devices
+            .into_iter()
+            .map(|device| device.certificate)
+            .collect(),
+    })
+    .into_response())
+}
+
+/// Returns true if a revocation of the key with the given fingerprint was registered.
+async fn is_revoked(db: &PrismaClient, fingerprint: &str) -> Result<bool, LighthouseError> {
+    let revocation = db
+        .key_statement()
+        .find_first(vec![
+            lighthouse_prisma::key_statement::fingerprint::equals(fingerprint.to_string()),
+            lighthouse_prisma::key_statement::kind::equals(
+                KeyStatementKind::Revocation.as_str().to_string(),
+            ),
+        ])
+        .exec()
+        .await?;
+    Ok(revocation.is_some())
+}
+
 /// Handles errors from middleware.
 async fn handle_error(error: BoxError) -> impl IntoResponse {
     if error.is::<tower::timeout::error::Elapsed>() {
@@ -289,6 +574,12 @@ async fn main() {
         .route("/nodes", post(register_node_addr))
         .route("/nodes/:fingerprint", get(get_node_addr))
         .route("/peers", get(list_potential_peers))
+        .route("/prekeys", post(publish_prekeys))
+        .route("/prekeys/:fingerprint", get(get_prekeys))
+        .route("/keys/statements", post(publish_key_statement))
+        .route("/keys/:fingerprint/statements", get(get_key_statements))
+        .route("/devices", post(publish_device))
+        .route("/identities/:fingerprint/devices", get(get_devices))
         .route("/nodes", delete(wipe_node_entries)) // Testing purposes
         .layer(
             ServiceBuilder::new()
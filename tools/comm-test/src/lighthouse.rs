@@ -1,12 +1,11 @@
 use base64::prelude::*;
-use pgp::{composed::SignedSecretKey, types::SecretKeyTrait};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::{
     net::Ipv4Addr,
     str::{from_utf8, FromStr},
 };
-use string_comm::crypto::{Crypto, SigningError};
+use string_comm::{crypto::SigningError, UnlockedKey};
 use thiserror::Error;
 
 #[allow(dead_code)]
@@ -81,7 +80,7 @@ pub async fn register_endpoint(
     lighthouse_url: &String,
     ip: Option<Ipv4Addr>,
     port: u16,
-    secret_key: SignedSecretKey,
+    key: UnlockedKey,
 ) -> Result<String, LighthouseClientError> {
     let ip_addr = match ip {
         Some(ip) => ip,
@@ -90,14 +89,8 @@ pub async fn register_endpoint(
 
     let now: u32 = chrono::Utc::now().timestamp() as u32;
     let endpoint = format!("{}:{}", ip_addr, port);
-    let signature = hex::encode(Crypto::sign_data_static(
-        &secret_key.clone(),
-        &format!("{}-{}", endpoint, now).into_bytes(),
-    )?);
-    let pubkey = secret_key
-        .public_key()
-        .sign(&secret_key, || "".to_string())?
-        .to_armored_string(None)?;
+    let signature = hex::encode(key.sign(format!("{}-{}", endpoint, now).into_bytes())?);
+    let pubkey = key.signed_public_key()?.to_armored_string(None)?;
 
     let payload = RegisterEndpointPayload {
         endpoint,
@@ -150,13 +143,10 @@ pub async fn lookup_endpoint(
 pub async fn list_conns(
     lighthouse_url: &String,
     id: String,
-    secret_key: SignedSecretKey,
+    key: UnlockedKey,
 ) -> Result<Vec<(String, String)>, LighthouseClientError> {
     let now: u32 = chrono::Utc::now().timestamp() as u32;
-    let signature = hex::encode(Crypto::sign_data_static(
-        &secret_key.clone(),
-        &format!("{}-{}", id, now).into_bytes(),
-    )?);
+    let signature = hex::encode(key.sign(format!("{}-{}", id, now).into_bytes())?);
 
     let payload = ListConnPayload {
         id,
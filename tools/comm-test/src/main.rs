@@ -1,5 +1,4 @@
 use clap::Parser;
-use smallvec::*;
 use std::{
     env,
     fs::File,
@@ -9,18 +8,13 @@ use std::{
     sync::Arc,
     time::Duration,
 };
-use string_comm::Socket;
+use string_comm::{generate_key, KeyAlgorithm, Socket, UnlockedKey};
 use string_protocol::{messages, AttachmentType, ProtocolPacket, ProtocolPacketType};
 use tokio::sync::{mpsc, RwLock};
 use tracing::{error, info, level_filters::LevelFilter};
 use tracing_subscriber::EnvFilter;
 
-use pgp::{
-    composed::{key::SecretKeyParamsBuilder, KeyType, SignedSecretKey},
-    crypto::{hash::HashAlgorithm, sym::SymmetricKeyAlgorithm},
-    types::{CompressionAlgorithm, KeyTrait, SecretKeyTrait},
-    Deserializable,
-};
+use pgp::{composed::SignedSecretKey, Deserializable};
 
 use image::{guess_format, ImageFormat};
 
@@ -38,29 +32,12 @@ struct Args {
     /// Username of node, needed for cert gen
     #[clap(long)]
     username: String,
-}
-
-fn generate_key(username: String, password: String) -> SignedSecretKey {
-    let mut key_params = SecretKeyParamsBuilder::default();
-    key_params
-        .key_type(KeyType::Rsa(2048))
-        .can_certify(false)
-        .can_sign(true)
-        .primary_user_id(username)
-        .preferred_symmetric_algorithms(smallvec![SymmetricKeyAlgorithm::AES256,])
-        .preferred_hash_algorithms(smallvec![HashAlgorithm::SHA2_256,])
-        .preferred_compression_algorithms(smallvec![CompressionAlgorithm::ZLIB,]);
-
-    let secret_key_params = key_params
-        .build()
-        .expect("Must be able to create secret key params");
-    let secret_key = secret_key_params
-        .generate()
-        .expect("Failed to generate a plain key.");
-    let passwd_fn = || password;
-    secret_key
-        .sign(passwd_fn)
-        .expect("Must be able to sign its own metadata")
+    /// Passphrase that encrypts the secret key on disk
+    #[clap(long)]
+    passphrase: String,
+    /// Algorithm of the key, if one is generated: ed25519, rsa3072 or rsa4096
+    #[clap(long, default_value_t = KeyAlgorithm::Ed25519)]
+    algorithm: KeyAlgorithm,
 }
 
 fn load_key(location: &String) -> Option<SignedSecretKey> {
@@ -129,6 +106,8 @@ async fn main() {
         port: bind_port,
         lighthouse_url,
         username,
+        passphrase,
+        algorithm,
     } = Args::parse();
 
     // if env::var("RUST_LOG").is_err() {
@@ -148,13 +127,29 @@ async fn main() {
     let secret_key = match load_key(&key_path) {
         Some(secret) => secret,
         None => {
-            info!("[*] Key not found, generating with username {0}", username);
-            let secret = generate_key(username.clone(), "testpassword".to_string());
+            info!(
+                "[*] Key not found, generating {0} key with username {1}",
+                algorithm, username
+            );
+            let secret = match generate_key(username.clone(), passphrase.clone(), algorithm) {
+                Ok(secret) => secret,
+                Err(err) => {
+                    error!("[-] Failed to generate key: {}", err);
+                    return;
+                }
+            };
             save_key(&key_path, secret.clone());
             secret
         }
     };
-    let myfingerprint = secret_key.public_key().fingerprint();
+    let secret_key = match UnlockedKey::unlock(secret_key, passphrase) {
+        Ok(key) => key,
+        Err(_) => {
+            error!("[-] Wrong passphrase for key.");
+            return;
+        }
+    };
+    let myfingerprint = secret_key.fingerprint();
 
     info!("[+] Key loaded!");
 
@@ -197,9 +192,11 @@ async fn main() {
     let socket_locked_1 = socket_locked.clone();
 
     info!("[+] Use /conn <info string> to connect to a new peer");
-    info!("[+] Use /dr <username> to start a chat with user");
-    info!("[+] Then use /msg <username> <message> to send a message");
-    info!("[+] Then use /msgimg <username> <image path> to send an image");
+    info!("[+] Your node ID is {0}", socket_locked_1.read().await.id);
+    info!("[+] Use /cert <username> to look up the node IDs of a user");
+    info!("[+] Use /dr <node id> to start a chat with a node");
+    info!("[+] Then use /msg <node id> <message> to send a message");
+    info!("[+] Then use /msgimg <node id> <image path> to send an image");
     info!("[+] Chat log follows below:");
 
     tokio::task::spawn(async move {
@@ -210,6 +207,11 @@ async fn main() {
                         info!("<{0}>: {1}", m.username.clone(), m.content);
                         display_attachments(m.username, m.attachments);
                     }
+                    Some(ProtocolPacketType::PktSentTranscript(t)) => {
+                        if let Some(m) = t.message {
+                            info!("<{0} to {1}>: {2}", m.username, t.destination, m.content);
+                        }
+                    }
                     Some(_) => {}
                     None => {}
                 }
@@ -262,11 +264,39 @@ async fn main() {
                             .await;
                         info!("[+] Done DR with {0}", rest.to_string());
                     } else if prefix == "cert" {
-                        let _ = socket_locked_1
+                        match socket_locked_1
                             .write()
                             .await
                             .get_node_cert(rest.to_string())
-                            .await;
+                            .await
+                        {
+                            Ok(ids) => {
+                                for id in ids {
+                                    info!("[+] {0} is used by {1}", rest, id);
+                                }
+                            }
+                            Err(err) => info!("[-] No node uses {0}: {1}", rest, err),
+                        }
+                    } else if prefix == "ping" {
+                        let socket = socket_locked_1.read().await.clone();
+                        match socket.ping(rest.to_string()).await {
+                            Ok(rtt) => info!("[+] {0} replied in {1:?}", rest, rtt),
+                            Err(err) => info!("[-] No reply from {0}: {1}", rest, err),
+                        }
+                    } else if prefix == "trace" {
+                        let socket = socket_locked_1.read().await.clone();
+                        match socket.trace(rest.to_string()).await {
+                            Ok(trace) => {
+                                info!("[+] {0} replied in {1:?}", rest, trace.rtt);
+                                for hop in trace.path {
+                                    info!("    -> {0} at {1:?}", hop.node_id, hop.time);
+                                }
+                                for hop in trace.return_path {
+                                    info!("    <- {0} at {1:?}", hop.node_id, hop.time);
+                                }
+                            }
+                            Err(err) => info!("[-] No reply from {0}: {1}", rest, err),
+                        }
                     } else if prefix == "msg" {
                         if let Some((destination, message)) = rest.split_once(' ') {
                             let message = messages::v1::Message {